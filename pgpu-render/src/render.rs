@@ -15,7 +15,7 @@
 // Also licensed under MIT license, at your choice.
 
 use piet_gpu::{EncodedSceneRef, PixelFormat, RenderConfig};
-use piet_gpu_hal::{QueryPool, Session};
+use piet_gpu_hal::Session;
 use piet_scene::geometry::{Affine, Rect};
 use piet_scene::glyph::pinot::{types::Tag, FontDataRef};
 use piet_scene::glyph::{GlyphContext, GlyphProvider};
@@ -26,7 +26,6 @@ use piet_scene::scene::{Fragment, Scene};
 pub struct PgpuRenderer {
     session: Session,
     pgpu_renderer: Option<piet_gpu::Renderer>,
-    query_pool: QueryPool,
     width: u32,
     height: u32,
     is_color: bool,
@@ -40,11 +39,9 @@ impl PgpuRenderer {
     pub fn new(device: &metal::DeviceRef, queue: &metal::CommandQueueRef) -> Self {
         let piet_device = piet_gpu_hal::Device::new_from_raw_mtl(device, &queue);
         let session = Session::new(piet_device);
-        let query_pool = session.create_query_pool(12).unwrap();
         Self {
             session,
             pgpu_renderer: None,
-            query_pool,
             width: 0,
             height: 0,
             is_color: false,
@@ -90,7 +87,7 @@ impl PgpuRenderer {
                 .image_from_raw_mtl(target, self.width, self.height);
             if let Some(renderer) = &mut self.pgpu_renderer {
                 renderer.upload_scene(&scene.encoded_scene(), 0).unwrap();
-                renderer.record(&mut cmd_buf, &self.query_pool, 0);
+                renderer.record(&mut cmd_buf, 0).unwrap();
                 // TODO later: we can bind the destination image and avoid the copy.
                 cmd_buf.blit_image(&renderer.image_dev, &dst_image);
                 cmd_buf.flush();
@@ -1,15 +1,20 @@
-use piet_gpu_hal::{include_shader, BindType, ComputePassDescriptor};
+use piet_gpu_hal::{include_shader, BindType, ComputePassDescriptor, CpuDispatch};
 use piet_gpu_hal::{BufferUsage, Instance, InstanceFlags, Session};
 
 fn main() {
-    let instance = Instance::new(InstanceFlags::empty()).unwrap();
+    let flags = if std::env::args().any(|arg| arg == "--cpu") {
+        InstanceFlags::CPU
+    } else {
+        InstanceFlags::empty()
+    };
+    let instance = Instance::new(flags).unwrap();
     unsafe {
         let device = instance.device().unwrap();
         let session = Session::new(device);
         let usage = BufferUsage::MAP_READ | BufferUsage::STORAGE;
         let src = (0..256).map(|x| x + 1).collect::<Vec<u32>>();
         let buffer = session.create_buffer_init(&src, usage).unwrap();
-        let code = include_shader!(&session, "./shader/gen/collatz");
+        let code = include_shader!(&session, "./shader/gen/collatz", collatz_cpu);
         let pipeline = session
             .create_compute_pipeline(code, &[BindType::Buffer])
             .unwrap();
@@ -37,3 +42,22 @@ fn main() {
         println!("{:?}", timestamps);
     }
 }
+
+/// A port of the collatz shader, for the CPU backend.
+fn collatz_cpu(dispatch: &CpuDispatch) {
+    let n_invocations = dispatch.workgroup_count.0 * dispatch.workgroup_size.0;
+    let mut indices = dispatch.bindings[0].as_buf();
+    for index in indices.iter_mut().take(n_invocations as usize) {
+        let mut n = *index;
+        let mut i = 0;
+        while n != 1 {
+            if n % 2 == 0 {
+                n /= 2;
+            } else {
+                n = 3 * n + 1;
+            }
+            i += 1;
+        }
+        *index = i;
+    }
+}
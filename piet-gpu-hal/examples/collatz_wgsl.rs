@@ -0,0 +1,65 @@
+use piet_gpu_hal::{BindType, ComputePassDescriptor, ShaderCode};
+use piet_gpu_hal::{BufferUsage, Instance, InstanceFlags, Session};
+
+const COLLATZ: &str = r#"
+struct Indices {
+    data: array<u32>;
+};
+
+[[group(0), binding(0)]]
+var<storage, read_write> indices: Indices;
+
+[[stage(compute), workgroup_size(1)]]
+fn collatz([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    var n = indices.data[id.x];
+    var i = 0u;
+    loop {
+        if (n <= 1u) {
+            break;
+        }
+        if (n % 2u == 0u) {
+            n = n / 2u;
+        } else {
+            n = 3u * n + 1u;
+        }
+        i = i + 1u;
+    }
+    indices.data[id.x] = i;
+}
+"#;
+
+fn main() {
+    let instance = Instance::new(InstanceFlags::empty()).unwrap();
+    unsafe {
+        let device = instance.device().unwrap();
+        let session = Session::new(device);
+        let usage = BufferUsage::MAP_READ | BufferUsage::STORAGE;
+        let src = (0..256).map(|x| x + 1).collect::<Vec<u32>>();
+        let buffer = session.create_buffer_init(&src, usage).unwrap();
+        let pipeline =
+            match session.create_compute_pipeline(ShaderCode::Wgsl(COLLATZ), &[BindType::Buffer]) {
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+        let descriptor_set = session
+            .create_simple_descriptor_set(&pipeline, &[&buffer])
+            .unwrap();
+        let mut cmd_buf = session.cmd_buf().unwrap();
+        cmd_buf.begin();
+        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.dispatch(&pipeline, &descriptor_set, (256, 1, 1), (1, 1, 1));
+        pass.end();
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        let submitted = session.run_cmd_buf(cmd_buf, &[], &[]).unwrap();
+        submitted.wait().unwrap();
+        let mut dst: Vec<u32> = Default::default();
+        buffer.read(&mut dst).unwrap();
+        for (i, val) in dst.iter().enumerate().take(16) {
+            println!("{}: {}", i, val);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Replay a capture made with `Session::start_capture`, and write the
+//! contents of the buffers to files.
+//!
+//! Usage: `replay <capture> [<output dir>] [--cpu] [--adapter <name>]`
+//!
+//! The adapter is the first one whose name contains the given string, for
+//! example "llvmpipe" for a software Vulkan device.
+
+use std::path::PathBuf;
+
+use piet_gpu_hal::{Instance, InstanceFlags, Session};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut paths = Vec::new();
+    let mut flags = InstanceFlags::empty();
+    let mut adapter = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cpu" => flags |= InstanceFlags::CPU,
+            "--adapter" => adapter = args.next(),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let capture_path = paths.first().expect("no capture file given");
+    let out_dir = paths.get(1).cloned().unwrap_or_else(|| ".".into());
+    let capture = std::fs::read(capture_path).unwrap();
+    let instance = Instance::new(flags).unwrap();
+    unsafe {
+        let device = match &adapter {
+            Some(name) => {
+                let adapters = instance.adapters().unwrap();
+                let adapter = adapters
+                    .iter()
+                    .find(|a| a.name.contains(name.as_str()))
+                    .unwrap_or_else(|| panic!("no adapter matching {:?}", name));
+                instance.device_from_adapter(adapter).unwrap()
+            }
+            None => instance.device().unwrap(),
+        };
+        let session = Session::new(device);
+        println!(
+            "replaying on {} ({:?})",
+            session.gpu_info().adapter_name,
+            session.backend_type()
+        );
+        let replay = session.replay(&capture).unwrap();
+        std::fs::create_dir_all(&out_dir).unwrap();
+        for (id, buffer) in replay.buffers() {
+            let contents = replay.read_buffer(&session, id).unwrap();
+            let path = out_dir.join(format!("buffer-{}.bin", id));
+            std::fs::write(&path, &contents).unwrap();
+            println!("{}: {} bytes", path.display(), buffer.size());
+        }
+    }
+}
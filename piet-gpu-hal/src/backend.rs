@@ -17,8 +17,9 @@
 //! The generic trait for backends to implement.
 
 use crate::{
-    BindType, BufferUsage, ComputePassDescriptor, Error, GpuInfo, ImageFormat, ImageLayout,
-    MapMode, SamplerParams,
+    BindType, BufferImageLayout, BufferUsage, ComputePassDescriptor, Error, FormatCapabilities,
+    GpuInfo, ImageFormat, ImageLayout, ImageRegion, MapMode, MemoryBudget, MemoryPoolStats,
+    PipelineOptions, QueueType, SamplerParams,
 };
 
 pub trait Device: Sized {
@@ -33,6 +34,7 @@ pub trait Device: Sized {
     type DescriptorSetBuilder: DescriptorSetBuilder<Self>;
     type Sampler;
     type ShaderSource: ?Sized;
+    type PipelineCache;
 
     /// Query the GPU info.
     ///
@@ -42,6 +44,31 @@ pub trait Device: Sized {
 
     fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Self::Buffer, Error>;
 
+    /// Sub-allocate buffers from pooled memory blocks of the given size.
+    ///
+    /// This only affects buffers created after the call. Enabling the pool
+    /// again with a different block size is an error. The default
+    /// implementation does no pooling.
+    fn enable_memory_pool(&self, _block_size: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Query statistics of the memory pool.
+    fn memory_pool_stats(&self) -> MemoryPoolStats {
+        MemoryPoolStats::default()
+    }
+
+    /// Free memory blocks of the pool that have no live buffers.
+    unsafe fn trim_memory_pool(&self) {}
+
+    /// Query the current budget and usage of each memory heap.
+    ///
+    /// The heaps are in the order of [`GpuInfo::memory_heaps`]. The default
+    /// implementation reports that the budget can't be queried.
+    fn query_memory_budget(&self) -> Option<Vec<MemoryBudget>> {
+        None
+    }
+
     /// Destroy a buffer.
     ///
     /// The same safety requirements hold as in Vulkan: the buffer cannot be used
@@ -50,6 +77,12 @@ pub trait Device: Sized {
     /// Maybe doesn't need result return?
     unsafe fn destroy_buffer(&self, buffer: &Self::Buffer) -> Result<(), Error>;
 
+    /// Query the operations supported for images of a format.
+    fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities;
+
+    /// Create a 2D image.
+    ///
+    /// The image supports all the capabilities reported for its format.
     unsafe fn create_image2d(
         &self,
         width: u32,
@@ -70,13 +103,45 @@ pub trait Device: Sized {
     /// Build a compute pipeline.
     ///
     /// A pipeline is a bit of shader IR plus a signature for what kinds of resources
-    /// it expects.
+    /// it expects. If a cache is given, it is used to look up the compiled
+    /// pipeline, and the result is stored in it.
+    ///
+    /// Backends that compile source at pipeline creation apply the
+    /// specialization constants in the options; others receive code that
+    /// was already specialized.
     unsafe fn create_compute_pipeline(
         &self,
         code: &Self::ShaderSource,
         bind_types: &[BindType],
+        options: &PipelineOptions,
+        cache: Option<&Self::PipelineCache>,
     ) -> Result<Self::Pipeline, Error>;
 
+    /// Identify the device and driver version.
+    ///
+    /// Cache data is only valid on a device with the same key.
+    fn pipeline_cache_key(&self) -> Vec<u8>;
+
+    /// Create a pipeline cache, initialized with data from
+    /// [`pipeline_cache_data`][Device::pipeline_cache_data], or empty.
+    ///
+    /// The caller is responsible for checking that the data was produced on
+    /// a device with the same [`pipeline_cache_key`][Device::pipeline_cache_key].
+    /// The driver can still reject the data, in which case the cache starts
+    /// out empty; the returned flag is whether the data was accepted.
+    unsafe fn create_pipeline_cache(
+        &self,
+        data: &[u8],
+    ) -> Result<(Self::PipelineCache, bool), Error>;
+
+    /// Serialize the contents of a pipeline cache.
+    unsafe fn pipeline_cache_data(&self, cache: &Self::PipelineCache) -> Result<Vec<u8>, Error>;
+
+    /// Destroy a pipeline cache.
+    ///
+    /// Pipelines created with the cache remain valid.
+    unsafe fn destroy_pipeline_cache(&self, cache: &Self::PipelineCache) -> Result<(), Error>;
+
     /// Start building a descriptor set.
     ///
     /// A descriptor set is a binding of resources for a given pipeline.
@@ -128,7 +193,15 @@ pub trait Device: Sized {
         image: &Self::Image,
     );
 
-    fn create_cmd_buf(&self) -> Result<Self::CmdBuf, Error>;
+    /// Create a command buffer to be submitted to the given queue.
+    fn create_cmd_buf(&self, queue: QueueType) -> Result<Self::CmdBuf, Error>;
+
+    /// Whether the device has a dedicated queue of the given type.
+    ///
+    /// When it does not, work for that queue is submitted to the main queue.
+    fn has_dedicated_queue(&self, queue: QueueType) -> bool {
+        queue == QueueType::Main
+    }
 
     /// If the command buffer was submitted, it must complete before this is called.
     unsafe fn destroy_cmd_buf(&self, cmd_buf: Self::CmdBuf) -> Result<(), Error>;
@@ -144,12 +217,16 @@ pub trait Device: Sized {
     /// All submitted commands that refer to this query pool must have completed.
     unsafe fn fetch_query_pool(&self, pool: &Self::QueryPool) -> Result<Vec<f64>, Error>;
 
+    /// Submit command buffers to a queue.
+    ///
+    /// The command buffers must have been created for that queue.
     unsafe fn run_cmd_bufs(
         &self,
         cmd_buf: &[&Self::CmdBuf],
         wait_semaphores: &[&Self::Semaphore],
         signal_semaphores: &[&Self::Semaphore],
         fence: Option<&mut Self::Fence>,
+        queue: QueueType,
     ) -> Result<(), Error>;
 
     /// Map the buffer into addressable memory.
@@ -218,6 +295,9 @@ pub trait CmdBuf<D: Device> {
 
     /// Dispatch
     ///
+    /// The push constants are empty unless the pipeline declares a block of
+    /// them, in which case they match its size.
+    ///
     /// State: in_compute_pass
     unsafe fn dispatch(
         &mut self,
@@ -225,6 +305,25 @@ pub trait CmdBuf<D: Device> {
         descriptor_set: &D::DescriptorSet,
         workgroup_count: (u32, u32, u32),
         workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    );
+
+    /// Dispatch, with the workgroup count read from a buffer.
+    ///
+    /// The buffer holds three `u32` values (x, y, z) at the given offset, which
+    /// must be a multiple of 4. It needs to be created with
+    /// `BufferUsage::INDIRECT`, and the values may be written by an earlier
+    /// dispatch, separated by a memory barrier.
+    ///
+    /// State: in_compute_pass
+    unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &D::Pipeline,
+        descriptor_set: &D::DescriptorSet,
+        buffer: &D::Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
     );
 
     /// State: in_compute_pass -> ready
@@ -252,6 +351,38 @@ pub trait CmdBuf<D: Device> {
         dst_layout: ImageLayout,
     );
 
+    /// Insert a buffer barrier that transfers ownership between queues.
+    ///
+    /// The same barrier is recorded on the source queue to release the
+    /// buffer, then on the destination queue to acquire it, with the
+    /// submissions ordered by a semaphore. Backends without queue ownership
+    /// only need a memory barrier.
+    unsafe fn buffer_queue_transfer(
+        &mut self,
+        _buffer: &D::Buffer,
+        _src: QueueType,
+        _dst: QueueType,
+    ) {
+        self.memory_barrier();
+    }
+
+    /// Insert an image barrier that transfers ownership between queues.
+    ///
+    /// This is recorded on both queues like [`buffer_queue_transfer`], with
+    /// the same layouts in each.
+    ///
+    /// [`buffer_queue_transfer`]: CmdBuf::buffer_queue_transfer
+    unsafe fn image_queue_transfer(
+        &mut self,
+        image: &D::Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+        _src: QueueType,
+        _dst: QueueType,
+    ) {
+        self.image_barrier(image, src_layout, dst_layout);
+    }
+
     /// Clear the buffer.
     ///
     /// This is readily supported in Vulkan, but for portability it is remarkably
@@ -268,6 +399,43 @@ pub trait CmdBuf<D: Device> {
     // low portability, dx12 doesn't support it natively
     unsafe fn blit_image(&mut self, src: &D::Image, dst: &D::Image);
 
+    /// Copy a range of bytes from one buffer to another.
+    unsafe fn copy_buffer_region(
+        &mut self,
+        src: &D::Buffer,
+        src_offset: u64,
+        dst: &D::Buffer,
+        dst_offset: u64,
+        size: u64,
+    );
+
+    /// Copy a region of an image to a buffer.
+    unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &D::Image,
+        region: ImageRegion,
+        dst: &D::Buffer,
+        layout: BufferImageLayout,
+    );
+
+    /// Copy from a buffer to a region of an image.
+    unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &D::Buffer,
+        layout: BufferImageLayout,
+        dst: &D::Image,
+        region: ImageRegion,
+    );
+
+    /// Copy a region of an image to another of the same format, without scaling.
+    unsafe fn copy_image_region(
+        &mut self,
+        src: &D::Image,
+        region: ImageRegion,
+        dst: &D::Image,
+        dst_origin: (u32, u32),
+    );
+
     /// Reset the query pool.
     ///
     /// The query pool must be reset before each use, to avoid validation errors.
@@ -291,9 +459,19 @@ pub trait CmdBuf<D: Device> {
 /// A builder for descriptor sets with more complex layouts.
 ///
 /// Note: the order needs to match the pipeline building, and it also needs to
-/// be buffers, then images, then textures.
+/// be buffers, then images, then textures, then sampled images.
 pub trait DescriptorSetBuilder<D: Device> {
+    /// Add an array of buffers.
+    ///
+    /// These are bound as storage or uniform buffers, depending on the bind
+    /// type in the pipeline.
     fn add_buffers(&mut self, buffers: &[&D::Buffer]);
+    /// Add an array of buffer slices, each given as (buffer, offset, size) in bytes.
+    ///
+    /// Slices take binding slots in sequence with whole buffers. The offset
+    /// must be a multiple of `GpuInfo::buffer_offset_alignment`, and the size
+    /// a multiple of 4.
+    fn add_buffer_slices(&mut self, slices: &[(&D::Buffer, u64, u64)]);
     /// Add an array of storage images.
     ///
     /// The images need to be in `ImageLayout::General` layout.
@@ -305,5 +483,9 @@ pub trait DescriptorSetBuilder<D: Device> {
     /// The same sampler is used for all textures, which is not very sophisticated;
     /// we should have a way to vary the sampler.
     fn add_textures(&mut self, images: &[&D::Image]);
+    /// Add an array of images, read through a sampler.
+    ///
+    /// The images need to be in `ImageLayout::ShaderRead` layout.
+    fn add_sampled_images(&mut self, images: &[&D::Image], sampler: &D::Sampler);
     unsafe fn build(self, device: &D, pipeline: &D::Pipeline) -> Result<D::DescriptorSet, Error>;
 }
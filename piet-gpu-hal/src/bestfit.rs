@@ -44,6 +44,15 @@ impl BestFit {
         Some(ix)
     }
 
+    /// The size of the largest free block.
+    pub fn largest_free(&self) -> u32 {
+        self.free_by_size
+            .iter()
+            .next_back()
+            .map(|(size, _)| *size)
+            .unwrap_or(0)
+    }
+
     pub fn free(&mut self, ix: u32, size: u32) {
         let next_ix = size + ix;
         if let Some((&prev_ix, &prev_size)) = self.free_by_ix.range(..ix).rev().next() {
@@ -0,0 +1,1023 @@
+// Copyright 2021 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! A backend that runs entirely on the host.
+//!
+//! Instead of compiling shaders, this backend runs Rust ports of compute
+//! kernels, provided as [`ShaderCode::Cpu`][crate::ShaderCode::Cpu]. Command
+//! buffers are recorded as a list of commands, and executed synchronously on
+//! submission. The main use is running tests on machines without a GPU, and
+//! comparing results against the GPU backends.
+//!
+//! Creating a pipeline from a shader without a CPU port fails with
+//! [`Error::Unsupported`]. That currently includes all the kernels of the
+//! piet-gpu renderer.
+
+use std::convert::TryFrom;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::{
+    AdapterInfo, BackendType, BindType, BufferImageLayout, BufferUsage, ComputePassDescriptor,
+    DeviceType, Error, FormatCapabilities, GpuInfo, ImageFormat, ImageLayout, ImageRegion, MapMode,
+    PipelineOptions, QueueType, SamplerParams, SubgroupFeatures, WorkgroupLimits,
+};
+
+const ADAPTER_NAME: &str = "CPU";
+
+/// A compute kernel ported to run on the CPU.
+///
+/// The function is called once per dispatch, and is responsible for the
+/// entire grid of workgroups.
+pub type CpuShader = fn(&CpuDispatch);
+
+/// The parameters of a dispatch, as seen by a CPU kernel.
+pub struct CpuDispatch<'a> {
+    /// The number of workgroups in each dimension.
+    pub workgroup_count: (u32, u32, u32),
+    /// The number of threads in a workgroup in each dimension.
+    pub workgroup_size: (u32, u32, u32),
+    /// The resources bound to the kernel, in binding order.
+    pub bindings: &'a [CpuBinding<'a>],
+    /// The push constants, empty if the pipeline doesn't declare any.
+    pub push_constants: &'a [u8],
+}
+
+/// A resource bound to a CPU kernel.
+///
+/// Contents are stored as 32 bit words, which suits the way our shaders
+/// address buffers. Each binding is separately locked; a kernel that binds
+/// the same buffer twice must not hold both locks at the same time.
+pub enum CpuBinding<'a> {
+    Buffer(&'a Mutex<Box<[u32]>>),
+    /// A slice of a buffer, with the range given in words.
+    BufferSlice {
+        data: &'a Mutex<Box<[u32]>>,
+        range: Range<usize>,
+    },
+    Image {
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        data: &'a Mutex<Box<[u32]>>,
+        /// The sampler, for a sampled image. Kernels do their own filtering.
+        sampler: Option<SamplerParams>,
+    },
+}
+
+/// A locked buffer binding, which dereferences to the words it covers.
+pub struct CpuBufGuard<'a> {
+    guard: MutexGuard<'a, Box<[u32]>>,
+    range: Range<usize>,
+}
+
+pub struct CpuInstance;
+
+pub struct CpuDevice {
+    /// Origin for timestamp queries.
+    start: Instant,
+}
+
+/// A surface for the CPU backend.
+///
+/// The CPU backend can't present, so this can never be constructed.
+pub enum CpuSurface {}
+
+/// A swapchain for the CPU backend, which can never be constructed.
+pub enum CpuSwapchain {}
+
+#[derive(Clone)]
+pub struct Buffer {
+    data: Arc<Mutex<Box<[u32]>>>,
+    pub size: u64,
+}
+
+#[derive(Clone)]
+pub struct Image {
+    data: Arc<Mutex<Box<[u32]>>>,
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+}
+
+pub struct Pipeline(CpuShader);
+
+#[derive(Clone)]
+pub struct DescriptorSet(Vec<Binding>);
+
+#[derive(Default)]
+pub struct DescriptorSetBuilder(Vec<Binding>);
+
+#[derive(Clone)]
+enum Binding {
+    Buffer(Buffer),
+    /// A buffer with offset and size in bytes.
+    BufferSlice(Buffer, u64, u64),
+    Image(Image),
+    SampledImage(Image, SamplerParams),
+}
+
+#[derive(Clone)]
+pub struct QueryPool(Arc<Mutex<Vec<f64>>>);
+
+pub struct CmdBuf {
+    commands: Vec<Command>,
+    start: Instant,
+    end_query: Option<(QueryPool, u32)>,
+}
+
+/// A recorded command.
+///
+/// Resources are retained by reference count, so the command buffer can
+/// be executed any time after recording.
+enum Command {
+    Dispatch {
+        shader: CpuShader,
+        bindings: Vec<Binding>,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: Vec<u8>,
+    },
+    DispatchIndirect {
+        shader: CpuShader,
+        bindings: Vec<Binding>,
+        buffer: Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: Vec<u8>,
+    },
+    ClearBuffer(Buffer, Option<u64>),
+    CopyBuffer(Buffer, Buffer),
+    CopyImageToBuffer(Image, Buffer),
+    CopyBufferToImage(Buffer, Image),
+    BlitImage(Image, Image),
+    CopyBufferRegion {
+        src: Buffer,
+        src_offset: u64,
+        dst: Buffer,
+        dst_offset: u64,
+        size: u64,
+    },
+    CopyImageRegionToBuffer(Image, ImageRegion, Buffer, BufferImageLayout),
+    CopyBufferToImageRegion(Buffer, BufferImageLayout, Image, ImageRegion),
+    CopyImageRegion(Image, ImageRegion, Image, (u32, u32)),
+    ResetQueryPool(QueryPool),
+    WriteTimestamp(QueryPool, u32),
+}
+
+/// A fence.
+///
+/// Since command buffers are run synchronously, this just records whether
+/// a submission has happened.
+pub struct Fence(bool);
+
+pub struct Semaphore;
+
+impl CpuInstance {
+    pub fn new() -> Result<CpuInstance, Error> {
+        Ok(CpuInstance)
+    }
+
+    pub unsafe fn surface(
+        &self,
+        _window_handle: &dyn raw_window_handle::HasRawWindowHandle,
+    ) -> Result<CpuSurface, Error> {
+        Err("CPU backend doesn't support presentation".into())
+    }
+
+    pub unsafe fn device(&self) -> Result<CpuDevice, Error> {
+        Ok(CpuDevice {
+            start: Instant::now(),
+        })
+    }
+
+    /// The CPU backend has a single adapter, the host.
+    pub fn adapters(&self) -> Result<Vec<AdapterInfo>, Error> {
+        Ok(vec![AdapterInfo {
+            name: ADAPTER_NAME.into(),
+            vendor_id: 0,
+            device_id: 0,
+            device_type: DeviceType::Software,
+            backend: BackendType::Cpu,
+            index: 0,
+        }])
+    }
+
+    pub unsafe fn device_from_adapter(&self, _adapter: &AdapterInfo) -> Result<CpuDevice, Error> {
+        self.device()
+    }
+
+    pub unsafe fn swapchain(
+        &self,
+        _width: usize,
+        _height: usize,
+        _device: &CpuDevice,
+        surface: &CpuSurface,
+        _formats: &[ImageFormat],
+    ) -> Result<CpuSwapchain, Error> {
+        match *surface {}
+    }
+}
+
+impl crate::backend::Device for CpuDevice {
+    type Buffer = Buffer;
+
+    type Image = Image;
+
+    type Pipeline = Pipeline;
+
+    type DescriptorSet = DescriptorSet;
+
+    type QueryPool = QueryPool;
+
+    type CmdBuf = CmdBuf;
+
+    type Fence = Fence;
+
+    type Semaphore = Semaphore;
+
+    type DescriptorSetBuilder = DescriptorSetBuilder;
+
+    type Sampler = SamplerParams;
+
+    type ShaderSource = CpuShader;
+
+    /// Nothing is compiled, so there's nothing to cache.
+    type PipelineCache = ();
+
+    fn query_gpu_info(&self) -> GpuInfo {
+        GpuInfo {
+            has_descriptor_indexing: false,
+            has_subgroups: false,
+            subgroup_features: SubgroupFeatures::empty(),
+            workgroup_limits: WorkgroupLimits {
+                max_size: [1024, 1024, 64],
+                max_invocations: 1024,
+            },
+            subgroup_size: None,
+            has_memory_model: false,
+            use_staging_buffers: false,
+            adapter_name: ADAPTER_NAME.into(),
+            buffer_offset_alignment: 4,
+            has_timestamp_writes: true,
+            // Buffers live in ordinary process memory.
+            memory_heaps: Vec::new(),
+        }
+    }
+
+    fn create_buffer(&self, size: u64, _usage: BufferUsage) -> Result<Self::Buffer, Error> {
+        Ok(Buffer {
+            data: Arc::new(Mutex::new(alloc_zeroed(size)?)),
+            size,
+        })
+    }
+
+    unsafe fn destroy_buffer(&self, _buffer: &Self::Buffer) -> Result<(), Error> {
+        // Memory is freed when the last reference is dropped.
+        Ok(())
+    }
+
+    fn format_capabilities(&self, _format: ImageFormat) -> FormatCapabilities {
+        // Kernels get the raw pixel data, so any format can be bound. Note
+        // that blits copy pixels without scaling or conversion.
+        FormatCapabilities::all()
+    }
+
+    unsafe fn create_image2d(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Self::Image, Error> {
+        let n_bytes = width as u64 * height as u64 * format.bytes_per_pixel() as u64;
+        Ok(Image {
+            data: Arc::new(Mutex::new(alloc_zeroed(n_bytes)?)),
+            width,
+            height,
+            format,
+        })
+    }
+
+    unsafe fn destroy_image(&self, _image: &Self::Image) -> Result<(), Error> {
+        Ok(())
+    }
+
+    unsafe fn create_compute_pipeline(
+        &self,
+        code: &Self::ShaderSource,
+        _bind_types: &[BindType],
+        options: &PipelineOptions,
+        _cache: Option<&()>,
+    ) -> Result<Self::Pipeline, Error> {
+        if !options.constants.is_empty() {
+            return Err(Error::unsupported("specialization constants on CPU"));
+        }
+        Ok(Pipeline(*code))
+    }
+
+    fn pipeline_cache_key(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    unsafe fn create_pipeline_cache(&self, _data: &[u8]) -> Result<((), bool), Error> {
+        Ok(((), false))
+    }
+
+    unsafe fn pipeline_cache_data(&self, _cache: &()) -> Result<Vec<u8>, Error> {
+        Ok(Vec::new())
+    }
+
+    unsafe fn destroy_pipeline_cache(&self, _cache: &()) -> Result<(), Error> {
+        Ok(())
+    }
+
+    unsafe fn descriptor_set_builder(&self) -> Self::DescriptorSetBuilder {
+        DescriptorSetBuilder::default()
+    }
+
+    unsafe fn update_buffer_descriptor(
+        &self,
+        ds: &mut Self::DescriptorSet,
+        index: u32,
+        buf: &Self::Buffer,
+    ) {
+        ds.0[index as usize] = Binding::Buffer(buf.clone());
+    }
+
+    unsafe fn update_image_descriptor(
+        &self,
+        ds: &mut Self::DescriptorSet,
+        index: u32,
+        image: &Self::Image,
+    ) {
+        ds.0[index as usize] = Binding::Image(image.clone());
+    }
+
+    fn create_cmd_buf(&self, _queue: QueueType) -> Result<Self::CmdBuf, Error> {
+        Ok(CmdBuf {
+            commands: Vec::new(),
+            start: self.start,
+            end_query: None,
+        })
+    }
+
+    unsafe fn destroy_cmd_buf(&self, _cmd_buf: Self::CmdBuf) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn create_query_pool(&self, n_queries: u32) -> Result<Self::QueryPool, Error> {
+        Ok(QueryPool(Arc::new(Mutex::new(vec![
+            0.0;
+            n_queries as usize
+        ]))))
+    }
+
+    unsafe fn fetch_query_pool(&self, pool: &Self::QueryPool) -> Result<Vec<f64>, Error> {
+        Ok(pool.0.lock().unwrap().clone())
+    }
+
+    unsafe fn run_cmd_bufs(
+        &self,
+        cmd_bufs: &[&Self::CmdBuf],
+        _wait_semaphores: &[&Self::Semaphore],
+        _signal_semaphores: &[&Self::Semaphore],
+        fence: Option<&mut Self::Fence>,
+        _queue: QueueType,
+    ) -> Result<(), Error> {
+        for cmd_buf in cmd_bufs {
+            cmd_buf.execute();
+        }
+        if let Some(fence) = fence {
+            fence.0 = true;
+        }
+        Ok(())
+    }
+
+    unsafe fn map_buffer(
+        &self,
+        buffer: &Self::Buffer,
+        offset: u64,
+        _size: u64,
+        _mode: MapMode,
+    ) -> Result<*mut u8, Error> {
+        // The boxed slice never moves, so the pointer stays valid after the
+        // lock is released. Command buffers run synchronously on submission,
+        // so there's no concurrent access from kernels.
+        let ptr = buffer.data.lock().unwrap().as_mut_ptr() as *mut u8;
+        Ok(ptr.add(offset as usize))
+    }
+
+    unsafe fn unmap_buffer(
+        &self,
+        _buffer: &Self::Buffer,
+        _offset: u64,
+        _size: u64,
+        _mode: MapMode,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    unsafe fn create_semaphore(&self) -> Result<Self::Semaphore, Error> {
+        Ok(Semaphore)
+    }
+
+    unsafe fn create_fence(&self, signaled: bool) -> Result<Self::Fence, Error> {
+        Ok(Fence(signaled))
+    }
+
+    unsafe fn destroy_fence(&self, _fence: Self::Fence) -> Result<(), Error> {
+        Ok(())
+    }
+
+    unsafe fn wait_and_reset(&self, fences: Vec<&mut Self::Fence>) -> Result<(), Error> {
+        for fence in fences {
+            fence.0 = false;
+        }
+        Ok(())
+    }
+
+    unsafe fn get_fence_status(&self, fence: &mut Self::Fence) -> Result<bool, Error> {
+        Ok(fence.0)
+    }
+
+    unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Self::Sampler, Error> {
+        Ok(params)
+    }
+}
+
+impl crate::backend::CmdBuf<CpuDevice> for CmdBuf {
+    unsafe fn begin(&mut self) {
+        self.commands.clear();
+    }
+
+    unsafe fn finish(&mut self) {}
+
+    unsafe fn flush(&mut self) {}
+
+    unsafe fn reset(&mut self) -> bool {
+        self.commands.clear();
+        self.end_query = None;
+        true
+    }
+
+    unsafe fn begin_compute_pass(&mut self, desc: &ComputePassDescriptor) {
+        if let Some((pool, start, end)) = &desc.timer_queries {
+            let pool = pool.cpu();
+            self.write_timestamp(pool, *start);
+            self.end_query = Some((pool.clone(), *end));
+        }
+    }
+
+    unsafe fn dispatch(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.commands.push(Command::Dispatch {
+            shader: pipeline.0,
+            bindings: descriptor_set.0.clone(),
+            workgroup_count,
+            workgroup_size,
+            push_constants: push_constants.to_owned(),
+        });
+    }
+
+    unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.commands.push(Command::DispatchIndirect {
+            shader: pipeline.0,
+            bindings: descriptor_set.0.clone(),
+            buffer: buffer.clone(),
+            offset,
+            workgroup_size,
+            push_constants: push_constants.to_owned(),
+        });
+    }
+
+    unsafe fn end_compute_pass(&mut self) {
+        if let Some((pool, end)) = self.end_query.take() {
+            self.write_timestamp(&pool, end);
+        }
+    }
+
+    unsafe fn memory_barrier(&mut self) {}
+
+    unsafe fn host_barrier(&mut self) {}
+
+    unsafe fn image_barrier(
+        &mut self,
+        _image: &Image,
+        _src_layout: ImageLayout,
+        _dst_layout: ImageLayout,
+    ) {
+    }
+
+    unsafe fn clear_buffer(&mut self, buffer: &Buffer, size: Option<u64>) {
+        self.commands
+            .push(Command::ClearBuffer(buffer.clone(), size));
+    }
+
+    unsafe fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) {
+        self.commands
+            .push(Command::CopyBuffer(src.clone(), dst.clone()));
+    }
+
+    unsafe fn copy_image_to_buffer(&mut self, src: &Image, dst: &Buffer) {
+        self.commands
+            .push(Command::CopyImageToBuffer(src.clone(), dst.clone()));
+    }
+
+    unsafe fn copy_buffer_to_image(&mut self, src: &Buffer, dst: &Image) {
+        self.commands
+            .push(Command::CopyBufferToImage(src.clone(), dst.clone()));
+    }
+
+    unsafe fn blit_image(&mut self, src: &Image, dst: &Image) {
+        self.commands
+            .push(Command::BlitImage(src.clone(), dst.clone()));
+    }
+
+    unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        self.commands.push(Command::CopyBufferRegion {
+            src: src.clone(),
+            src_offset,
+            dst: dst.clone(),
+            dst_offset,
+            size,
+        });
+    }
+
+    unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        self.commands.push(Command::CopyImageRegionToBuffer(
+            src.clone(),
+            region,
+            dst.clone(),
+            layout,
+        ));
+    }
+
+    unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        self.commands.push(Command::CopyBufferToImageRegion(
+            src.clone(),
+            layout,
+            dst.clone(),
+            region,
+        ));
+    }
+
+    unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        self.commands.push(Command::CopyImageRegion(
+            src.clone(),
+            region,
+            dst.clone(),
+            dst_origin,
+        ));
+    }
+
+    unsafe fn reset_query_pool(&mut self, pool: &QueryPool) {
+        self.commands.push(Command::ResetQueryPool(pool.clone()));
+    }
+
+    unsafe fn write_timestamp(&mut self, pool: &QueryPool, query: u32) {
+        self.commands
+            .push(Command::WriteTimestamp(pool.clone(), query));
+    }
+}
+
+impl CmdBuf {
+    fn execute(&self) {
+        for command in &self.commands {
+            match command {
+                Command::Dispatch {
+                    shader,
+                    bindings,
+                    workgroup_count,
+                    workgroup_size,
+                    push_constants,
+                } => {
+                    run_dispatch(
+                        *shader,
+                        bindings,
+                        *workgroup_count,
+                        *workgroup_size,
+                        push_constants,
+                    );
+                }
+                Command::DispatchIndirect {
+                    shader,
+                    bindings,
+                    buffer,
+                    offset,
+                    workgroup_size,
+                    push_constants,
+                } => {
+                    // The counts are read when the command executes, so that
+                    // earlier commands in this buffer can write them.
+                    let workgroup_count = {
+                        let data = buffer.data.lock().unwrap();
+                        let ix = (*offset / 4) as usize;
+                        match data.get(ix..ix + 3) {
+                            Some(counts) => (counts[0], counts[1], counts[2]),
+                            None => {
+                                log::error!(
+                                    "indirect dispatch arguments at offset {} out of bounds, skipping",
+                                    offset
+                                );
+                                continue;
+                            }
+                        }
+                    };
+                    run_dispatch(
+                        *shader,
+                        bindings,
+                        workgroup_count,
+                        *workgroup_size,
+                        push_constants,
+                    );
+                }
+                Command::ClearBuffer(buffer, size) => {
+                    let mut data = buffer.data.lock().unwrap();
+                    let n_words = size
+                        .map(|size| (size as usize + 3) / 4)
+                        .map_or(data.len(), |n_words| n_words.min(data.len()));
+                    data[..n_words].fill(0);
+                }
+                Command::CopyBuffer(src, dst) => {
+                    let size = src.size.min(dst.size) as usize;
+                    copy_bytes(&src.data, &dst.data, size);
+                }
+                Command::CopyImageToBuffer(src, dst) => {
+                    copy_bytes(&src.data, &dst.data, dst.size as usize);
+                }
+                Command::CopyBufferToImage(src, dst) => {
+                    copy_bytes(&src.data, &dst.data, src.size as usize);
+                }
+                Command::BlitImage(src, dst) => {
+                    let bpp = src.format.bytes_per_pixel() as usize;
+                    let src_data = src.data.lock().unwrap();
+                    let mut dst_data = dst.data.lock().unwrap();
+                    let src_bytes: &[u8] = bytemuck::cast_slice(&src_data);
+                    let dst_bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut dst_data);
+                    let row_bytes = src.width.min(dst.width) as usize * bpp;
+                    for y in 0..src.height.min(dst.height) as usize {
+                        let src_ix = y * src.width as usize * bpp;
+                        let dst_ix = y * dst.width as usize * bpp;
+                        dst_bytes[dst_ix..dst_ix + row_bytes]
+                            .copy_from_slice(&src_bytes[src_ix..src_ix + row_bytes]);
+                    }
+                }
+                Command::CopyBufferRegion {
+                    src,
+                    src_offset,
+                    dst,
+                    dst_offset,
+                    size,
+                } => {
+                    let src_rows = Rows::linear(*src_offset, 0);
+                    let dst_rows = Rows::linear(*dst_offset, 0);
+                    copy_rows(&src.data, src_rows, &dst.data, dst_rows, *size as usize, 1);
+                }
+                Command::CopyImageRegionToBuffer(src, region, dst, layout) => {
+                    let bpp = src.format.bytes_per_pixel() as usize;
+                    let row_bytes = src.clamp_width(region.origin.0, region.size.0) * bpp;
+                    let n_rows = src.clamp_height(region.origin.1, region.size.1);
+                    let dst_rows = Rows::linear(layout.offset, layout.row_pitch);
+                    copy_rows(
+                        &src.data,
+                        src.rows(region.origin),
+                        &dst.data,
+                        dst_rows,
+                        row_bytes,
+                        n_rows,
+                    );
+                }
+                Command::CopyBufferToImageRegion(src, layout, dst, region) => {
+                    let bpp = dst.format.bytes_per_pixel() as usize;
+                    let row_bytes = dst.clamp_width(region.origin.0, region.size.0) * bpp;
+                    let n_rows = dst.clamp_height(region.origin.1, region.size.1);
+                    let src_rows = Rows::linear(layout.offset, layout.row_pitch);
+                    copy_rows(
+                        &src.data,
+                        src_rows,
+                        &dst.data,
+                        dst.rows(region.origin),
+                        row_bytes,
+                        n_rows,
+                    );
+                }
+                Command::CopyImageRegion(src, region, dst, dst_origin) => {
+                    let bpp = src.format.bytes_per_pixel() as usize;
+                    let width = src
+                        .clamp_width(region.origin.0, region.size.0)
+                        .min(dst.clamp_width(dst_origin.0, region.size.0));
+                    let n_rows = src
+                        .clamp_height(region.origin.1, region.size.1)
+                        .min(dst.clamp_height(dst_origin.1, region.size.1));
+                    let src_rows = src.rows(region.origin);
+                    let dst_rows = dst.rows(*dst_origin);
+                    copy_rows(
+                        &src.data,
+                        src_rows,
+                        &dst.data,
+                        dst_rows,
+                        width * bpp,
+                        n_rows,
+                    );
+                }
+                Command::ResetQueryPool(pool) => {
+                    pool.0.lock().unwrap().fill(0.0);
+                }
+                Command::WriteTimestamp(pool, query) => {
+                    pool.0.lock().unwrap()[*query as usize] = self.start.elapsed().as_secs_f64();
+                }
+            }
+        }
+    }
+}
+
+fn run_dispatch(
+    shader: CpuShader,
+    bindings: &[Binding],
+    workgroup_count: (u32, u32, u32),
+    workgroup_size: (u32, u32, u32),
+    push_constants: &[u8],
+) {
+    let bindings = bindings
+        .iter()
+        .map(|binding| match binding {
+            Binding::Buffer(b) => CpuBinding::Buffer(&b.data),
+            Binding::BufferSlice(b, offset, size) => {
+                let start = (*offset / 4) as usize;
+                CpuBinding::BufferSlice {
+                    data: &b.data,
+                    range: start..start + (*size / 4) as usize,
+                }
+            }
+            Binding::Image(i) => CpuBinding::Image {
+                width: i.width,
+                height: i.height,
+                format: i.format,
+                data: &i.data,
+                sampler: None,
+            },
+            Binding::SampledImage(i, sampler) => CpuBinding::Image {
+                width: i.width,
+                height: i.height,
+                format: i.format,
+                data: &i.data,
+                sampler: Some(*sampler),
+            },
+        })
+        .collect::<Vec<_>>();
+    shader(&CpuDispatch {
+        workgroup_count,
+        workgroup_size,
+        bindings: &bindings,
+        push_constants,
+    });
+}
+
+impl crate::backend::DescriptorSetBuilder<CpuDevice> for DescriptorSetBuilder {
+    fn add_buffers(&mut self, buffers: &[&Buffer]) {
+        self.0
+            .extend(buffers.iter().map(|b| Binding::Buffer((*b).clone())));
+    }
+
+    fn add_buffer_slices(&mut self, slices: &[(&Buffer, u64, u64)]) {
+        self.0.extend(
+            slices
+                .iter()
+                .map(|(b, offset, size)| Binding::BufferSlice((*b).clone(), *offset, *size)),
+        );
+    }
+
+    fn add_images(&mut self, images: &[&Image]) {
+        self.0
+            .extend(images.iter().map(|i| Binding::Image((*i).clone())));
+    }
+
+    fn add_textures(&mut self, images: &[&Image]) {
+        self.add_images(images);
+    }
+
+    fn add_sampled_images(&mut self, images: &[&Image], sampler: &SamplerParams) {
+        self.0.extend(
+            images
+                .iter()
+                .map(|i| Binding::SampledImage((*i).clone(), *sampler)),
+        );
+    }
+
+    unsafe fn build(
+        self,
+        _device: &CpuDevice,
+        _pipeline: &Pipeline,
+    ) -> Result<DescriptorSet, Error> {
+        Ok(DescriptorSet(self.0))
+    }
+}
+
+impl<'a> CpuBinding<'a> {
+    /// Lock a buffer binding, for access as 32 bit words.
+    ///
+    /// Panics if the binding is an image.
+    pub fn as_buf(&self) -> CpuBufGuard<'a> {
+        match self {
+            CpuBinding::Buffer(data) => {
+                let guard = data.lock().unwrap();
+                let range = 0..guard.len();
+                CpuBufGuard { guard, range }
+            }
+            CpuBinding::BufferSlice { data, range } => CpuBufGuard {
+                guard: data.lock().unwrap(),
+                range: range.clone(),
+            },
+            _ => panic!("binding is not a buffer"),
+        }
+    }
+
+    /// Lock an image binding, returning its dimensions and contents.
+    ///
+    /// Panics if the binding is a buffer.
+    pub fn as_image(&self) -> (u32, u32, MutexGuard<'a, Box<[u32]>>) {
+        match self {
+            CpuBinding::Image {
+                width,
+                height,
+                data,
+                ..
+            } => (*width, *height, data.lock().unwrap()),
+            _ => panic!("binding is not an image"),
+        }
+    }
+}
+
+impl<'a> Deref for CpuBufGuard<'a> {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        &self.guard[self.range.clone()]
+    }
+}
+
+impl<'a> DerefMut for CpuBufGuard<'a> {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        &mut self.guard[self.range.clone()]
+    }
+}
+
+impl Image {
+    /// The rows of the image, starting at the given texel.
+    fn rows(&self, origin: (u32, u32)) -> Rows {
+        let bpp = self.format.bytes_per_pixel() as usize;
+        let pitch = self.width as usize * bpp;
+        Rows {
+            offset: origin.1 as usize * pitch + origin.0 as usize * bpp,
+            pitch,
+        }
+    }
+
+    fn clamp_width(&self, x: u32, width: u32) -> usize {
+        width.min(self.width.saturating_sub(x)) as usize
+    }
+
+    fn clamp_height(&self, y: u32, height: u32) -> usize {
+        height.min(self.height.saturating_sub(y)) as usize
+    }
+}
+
+/// A byte offset and pitch describing rows within a resource.
+#[derive(Clone, Copy)]
+struct Rows {
+    offset: usize,
+    pitch: usize,
+}
+
+impl Rows {
+    fn linear(offset: u64, pitch: u32) -> Rows {
+        Rows {
+            offset: offset as usize,
+            pitch: pitch as usize,
+        }
+    }
+
+    fn range(&self, row: usize, row_bytes: usize) -> std::ops::Range<usize> {
+        let start = self.offset + row * self.pitch;
+        start..start + row_bytes
+    }
+}
+
+/// Copy rows of bytes between two resources, which may be the same.
+///
+/// Rows that fall outside either resource are skipped.
+fn copy_rows(
+    src: &Mutex<Box<[u32]>>,
+    src_rows: Rows,
+    dst: &Mutex<Box<[u32]>>,
+    dst_rows: Rows,
+    row_bytes: usize,
+    n_rows: usize,
+) {
+    if std::ptr::eq(src, dst) {
+        let mut data = src.lock().unwrap();
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+        // Copy from the end when moving forward, so overlapping rows are
+        // read before they are overwritten.
+        let backward = dst_rows.offset > src_rows.offset;
+        for i in 0..n_rows {
+            let row = if backward { n_rows - 1 - i } else { i };
+            let src_range = src_rows.range(row, row_bytes);
+            let dst_range = dst_rows.range(row, row_bytes);
+            if src_range.end <= bytes.len() && dst_range.end <= bytes.len() {
+                bytes.copy_within(src_range, dst_range.start);
+            }
+        }
+        return;
+    }
+    let src = src.lock().unwrap();
+    let mut dst = dst.lock().unwrap();
+    let src_bytes: &[u8] = bytemuck::cast_slice(&src);
+    let dst_bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut dst);
+    for row in 0..n_rows {
+        let src_range = src_rows.range(row, row_bytes);
+        let dst_range = dst_rows.range(row, row_bytes);
+        if src_range.end <= src_bytes.len() && dst_range.end <= dst_bytes.len() {
+            dst_bytes[dst_range].copy_from_slice(&src_bytes[src_range]);
+        }
+    }
+}
+
+/// Allocate zeroed words for a resource of the given size in bytes.
+///
+/// This reports allocations that can't be satisfied instead of aborting.
+fn alloc_zeroed(n_bytes: u64) -> Result<Box<[u32]>, Error> {
+    let n_words = usize::try_from(n_bytes / 4 + u64::from(n_bytes & 3 != 0))
+        .map_err(|_| Error::OutOfHostMemory)?;
+    let mut data = Vec::new();
+    data.try_reserve_exact(n_words)
+        .map_err(|_| Error::OutOfHostMemory)?;
+    data.resize(n_words, 0);
+    Ok(data.into_boxed_slice())
+}
+
+/// Copy bytes between two resources, clamped to both sizes.
+fn copy_bytes(src: &Mutex<Box<[u32]>>, dst: &Mutex<Box<[u32]>>, size: usize) {
+    if std::ptr::eq(src, dst) {
+        return;
+    }
+    let src = src.lock().unwrap();
+    let mut dst = dst.lock().unwrap();
+    let src_bytes: &[u8] = bytemuck::cast_slice(&src);
+    let dst_bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut dst);
+    let size = size.min(src_bytes.len()).min(dst_bytes.len());
+    dst_bytes[..size].copy_from_slice(&src_bytes[..size]);
+}
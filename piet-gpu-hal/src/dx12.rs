@@ -14,22 +14,27 @@ use std::{
 #[allow(unused)]
 use winapi::shared::dxgi1_3; // for error reporting in debug mode
 use winapi::shared::minwindef::TRUE;
-use winapi::shared::{dxgi, dxgi1_2, dxgitype};
-use winapi::um::d3d12;
+use winapi::shared::{dxgi, dxgi1_2, dxgi1_4, dxgitype};
+use winapi::um::{d3d12, d3dcommon};
 
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 
 use smallvec::SmallVec;
 
+use crate::pipeline_cache;
 use crate::{
-    BindType, BufferUsage, ComputePassDescriptor, Error, GpuInfo, ImageFormat, ImageLayout,
-    MapMode, WorkgroupLimits,
+    AdapterInfo, BackendType, BindType, BufferImageLayout, BufferUsage, ComputePassDescriptor,
+    DeviceType, Error, FormatCapabilities, GpuInfo, ImageFormat, ImageLayout, ImageRegion, MapMode,
+    MemoryBudget, MemoryHeap, PipelineOptions, QueueType, SamplerParams, SpecValue,
+    SubgroupFeatures, SubgroupSize, WorkgroupLimits,
 };
+use crate::{AddressMode, FilterMode};
 
 use self::{
     descriptor::{CpuHeapRefOwned, DescriptorPool, GpuHeapRefOwned},
     wrappers::{
-        CommandAllocator, CommandQueue, DescriptorHeap, Device, Factory4, Resource, ShaderByteCode,
+        Adapter1, Adapter3, CommandAllocator, CommandQueue, CommandSignature, DescriptorHeap,
+        Device, Factory4, InfoQueue, PipelineLibrary, Resource, ShaderByteCode,
     },
 };
 
@@ -44,15 +49,34 @@ pub struct Dx12Surface {
 pub struct Dx12Swapchain {
     swapchain: wrappers::SwapChain3,
     size: (u32, u32),
+    format: ImageFormat,
 }
 
 pub struct Dx12Device {
     device: Device,
     command_queue: CommandQueue,
+    compute_queue: CommandQueue,
+    copy_queue: CommandQueue,
     ts_freq: u64,
     gpu_info: GpuInfo,
     memory_arch: MemoryArchitecture,
     descriptor_pool: Mutex<DescriptorPool>,
+    sampler_pool: Mutex<DescriptorPool>,
+    dispatch_signature: CommandSignature,
+    /// Identifies the adapter and driver, for validating pipeline cache data.
+    pipeline_cache_key: Vec<u8>,
+    /// The adapter, if it can query memory budgets.
+    adapter3: Option<Adapter3>,
+    /// The debug layer's messages, drained into `log` on submission and
+    /// waits.
+    info_queue: Option<InfoQueue>,
+}
+
+pub struct PipelineCache {
+    /// The library, or `None` if the device doesn't support them.
+    library: Option<PipelineLibrary>,
+    /// The serialized library, which must outlive it.
+    _data: Vec<u8>,
 }
 
 #[derive(Clone)]
@@ -61,6 +85,8 @@ pub struct Buffer {
     pub size: u64,
     // Always present except for query readback buffer.
     cpu_ref: Option<Arc<CpuHeapRefOwned>>,
+    // A constant buffer view, present when created with UNIFORM usage.
+    cbv_ref: Option<Arc<CpuHeapRefOwned>>,
     // Present when created with CLEAR usage. Heap is here for
     // the same reason it's in DescriptorSet, and might be removed
     // when CmdBuf has access to the descriptor pool.
@@ -72,7 +98,10 @@ pub struct Image {
     resource: Resource,
     // Present except for swapchain images.
     cpu_ref: Option<Arc<CpuHeapRefOwned>>,
-    size: (u32, u32),
+    // A shader resource view, present when the format can be sampled.
+    srv_ref: Option<Arc<CpuHeapRefOwned>>,
+    pub size: (u32, u32),
+    pub format: ImageFormat,
 }
 
 pub struct CmdBuf {
@@ -80,11 +109,21 @@ pub struct CmdBuf {
     allocator: CommandAllocator,
     needs_reset: bool,
     end_query: Option<(wrappers::QueryHeap, u32)>,
+    dispatch_signature: CommandSignature,
+    queue: QueueType,
+    // The states of the buffers used so far. Buffers decay to the common
+    // state when a command list completes and are promoted on first use,
+    // so this starts out empty.
+    buffer_states: Vec<(Resource, d3d12::D3D12_RESOURCE_STATES)>,
 }
 
 pub struct Pipeline {
     pipeline_state: wrappers::PipelineState,
     root_signature: wrappers::RootSignature,
+    bind_types: Vec<BindType>,
+    // The root parameter of the push constants, following the descriptor
+    // tables.
+    push_constant_parameter: u32,
 }
 
 pub struct DescriptorSet {
@@ -93,6 +132,15 @@ pub struct DescriptorSet {
     // use it easily. If CmdBuf had a reference to the Device (or just
     // the descriptor pool), we could get rid of this.
     heap: DescriptorHeap,
+    // The samplers of sampled images, in their own heap.
+    samplers: Option<(GpuHeapRefOwned, DescriptorHeap)>,
+    bind_types: Vec<BindType>,
+    // The bound buffers by index, and the states a dispatch needs them in.
+    buffers: Vec<(usize, Resource, d3d12::D3D12_RESOURCE_STATES)>,
+}
+
+pub struct Sampler {
+    cpu_ref: CpuHeapRefOwned,
 }
 
 pub struct QueryPool {
@@ -109,13 +157,27 @@ pub struct Fence {
     val: Cell<u64>,
 }
 
-/// This will probably be renamed "PresentSem" or similar. I believe no
-/// semaphore is needed for presentation on DX12.
-pub struct Semaphore;
+/// A semaphore, implemented as a fence signaled and waited on by queues.
+///
+/// No semaphore is needed for presentation on DX12, so the ones returned
+/// by the swapchain have no fence.
+pub struct Semaphore {
+    fence: Option<wrappers::Fence>,
+    val: Cell<u64>,
+}
 
 #[derive(Default)]
 pub struct DescriptorSetBuilder {
     handles: SmallVec<[d3d12::D3D12_CPU_DESCRIPTOR_HANDLE; 16]>,
+    // Parallel to `handles`, used instead when the binding is a uniform buffer.
+    cbv_handles: SmallVec<[Option<d3d12::D3D12_CPU_DESCRIPTOR_HANDLE>; 16]>,
+    // Parallel to `handles`, the offset and size of buffer slices. These get
+    // their own views, written when the descriptor set is built.
+    slices: SmallVec<[Option<(Buffer, u64, u64)>; 16]>,
+    // The samplers of sampled images, in binding order.
+    samplers: SmallVec<[d3d12::D3D12_CPU_DESCRIPTOR_HANDLE; 4]>,
+    // The bound buffers, with the index of their handle.
+    buffers: SmallVec<[(usize, Resource); 16]>,
 }
 
 #[derive(PartialEq, Eq)]
@@ -130,19 +192,22 @@ enum MemoryArchitecture {
 
 impl Dx12Instance {
     /// Create a new instance.
-    pub fn new() -> Result<Dx12Instance, Error> {
+    ///
+    /// The debug layer is enabled if `validation` is set, or in debug builds.
+    pub fn new(validation: bool) -> Result<Dx12Instance, Error> {
         unsafe {
-            #[cfg(debug_assertions)]
-            if let Err(e) = wrappers::enable_debug_layer() {
-                // Maybe a better logging solution?
-                println!("{}", e);
+            let debug = validation || cfg!(debug_assertions);
+            if debug {
+                if let Err(e) = wrappers::enable_debug_layer() {
+                    log::warn!("{}", e);
+                }
             }
 
-            #[cfg(debug_assertions)]
-            let factory_flags = dxgi1_3::DXGI_CREATE_FACTORY_DEBUG;
-
-            #[cfg(not(debug_assertions))]
-            let factory_flags: u32 = 0;
+            let factory_flags = if debug {
+                dxgi1_3::DXGI_CREATE_FACTORY_DEBUG
+            } else {
+                0
+            };
 
             let factory = Factory4::create(factory_flags)?;
 
@@ -166,48 +231,184 @@ impl Dx12Instance {
     /// Get a device suitable for compute workloads.
     pub fn device(&self) -> Result<Dx12Device, Error> {
         unsafe {
-            let device = Device::create_device(&self.factory)?;
-            let list_type = d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT;
-            let command_queue = device.create_command_queue(
-                list_type,
-                0,
-                d3d12::D3D12_COMMAND_QUEUE_FLAG_NONE,
-                0,
-            )?;
+            let mut id = 0;
+            loop {
+                // This always returns DXGI_ERROR_NOT_FOUND if no suitable adapter is found.
+                // Might be slightly more useful to retain the error from the attempt to create.
+                let adapter = self.factory.enumerate_adapters(id)?;
+                if let Ok(device) =
+                    Device::create_using_adapter(&adapter, d3dcommon::D3D_FEATURE_LEVEL_12_0)
+                {
+                    return self.init_device(device, &adapter);
+                }
+                id += 1;
+            }
+        }
+    }
 
-            let ts_freq = command_queue.get_timestamp_frequency()?;
-            let features_architecture = device.get_features_architecture()?;
-            let uma = features_architecture.UMA == TRUE;
-            let cc_uma = features_architecture.CacheCoherentUMA == TRUE;
-            let memory_arch = match (uma, cc_uma) {
-                (true, true) => MemoryArchitecture::CacheCoherentUMA,
-                (true, false) => MemoryArchitecture::UMA,
-                _ => MemoryArchitecture::NUMA,
-            };
-            let use_staging_buffers = memory_arch == MemoryArchitecture::NUMA;
-            // These values are appropriate for Shader Model 5. When we open up
-            // DXIL, fix this with proper dynamic queries.
-            let gpu_info = GpuInfo {
-                has_descriptor_indexing: false,
-                has_subgroups: false,
-                subgroup_size: None,
-                workgroup_limits: WorkgroupLimits {
-                    max_size: [1024, 1024, 64],
-                    max_invocations: 1024,
+    /// List the adapters that support D3D12.
+    pub fn adapters(&self) -> Result<Vec<AdapterInfo>, Error> {
+        unsafe {
+            let mut adapters = Vec::new();
+            let mut id = 0;
+            while let Ok(adapter) = self.factory.enumerate_adapters(id) {
+                let index = id as usize;
+                id += 1;
+                let device =
+                    match Device::create_using_adapter(&adapter, d3dcommon::D3D_FEATURE_LEVEL_12_0)
+                    {
+                        Ok(device) => device,
+                        Err(_) => continue,
+                    };
+                let desc = adapter.get_desc();
+                let device_type = if desc.Flags & dxgi::DXGI_ADAPTER_FLAG_SOFTWARE != 0 {
+                    DeviceType::Software
+                } else if device.get_features_architecture()?.UMA == TRUE {
+                    DeviceType::Integrated
+                } else {
+                    DeviceType::Discrete
+                };
+                adapters.push(AdapterInfo {
+                    name: adapter_name(&desc),
+                    vendor_id: desc.VendorId,
+                    device_id: desc.DeviceId,
+                    device_type,
+                    backend: BackendType::Dx12,
+                    index,
+                });
+            }
+            Ok(adapters)
+        }
+    }
+
+    /// Get a device from a specific adapter.
+    pub fn device_from_adapter(&self, adapter: &AdapterInfo) -> Result<Dx12Device, Error> {
+        unsafe {
+            let adapter = self.factory.enumerate_adapters(adapter.index.try_into()?)?;
+            let device = Device::create_using_adapter(&adapter, d3dcommon::D3D_FEATURE_LEVEL_12_0)?;
+            self.init_device(device, &adapter)
+        }
+    }
+
+    unsafe fn init_device(&self, device: Device, adapter: &Adapter1) -> Result<Dx12Device, Error> {
+        let list_type = d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT;
+        let command_queue =
+            device.create_command_queue(list_type, 0, d3d12::D3D12_COMMAND_QUEUE_FLAG_NONE, 0)?;
+
+        let compute_queue = device.create_command_queue(
+            d3d12::D3D12_COMMAND_LIST_TYPE_COMPUTE,
+            0,
+            d3d12::D3D12_COMMAND_QUEUE_FLAG_NONE,
+            0,
+        )?;
+        let copy_queue = device.create_command_queue(
+            d3d12::D3D12_COMMAND_LIST_TYPE_COPY,
+            0,
+            d3d12::D3D12_COMMAND_QUEUE_FLAG_NONE,
+            0,
+        )?;
+
+        let ts_freq = command_queue.get_timestamp_frequency()?;
+        let features_architecture = device.get_features_architecture()?;
+        let uma = features_architecture.UMA == TRUE;
+        let cc_uma = features_architecture.CacheCoherentUMA == TRUE;
+        let memory_arch = match (uma, cc_uma) {
+            (true, true) => MemoryArchitecture::CacheCoherentUMA,
+            (true, false) => MemoryArchitecture::UMA,
+            _ => MemoryArchitecture::NUMA,
+        };
+        let use_staging_buffers = memory_arch == MemoryArchitecture::NUMA;
+        let desc = adapter.get_desc();
+        // The heaps correspond to the DXGI segment groups. On UMA, the local
+        // group includes shared system memory.
+        let memory_heaps = if memory_arch == MemoryArchitecture::NUMA {
+            vec![
+                MemoryHeap {
+                    size: desc.DedicatedVideoMemory as u64,
+                    device_local: true,
                 },
-                has_memory_model: false,
-                use_staging_buffers,
-            };
-            let descriptor_pool = Default::default();
-            Ok(Dx12Device {
-                device,
-                command_queue,
-                ts_freq,
-                memory_arch,
-                gpu_info,
-                descriptor_pool,
-            })
+                MemoryHeap {
+                    size: desc.SharedSystemMemory as u64,
+                    device_local: false,
+                },
+            ]
+        } else {
+            vec![MemoryHeap {
+                size: (desc.DedicatedVideoMemory + desc.SharedSystemMemory) as u64,
+                device_local: true,
+            }]
+        };
+        // Wave intrinsics need Shader Model 6, so they are only available to
+        // shaders precompiled to DXIL. Choosing the lane count needs the
+        // `WaveSize` attribute of Shader Model 6.6, which FXC can't compile,
+        // so the size isn't controllable until HLSL is compiled with DXC.
+        let options1 = device.get_options1().ok();
+        let has_subgroups = options1.map_or(false, |options1| options1.WaveOps == TRUE);
+        let subgroup_features = if has_subgroups {
+            SubgroupFeatures::BASIC
+                | SubgroupFeatures::VOTE
+                | SubgroupFeatures::ARITHMETIC
+                | SubgroupFeatures::BALLOT
+                | SubgroupFeatures::SHUFFLE
+                | SubgroupFeatures::QUAD
+        } else {
+            SubgroupFeatures::empty()
+        };
+        let subgroup_size = options1
+            .filter(|_| has_subgroups)
+            .map(|options1| SubgroupSize {
+                min: options1.WaveLaneCountMin,
+                max: options1.WaveLaneCountMax,
+                control: false,
+            });
+        // These values are appropriate for Shader Model 5. When we open up
+        // DXIL, fix this with proper dynamic queries.
+        let gpu_info = GpuInfo {
+            has_descriptor_indexing: false,
+            has_subgroups,
+            subgroup_features,
+            subgroup_size,
+            workgroup_limits: WorkgroupLimits {
+                max_size: [1024, 1024, 64],
+                max_invocations: 1024,
+            },
+            has_memory_model: false,
+            use_staging_buffers,
+            adapter_name: adapter_name(&desc),
+            // Constant buffer views have the strictest requirement.
+            buffer_offset_alignment: d3d12::D3D12_CONSTANT_BUFFER_DATA_PLACEMENT_ALIGNMENT as u64,
+            has_timestamp_writes: true,
+            memory_heaps,
+        };
+        let descriptor_pool = Mutex::new(DescriptorPool::new(
+            d3d12::D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV,
+        ));
+        let sampler_pool = Mutex::new(DescriptorPool::new(
+            d3d12::D3D12_DESCRIPTOR_HEAP_TYPE_SAMPLER,
+        ));
+        let dispatch_signature = device.create_dispatch_command_signature()?;
+        let mut pipeline_cache_key = Vec::new();
+        for id in [desc.VendorId, desc.DeviceId, desc.SubSysId, desc.Revision] {
+            pipeline_cache_key.extend_from_slice(&id.to_le_bytes());
         }
+        let driver_version = adapter.get_driver_version().unwrap_or(0);
+        pipeline_cache_key.extend_from_slice(&driver_version.to_le_bytes());
+        let info_queue = device.cast_info_queue();
+        Ok(Dx12Device {
+            device,
+            command_queue,
+            compute_queue,
+            copy_queue,
+            ts_freq,
+            memory_arch,
+            gpu_info,
+            descriptor_pool,
+            sampler_pool,
+            dispatch_signature,
+            pipeline_cache_key,
+            adapter3: adapter.cast_adapter3(),
+            info_queue,
+        })
     }
 
     pub unsafe fn swapchain(
@@ -216,14 +417,27 @@ impl Dx12Instance {
         height: usize,
         device: &Dx12Device,
         surface: &Dx12Surface,
+        formats: &[ImageFormat],
     ) -> Result<Dx12Swapchain, Error> {
         const FRAME_COUNT: u32 = 2;
+        // Flip model swapchains don't support sRGB formats; those would need
+        // an sRGB render target view.
+        let format = formats
+            .iter()
+            .copied()
+            .find(|format| {
+                matches!(
+                    format,
+                    ImageFormat::Rgba8 | ImageFormat::Bgra8 | ImageFormat::Rgba16Float
+                )
+            })
+            .unwrap_or(ImageFormat::Rgba8);
         let desc = dxgi1_2::DXGI_SWAP_CHAIN_DESC1 {
             Width: width as u32,
             Height: height as u32,
             AlphaMode: dxgi1_2::DXGI_ALPHA_MODE_IGNORE,
             BufferCount: FRAME_COUNT,
-            Format: winapi::shared::dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM,
+            Format: dxgi_format(format),
             Flags: 0,
             BufferUsage: dxgitype::DXGI_USAGE_RENDER_TARGET_OUTPUT,
             SampleDesc: dxgitype::DXGI_SAMPLE_DESC {
@@ -238,7 +452,11 @@ impl Dx12Instance {
             self.factory
                 .create_swapchain_for_hwnd(&device.command_queue, surface.hwnd, desc)?;
         let size = (width as u32, height as u32);
-        Ok(Dx12Swapchain { swapchain, size })
+        Ok(Dx12Swapchain {
+            swapchain,
+            size,
+            format,
+        })
     }
 }
 
@@ -261,22 +479,31 @@ impl crate::backend::Device for Dx12Device {
 
     type DescriptorSetBuilder = DescriptorSetBuilder;
 
-    type Sampler = ();
+    type Sampler = Sampler;
 
     // Currently due to type inflexibility this is hardcoded to either HLSL or
     // DXIL, but it would be nice to be able to handle both at runtime.
     type ShaderSource = [u8];
 
+    type PipelineCache = PipelineCache;
+
     fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Self::Buffer, Error> {
         // TODO: consider supporting BufferUsage::QUERY_RESOLVE here rather than
         // having a separate function.
         unsafe {
+            // Constant buffer views need to be a multiple of 256 bytes, so
+            // the resource is padded to allow views to the end of the buffer.
+            let resource_size = if usage.contains(BufferUsage::UNIFORM) {
+                cbv_size(size)
+            } else {
+                size
+            };
             let page_property = self.memory_arch.page_property(usage);
             let memory_pool = self.memory_arch.memory_pool(usage);
             //TODO: consider flag D3D12_HEAP_FLAG_ALLOW_SHADER_ATOMICS?
             let flags = d3d12::D3D12_RESOURCE_FLAG_ALLOW_UNORDERED_ACCESS;
             let resource = self.device.create_buffer(
-                size,
+                resource_size,
                 d3d12::D3D12_HEAP_TYPE_CUSTOM,
                 page_property,
                 memory_pool,
@@ -293,6 +520,19 @@ impl crate::backend::Device for Dx12Device {
                     0,
                     (size / 4).try_into()?,
                 );
+            let cbv_ref = if usage.contains(BufferUsage::UNIFORM) {
+                let cbv_ref = Arc::new(descriptor_pool.alloc_cpu(&self.device)?);
+                let cbv_handle = descriptor_pool.cpu_handle(&cbv_ref);
+                self.device.create_constant_buffer_view(
+                    &resource,
+                    cbv_handle,
+                    0,
+                    resource_size.try_into()?,
+                );
+                Some(cbv_ref)
+            } else {
+                None
+            };
             let gpu_ref = if usage.contains(BufferUsage::CLEAR) {
                 let gpu_ref = Arc::new(descriptor_pool.alloc_gpu(&self.device, 1)?);
                 let gpu_handle = descriptor_pool.cpu_handle_of_gpu(&gpu_ref, 0);
@@ -312,6 +552,7 @@ impl crate::backend::Device for Dx12Device {
                 resource,
                 size,
                 cpu_ref: Some(cpu_ref),
+                cbv_ref,
                 gpu_ref,
             })
         }
@@ -322,30 +563,76 @@ impl crate::backend::Device for Dx12Device {
         Ok(())
     }
 
+    fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities {
+        let support = match unsafe { self.device.get_format_support(dxgi_format(format)) } {
+            Ok(support) => support,
+            Err(_) => return FormatCapabilities::empty(),
+        };
+        let mut capabilities = FormatCapabilities::empty();
+        if support.Support1 & d3d12::D3D12_FORMAT_SUPPORT1_TEXTURE2D == 0 {
+            return capabilities;
+        }
+        // Blits are whole resource copies, so have the same requirements.
+        capabilities |= FormatCapabilities::COPY | FormatCapabilities::BLIT;
+        if support.Support1 & d3d12::D3D12_FORMAT_SUPPORT1_SHADER_SAMPLE != 0 {
+            capabilities |= FormatCapabilities::SAMPLED;
+        }
+        let uav_load_store = d3d12::D3D12_FORMAT_SUPPORT2_UAV_TYPED_LOAD
+            | d3d12::D3D12_FORMAT_SUPPORT2_UAV_TYPED_STORE;
+        if support.Support1 & d3d12::D3D12_FORMAT_SUPPORT1_TYPED_UNORDERED_ACCESS_VIEW != 0
+            && support.Support2 & uav_load_store == uav_load_store
+        {
+            capabilities |= FormatCapabilities::STORAGE;
+        }
+        capabilities
+    }
+
     unsafe fn create_image2d(
         &self,
         width: u32,
         height: u32,
         format: ImageFormat,
     ) -> Result<Self::Image, Error> {
-        let format = match format {
-            ImageFormat::A8 => winapi::shared::dxgiformat::DXGI_FORMAT_R8_UNORM,
-            ImageFormat::Rgba8 => winapi::shared::dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM,
-        };
-        let resource = self
-            .device
-            .create_texture2d_buffer(width.into(), height, format, true)?;
+        let capabilities = self.format_capabilities(format);
+        if capabilities.is_empty() {
+            return Err(Error::unsupported(format!("image format {:?}", format)));
+        }
+        // Storage images are bound as UAVs and sampled images as SRVs, which
+        // not all formats support.
+        let storage = capabilities.contains(FormatCapabilities::STORAGE);
+        let resource = self.device.create_texture2d_buffer(
+            width.into(),
+            height,
+            dxgi_format(format),
+            storage,
+        )?;
 
         let mut descriptor_pool = self.descriptor_pool.lock().unwrap();
-        let cpu_ref = Arc::new(descriptor_pool.alloc_cpu(&self.device)?);
-        let cpu_handle = descriptor_pool.cpu_handle(&cpu_ref);
-        self.device
-            .create_unordered_access_view(&resource, cpu_handle);
+        let cpu_ref = if storage {
+            let cpu_ref = Arc::new(descriptor_pool.alloc_cpu(&self.device)?);
+            let cpu_handle = descriptor_pool.cpu_handle(&cpu_ref);
+            self.device
+                .create_unordered_access_view(&resource, cpu_handle);
+            Some(cpu_ref)
+        } else {
+            None
+        };
+        let srv_ref = if capabilities.contains(FormatCapabilities::SAMPLED) {
+            let srv_ref = Arc::new(descriptor_pool.alloc_cpu(&self.device)?);
+            let srv_handle = descriptor_pool.cpu_handle(&srv_ref);
+            self.device
+                .create_shader_resource_view(&resource, srv_handle);
+            Some(srv_ref)
+        } else {
+            None
+        };
         let size = (width, height);
         Ok(Image {
             resource,
-            cpu_ref: Some(cpu_ref),
+            cpu_ref,
+            srv_ref,
             size,
+            format,
         })
     }
 
@@ -354,8 +641,16 @@ impl crate::backend::Device for Dx12Device {
         Ok(())
     }
 
-    fn create_cmd_buf(&self) -> Result<Self::CmdBuf, Error> {
-        let list_type = d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT;
+    fn has_dedicated_queue(&self, _queue: QueueType) -> bool {
+        true
+    }
+
+    fn create_cmd_buf(&self, queue: QueueType) -> Result<Self::CmdBuf, Error> {
+        let list_type = match queue {
+            QueueType::Main => d3d12::D3D12_COMMAND_LIST_TYPE_DIRECT,
+            QueueType::Compute => d3d12::D3D12_COMMAND_LIST_TYPE_COMPUTE,
+            QueueType::Transfer => d3d12::D3D12_COMMAND_LIST_TYPE_COPY,
+        };
         let allocator = unsafe { self.device.create_command_allocator(list_type)? };
         let node_mask = 0;
         unsafe {
@@ -367,6 +662,9 @@ impl crate::backend::Device for Dx12Device {
                 allocator,
                 needs_reset: false,
                 end_query: None,
+                dispatch_signature: self.dispatch_signature.clone(),
+                queue,
+                buffer_states: Vec::new(),
             })
         }
     }
@@ -403,22 +701,40 @@ impl crate::backend::Device for Dx12Device {
     unsafe fn run_cmd_bufs(
         &self,
         cmd_bufs: &[&Self::CmdBuf],
-        _wait_semaphores: &[&Self::Semaphore],
-        _signal_semaphores: &[&Self::Semaphore],
+        wait_semaphores: &[&Self::Semaphore],
+        signal_semaphores: &[&Self::Semaphore],
         fence: Option<&mut Self::Fence>,
+        queue: QueueType,
     ) -> Result<(), Error> {
-        // TODO: handle semaphores
+        let command_queue = match queue {
+            QueueType::Main => &self.command_queue,
+            QueueType::Compute => &self.compute_queue,
+            QueueType::Transfer => &self.copy_queue,
+        };
+        for semaphore in wait_semaphores {
+            if let Some(sem_fence) = &semaphore.fence {
+                command_queue.wait(sem_fence, semaphore.val.get())?;
+            }
+        }
         let lists = cmd_bufs
             .iter()
             .map(|c| c.c.as_raw_command_list())
             .collect::<SmallVec<[_; 4]>>();
-        self.command_queue.execute_command_lists(&lists);
+        command_queue.execute_command_lists(&lists);
+        for semaphore in signal_semaphores {
+            if let Some(sem_fence) = &semaphore.fence {
+                let val = semaphore.val.get() + 1;
+                semaphore.val.set(val);
+                command_queue.signal(sem_fence, val)?;
+            }
+        }
         if let Some(fence) = fence {
             let val = fence.val.get() + 1;
             fence.val.set(val);
-            self.command_queue.signal(&fence.fence, val)?;
+            command_queue.signal(&fence.fence, val)?;
             fence.fence.set_event_on_completion(&fence.event, val)?;
         }
+        self.drain_messages();
         Ok(())
     }
 
@@ -445,7 +761,11 @@ impl crate::backend::Device for Dx12Device {
     }
 
     unsafe fn create_semaphore(&self) -> Result<Self::Semaphore, Error> {
-        Ok(Semaphore)
+        let fence = self.device.create_fence(0)?;
+        Ok(Semaphore {
+            fence: Some(fence),
+            val: Cell::new(0),
+        })
     }
 
     unsafe fn create_fence(&self, signaled: bool) -> Result<Self::Fence, Error> {
@@ -463,12 +783,20 @@ impl crate::backend::Device for Dx12Device {
         for fence in fences {
             // TODO: probably handle errors here.
             let _status = fence.event.wait(winapi::um::winbase::INFINITE);
+            self.drain_messages();
+            // On device removal, the event is signaled with the fence set to u64::MAX.
+            if fence.fence.get_value() == u64::MAX {
+                return Err(Error::DeviceLost);
+            }
         }
         Ok(())
     }
 
     unsafe fn get_fence_status(&self, fence: &mut Self::Fence) -> Result<bool, Error> {
         let fence_val = fence.fence.get_value();
+        if fence_val == u64::MAX {
+            return Err(Error::DeviceLost);
+        }
         Ok(fence_val == fence.val.get())
     }
 
@@ -476,14 +804,35 @@ impl crate::backend::Device for Dx12Device {
         self.gpu_info.clone()
     }
 
+    fn query_memory_budget(&self) -> Option<Vec<MemoryBudget>> {
+        let adapter3 = self.adapter3.as_ref()?;
+        let mut segment_groups = vec![dxgi1_4::DXGI_MEMORY_SEGMENT_GROUP_LOCAL];
+        if self.memory_arch == MemoryArchitecture::NUMA {
+            segment_groups.push(dxgi1_4::DXGI_MEMORY_SEGMENT_GROUP_NON_LOCAL);
+        }
+        segment_groups
+            .into_iter()
+            .map(|segment_group| unsafe {
+                let info = adapter3.query_video_memory_info(segment_group).ok()?;
+                Some(MemoryBudget {
+                    budget: info.Budget,
+                    usage: info.CurrentUsage,
+                })
+            })
+            .collect()
+    }
+
     unsafe fn create_compute_pipeline(
         &self,
         code: &Self::ShaderSource,
         bind_types: &[BindType],
+        _options: &PipelineOptions,
+        cache: Option<&PipelineCache>,
     ) -> Result<Pipeline, Error> {
         if u32::try_from(bind_types.len()).is_err() {
             panic!("bind type length overflow");
         }
+        let (bind_types, push_constant_size) = BindType::split_push_constants(bind_types)?;
         let mut ranges = Vec::new();
         let mut i = 0;
         fn map_range_type(bind_type: BindType) -> d3d12::D3D12_DESCRIPTOR_RANGE_TYPE {
@@ -491,7 +840,11 @@ impl crate::backend::Device for Dx12Device {
                 BindType::Buffer | BindType::Image | BindType::ImageRead => {
                     d3d12::D3D12_DESCRIPTOR_RANGE_TYPE_UAV
                 }
-                BindType::BufReadOnly => d3d12::D3D12_DESCRIPTOR_RANGE_TYPE_SRV,
+                BindType::BufReadOnly | BindType::SampledImage => {
+                    d3d12::D3D12_DESCRIPTOR_RANGE_TYPE_SRV
+                }
+                BindType::Uniform => d3d12::D3D12_DESCRIPTOR_RANGE_TYPE_CBV,
+                BindType::PushConstants(_) => unreachable!("push constants have no descriptor"),
             }
         }
         while i < bind_types.len() {
@@ -510,6 +863,20 @@ impl crate::backend::Device for Dx12Device {
             });
             i = end;
         }
+        // Samplers go in a second table, as they have their own heap. The
+        // sampler of a sampled image takes the register of its binding.
+        let sampler_ranges = bind_types
+            .iter()
+            .enumerate()
+            .filter(|(_, bind_type)| **bind_type == BindType::SampledImage)
+            .map(|(i, _)| d3d12::D3D12_DESCRIPTOR_RANGE {
+                RangeType: d3d12::D3D12_DESCRIPTOR_RANGE_TYPE_SAMPLER,
+                NumDescriptors: 1,
+                BaseShaderRegister: i as u32,
+                RegisterSpace: 0,
+                OffsetInDescriptorsFromTableStart: d3d12::D3D12_DESCRIPTOR_RANGE_OFFSET_APPEND,
+            })
+            .collect::<Vec<_>>();
 
         // We could always have ShaderSource as [u8] even when it's HLSL, and use the
         // magic number to distinguish. In any case, for now it's hardcoded as one or
@@ -537,9 +904,38 @@ impl crate::backend::Device for Dx12Device {
             NumDescriptorRanges: ranges.len() as u32,
             pDescriptorRanges: ranges.as_ptr(),
         };
+        let mut root_parameters: SmallVec<[_; 3]> = SmallVec::new();
+        root_parameters.push(root_parameter);
+        if !sampler_ranges.is_empty() {
+            let mut sampler_parameter = d3d12::D3D12_ROOT_PARAMETER {
+                ParameterType: d3d12::D3D12_ROOT_PARAMETER_TYPE_DESCRIPTOR_TABLE,
+                ShaderVisibility: d3d12::D3D12_SHADER_VISIBILITY_ALL,
+                ..mem::zeroed()
+            };
+            *sampler_parameter.u.DescriptorTable_mut() = d3d12::D3D12_ROOT_DESCRIPTOR_TABLE {
+                NumDescriptorRanges: sampler_ranges.len() as u32,
+                pDescriptorRanges: sampler_ranges.as_ptr(),
+            };
+            root_parameters.push(sampler_parameter);
+        }
+        let push_constant_parameter = root_parameters.len() as u32;
+        if push_constant_size > 0 {
+            // Root constants take the register following the last binding.
+            let mut constants_parameter = d3d12::D3D12_ROOT_PARAMETER {
+                ParameterType: d3d12::D3D12_ROOT_PARAMETER_TYPE_32BIT_CONSTANTS,
+                ShaderVisibility: d3d12::D3D12_SHADER_VISIBILITY_ALL,
+                ..mem::zeroed()
+            };
+            *constants_parameter.u.Constants_mut() = d3d12::D3D12_ROOT_CONSTANTS {
+                ShaderRegister: bind_types.len() as u32,
+                RegisterSpace: 0,
+                Num32BitValues: push_constant_size / 4,
+            };
+            root_parameters.push(constants_parameter);
+        }
         let root_signature_desc = d3d12::D3D12_ROOT_SIGNATURE_DESC {
-            NumParameters: 1,
-            pParameters: &root_parameter,
+            NumParameters: root_parameters.len() as u32,
+            pParameters: root_parameters.as_ptr(),
             NumStaticSamplers: 0,
             pStaticSamplers: ptr::null(),
             Flags: d3d12::D3D12_ROOT_SIGNATURE_FLAG_NONE,
@@ -548,6 +944,17 @@ impl crate::backend::Device for Dx12Device {
             &root_signature_desc,
             d3d12::D3D_ROOT_SIGNATURE_VERSION_1,
         )?;
+        // Pipelines in the library are keyed by the shader and root signature.
+        let root_signature_bytes = std::slice::from_raw_parts(
+            root_signature_blob.0.GetBufferPointer() as *const u8,
+            root_signature_blob.0.GetBufferSize(),
+        );
+        let name = format!(
+            "{:016x}-{:016x}",
+            pipeline_cache::hash(code),
+            pipeline_cache::hash(root_signature_bytes)
+        );
+        let name = name.encode_utf16().chain(Some(0)).collect::<Vec<_>>();
         let root_signature = self.device.create_root_signature(0, root_signature_blob)?;
         let desc = d3d12::D3D12_COMPUTE_PIPELINE_STATE_DESC {
             pRootSignature: root_signature.0.as_raw(),
@@ -559,14 +966,65 @@ impl crate::backend::Device for Dx12Device {
             },
             Flags: d3d12::D3D12_PIPELINE_STATE_FLAG_NONE,
         };
-        let pipeline_state = self.device.create_compute_pipeline_state(&desc)?;
+        let library = cache.and_then(|cache| cache.library.as_ref());
+        let pipeline_state = match library.map(|l| l.load_compute_pipeline(&name, &desc)) {
+            Some(Ok(pipeline_state)) => pipeline_state,
+            _ => {
+                let pipeline_state = self.device.create_compute_pipeline_state(&desc)?;
+                if let Some(library) = library {
+                    // This fails if another thread stored the pipeline first.
+                    let _ = library.store_pipeline(&name, &pipeline_state);
+                }
+                pipeline_state
+            }
+        };
 
         Ok(Pipeline {
             pipeline_state,
             root_signature,
+            bind_types,
+            push_constant_parameter,
         })
     }
 
+    fn pipeline_cache_key(&self) -> Vec<u8> {
+        self.pipeline_cache_key.clone()
+    }
+
+    unsafe fn create_pipeline_cache(&self, data: &[u8]) -> Result<(PipelineCache, bool), Error> {
+        let data = data.to_vec();
+        let library = match self.device.create_pipeline_library(&data) {
+            Ok(library) => Some(library),
+            Err(e) if data.is_empty() => {
+                log::info!("pipeline libraries not available: {}", e);
+                None
+            }
+            Err(e) => {
+                // The driver can still reject the data, for example when it
+                // was saved by an older driver with the same version.
+                log::info!("ignoring saved pipeline library: {}", e);
+                return self.create_pipeline_cache(&[]);
+            }
+        };
+        let restored = library.is_some() && !data.is_empty();
+        let cache = PipelineCache {
+            library,
+            _data: data,
+        };
+        Ok((cache, restored))
+    }
+
+    unsafe fn pipeline_cache_data(&self, cache: &PipelineCache) -> Result<Vec<u8>, Error> {
+        match &cache.library {
+            Some(library) => Ok(library.serialize()?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    unsafe fn destroy_pipeline_cache(&self, _cache: &PipelineCache) -> Result<(), Error> {
+        Ok(())
+    }
+
     unsafe fn descriptor_set_builder(&self) -> Self::DescriptorSetBuilder {
         DescriptorSetBuilder::default()
     }
@@ -577,9 +1035,16 @@ impl crate::backend::Device for Dx12Device {
         index: u32,
         buf: &Self::Buffer,
     ) {
-        let src_cpu_ref = buf.cpu_ref.as_ref().unwrap().handle();
+        let cpu_ref = match ds.bind_types.get(index as usize) {
+            Some(BindType::Uniform) => &buf.cbv_ref,
+            _ => &buf.cpu_ref,
+        };
+        let src_cpu_ref = cpu_ref.as_ref().unwrap().handle();
         ds.gpu_ref
             .copy_one_descriptor(&self.device, src_cpu_ref, index);
+        if let Some(entry) = ds.buffers.iter_mut().find(|(i, ..)| *i == index as usize) {
+            entry.1 = buf.resource.clone();
+        }
     }
 
     unsafe fn update_image_descriptor(
@@ -588,17 +1053,55 @@ impl crate::backend::Device for Dx12Device {
         index: u32,
         image: &Self::Image,
     ) {
-        let src_cpu_ref = image.cpu_ref.as_ref().unwrap().handle();
+        let cpu_ref = match ds.bind_types.get(index as usize) {
+            Some(BindType::SampledImage) => &image.srv_ref,
+            _ => &image.cpu_ref,
+        };
+        let src_cpu_ref = cpu_ref.as_ref().unwrap().handle();
         ds.gpu_ref
             .copy_one_descriptor(&self.device, src_cpu_ref, index);
     }
 
-    unsafe fn create_sampler(&self, _params: crate::SamplerParams) -> Result<Self::Sampler, Error> {
-        todo!()
+    unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Self::Sampler, Error> {
+        let filter = match params.filter {
+            FilterMode::Nearest => d3d12::D3D12_FILTER_MIN_MAG_MIP_POINT,
+            FilterMode::Linear => d3d12::D3D12_FILTER_MIN_MAG_MIP_LINEAR,
+        };
+        let address_mode = match params.address_mode {
+            AddressMode::ClampToEdge => d3d12::D3D12_TEXTURE_ADDRESS_MODE_CLAMP,
+            AddressMode::ClampToBorder => d3d12::D3D12_TEXTURE_ADDRESS_MODE_BORDER,
+            AddressMode::Repeat => d3d12::D3D12_TEXTURE_ADDRESS_MODE_WRAP,
+            AddressMode::MirrorRepeat => d3d12::D3D12_TEXTURE_ADDRESS_MODE_MIRROR,
+        };
+        let desc = d3d12::D3D12_SAMPLER_DESC {
+            Filter: filter,
+            AddressU: address_mode,
+            AddressV: address_mode,
+            AddressW: address_mode,
+            MipLODBias: 0.0,
+            MaxAnisotropy: 1,
+            ComparisonFunc: d3d12::D3D12_COMPARISON_FUNC_NEVER,
+            // Transparent black, as on the other backends.
+            BorderColor: [0.0; 4],
+            MinLOD: 0.0,
+            MaxLOD: 0.0,
+        };
+        let mut sampler_pool = self.sampler_pool.lock().unwrap();
+        let cpu_ref = sampler_pool.alloc_cpu(&self.device)?;
+        self.device
+            .create_sampler(&desc, sampler_pool.cpu_handle(&cpu_ref));
+        Ok(Sampler { cpu_ref })
     }
 }
 
 impl Dx12Device {
+    /// Forward messages from the debug layer to `log`, if it's enabled.
+    unsafe fn drain_messages(&self) {
+        if let Some(info_queue) = &self.info_queue {
+            info_queue.drain();
+        }
+    }
+
     fn create_readback_buffer(&self, size: u64) -> Result<Buffer, Error> {
         unsafe {
             let resource = self.device.create_buffer(
@@ -615,15 +1118,78 @@ impl Dx12Device {
                 resource,
                 size,
                 cpu_ref,
+                cbv_ref: None,
                 gpu_ref,
             })
         }
     }
+
+    /// Compile HLSL source to bytecode, specialized with the constants in the options.
+    ///
+    /// The entry point must be named `main`.
+    pub fn compile_hlsl(&self, source: &str, options: &PipelineOptions) -> Result<Vec<u8>, Error> {
+        #[cfg(debug_assertions)]
+        let flags = winapi::um::d3dcompiler::D3DCOMPILE_DEBUG
+            | winapi::um::d3dcompiler::D3DCOMPILE_SKIP_OPTIMIZATION;
+        #[cfg(not(debug_assertions))]
+        let flags = 0;
+        // spirv-cross defines a macro for each specialization constant,
+        // guarded so that it can be overridden.
+        let defines = options
+            .constants
+            .iter()
+            .map(|c| {
+                let name = format!("SPIRV_CROSS_CONSTANT_ID_{}", c.id);
+                let value = match c.value {
+                    SpecValue::Bool(b) => b.to_string(),
+                    SpecValue::U32(u) => format!("{}u", u),
+                    SpecValue::I32(i) => format!("{}", i),
+                    SpecValue::F32(f) => format!("asfloat({}u)", f.to_bits()),
+                };
+                (name, value)
+            })
+            .collect::<Vec<_>>();
+        unsafe {
+            let blob = ShaderByteCode::compile(source, "cs_5_1", "main", &defines, flags)?;
+            let bytes = std::slice::from_raw_parts(
+                blob.0.GetBufferPointer() as *const u8,
+                blob.0.GetBufferSize(),
+            );
+            Ok(bytes.to_vec())
+        }
+    }
+}
+
+impl CmdBuf {
+    /// Transition a buffer to the state of its next use, if needed.
+    unsafe fn use_buffer(&mut self, resource: &Resource, state: d3d12::D3D12_RESOURCE_STATES) {
+        let ptr = resource.get_mut();
+        match self
+            .buffer_states
+            .iter_mut()
+            .find(|(r, _)| r.get_mut() == ptr)
+        {
+            Some((_, prev)) if *prev != state => {
+                let bar = wrappers::create_transition_resource_barrier(ptr, *prev, state);
+                self.c.resource_barrier(&[bar]);
+                *prev = state;
+            }
+            Some(_) => (),
+            None => self.buffer_states.push((resource.clone(), state)),
+        }
+    }
+
+    unsafe fn use_descriptor_set(&mut self, descriptor_set: &DescriptorSet) {
+        for (_, resource, state) in &descriptor_set.buffers {
+            self.use_buffer(resource, *state);
+        }
+    }
 }
 
 impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
     unsafe fn begin(&mut self) {
         if self.needs_reset {}
+        self.buffer_states.clear();
     }
 
     unsafe fn finish(&mut self) {
@@ -634,7 +1200,14 @@ impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
     unsafe fn flush(&mut self) {}
 
     unsafe fn reset(&mut self) -> bool {
-        self.allocator.reset().is_ok() && self.c.reset(&self.allocator, None).is_ok()
+        // A command list that is still recording can't be reset.
+        if !self.needs_reset {
+            return false;
+        }
+        self.end_query = None;
+        let ok = self.allocator.reset().is_ok() && self.c.reset(&self.allocator, None).is_ok();
+        self.needs_reset = !ok;
+        ok
     }
 
     unsafe fn begin_compute_pass(&mut self, desc: &ComputePassDescriptor) {
@@ -653,18 +1226,48 @@ impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
         descriptor_set: &DescriptorSet,
         workgroup_count: (u32, u32, u32),
         _workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
     ) {
+        self.use_descriptor_set(descriptor_set);
         self.c.set_pipeline_state(&pipeline.pipeline_state);
         self.c
             .set_compute_pipeline_root_signature(&pipeline.root_signature);
         // TODO: persist heap ix and only set if changed.
-        self.c.set_descriptor_heaps(&[&descriptor_set.heap]);
-        self.c
-            .set_compute_root_descriptor_table(0, descriptor_set.gpu_ref.gpu_handle());
+        descriptor_set.bind(&self.c);
+        if !push_constants.is_empty() {
+            self.c
+                .set_compute_root_32bit_constants(pipeline.push_constant_parameter, push_constants);
+        }
         self.c
             .dispatch(workgroup_count.0, workgroup_count.1, workgroup_count.2);
     }
 
+    unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        _workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.use_descriptor_set(descriptor_set);
+        self.use_buffer(
+            &buffer.resource,
+            d3d12::D3D12_RESOURCE_STATE_INDIRECT_ARGUMENT,
+        );
+        self.c.set_pipeline_state(&pipeline.pipeline_state);
+        self.c
+            .set_compute_pipeline_root_signature(&pipeline.root_signature);
+        descriptor_set.bind(&self.c);
+        if !push_constants.is_empty() {
+            self.c
+                .set_compute_root_32bit_constants(pipeline.push_constant_parameter, push_constants);
+        }
+        self.c
+            .execute_indirect(&self.dispatch_signature, &buffer.resource, offset);
+    }
+
     unsafe fn end_compute_pass(&mut self) {
         if let Some((heap, end)) = self.end_query.take() {
             self.c.end_timing_query(&heap, end);
@@ -709,12 +1312,41 @@ impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
         self.memory_barrier();
     }
 
+    unsafe fn buffer_queue_transfer(&mut self, _buffer: &Buffer, src: QueueType, dst: QueueType) {
+        // Resources have no queue ownership in DX12, and the semaphore
+        // between the submissions makes the writes visible.
+        if src == dst {
+            self.memory_barrier();
+        }
+    }
+
+    unsafe fn image_queue_transfer(
+        &mut self,
+        image: &Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        // The transfer is recorded on both queues, but the state transition
+        // must happen once. Copy queues can't transition to most states, so
+        // prefer the destination unless it is the copy queue.
+        let transition_queue = if dst == QueueType::Transfer { src } else { dst };
+        if src == dst || self.queue == transition_queue {
+            self.image_barrier(image, src_layout, dst_layout);
+        }
+    }
+
     unsafe fn clear_buffer(&mut self, buffer: &Buffer, size: Option<u64>) {
         let cpu_ref = buffer.cpu_ref.as_ref().unwrap();
         let (gpu_ref, heap) = buffer
             .gpu_ref
             .as_ref()
             .expect("Need to set CLEAR usage on buffer");
+        self.use_buffer(
+            &buffer.resource,
+            d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
+        );
         // Same TODO as dispatch: track and only set if changed.
         self.c.set_descriptor_heaps(&[heap]);
         // Discussion question: would compute shader be faster? Should measure.
@@ -730,23 +1362,99 @@ impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
     unsafe fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) {
         // TODO: consider using copy_resource here (if sizes match)
         let size = src.size.min(dst.size);
+        self.use_buffer(&src.resource, d3d12::D3D12_RESOURCE_STATE_COPY_SOURCE);
+        self.use_buffer(&dst.resource, d3d12::D3D12_RESOURCE_STATE_COPY_DEST);
         self.c.copy_buffer(&dst.resource, 0, &src.resource, 0, size);
     }
 
     unsafe fn copy_image_to_buffer(&mut self, src: &Image, dst: &Buffer) {
-        self.c
-            .copy_texture_to_buffer(&src.resource, &dst.resource, src.size.0, src.size.1);
+        let region = ImageRegion {
+            origin: (0, 0),
+            size: src.size,
+        };
+        self.copy_image_region_to_buffer(src, region, dst, src.tight_layout());
     }
 
     unsafe fn copy_buffer_to_image(&mut self, src: &Buffer, dst: &Image) {
-        self.c
-            .copy_buffer_to_texture(&src.resource, &dst.resource, dst.size.0, dst.size.1);
+        let region = ImageRegion {
+            origin: (0, 0),
+            size: dst.size,
+        };
+        self.copy_buffer_to_image_region(src, dst.tight_layout(), dst, region);
     }
 
     unsafe fn blit_image(&mut self, src: &Image, dst: &Image) {
         self.c.copy_resource(&src.resource, &dst.resource);
     }
 
+    unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        self.use_buffer(&src.resource, d3d12::D3D12_RESOURCE_STATE_COPY_SOURCE);
+        self.use_buffer(&dst.resource, d3d12::D3D12_RESOURCE_STATE_COPY_DEST);
+        self.c
+            .copy_buffer(&dst.resource, dst_offset, &src.resource, src_offset, size);
+    }
+
+    unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        self.use_buffer(&dst.resource, d3d12::D3D12_RESOURCE_STATE_COPY_DEST);
+        self.c.copy_texture_to_buffer(
+            &src.resource,
+            region.origin,
+            region.size,
+            &dst.resource,
+            layout.offset,
+            layout.row_pitch,
+            dxgi_format(src.format),
+        );
+    }
+
+    unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        self.use_buffer(&src.resource, d3d12::D3D12_RESOURCE_STATE_COPY_SOURCE);
+        self.c.copy_buffer_to_texture(
+            &src.resource,
+            layout.offset,
+            layout.row_pitch,
+            dxgi_format(dst.format),
+            &dst.resource,
+            region.origin,
+            region.size,
+        );
+    }
+
+    unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        self.c.copy_texture_region(
+            &src.resource,
+            region.origin,
+            region.size,
+            &dst.resource,
+            dst_origin,
+        );
+    }
+
     unsafe fn reset_query_pool(&mut self, _pool: &QueryPool) {}
 
     unsafe fn write_timestamp(&mut self, pool: &QueryPool, query: u32) {
@@ -762,33 +1470,142 @@ impl crate::backend::CmdBuf<Dx12Device> for CmdBuf {
 impl crate::backend::DescriptorSetBuilder<Dx12Device> for DescriptorSetBuilder {
     fn add_buffers(&mut self, buffers: &[&Buffer]) {
         for buf in buffers {
+            self.buffers
+                .push((self.handles.len(), buf.resource.clone()));
+            self.handles.push(buf.cpu_ref.as_ref().unwrap().handle());
+            self.cbv_handles
+                .push(buf.cbv_ref.as_ref().map(|cbv_ref| cbv_ref.handle()));
+            self.slices.push(None);
+        }
+    }
+
+    fn add_buffer_slices(&mut self, slices: &[(&Buffer, u64, u64)]) {
+        for (buf, offset, size) in slices {
+            self.buffers
+                .push((self.handles.len(), buf.resource.clone()));
+            // Placeholder, overwritten by a view of the slice in `build`.
             self.handles.push(buf.cpu_ref.as_ref().unwrap().handle());
+            self.cbv_handles.push(None);
+            self.slices.push(Some(((*buf).clone(), *offset, *size)));
         }
     }
 
     fn add_images(&mut self, images: &[&Image]) {
         for img in images {
             self.handles.push(img.cpu_ref.as_ref().unwrap().handle());
+            self.cbv_handles.push(None);
+            self.slices.push(None);
         }
     }
 
     fn add_textures(&mut self, images: &[&Image]) {
+        self.add_images(images);
+    }
+
+    fn add_sampled_images(&mut self, images: &[&Image], sampler: &Sampler) {
         for img in images {
-            self.handles.push(img.cpu_ref.as_ref().unwrap().handle());
+            self.handles.push(img.srv_ref.as_ref().unwrap().handle());
+            self.cbv_handles.push(None);
+            self.slices.push(None);
+            self.samplers.push(sampler.cpu_ref.handle());
         }
     }
 
     unsafe fn build(
-        self,
+        mut self,
         device: &Dx12Device,
-        _pipeline: &Pipeline,
+        pipeline: &Pipeline,
     ) -> Result<DescriptorSet, Error> {
+        for (i, bind_type) in pipeline.bind_types.iter().enumerate() {
+            if *bind_type == BindType::Uniform && i < self.handles.len() && self.slices[i].is_none()
+            {
+                self.handles[i] =
+                    self.cbv_handles[i].ok_or("uniform buffer needs UNIFORM usage")?;
+            }
+        }
         let mut descriptor_pool = device.descriptor_pool.lock().unwrap();
         let n_descriptors = self.handles.len().try_into()?;
         let gpu_ref = descriptor_pool.alloc_gpu(&device.device, n_descriptors)?;
         gpu_ref.copy_descriptors(&device.device, &self.handles);
+        for (i, slice) in self.slices.iter().enumerate() {
+            if let Some((buf, offset, size)) = slice {
+                let handle = descriptor_pool.cpu_handle_of_gpu(&gpu_ref, i as u32);
+                if pipeline.bind_types.get(i) == Some(&BindType::Uniform) {
+                    // The padding of the resource covers the rounded size,
+                    // as the offset is aligned to 256 bytes.
+                    if buf.cbv_ref.is_none() {
+                        return Err("uniform buffer needs UNIFORM usage".into());
+                    }
+                    device.device.create_constant_buffer_view(
+                        &buf.resource,
+                        handle,
+                        *offset,
+                        cbv_size(*size).try_into()?,
+                    );
+                } else {
+                    device
+                        .device
+                        .create_byte_addressed_buffer_unordered_access_view(
+                            &buf.resource,
+                            handle,
+                            offset / 4,
+                            (size / 4).try_into()?,
+                        );
+                }
+            }
+        }
         let heap = descriptor_pool.gpu_heap(&gpu_ref).to_owned();
-        Ok(DescriptorSet { gpu_ref, heap })
+        drop(descriptor_pool);
+        let samplers = if self.samplers.is_empty() {
+            None
+        } else {
+            let mut sampler_pool = device.sampler_pool.lock().unwrap();
+            let n_samplers = self.samplers.len().try_into()?;
+            let sampler_ref = sampler_pool.alloc_gpu(&device.device, n_samplers)?;
+            sampler_ref.copy_descriptors(&device.device, &self.samplers);
+            let sampler_heap = sampler_pool.gpu_heap(&sampler_ref).to_owned();
+            Some((sampler_ref, sampler_heap))
+        };
+        let buffers = self
+            .buffers
+            .into_iter()
+            .map(|(i, resource)| {
+                let state = match pipeline.bind_types.get(i) {
+                    Some(BindType::Uniform) => {
+                        d3d12::D3D12_RESOURCE_STATE_VERTEX_AND_CONSTANT_BUFFER
+                    }
+                    Some(BindType::BufReadOnly) => {
+                        d3d12::D3D12_RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE
+                    }
+                    _ => d3d12::D3D12_RESOURCE_STATE_UNORDERED_ACCESS,
+                };
+                (i, resource, state)
+            })
+            .collect();
+        Ok(DescriptorSet {
+            gpu_ref,
+            heap,
+            samplers,
+            bind_types: pipeline.bind_types.clone(),
+            buffers,
+        })
+    }
+}
+
+impl DescriptorSet {
+    /// Set the descriptor heaps and tables for a dispatch.
+    unsafe fn bind(&self, c: &wrappers::GraphicsCommandList) {
+        match &self.samplers {
+            Some((sampler_ref, sampler_heap)) => {
+                c.set_descriptor_heaps(&[&self.heap, sampler_heap]);
+                c.set_compute_root_descriptor_table(0, self.gpu_ref.gpu_handle());
+                c.set_compute_root_descriptor_table(1, sampler_ref.gpu_handle());
+            }
+            None => {
+                c.set_descriptor_heaps(&[&self.heap]);
+                c.set_compute_root_descriptor_table(0, self.gpu_ref.gpu_handle());
+            }
+        }
     }
 }
 
@@ -820,6 +1637,15 @@ impl MemoryArchitecture {
     }
 }
 
+fn adapter_name(desc: &dxgi::DXGI_ADAPTER_DESC1) -> String {
+    let len = desc
+        .Description
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(desc.Description.len());
+    String::from_utf16_lossy(&desc.Description[..len])
+}
+
 fn resource_state_for_image_layout(layout: ImageLayout) -> d3d12::D3D12_RESOURCE_STATES {
     match layout {
         ImageLayout::Undefined => d3d12::D3D12_RESOURCE_STATE_COMMON,
@@ -827,14 +1653,49 @@ fn resource_state_for_image_layout(layout: ImageLayout) -> d3d12::D3D12_RESOURCE
         ImageLayout::BlitSrc => d3d12::D3D12_RESOURCE_STATE_COPY_SOURCE,
         ImageLayout::BlitDst => d3d12::D3D12_RESOURCE_STATE_COPY_DEST,
         ImageLayout::General => d3d12::D3D12_RESOURCE_STATE_COMMON,
-        ImageLayout::ShaderRead => d3d12::D3D12_RESOURCE_STATE_PIXEL_SHADER_RESOURCE,
+        ImageLayout::ShaderRead => d3d12::D3D12_RESOURCE_STATE_NON_PIXEL_SHADER_RESOURCE,
+    }
+}
+
+/// The size of a constant buffer view, which must be a multiple of 256 bytes.
+fn cbv_size(size: u64) -> u64 {
+    (size + 255) & !255
+}
+
+fn dxgi_format(format: ImageFormat) -> winapi::shared::dxgiformat::DXGI_FORMAT {
+    use winapi::shared::dxgiformat::*;
+    match format {
+        ImageFormat::A8 => DXGI_FORMAT_R8_UNORM,
+        ImageFormat::Rgba8 => DXGI_FORMAT_R8G8B8A8_UNORM,
+        ImageFormat::Bgra8 => DXGI_FORMAT_B8G8R8A8_UNORM,
+        ImageFormat::Rgba8Srgb => DXGI_FORMAT_R8G8B8A8_UNORM_SRGB,
+        ImageFormat::Bgra8Srgb => DXGI_FORMAT_B8G8R8A8_UNORM_SRGB,
+        ImageFormat::Rgba16Float => DXGI_FORMAT_R16G16B16A16_FLOAT,
+        ImageFormat::R32Float => DXGI_FORMAT_R32_FLOAT,
+        ImageFormat::Rgba32Float => DXGI_FORMAT_R32G32B32A32_FLOAT,
+    }
+}
+
+impl Image {
+    /// Tightly packed layout for the whole image.
+    ///
+    /// This only satisfies the D3D12 pitch alignment for suitable widths.
+    fn tight_layout(&self) -> BufferImageLayout {
+        BufferImageLayout {
+            offset: 0,
+            row_pitch: self.size.0 * self.format.bytes_per_pixel(),
+        }
     }
 }
 
 impl Dx12Swapchain {
     pub unsafe fn next(&mut self) -> Result<(usize, Semaphore), Error> {
         let idx = self.swapchain.get_current_back_buffer_index();
-        Ok((idx as usize, Semaphore))
+        let semaphore = Semaphore {
+            fence: None,
+            val: Cell::new(0),
+        };
+        Ok((idx as usize, semaphore))
     }
 
     pub unsafe fn image(&self, idx: usize) -> Image {
@@ -842,10 +1703,16 @@ impl Dx12Swapchain {
         Image {
             resource: buffer,
             cpu_ref: None,
+            srv_ref: None,
             size: self.size,
+            format: self.format,
         }
     }
 
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
     pub unsafe fn present(
         &self,
         _image_idx: usize,
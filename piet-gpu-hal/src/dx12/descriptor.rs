@@ -17,8 +17,8 @@ use std::{
 use smallvec::SmallVec;
 use winapi::um::d3d12::{
     D3D12_CPU_DESCRIPTOR_HANDLE, D3D12_DESCRIPTOR_HEAP_DESC, D3D12_DESCRIPTOR_HEAP_FLAG_NONE,
-    D3D12_DESCRIPTOR_HEAP_FLAG_SHADER_VISIBLE, D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV,
-    D3D12_GPU_DESCRIPTOR_HANDLE,
+    D3D12_DESCRIPTOR_HEAP_FLAG_SHADER_VISIBLE, D3D12_DESCRIPTOR_HEAP_TYPE,
+    D3D12_DESCRIPTOR_HEAP_TYPE_SAMPLER, D3D12_GPU_DESCRIPTOR_HANDLE,
 };
 
 use crate::{bestfit::BestFit, Error};
@@ -27,9 +27,12 @@ use super::wrappers::{DescriptorHeap, Device};
 
 const CPU_CHUNK_SIZE: u32 = 256;
 const GPU_CHUNK_SIZE: u32 = 4096;
+// The maximum size of a shader visible sampler heap.
+const GPU_SAMPLER_HEAP_SIZE: u32 = 2048;
 
-#[derive(Default)]
+/// A pool of descriptors of one heap type.
 pub struct DescriptorPool {
+    heap_type: D3D12_DESCRIPTOR_HEAP_TYPE,
     cpu_visible: Vec<CpuHeap>,
     gpu_visible: Vec<GpuHeap>,
     free_list: Arc<Mutex<DescriptorFreeList>>,
@@ -85,10 +88,20 @@ pub struct GpuHeapRefOwned {
     cpu_handle: D3D12_CPU_DESCRIPTOR_HANDLE,
     gpu_handle: D3D12_GPU_DESCRIPTOR_HANDLE,
     increment_size: u32,
+    heap_type: D3D12_DESCRIPTOR_HEAP_TYPE,
     free_list: Weak<Mutex<DescriptorFreeList>>,
 }
 
 impl DescriptorPool {
+    pub fn new(heap_type: D3D12_DESCRIPTOR_HEAP_TYPE) -> DescriptorPool {
+        DescriptorPool {
+            heap_type,
+            cpu_visible: Vec::new(),
+            gpu_visible: Vec::new(),
+            free_list: Default::default(),
+        }
+    }
+
     pub fn alloc_cpu(&mut self, device: &Device) -> Result<CpuHeapRefOwned, Error> {
         let free_list = &self.free_list;
         let mk_owned = |heap_ref, handle| CpuHeapRefOwned {
@@ -104,7 +117,7 @@ impl DescriptorPool {
             }
         }
         unsafe {
-            let heap_type = D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV;
+            let heap_type = self.heap_type;
             let desc = D3D12_DESCRIPTOR_HEAP_DESC {
                 Type: heap_type,
                 NumDescriptors: CPU_CHUNK_SIZE,
@@ -138,13 +151,14 @@ impl DescriptorPool {
 
     pub fn alloc_gpu(&mut self, device: &Device, n: u32) -> Result<GpuHeapRefOwned, Error> {
         let free_list = &self.free_list;
-        let heap_type = D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV;
+        let heap_type = self.heap_type;
         let increment_size = unsafe { device.get_descriptor_increment_size(heap_type) };
         let mk_owned = |heap_ref, cpu_handle, gpu_handle| GpuHeapRefOwned {
             heap_ref,
             cpu_handle,
             gpu_handle,
             increment_size,
+            heap_type,
             free_list: Arc::downgrade(free_list),
         };
         let mut free_list = free_list.lock().unwrap();
@@ -162,6 +176,15 @@ impl DescriptorPool {
         }
         unsafe {
             let size = n.max(GPU_CHUNK_SIZE).next_power_of_two();
+            // Shader visible sampler heaps are limited in size.
+            let size = if heap_type == D3D12_DESCRIPTOR_HEAP_TYPE_SAMPLER {
+                if n > GPU_SAMPLER_HEAP_SIZE {
+                    return Err("too many samplers in descriptor set".into());
+                }
+                GPU_SAMPLER_HEAP_SIZE
+            } else {
+                size
+            };
             let desc = D3D12_DESCRIPTOR_HEAP_DESC {
                 Type: heap_type,
                 NumDescriptors: size,
@@ -241,13 +264,7 @@ impl GpuHeapRefOwned {
         // TODO: optimize a bit (use simple variant where appropriate)
         let n = src.len().try_into().unwrap();
         let sizes = (0..n).map(|_| 1).collect::<SmallVec<[u32; 16]>>();
-        device.copy_descriptors(
-            &[self.cpu_handle],
-            &[n],
-            src,
-            &sizes,
-            D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV,
-        );
+        device.copy_descriptors(&[self.cpu_handle], &[n], src, &sizes, self.heap_type);
     }
 
     pub unsafe fn copy_one_descriptor(
@@ -258,7 +275,7 @@ impl GpuHeapRefOwned {
     ) {
         let mut dst = self.cpu_handle;
         dst.ptr += (index * self.increment_size) as usize;
-        device.copy_one_descriptor(dst, src, D3D12_DESCRIPTOR_HEAP_TYPE_CBV_SRV_UAV);
+        device.copy_one_descriptor(dst, src, self.heap_type);
     }
 }
 
@@ -14,6 +14,8 @@ use winapi::shared::winerror;
 pub enum Error {
     Hresult(winerror::HRESULT),
     ExplainedHr(&'static str, winerror::HRESULT),
+    /// Shader compilation failed, with the messages from the compiler.
+    ShaderCompile(String),
 }
 
 impl std::fmt::Debug for Error {
@@ -24,6 +26,7 @@ impl std::fmt::Debug for Error {
                 write!(f, "{}: ", exp)?;
                 write_hr(f, *hr)
             }
+            Error::ShaderCompile(log) => write!(f, "shader compilation failed: {}", log),
         }
     }
 }
@@ -36,6 +39,22 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl From<Error> for crate::Error {
+    fn from(e: Error) -> crate::Error {
+        let hr = match &e {
+            Error::Hresult(hr) | Error::ExplainedHr(_, hr) => *hr as u32,
+            Error::ShaderCompile(log) => return crate::Error::ShaderCompile { log: log.clone() },
+        };
+        match hr {
+            // E_OUTOFMEMORY
+            0x8007000e => crate::Error::OutOfHostMemory,
+            // DXGI_ERROR_DEVICE_REMOVED, DXGI_ERROR_DEVICE_HUNG, DXGI_ERROR_DEVICE_RESET
+            0x887a0005 | 0x887a0006 | 0x887a0007 => crate::Error::DeviceLost,
+            _ => crate::Error::Backend(Box::new(e)),
+        }
+    }
+}
+
 /// Strings for errors we're likely to see.
 ///
 /// See https://docs.microsoft.com/en-us/windows/win32/direct3ddxgi/dxgi-error
@@ -43,11 +62,13 @@ fn err_str_for_hr(hr: winerror::HRESULT) -> Option<&'static str> {
     Some(match hr as u32 {
         0x80004005 => "E_FAIL",
         0x80070057 => "E_INVALIDARG",
+        0x8007000e => "E_OUTOFMEMORY",
         0x887a0001 => "DXGI_ERROR_INVALID_CALL",
         0x887a0002 => "DXGI_ERROR_NOT_FOUND",
         0x887a0004 => "DXGI_ERROR_UNSUPPORTED",
         0x887a0005 => "DXGI_ERROR_DEVICE_REMOVED",
         0x887a0006 => "DXGI_ERROR_DEVICE_HUNG",
+        0x887a0007 => "DXGI_ERROR_DEVICE_RESET",
         _ => return None,
     })
 }
@@ -12,7 +12,9 @@ use smallvec::SmallVec;
 use std::convert::{TryFrom, TryInto};
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::{ffi, mem, ptr};
-use winapi::shared::{dxgi, dxgi1_2, dxgi1_3, dxgi1_4, dxgiformat, dxgitype, minwindef, windef};
+use winapi::shared::{
+    dxgi, dxgi1_2, dxgi1_3, dxgi1_4, dxgiformat, dxgitype, minwindef, windef, winerror,
+};
 use winapi::um::d3dcommon::ID3DBlob;
 use winapi::um::{
     d3d12, d3d12sdklayers, d3dcommon, d3dcompiler, dxgidebug, handleapi, synchapi, winnt,
@@ -34,6 +36,8 @@ pub struct Resource {
 #[derive(Clone)]
 pub struct Adapter1(pub ComPtr<dxgi::IDXGIAdapter1>);
 #[derive(Clone)]
+pub struct Adapter3(pub ComPtr<dxgi1_4::IDXGIAdapter3>);
+#[derive(Clone)]
 pub struct Factory2(pub ComPtr<dxgi1_2::IDXGIFactory2>);
 #[derive(Clone)]
 pub struct Factory4(pub ComPtr<dxgi1_4::IDXGIFactory4>);
@@ -70,6 +74,9 @@ pub struct Fence(pub ComPtr<d3d12::ID3D12Fence>);
 #[derive(Clone)]
 pub struct PipelineState(pub ComPtr<d3d12::ID3D12PipelineState>);
 
+#[derive(Clone)]
+pub struct PipelineLibrary(pub ComPtr<d3d12::ID3D12PipelineLibrary>);
+
 #[derive(Clone)]
 pub struct CachedPSO(d3d12::D3D12_CACHED_PIPELINE_STATE);
 
@@ -84,6 +91,9 @@ pub struct ShaderByteCode {
 #[derive(Clone)]
 pub struct QueryHeap(pub ComPtr<d3d12::ID3D12QueryHeap>);
 
+#[derive(Clone)]
+pub struct InfoQueue(pub ComPtr<d3d12sdklayers::ID3D12InfoQueue>);
+
 impl Resource {
     pub unsafe fn new(ptr: *mut d3d12::ID3D12Resource) -> Resource {
         Resource {
@@ -99,6 +109,10 @@ impl Resource {
         self.ptr.load(Ordering::Relaxed)
     }
 
+    pub unsafe fn get_gpu_virtual_address(&self) -> d3d12::D3D12_GPU_VIRTUAL_ADDRESS {
+        (*self.get()).GetGPUVirtualAddress()
+    }
+
     // Safety: call only single-threaded.
     pub unsafe fn destroy(&self) {
         (*self.get()).Release();
@@ -164,6 +178,125 @@ impl Clone for Resource {
     }
 }
 
+impl Adapter1 {
+    pub unsafe fn get_desc(&self) -> dxgi::DXGI_ADAPTER_DESC1 {
+        let mut desc = mem::zeroed();
+        self.0.GetDesc1(&mut desc);
+        desc
+    }
+
+    /// The version of the user mode driver, if it can be queried.
+    pub unsafe fn get_driver_version(&self) -> Option<i64> {
+        let mut version: winnt::LARGE_INTEGER = mem::zeroed();
+        let hr = self
+            .0
+            .CheckInterfaceSupport(&dxgi::IDXGIDevice::uuidof(), &mut version);
+        if winerror::SUCCEEDED(hr) {
+            Some(*version.QuadPart())
+        } else {
+            None
+        }
+    }
+
+    /// The adapter as an `IDXGIAdapter3`, which can query memory budgets.
+    pub fn cast_adapter3(&self) -> Option<Adapter3> {
+        self.0.cast::<dxgi1_4::IDXGIAdapter3>().ok().map(Adapter3)
+    }
+}
+
+impl InfoQueue {
+    /// Forward the stored messages to `log` at matching levels, and clear them.
+    pub unsafe fn drain(&self) {
+        let n_messages = self.0.GetNumStoredMessagesAllowedByRetrievalFilter();
+        for i in 0..n_messages {
+            let mut len = 0;
+            if !winerror::SUCCEEDED(self.0.GetMessage(i, ptr::null_mut(), &mut len)) {
+                continue;
+            }
+            // The description is stored after the message, in the same
+            // allocation; u64 elements keep it aligned.
+            let mut buf = vec![0u64; (len + 7) / 8];
+            let message = buf.as_mut_ptr() as *mut d3d12sdklayers::D3D12_MESSAGE;
+            if !winerror::SUCCEEDED(self.0.GetMessage(i, message, &mut len)) {
+                continue;
+            }
+            let message = &*message;
+            let description = if message.pDescription.is_null() {
+                Default::default()
+            } else {
+                ffi::CStr::from_ptr(message.pDescription).to_string_lossy()
+            };
+            let level = match message.Severity {
+                d3d12sdklayers::D3D12_MESSAGE_SEVERITY_CORRUPTION
+                | d3d12sdklayers::D3D12_MESSAGE_SEVERITY_ERROR => log::Level::Error,
+                d3d12sdklayers::D3D12_MESSAGE_SEVERITY_WARNING => log::Level::Warn,
+                d3d12sdklayers::D3D12_MESSAGE_SEVERITY_INFO => log::Level::Info,
+                _ => log::Level::Trace,
+            };
+            log::log!(level, "[{}] : {}", message.ID, description);
+        }
+        self.0.ClearStoredMessages();
+    }
+}
+
+impl Adapter3 {
+    /// Query the budget and usage of a memory segment group, for the first node.
+    pub unsafe fn query_video_memory_info(
+        &self,
+        segment_group: dxgi1_4::DXGI_MEMORY_SEGMENT_GROUP,
+    ) -> Result<dxgi1_4::DXGI_QUERY_VIDEO_MEMORY_INFO, Error> {
+        let mut info = mem::zeroed();
+        explain_error(
+            self.0.QueryVideoMemoryInfo(0, segment_group, &mut info),
+            "could not query video memory info",
+        )?;
+        Ok(info)
+    }
+}
+
+impl PipelineLibrary {
+    /// Load a pipeline stored under the given name.
+    ///
+    /// This fails if there is no such pipeline, or if it was stored with a
+    /// different description. The name must be nul-terminated.
+    pub unsafe fn load_compute_pipeline(
+        &self,
+        name: &[u16],
+        desc: &d3d12::D3D12_COMPUTE_PIPELINE_STATE_DESC,
+    ) -> Result<PipelineState, Error> {
+        let mut pipeline_state = ptr::null_mut();
+        error_if_failed_else_unit(self.0.LoadComputePipeline(
+            name.as_ptr(),
+            desc,
+            &d3d12::ID3D12PipelineState::uuidof(),
+            &mut pipeline_state as *mut _ as *mut _,
+        ))?;
+        Ok(PipelineState(ComPtr::from_raw(pipeline_state)))
+    }
+
+    /// Store a pipeline under the given name, which must be nul-terminated.
+    pub unsafe fn store_pipeline(
+        &self,
+        name: &[u16],
+        pipeline_state: &PipelineState,
+    ) -> Result<(), Error> {
+        explain_error(
+            self.0
+                .StorePipeline(name.as_ptr(), pipeline_state.0.as_raw()),
+            "could not store pipeline in library",
+        )
+    }
+
+    pub unsafe fn serialize(&self) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0u8; self.0.GetSerializedSize()];
+        explain_error(
+            self.0.Serialize(data.as_mut_ptr() as *mut _, data.len()),
+            "could not serialize pipeline library",
+        )?;
+        Ok(data)
+    }
+}
+
 impl Factory4 {
     pub unsafe fn create(flags: minwindef::UINT) -> Result<Factory4, Error> {
         let mut factory = ptr::null_mut();
@@ -183,9 +316,6 @@ impl Factory4 {
     pub unsafe fn enumerate_adapters(&self, id: u32) -> Result<Adapter1, Error> {
         let mut adapter = ptr::null_mut();
         error_if_failed_else_unit(self.0.EnumAdapters1(id, &mut adapter))?;
-        let mut desc = mem::zeroed();
-        (*adapter).GetDesc(&mut desc);
-        //println!("desc: {:?}", desc.Description);
         Ok(Adapter1(ComPtr::from_raw(adapter)))
     }
 
@@ -220,6 +350,13 @@ impl CommandQueue {
         )
     }
 
+    pub unsafe fn wait(&self, fence: &Fence, value: u64) -> Result<(), Error> {
+        explain_error(
+            self.0.Wait(fence.0.as_raw(), value),
+            "error waiting on fence",
+        )
+    }
+
     pub unsafe fn execute_command_lists(&self, command_lists: &[*mut d3d12::ID3D12CommandList]) {
         let num_command_lists = command_lists.len().try_into().unwrap();
         self.0
@@ -268,35 +405,20 @@ impl Blob {
     #[allow(unused)]
     pub unsafe fn print_to_console(blob: &Blob) {
         println!("==SHADER COMPILE MESSAGES==");
-        let message = {
-            let pointer = blob.0.GetBufferPointer();
-            let size = blob.0.GetBufferSize();
-            let slice = std::slice::from_raw_parts(pointer as *const u8, size as usize);
-            String::from_utf8_lossy(slice).into_owned()
-        };
-        println!("{}", message);
+        println!("{}", blob.to_string_lossy());
         println!("===========================");
     }
-}
 
-impl Device {
-    pub unsafe fn create_device(factory4: &Factory4) -> Result<Device, Error> {
-        let mut id = 0;
-
-        loop {
-            // This always returns DXGI_ERROR_NOT_FOUND if no suitable adapter is found.
-            // Might be slightly more useful to retain the error from the attempt to create.
-            let adapter = factory4.enumerate_adapters(id)?;
-
-            if let Ok(device) =
-                Self::create_using_adapter(&adapter, d3dcommon::D3D_FEATURE_LEVEL_12_0)
-            {
-                return Ok(device);
-            }
-            id += 1;
-        }
+    /// The contents of the blob, interpreted as text.
+    pub unsafe fn to_string_lossy(&self) -> String {
+        let pointer = self.0.GetBufferPointer();
+        let size = self.0.GetBufferSize();
+        let slice = std::slice::from_raw_parts(pointer as *const u8, size as usize);
+        String::from_utf8_lossy(slice).into_owned()
     }
+}
 
+impl Device {
     pub unsafe fn create_using_adapter(
         adapter: &Adapter1,
         feature_level: d3dcommon::D3D_FEATURE_LEVEL,
@@ -433,6 +555,36 @@ impl Device {
         Ok(PipelineState(ComPtr::from_raw(pipeline_state)))
     }
 
+    /// The device's info queue, present when the debug layer is enabled.
+    pub fn cast_info_queue(&self) -> Option<InfoQueue> {
+        self.0
+            .cast::<d3d12sdklayers::ID3D12InfoQueue>()
+            .ok()
+            .map(InfoQueue)
+    }
+
+    /// Create a pipeline library, from serialized data or empty.
+    ///
+    /// The data must outlive the library.
+    pub unsafe fn create_pipeline_library(&self, data: &[u8]) -> Result<PipelineLibrary, Error> {
+        let device1 = self
+            .0
+            .cast::<d3d12::ID3D12Device1>()
+            .map_err(|hr| Error::ExplainedHr("device does not support pipeline libraries", hr))?;
+        let mut library = ptr::null_mut();
+        explain_error(
+            device1.CreatePipelineLibrary(
+                data.as_ptr() as *const _,
+                data.len(),
+                &d3d12::ID3D12PipelineLibrary::uuidof(),
+                &mut library as *mut _ as *mut _,
+            ),
+            "device could not create pipeline library",
+        )?;
+
+        Ok(PipelineLibrary(ComPtr::from_raw(library)))
+    }
+
     pub unsafe fn create_root_signature(
         &self,
         node_mask: minwindef::UINT,
@@ -453,6 +605,32 @@ impl Device {
         Ok(RootSignature(ComPtr::from_raw(signature)))
     }
 
+    /// Create a command signature for indirect dispatch, with no root arguments.
+    pub unsafe fn create_dispatch_command_signature(&self) -> Result<CommandSignature, Error> {
+        let argument_desc = d3d12::D3D12_INDIRECT_ARGUMENT_DESC {
+            Type: d3d12::D3D12_INDIRECT_ARGUMENT_TYPE_DISPATCH,
+            ..mem::zeroed()
+        };
+        let desc = d3d12::D3D12_COMMAND_SIGNATURE_DESC {
+            ByteStride: mem::size_of::<d3d12::D3D12_DISPATCH_ARGUMENTS>() as u32,
+            NumArgumentDescs: 1,
+            pArgumentDescs: &argument_desc,
+            NodeMask: 0,
+        };
+        let mut signature = ptr::null_mut();
+        explain_error(
+            self.0.CreateCommandSignature(
+                &desc,
+                ptr::null_mut(),
+                &d3d12::ID3D12CommandSignature::uuidof(),
+                &mut signature as *mut _ as *mut _,
+            ),
+            "device could not create command signature",
+        )?;
+
+        Ok(CommandSignature(ComPtr::from_raw(signature)))
+    }
+
     pub unsafe fn create_graphics_command_list(
         &self,
         list_type: d3d12::D3D12_COMMAND_LIST_TYPE,
@@ -503,6 +681,20 @@ impl Device {
             .CreateUnorderedAccessView(resource.get_mut(), ptr::null_mut(), &uav_desc, descriptor)
     }
 
+    pub unsafe fn create_constant_buffer_view(
+        &self,
+        resource: &Resource,
+        descriptor: CpuDescriptor,
+        offset: u64,
+        size_in_bytes: u32,
+    ) {
+        let cbv_desc = d3d12::D3D12_CONSTANT_BUFFER_VIEW_DESC {
+            BufferLocation: resource.get_gpu_virtual_address() + offset,
+            SizeInBytes: size_in_bytes,
+        };
+        self.0.CreateConstantBufferView(&cbv_desc, descriptor)
+    }
+
     pub unsafe fn create_unordered_access_view(
         &self,
         resource: &Resource,
@@ -516,6 +708,23 @@ impl Device {
         )
     }
 
+    pub unsafe fn create_shader_resource_view(
+        &self,
+        resource: &Resource,
+        descriptor: CpuDescriptor,
+    ) {
+        self.0
+            .CreateShaderResourceView(resource.get_mut(), ptr::null(), descriptor)
+    }
+
+    pub unsafe fn create_sampler(
+        &self,
+        sampler_desc: &d3d12::D3D12_SAMPLER_DESC,
+        descriptor: CpuDescriptor,
+    ) {
+        self.0.CreateSampler(sampler_desc, descriptor)
+    }
+
     pub unsafe fn create_fence(&self, initial: u64) -> Result<Fence, Error> {
         let mut fence = ptr::null_mut();
         explain_error(
@@ -698,6 +907,38 @@ impl Device {
         )?;
         Ok(features_architecture)
     }
+
+    pub unsafe fn get_options1(&self) -> Result<d3d12::D3D12_FEATURE_DATA_D3D12_OPTIONS1, Error> {
+        let mut options1 = mem::zeroed();
+        explain_error(
+            self.0.CheckFeatureSupport(
+                d3d12::D3D12_FEATURE_D3D12_OPTIONS1,
+                &mut options1 as *mut _ as *mut _,
+                mem::size_of::<d3d12::D3D12_FEATURE_DATA_D3D12_OPTIONS1>() as u32,
+            ),
+            "error querying options1",
+        )?;
+        Ok(options1)
+    }
+
+    pub unsafe fn get_format_support(
+        &self,
+        format: dxgiformat::DXGI_FORMAT,
+    ) -> Result<d3d12::D3D12_FEATURE_DATA_FORMAT_SUPPORT, Error> {
+        let mut format_support = d3d12::D3D12_FEATURE_DATA_FORMAT_SUPPORT {
+            Format: format,
+            ..mem::zeroed()
+        };
+        explain_error(
+            self.0.CheckFeatureSupport(
+                d3d12::D3D12_FEATURE_FORMAT_SUPPORT,
+                &mut format_support as *mut _ as *mut _,
+                mem::size_of::<d3d12::D3D12_FEATURE_DATA_FORMAT_SUPPORT>() as u32,
+            ),
+            "error querying format support",
+        )?;
+        Ok(format_support)
+    }
 }
 
 impl DescriptorHeap {
@@ -761,6 +1002,7 @@ impl ShaderByteCode {
         source: &str,
         target: &str,
         entry: &str,
+        defines: &[(String, String)],
         flags: minwindef::DWORD,
     ) -> Result<Blob, Error> {
         let mut shader_blob_ptr: *mut ID3DBlob = ptr::null_mut();
@@ -771,12 +1013,33 @@ impl ShaderByteCode {
             .expect("could not convert target format string into ffi::CString");
         let entry = ffi::CString::new(entry)
             .expect("could not convert entry name String into ffi::CString");
+        let defines = defines
+            .iter()
+            .map(|(name, value)| {
+                (
+                    ffi::CString::new(name.as_str()).expect("invalid define name"),
+                    ffi::CString::new(value.as_str()).expect("invalid define value"),
+                )
+            })
+            .collect::<Vec<_>>();
+        // The list of macros is terminated by a null entry.
+        let macros = defines
+            .iter()
+            .map(|(name, value)| d3dcommon::D3D_SHADER_MACRO {
+                Name: name.as_ptr(),
+                Definition: value.as_ptr(),
+            })
+            .chain(Some(d3dcommon::D3D_SHADER_MACRO {
+                Name: ptr::null(),
+                Definition: ptr::null(),
+            }))
+            .collect::<Vec<_>>();
 
         let hresult = d3dcompiler::D3DCompile(
             source.as_ptr() as *const _,
             source.len(),
             ptr::null(),
-            ptr::null(),
+            macros.as_ptr(),
             d3dcompiler::D3D_COMPILE_STANDARD_FILE_INCLUDE,
             entry.as_ptr(),
             target.as_ptr(),
@@ -791,6 +1054,11 @@ impl ShaderByteCode {
         } else {
             Some(Blob(ComPtr::from_raw(error_blob_ptr)))
         };
+        if !winerror::SUCCEEDED(hresult) {
+            if let Some(error_blob) = &error_blob {
+                return Err(Error::ShaderCompile(error_blob.to_string_lossy()));
+            }
+        }
         #[cfg(debug_assertions)]
         {
             if let Some(error_blob) = &error_blob {
@@ -798,7 +1066,6 @@ impl ShaderByteCode {
             }
         }
 
-        // TODO: we can put the shader compilation error into the returned error.
         explain_error(hresult, "shader compilation failed")?;
 
         Ok(Blob(ComPtr::from_raw(shader_blob_ptr)))
@@ -913,6 +1180,22 @@ impl GraphicsCommandList {
         self.0.Dispatch(count_x, count_y, count_z);
     }
 
+    pub unsafe fn execute_indirect(
+        &self,
+        command_signature: &CommandSignature,
+        argument_buffer: &Resource,
+        argument_offset: u64,
+    ) {
+        self.0.ExecuteIndirect(
+            command_signature.0.as_raw(),
+            1,
+            argument_buffer.get_mut(),
+            argument_offset,
+            ptr::null_mut(),
+            0,
+        );
+    }
+
     pub unsafe fn set_pipeline_state(&self, pipeline_state: &PipelineState) {
         self.0.SetPipelineState(pipeline_state.0.as_raw());
     }
@@ -926,6 +1209,15 @@ impl GraphicsCommandList {
             .SetComputeRootDescriptorTable(root_parameter_index, base_descriptor);
     }
 
+    pub unsafe fn set_compute_root_32bit_constants(&self, root_parameter_index: u32, data: &[u8]) {
+        self.0.SetComputeRoot32BitConstants(
+            root_parameter_index,
+            (data.len() / 4) as u32,
+            data.as_ptr() as *const _,
+            0,
+        );
+    }
+
     pub unsafe fn set_descriptor_heaps(&self, descriptor_heaps: &[&DescriptorHeap]) {
         let mut descriptor_heap_pointers: SmallVec<[_; 4]> =
             descriptor_heaps.iter().map(|dh| dh.0.as_raw()).collect();
@@ -1016,34 +1308,26 @@ impl GraphicsCommandList {
         );
     }
 
+    /// Copy from a buffer to a rectangle of a texture.
+    ///
+    /// The buffer data starts at `offset` and rows are `row_pitch` bytes
+    /// apart; D3D12 requires these to be 512 and 256 byte aligned.
     pub unsafe fn copy_buffer_to_texture(
         &self,
         buffer: &Resource,
+        offset: u64,
+        row_pitch: u32,
+        format: dxgiformat::DXGI_FORMAT,
         texture: &Resource,
-        width: u32,
-        height: u32,
+        origin: (u32, u32),
+        size: (u32, u32),
     ) {
         let mut src = d3d12::D3D12_TEXTURE_COPY_LOCATION {
             pResource: buffer.get_mut(),
             Type: d3d12::D3D12_TEXTURE_COPY_TYPE_PLACED_FOOTPRINT,
             ..mem::zeroed()
         };
-        let row_pitch = width * 4;
-        assert!(
-            row_pitch % d3d12::D3D12_TEXTURE_DATA_PITCH_ALIGNMENT == 0,
-            "TODO: handle unaligned row pitch"
-        );
-        let footprint = d3d12::D3D12_PLACED_SUBRESOURCE_FOOTPRINT {
-            Offset: 0,
-            Footprint: d3d12::D3D12_SUBRESOURCE_FOOTPRINT {
-                Format: dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM,
-                Width: width,
-                Height: height,
-                Depth: 1,
-                RowPitch: row_pitch,
-            },
-        };
-        *src.u.PlacedFootprint_mut() = footprint;
+        *src.u.PlacedFootprint_mut() = placed_footprint(offset, row_pitch, format, size);
 
         let mut dst = d3d12::D3D12_TEXTURE_COPY_LOCATION {
             pResource: texture.get_mut(),
@@ -1052,15 +1336,23 @@ impl GraphicsCommandList {
         };
         *dst.u.SubresourceIndex_mut() = 0;
 
-        self.0.CopyTextureRegion(&dst, 0, 0, 0, &src, ptr::null());
+        self.0
+            .CopyTextureRegion(&dst, origin.0, origin.1, 0, &src, ptr::null());
     }
 
+    /// Copy a rectangle of a texture to a buffer.
+    ///
+    /// The buffer layout has the same alignment requirements as
+    /// `copy_buffer_to_texture`.
     pub unsafe fn copy_texture_to_buffer(
         &self,
         texture: &Resource,
+        origin: (u32, u32),
+        size: (u32, u32),
         buffer: &Resource,
-        width: u32,
-        height: u32,
+        offset: u64,
+        row_pitch: u32,
+        format: dxgiformat::DXGI_FORMAT,
     ) {
         let mut src = d3d12::D3D12_TEXTURE_COPY_LOCATION {
             pResource: texture.get_mut(),
@@ -1074,24 +1366,79 @@ impl GraphicsCommandList {
             Type: d3d12::D3D12_TEXTURE_COPY_TYPE_PLACED_FOOTPRINT,
             ..mem::zeroed()
         };
-        let row_pitch = width * 4;
-        assert!(
-            row_pitch % d3d12::D3D12_TEXTURE_DATA_PITCH_ALIGNMENT == 0,
-            "TODO: handle unaligned row pitch"
-        );
-        let footprint = d3d12::D3D12_PLACED_SUBRESOURCE_FOOTPRINT {
-            Offset: 0,
-            Footprint: d3d12::D3D12_SUBRESOURCE_FOOTPRINT {
-                Format: dxgiformat::DXGI_FORMAT_R8G8B8A8_UNORM,
-                Width: width,
-                Height: height,
-                Depth: 1,
-                RowPitch: row_pitch,
-            },
+        *dst.u.PlacedFootprint_mut() = placed_footprint(offset, row_pitch, format, size);
+
+        let src_box = texture_box(origin, size);
+        self.0.CopyTextureRegion(&dst, 0, 0, 0, &src, &src_box);
+    }
+
+    /// Copy a rectangle of one texture to another.
+    pub unsafe fn copy_texture_region(
+        &self,
+        src_texture: &Resource,
+        origin: (u32, u32),
+        size: (u32, u32),
+        dst_texture: &Resource,
+        dst_origin: (u32, u32),
+    ) {
+        let mut src = d3d12::D3D12_TEXTURE_COPY_LOCATION {
+            pResource: src_texture.get_mut(),
+            Type: d3d12::D3D12_TEXTURE_COPY_TYPE_SUBRESOURCE_INDEX,
+            ..mem::zeroed()
+        };
+        *src.u.SubresourceIndex_mut() = 0;
+
+        let mut dst = d3d12::D3D12_TEXTURE_COPY_LOCATION {
+            pResource: dst_texture.get_mut(),
+            Type: d3d12::D3D12_TEXTURE_COPY_TYPE_SUBRESOURCE_INDEX,
+            ..mem::zeroed()
         };
-        *dst.u.PlacedFootprint_mut() = footprint;
+        *dst.u.SubresourceIndex_mut() = 0;
+
+        let src_box = texture_box(origin, size);
+        self.0
+            .CopyTextureRegion(&dst, dst_origin.0, dst_origin.1, 0, &src, &src_box);
+    }
+}
+
+fn placed_footprint(
+    offset: u64,
+    row_pitch: u32,
+    format: dxgiformat::DXGI_FORMAT,
+    size: (u32, u32),
+) -> d3d12::D3D12_PLACED_SUBRESOURCE_FOOTPRINT {
+    assert!(
+        offset % d3d12::D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT as u64 == 0,
+        "buffer offset {} must be a multiple of {} bytes",
+        offset,
+        d3d12::D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT
+    );
+    assert!(
+        row_pitch % d3d12::D3D12_TEXTURE_DATA_PITCH_ALIGNMENT == 0,
+        "row pitch {} must be a multiple of {} bytes",
+        row_pitch,
+        d3d12::D3D12_TEXTURE_DATA_PITCH_ALIGNMENT
+    );
+    d3d12::D3D12_PLACED_SUBRESOURCE_FOOTPRINT {
+        Offset: offset,
+        Footprint: d3d12::D3D12_SUBRESOURCE_FOOTPRINT {
+            Format: format,
+            Width: size.0,
+            Height: size.1,
+            Depth: 1,
+            RowPitch: row_pitch,
+        },
+    }
+}
 
-        self.0.CopyTextureRegion(&dst, 0, 0, 0, &src, ptr::null());
+fn texture_box(origin: (u32, u32), size: (u32, u32)) -> d3d12::D3D12_BOX {
+    d3d12::D3D12_BOX {
+        left: origin.0,
+        top: origin.1,
+        front: 0,
+        right: origin.0 + size.0,
+        bottom: origin.1 + size.1,
+        back: 1,
     }
 }
 
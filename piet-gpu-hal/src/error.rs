@@ -0,0 +1,96 @@
+// Copyright 2021 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! The error type for the crate.
+
+use std::fmt;
+
+/// The common error type for the crate.
+///
+/// The variants other than `Backend` are conditions an application may want
+/// to recover from; each backend maps its native error codes onto them.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Device memory is exhausted.
+    OutOfDeviceMemory,
+    /// Host memory is exhausted.
+    OutOfHostMemory,
+    /// The device was lost, for example by a driver reset or removal.
+    ///
+    /// Resources created on the device are no longer usable.
+    DeviceLost,
+    /// The surface is no longer available.
+    SurfaceLost,
+    /// The surface has changed such that the swapchain needs to be recreated.
+    SurfaceOutdated,
+    /// A shader failed to compile.
+    ShaderCompile { log: String },
+    /// A feature is not supported by the backend or device.
+    Unsupported { feature: String },
+    /// Any other error, as reported by the backend or this crate.
+    Backend(Box<dyn std::error::Error>),
+}
+
+impl Error {
+    pub(crate) fn unsupported(feature: impl Into<String>) -> Error {
+        Error::Unsupported {
+            feature: feature.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OutOfDeviceMemory => write!(f, "out of device memory"),
+            Error::OutOfHostMemory => write!(f, "out of host memory"),
+            Error::DeviceLost => write!(f, "device lost"),
+            Error::SurfaceLost => write!(f, "surface lost"),
+            Error::SurfaceOutdated => write!(f, "surface outdated"),
+            Error::ShaderCompile { log } => write!(f, "shader compilation failed: {}", log),
+            Error::Unsupported { feature } => write!(f, "unsupported: {}", feature),
+            Error::Backend(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Backend(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Error {
+    fn from(s: &str) -> Self {
+        Error::Backend(s.into())
+    }
+}
+
+impl From<String> for Error {
+    fn from(s: String) -> Self {
+        Error::Backend(s.into())
+    }
+}
+
+impl From<std::num::TryFromIntError> for Error {
+    fn from(e: std::num::TryFromIntError) -> Self {
+        Error::Backend(Box::new(e))
+    }
+}
@@ -6,18 +6,38 @@
 //! submission is complete, and a bit more. These conveniences might expand
 //! even more in time.
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
-use std::ops::{Bound, RangeBounds};
+use std::ops::{Bound, Deref, RangeBounds};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
 use bytemuck::Pod;
 use smallvec::SmallVec;
 
-use crate::{mux, BackendType, BufWrite, ComputePassDescriptor, ImageFormat, MapMode};
+use crate::pipeline_cache;
+use crate::profiler::{PendingProfile, ProfileRecorder};
+use crate::{
+    mux, BackendType, BufWrite, ComputePassDescriptor, ImageFormat, MapMode, SamplerParams,
+};
 
-use crate::{BindType, BufferUsage, Error, GpuInfo, ImageLayout, SamplerParams};
+use crate::{BindType, BufferImageLayout, BufferUsage, Error, GpuInfo, ImageLayout, ImageRegion};
+#[cfg(target_os = "linux")]
+use crate::{ExternalHandleType, ExternalMemory};
+use crate::{FormatCapabilities, MemoryPoolStats, MemoryReport, PipelineOptions, QueueType};
 
-pub use crate::mux::{DescriptorSet, Fence, Pipeline, QueryPool, Sampler, Semaphore, ShaderCode};
+pub use crate::mux::{Fence, QueryPool, Sampler, Semaphore, ShaderCode};
+
+mod capture;
+mod recording;
+mod staging;
+
+pub use capture::Replay;
+use capture::{BufferBinding, Event, History, Op, Recorder, Registry};
+pub use recording::{Binding, Bindings, Kernel, Readback, Recording, Submission};
+use staging::StagingRing;
 
 /// A session of GPU operations.
 ///
@@ -34,15 +54,30 @@ struct SessionInner {
     device: mux::Device,
     /// A pool of command buffers that can be reused.
     ///
-    /// Currently this is not used, as it only works well on Vulkan. At some
-    /// point, we will want to efficiently reuse command buffers rather than
-    /// allocating them each time, but that is a TODO.
-    cmd_buf_pool: Mutex<Vec<(mux::CmdBuf, Fence)>>,
+    /// Command buffers are returned here once complete, if the backend can
+    /// reset them; the fences are unsignaled.
+    cmd_buf_pool: Mutex<Vec<(QueueType, mux::CmdBuf, Fence)>>,
+    /// Query pools that can be reused, bucketed by number of queries.
+    query_pool_pool: Mutex<BTreeMap<u32, Vec<QueryPool>>>,
     /// Command buffers that are still pending (so resources can't be freed yet).
     pending: Mutex<Vec<SubmittedCmdBufInner>>,
     /// A command buffer that is used for copying from staging buffers.
     staging_cmd_buf: Mutex<Option<CmdBuf>>,
     gpu_info: GpuInfo,
+    /// Set when the backend reports that the device was lost.
+    lost: AtomicBool,
+    /// The cache used when creating pipelines.
+    pipeline_cache: Mutex<Option<PipelineCache>>,
+    /// The size of live buffers, by usage.
+    buffer_bytes: Mutex<BTreeMap<BufferUsage, u64>>,
+    /// The next id for captured objects.
+    next_id: AtomicU64,
+    /// The capture in progress, if any.
+    capture: Mutex<Option<Recorder>>,
+    /// The live objects, for captures.
+    registry: Mutex<Registry>,
+    /// Staging buffers for uploads.
+    staging_ring: Mutex<StagingRing>,
 }
 
 /// A command buffer.
@@ -58,6 +93,14 @@ pub struct CmdBuf {
     fence: Option<Fence>,
     resources: Vec<RetainResource>,
     session: Weak<SessionInner>,
+    /// Timer scopes, when profiling.
+    profile: Option<ProfileRecorder>,
+    /// The queue the command buffer will be submitted to.
+    queue: QueueType,
+    /// The images whose layout this command buffer changes.
+    image_layouts: Vec<ImageUse>,
+    /// The commands recorded so far, when capturing.
+    capture: Option<Vec<Op>>,
 }
 
 /// A command buffer in submitted state.
@@ -76,6 +119,7 @@ struct SubmittedCmdBufInner {
     fence: Fence,
     resources: Vec<RetainResource>,
     staging_cmd_buf: Option<CmdBuf>,
+    queue: QueueType,
 }
 
 /// An image or texture.
@@ -86,9 +130,34 @@ pub struct Image(Arc<ImageInner>);
 
 struct ImageInner {
     image: mux::Image,
+    layout: Mutex<LayoutState>,
+    /// The id of the image in captures, or 0 if it isn't captured.
+    id: u64,
     session: Weak<SessionInner>,
 }
 
+/// The tracked layout of an image.
+#[derive(Clone, Copy)]
+struct LayoutState {
+    /// The layout after the most recently recorded command buffer that
+    /// changes it.
+    ///
+    /// This is where a command buffer assumes the image starts. If that
+    /// turns out to be wrong at submission, a transition is inserted.
+    recorded: ImageLayout,
+    /// The layout once submitted work completes.
+    submitted: ImageLayout,
+}
+
+/// The use of an image within a command buffer.
+struct ImageUse {
+    image: Image,
+    /// The layout the command buffer expects the image to be in at submission.
+    initial: ImageLayout,
+    /// The layout after the commands recorded so far.
+    current: ImageLayout,
+}
+
 /// A buffer.
 ///
 /// A buffer is a segment of memory that can be accessed by the GPU, and
@@ -99,18 +168,98 @@ pub struct Buffer(Arc<BufferInner>);
 
 struct BufferInner {
     buffer: mux::Buffer,
+    usage: BufferUsage,
+    /// The id of the buffer in captures.
+    id: u64,
+    /// The required alignment of slice offsets, which is checked even after
+    /// the session is dropped.
+    offset_alignment: u64,
+    session: Weak<SessionInner>,
+}
+
+/// A compute pipeline; basically a compiled shader.
+pub struct Pipeline {
+    pipeline: mux::Pipeline,
+    /// The id of the pipeline in captures.
+    id: u64,
+    /// The size of the push constant block, or 0 if there is none.
+    push_constant_size: u32,
+    /// The creation of the pipeline, for captures started later. The
+    /// registry only refers to it while the pipeline is live.
+    #[allow(unused)]
+    history: History,
+}
+
+/// A cache of compiled pipelines.
+///
+/// Pipeline creation can be expensive, as it involves compiling shaders
+/// for the GPU. A cache can be serialized with
+/// [`Session::serialize_pipeline_cache`] and loaded on the next run, so
+/// that pipelines are compiled only once.
+#[derive(Clone)]
+pub struct PipelineCache(Arc<PipelineCacheInner>);
+
+struct PipelineCacheInner {
+    cache: mux::PipelineCache,
+    session: Weak<SessionInner>,
+    restored: bool,
+}
+
+/// A range of a buffer.
+///
+/// A slice can be bound in a descriptor set in place of a whole buffer, so
+/// that several logical buffers can share one allocation.
+#[derive(Clone)]
+pub struct BufferSlice {
+    buffer: Buffer,
+    offset: u64,
+    size: u64,
+}
+
+/// A query pool drawn from the session.
+///
+/// This dereferences to a [`QueryPool`], and is returned to the session
+/// for reuse when dropped. As with buffers, it must not be dropped while
+/// a command buffer writing it is pending.
+pub struct PooledQueryPool {
+    pool: Option<QueryPool>,
+    n_queries: u32,
     session: Weak<SessionInner>,
 }
 
+/// A descriptor set.
+///
+/// Images in the descriptor set are transitioned to the layout they are
+/// bound with when a shader using it is dispatched.
+pub struct DescriptorSet {
+    descriptor_set: mux::DescriptorSet,
+    /// The bound images, by binding slot, with the layout they need.
+    images: Vec<(u32, Image, ImageLayout)>,
+    /// The id of the descriptor set in captures.
+    id: u64,
+    /// The creation and updates of the descriptor set, for captures started
+    /// later.
+    history: History,
+}
+
 /// A builder for creating descriptor sets.
 ///
 /// Add bindings to the descriptor set before dispatching a shader.
-pub struct DescriptorSetBuilder(mux::DescriptorSetBuilder);
+pub struct DescriptorSetBuilder {
+    builder: mux::DescriptorSetBuilder,
+    n_buffers: u32,
+    /// The bound buffers, for captures.
+    buffers: Vec<BufferBinding>,
+    images: Vec<Image>,
+    textures: Vec<Image>,
+    sampled_images: Vec<Image>,
+}
 
 /// A resource to retain during the lifetime of a command submission.
 pub enum RetainResource {
     Buffer(Buffer),
     Image(Image),
+    Bindings(Bindings),
 }
 
 /// A buffer mapped for writing.
@@ -120,6 +269,8 @@ pub struct BufWriteGuard<'a> {
     buf_write: BufWrite,
     session: Arc<SessionInner>,
     buffer: &'a mux::Buffer,
+    /// The id of the buffer, for capturing the written contents.
+    id: u64,
     offset: u64,
     size: u64,
 }
@@ -148,11 +299,30 @@ impl Session {
             device,
             gpu_info,
             cmd_buf_pool: Default::default(),
+            query_pool_pool: Default::default(),
             pending: Default::default(),
             staging_cmd_buf: Default::default(),
+            lost: Default::default(),
+            pipeline_cache: Default::default(),
+            buffer_bytes: Default::default(),
+            next_id: AtomicU64::new(1),
+            capture: Default::default(),
+            registry: Default::default(),
+            staging_ring: Default::default(),
         }))
     }
 
+    /// Whether the device has been lost.
+    ///
+    /// This becomes true when the backend reports [`Error::DeviceLost`], for
+    /// example after a driver timeout. From then on, submission fails with
+    /// that error, and the session and all resources created from it should
+    /// be dropped. To recover, create a new device from the instance and a
+    /// new session, then recreate pipelines and buffers.
+    pub fn is_lost(&self) -> bool {
+        self.0.lost.load(Ordering::Relaxed)
+    }
+
     /// Create a new command buffer.
     ///
     /// The caller is responsible for inserting pipeline barriers and other
@@ -161,12 +331,35 @@ impl Session {
     /// uploads by the host before command submission, but a host barrier is
     /// needed if the host will do readback of any buffers written by the
     /// command list.
+    ///
+    /// Command buffers are recycled once their submission completes, so this
+    /// only allocates when none are available.
     pub fn cmd_buf(&self) -> Result<CmdBuf, Error> {
+        self.cmd_buf_for_queue(QueueType::Main)
+    }
+
+    /// Create a new command buffer for the given queue.
+    ///
+    /// Resources are owned by the queue that last used them. To use a
+    /// resource written on another queue, order the submissions with a
+    /// semaphore and transfer ownership with
+    /// [`buffer_queue_transfer`](CmdBuf::buffer_queue_transfer) or
+    /// [`image_queue_transfer`](CmdBuf::image_queue_transfer) on both queues.
+    /// Buffers initialized with [`create_buffer_init`](Session::create_buffer_init)
+    /// may be uploaded on the main queue.
+    pub fn cmd_buf_for_queue(&self, queue: QueueType) -> Result<CmdBuf, Error> {
         self.poll_cleanup();
-        let (cmd_buf, fence) = if let Some(cf) = self.0.cmd_buf_pool.lock().unwrap().pop() {
-            cf
+        self.0.check_lost()?;
+        let pooled = {
+            let mut pool = self.0.cmd_buf_pool.lock().unwrap();
+            pool.iter()
+                .position(|(q, _, _)| *q == queue)
+                .map(|ix| pool.swap_remove(ix))
+        };
+        let (cmd_buf, fence) = if let Some((_, cmd_buf, fence)) = pooled {
+            (cmd_buf, fence)
         } else {
-            let cmd_buf = self.0.device.create_cmd_buf()?;
+            let cmd_buf = self.0.device.create_cmd_buf(queue)?;
             let fence = unsafe { self.0.device.create_fence(false)? };
             (cmd_buf, fence)
         };
@@ -175,21 +368,40 @@ impl Session {
             fence: Some(fence),
             resources: Vec::new(),
             session: Arc::downgrade(&self.0),
+            profile: None,
+            queue,
+            image_layouts: Vec::new(),
+            capture: None,
         })
     }
 
+    /// Whether the device has a dedicated queue of the given type.
+    ///
+    /// Otherwise, command buffers for that queue run on the main queue.
+    pub fn has_dedicated_queue(&self, queue: QueueType) -> bool {
+        self.0.device.has_dedicated_queue(queue)
+    }
+
     fn poll_cleanup(&self) {
         let mut pending = self.0.pending.lock().unwrap();
         unsafe {
             let mut i = 0;
             while i < pending.len() {
-                if let Ok(true) = self.0.device.get_fence_status(&mut pending[i].fence) {
-                    let mut item = pending.swap_remove(i);
-                    // TODO: wait is superfluous, can just reset
-                    let _ = self.0.device.wait_and_reset(vec![&mut item.fence]);
-                    self.0.cleanup_submitted_cmd_buf(item);
-                } else {
-                    i += 1;
+                let status = self.0.device.get_fence_status(&mut pending[i].fence);
+                match self.0.note_lost(status) {
+                    Ok(true) => {
+                        let mut item = pending.swap_remove(i);
+                        // TODO: wait is superfluous, can just reset
+                        let _ = self.0.device.wait_and_reset(vec![&mut item.fence]);
+                        self.0.cleanup_submitted_cmd_buf(item);
+                    }
+                    Err(Error::DeviceLost) => {
+                        // The work will never complete, so release everything.
+                        for item in pending.drain(..) {
+                            self.0.discard_submitted_cmd_buf(item);
+                        }
+                    }
+                    _ => i += 1,
                 }
             }
         }
@@ -197,20 +409,49 @@ impl Session {
 
     /// Run a command buffer.
     ///
-    /// The semaphores are for swapchain presentation and can be empty for
-    /// compute-only work. When provided, work is synchronized to start only
-    /// when the wait semaphores are signaled, and when work is complete, the
-    /// signal semaphores are signaled.
+    /// The command buffer is submitted to the queue it was created for.
+    ///
+    /// If an image used by the command buffer is not in the layout the
+    /// command buffer assumed when it started using it, for example because
+    /// command buffers were submitted in a different order than recorded,
+    /// a transition is inserted before the command buffer.
+    ///
+    /// The semaphores are for swapchain presentation and synchronization
+    /// between queues, and can be empty for compute-only work on one queue.
+    /// When provided, work is synchronized to start only when the wait
+    /// semaphores are signaled, and when work is complete, the signal
+    /// semaphores are signaled.
     pub unsafe fn run_cmd_buf(
         &self,
         mut cmd_buf: CmdBuf,
         wait_semaphores: &[&Semaphore],
         signal_semaphores: &[&Semaphore],
     ) -> Result<SubmittedCmdBuf, Error> {
+        self.0.check_lost()?;
+        let queue = cmd_buf.queue;
         // Again, SmallVec here?
         let mut cmd_bufs = Vec::with_capacity(2);
-        let mut staging_cmd_buf = self.0.staging_cmd_buf.lock().unwrap().take();
+        // Staging uploads are recorded for the main queue, so they wait for
+        // the next submission there.
+        let mut staging_cmd_buf = if queue == QueueType::Main {
+            self.0.staging_cmd_buf.lock().unwrap().take()
+        } else {
+            None
+        };
+        // Layout fixups are recorded along with the staging uploads. The
+        // layouts are only committed once the submission succeeds.
+        let fixups = cmd_buf.layout_fixups();
+        if !fixups.is_empty() && staging_cmd_buf.is_none() {
+            let mut prelude = self.cmd_buf_for_queue(queue)?;
+            prelude.begin();
+            staging_cmd_buf = Some(prelude);
+        }
         if let Some(staging) = &mut staging_cmd_buf {
+            for (image, src_layout, dst_layout) in &fixups {
+                staging
+                    .cmd_buf()
+                    .image_barrier(image.mux_image(), *src_layout, *dst_layout);
+            }
             // With finer grained resource tracking, we might be able to avoid this in
             // some cases.
             staging.memory_barrier();
@@ -218,18 +459,38 @@ impl Session {
             cmd_bufs.push(staging.cmd_buf.as_ref().unwrap());
         }
         cmd_bufs.push(cmd_buf.cmd_buf.as_ref().unwrap());
-        self.0.device.run_cmd_bufs(
+        let result = self.0.device.run_cmd_bufs(
             &cmd_bufs,
             wait_semaphores,
             signal_semaphores,
             Some(cmd_buf.fence.as_mut().unwrap()),
-        )?;
+            queue,
+        );
+        self.0.note_lost(result)?;
+        cmd_buf.commit_layouts();
+        let ops = cmd_buf.capture.take();
+        self.0.capture(|| match ops {
+            Some(ops) => {
+                let fixups = fixups.iter().filter(|(image, _, _)| image.0.id != 0);
+                let mut submit_ops = fixups
+                    .map(|(image, src_layout, dst_layout)| Op::ImageBarrier {
+                        image: image.0.id,
+                        src_layout: *src_layout,
+                        dst_layout: *dst_layout,
+                    })
+                    .collect::<Vec<_>>();
+                submit_ops.extend(ops);
+                Event::Submit(submit_ops)
+            }
+            None => Event::Unsupported("command buffer begun before the capture".into()),
+        });
         Ok(SubmittedCmdBuf(
             Some(SubmittedCmdBufInner {
                 cmd_buf: cmd_buf.cmd_buf.take().unwrap(),
                 fence: cmd_buf.fence.take().unwrap(),
                 resources: std::mem::take(&mut cmd_buf.resources),
                 staging_cmd_buf,
+                queue,
             }),
             std::mem::replace(&mut cmd_buf.session, Weak::new()),
         ))
@@ -244,10 +505,83 @@ impl Session {
     /// discrete GPUs).
     pub fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Buffer, Error> {
         let buffer = self.0.device.create_buffer(size, usage)?;
-        Ok(Buffer(Arc::new(BufferInner {
+        let buffer = self.wrap_buffer(buffer, usage);
+        self.capture_buffer(&buffer);
+        Ok(buffer)
+    }
+
+    fn wrap_buffer(&self, buffer: mux::Buffer, usage: BufferUsage) -> Buffer {
+        let mut buffer_bytes = self.0.buffer_bytes.lock().unwrap();
+        *buffer_bytes.entry(usage).or_default() += buffer.size();
+        let buffer = Arc::new(BufferInner {
             buffer,
+            usage,
+            id: self.0.alloc_id(),
+            offset_alignment: self.0.gpu_info.buffer_offset_alignment,
             session: Arc::downgrade(&self.0),
-        })))
+        });
+        self.0.registry.lock().unwrap().add_buffer(&buffer);
+        Buffer(buffer)
+    }
+
+    fn capture_buffer(&self, buffer: &Buffer) {
+        self.0.capture(|| Event::CreateBuffer {
+            id: buffer.0.id,
+            size: buffer.size(),
+            usage: buffer.usage(),
+        });
+    }
+
+    /// Sub-allocate buffers from pooled memory blocks.
+    ///
+    /// Buffers created after this call share large driver allocations,
+    /// grouped by usage, which avoids per-allocation overhead and limits when
+    /// creating many small or short-lived buffers. Buffers larger than
+    /// `block_size` still get their own allocation.
+    ///
+    /// The pool can't be resized once enabled; calling this again with a
+    /// different block size returns an error.
+    ///
+    /// Currently only the Vulkan backend pools memory; on others this has no
+    /// effect.
+    pub fn enable_memory_pool(&self, block_size: u64) -> Result<(), Error> {
+        self.0.device.enable_memory_pool(block_size)
+    }
+
+    /// Query statistics of the memory pool.
+    pub fn memory_pool_stats(&self) -> MemoryPoolStats {
+        self.0.device.memory_pool_stats()
+    }
+
+    /// Report the memory heaps of the device, their current budget and usage,
+    /// and the size of buffers created by this session.
+    ///
+    /// The budget is queried with `VK_EXT_memory_budget` on Vulkan,
+    /// `QueryVideoMemoryInfo` on DX12, and the recommended working set size
+    /// on Metal. It covers all allocations of the process, which can include
+    /// other sessions and other APIs.
+    pub fn memory_report(&self) -> MemoryReport {
+        let buffer_bytes = self
+            .0
+            .buffer_bytes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, bytes)| **bytes != 0)
+            .map(|(usage, bytes)| (*usage, *bytes))
+            .collect();
+        MemoryReport {
+            heaps: self.0.gpu_info.memory_heaps.clone(),
+            budgets: self.0.device.query_memory_budget(),
+            buffer_bytes,
+        }
+    }
+
+    /// Release pooled memory blocks that no longer hold any buffers.
+    ///
+    /// Buffers still referenced by pending command buffers count as live.
+    pub unsafe fn trim_memory_pool(&self) {
+        self.0.device.trim_memory_pool();
     }
 
     /// Create a buffer with initialized data.
@@ -284,18 +618,25 @@ impl Session {
             } else {
                 usage | BufferUsage::MAP_WRITE
             };
-            let create_buf = self.create_buffer(size, create_usage)?;
+            // Only the resulting buffer and its contents are captured.
+            let create_buf = self.wrap_buffer(
+                self.0.device.create_buffer(size, create_usage)?,
+                create_usage,
+            );
             let mapped =
                 self.0
                     .device
                     .map_buffer(&create_buf.mux_buffer(), 0, size, MapMode::Write)?;
             let mut buf_write = BufWrite::new(mapped, 0, size as usize);
             f(&mut buf_write);
+            let contents = self.0.is_capturing().then(|| buf_write.to_vec());
             self.0
                 .device
                 .unmap_buffer(&create_buf.mux_buffer(), 0, size, MapMode::Write)?;
-            if use_staging_buffer {
-                let buf = self.create_buffer(size, usage | BufferUsage::COPY_DST)?;
+            let buf = if use_staging_buffer {
+                let buf_usage = usage | BufferUsage::COPY_DST;
+                let buf =
+                    self.wrap_buffer(self.0.device.create_buffer(size, buf_usage)?, buf_usage);
                 let mut staging_cmd_buf = self.0.staging_cmd_buf.lock().unwrap();
                 if staging_cmd_buf.is_none() {
                     let mut cmd_buf = self.cmd_buf()?;
@@ -306,13 +647,30 @@ impl Session {
                 // This will ensure the staging buffer is deallocated.
                 staging_cmd_buf.copy_buffer(&create_buf, &buf);
                 staging_cmd_buf.add_resource(create_buf);
-                Ok(buf)
+                buf
             } else {
-                Ok(create_buf)
+                create_buf
+            };
+            self.capture_buffer(&buf);
+            if let Some(data) = contents {
+                self.0.capture(|| Event::WriteBuffer {
+                    id: buf.0.id,
+                    offset: 0,
+                    data,
+                });
             }
+            Ok(buf)
         }
     }
 
+    /// Query the operations supported for images of a format.
+    ///
+    /// Images created with [`create_image2d`](Session::create_image2d)
+    /// support all of these.
+    pub fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities {
+        self.0.device.format_capabilities(format)
+    }
+
     /// Create an image of the given size and pixel format.
     pub unsafe fn create_image2d(
         &self,
@@ -321,30 +679,268 @@ impl Session {
         format: ImageFormat,
     ) -> Result<Image, Error> {
         let image = self.0.device.create_image2d(width, height, format)?;
-        Ok(Image(Arc::new(ImageInner {
+        Ok(self.wrap_image(image))
+    }
+
+    fn wrap_image(&self, image: mux::Image) -> Image {
+        let (width, height) = image.size();
+        let format = image.format();
+        let image = Image(Arc::new(ImageInner {
             image,
+            layout: Mutex::new(LayoutState::default()),
+            id: self.0.alloc_id(),
             session: Arc::downgrade(&self.0),
-        })))
+        }));
+        self.0.registry.lock().unwrap().add_image(&image.0);
+        self.0.capture(|| Event::CreateImage {
+            id: image.0.id,
+            width,
+            height,
+            format,
+        });
+        image
     }
 
     /// Create a semaphore.
     ///
-    /// These "semaphores" are only for swapchain integration and may be
-    /// stubs on back-ends that don't require semaphore synchronization.
+    /// These "semaphores" are for swapchain integration and synchronization
+    /// between queues, and may be stubs on back-ends that don't require
+    /// semaphore synchronization.
     pub unsafe fn create_semaphore(&self) -> Result<Semaphore, Error> {
         self.0.device.create_semaphore()
     }
 
+    /// Create a buffer whose memory can be shared with other APIs or processes.
+    ///
+    /// This is currently only supported by the Vulkan backend, when the
+    /// driver supports the handle type.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_buffer_exportable(
+        &self,
+        size: u64,
+        usage: BufferUsage,
+        handle_type: ExternalHandleType,
+    ) -> Result<Buffer, Error> {
+        let buffer = self
+            .0
+            .device
+            .create_buffer_exportable(size, usage, handle_type)?;
+        let buffer = self.wrap_buffer(buffer, usage);
+        self.capture_buffer(&buffer);
+        Ok(buffer)
+    }
+
+    /// Create an image whose memory can be shared with other APIs or processes.
+    ///
+    /// Images shared as dma-bufs use linear tiling, which may support fewer
+    /// usages than the [format capabilities](Session::format_capabilities).
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_image2d_exportable(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        handle_type: ExternalHandleType,
+    ) -> Result<Image, Error> {
+        let image = self
+            .0
+            .device
+            .create_image2d_exportable(width, height, format, handle_type)?;
+        Ok(self.wrap_image(image))
+    }
+
+    /// Export the memory of a buffer.
+    ///
+    /// The buffer must have been created with
+    /// [`create_buffer_exportable`](Session::create_buffer_exportable) with
+    /// the same handle type. Each call returns a new fd, and the buffer must
+    /// be kept alive while the memory is in use elsewhere.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_buffer(
+        &self,
+        buffer: &Buffer,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        self.0
+            .device
+            .export_buffer(buffer.mux_buffer(), handle_type)
+    }
+
+    /// Export the memory of an image.
+    ///
+    /// The image must have been created with
+    /// [`create_image2d_exportable`](Session::create_image2d_exportable) with
+    /// the same handle type. For dma-bufs, the result includes the placement
+    /// of the pixels.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_image(
+        &self,
+        image: &Image,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        self.0.device.export_image(image.mux_image(), handle_type)
+    }
+
+    /// Create a buffer from memory shared by another API or process.
+    ///
+    /// On success, the driver takes ownership of the fd.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_buffer(
+        &self,
+        memory: ExternalMemory,
+        size: u64,
+        usage: BufferUsage,
+    ) -> Result<Buffer, Error> {
+        let buffer = self.0.device.import_buffer(memory, size, usage)?;
+        let buffer = self.wrap_buffer(buffer, usage);
+        self.capture_buffer(&buffer);
+        Ok(buffer)
+    }
+
+    /// Create an image from memory shared by another API or process.
+    ///
+    /// Dma-buf images must use the linear layout this device would choose,
+    /// which is checked against [`ExternalMemory::layout`] when present,
+    /// before the fd is imported. On success, the driver takes ownership of
+    /// the fd.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_image2d(
+        &self,
+        memory: ExternalMemory,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Image, Error> {
+        let image = self
+            .0
+            .device
+            .import_image2d(memory, width, height, format)?;
+        Ok(self.wrap_image(image))
+    }
+
+    /// Create a semaphore that can be shared with other APIs or processes.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_semaphore_exportable(&self) -> Result<Semaphore, Error> {
+        self.0.device.create_semaphore_exportable()
+    }
+
+    /// Export a semaphore as an opaque fd.
+    ///
+    /// The semaphore must have been created with
+    /// [`create_semaphore_exportable`](Session::create_semaphore_exportable).
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_semaphore(&self, semaphore: &Semaphore) -> Result<RawFd, Error> {
+        self.0.device.export_semaphore(semaphore)
+    }
+
+    /// Create a semaphore from an opaque fd exported elsewhere.
+    ///
+    /// On success, the driver takes ownership of the fd.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_semaphore(&self, fd: RawFd) -> Result<Semaphore, Error> {
+        self.0.device.import_semaphore(fd)
+    }
+
     /// Create a compute shader pipeline.
     ///
     /// A pipeline is essentially a compiled shader, with more specific
     /// details about what resources may be bound to it.
+    ///
+    /// The pipeline cache set with [`Session::set_pipeline_cache`] is used,
+    /// if any.
     pub unsafe fn create_compute_pipeline<'a>(
         &self,
         code: ShaderCode<'a>,
         bind_types: &[BindType],
     ) -> Result<Pipeline, Error> {
-        self.0.device.create_compute_pipeline(code, bind_types)
+        self.create_compute_pipeline_with_options(code, bind_types, &Default::default())
+    }
+
+    /// Create a compute shader pipeline with options.
+    ///
+    /// Use this to specialize a shader, for example with workgroup sizes
+    /// chosen for the device.
+    pub unsafe fn create_compute_pipeline_with_options<'a>(
+        &self,
+        code: ShaderCode<'a>,
+        bind_types: &[BindType],
+        options: &PipelineOptions,
+    ) -> Result<Pipeline, Error> {
+        let (_, push_constant_size) = BindType::split_push_constants(bind_types)?;
+        let cache = self.0.pipeline_cache.lock().unwrap().clone();
+        let cache = cache.as_ref().map(|cache| &cache.0.cache);
+        let shaders = match self.0.capture.lock().unwrap().as_ref() {
+            Some(recorder) => recorder.shaders(&code),
+            None => capture::shaders(&code),
+        };
+        let pipeline = self
+            .0
+            .device
+            .create_compute_pipeline(code, bind_types, options, cache)?;
+        let id = self.0.alloc_id();
+        let event = (!shaders.is_empty()).then(|| Event::CreatePipeline {
+            id,
+            shaders,
+            bind_types: bind_types.to_vec(),
+            constants: options.constants.to_vec(),
+            subgroup_size: options.subgroup_size,
+        });
+        self.0.capture(|| {
+            event
+                .clone()
+                .unwrap_or_else(|| Event::Unsupported("pipeline with a CPU shader".into()))
+        });
+        let history = self
+            .0
+            .registry
+            .lock()
+            .unwrap()
+            .add_object(event.into_iter().collect());
+        Ok(Pipeline {
+            pipeline,
+            id,
+            push_constant_size,
+            history,
+        })
+    }
+
+    /// Create a pipeline cache.
+    ///
+    /// If given, the cache is initialized from data previously returned by
+    /// [`Session::serialize_pipeline_cache`]. Data that was saved on a
+    /// different device or driver version, or is otherwise invalid, is
+    /// ignored, and the cache starts out empty.
+    pub unsafe fn create_pipeline_cache(
+        &self,
+        data: Option<&[u8]>,
+    ) -> Result<PipelineCache, Error> {
+        let key = self.0.device.pipeline_cache_key();
+        let data = data.and_then(|data| {
+            pipeline_cache::decode(self.backend_type(), &key, data)
+                .map_err(|reason| log::info!("ignoring saved pipeline cache: {}", reason))
+                .ok()
+        });
+        let (cache, restored) = self.0.device.create_pipeline_cache(data.unwrap_or(&[]))?;
+        Ok(PipelineCache(Arc::new(PipelineCacheInner {
+            cache,
+            session: Arc::downgrade(&self.0),
+            restored,
+        })))
+    }
+
+    /// Set the cache used for subsequently created pipelines.
+    pub fn set_pipeline_cache(&self, cache: Option<&PipelineCache>) {
+        *self.0.pipeline_cache.lock().unwrap() = cache.cloned();
+    }
+
+    /// Serialize the contents of a pipeline cache.
+    ///
+    /// The data identifies the device and driver, so it can be loaded with
+    /// [`Session::create_pipeline_cache`] without further checks.
+    pub unsafe fn serialize_pipeline_cache(&self, cache: &PipelineCache) -> Result<Vec<u8>, Error> {
+        let data = self.0.device.pipeline_cache_data(&cache.0.cache)?;
+        let key = self.0.device.pipeline_cache_key();
+        Ok(pipeline_cache::encode(self.backend_type(), &key, &data))
     }
 
     /// Create a descriptor set for a simple pipeline that just references buffers.
@@ -363,7 +959,14 @@ impl Session {
     /// A descriptor set is a binding of actual resources (buffers and
     /// images) to slots as specified in the pipeline.
     pub unsafe fn descriptor_set_builder(&self) -> DescriptorSetBuilder {
-        DescriptorSetBuilder(self.0.device.descriptor_set_builder())
+        DescriptorSetBuilder {
+            builder: self.0.device.descriptor_set_builder(),
+            n_buffers: 0,
+            buffers: Vec::new(),
+            images: Vec::new(),
+            textures: Vec::new(),
+            sampled_images: Vec::new(),
+        }
     }
 
     /// Update a buffer in a descriptor set.
@@ -375,7 +978,14 @@ impl Session {
     ) {
         self.0
             .device
-            .update_buffer_descriptor(ds, index, &buffer.0.buffer)
+            .update_buffer_descriptor(&mut ds.descriptor_set, index, &buffer.0.buffer);
+        let event = Event::UpdateBufferDescriptor {
+            set: ds.id,
+            index,
+            buffer: buffer.0.id,
+        };
+        capture::update_history(&ds.history, &event);
+        self.0.capture(|| event);
     }
 
     /// Update an image in a descriptor set.
@@ -387,7 +997,16 @@ impl Session {
     ) {
         self.0
             .device
-            .update_image_descriptor(ds, index, &image.0.image)
+            .update_image_descriptor(&mut ds.descriptor_set, index, &image.0.image);
+        ds.images.retain(|(slot, _, _)| *slot != index);
+        ds.images.push((index, image.clone(), ImageLayout::General));
+        let event = Event::UpdateImageDescriptor {
+            set: ds.id,
+            index,
+            image: image.0.id,
+        };
+        capture::update_history(&ds.history, &event);
+        self.0.capture(|| event);
     }
 
     /// Create a query pool for timestamp queries.
@@ -395,6 +1014,29 @@ impl Session {
         self.0.device.create_query_pool(n_queries)
     }
 
+    /// Get a query pool for timestamp queries, reusing one if available.
+    ///
+    /// Pools are reused only for the same number of queries, as fetching
+    /// reads all of them.
+    pub fn query_pool(&self, n_queries: u32) -> Result<PooledQueryPool, Error> {
+        let pool = self
+            .0
+            .query_pool_pool
+            .lock()
+            .unwrap()
+            .get_mut(&n_queries)
+            .and_then(|bucket| bucket.pop());
+        let pool = match pool {
+            Some(pool) => pool,
+            None => self.create_query_pool(n_queries)?,
+        };
+        Ok(PooledQueryPool {
+            pool: Some(pool),
+            n_queries,
+            session: Arc::downgrade(&self.0),
+        })
+    }
+
     /// Fetch the contents of the query pool.
     ///
     /// This should be called after waiting on the command buffer that wrote the
@@ -405,6 +1047,10 @@ impl Session {
     /// the raw timestamps, but that change should be made consistently.
     pub unsafe fn fetch_query_pool(&self, pool: &QueryPool) -> Result<Vec<f64>, Error> {
         let result = self.0.device.fetch_query_pool(pool)?;
+        if result.is_empty() {
+            // The backend doesn't support timer queries.
+            return Ok(result);
+        }
         // Subtract off first timestamp.
         Ok(result[1..]
             .iter()
@@ -412,13 +1058,9 @@ impl Session {
             .collect())
     }
 
-    #[doc(hidden)]
-    /// Create a sampler.
-    ///
-    /// Not yet implemented.
-    pub unsafe fn create_sampler(&self, _params: SamplerParams) -> Result<Sampler, Error> {
-        todo!()
-        //self.0.device.create_sampler(params)
+    /// Create a sampler, for binding sampled images.
+    pub unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Sampler, Error> {
+        self.0.device.create_sampler(params)
     }
 
     /// Query the GPU info.
@@ -434,7 +1076,12 @@ impl Session {
         dxil: &'a [u8],
         msl: &'a str,
     ) -> ShaderCode<'a> {
-        self.0.device.choose_shader(spv, hlsl, dxil, msl)
+        let code = self.0.device.choose_shader(spv, hlsl, dxil, msl);
+        // Captures keep all the choices, to replay on any backend.
+        if let Some(recorder) = self.0.capture.lock().unwrap().as_mut() {
+            recorder.note_shader_choice(&code, spv, hlsl, dxil, msl);
+        }
+        code
     }
 
     /// Report the backend type that was chosen.
@@ -453,6 +1100,10 @@ impl Session {
             fence: None,
             resources,
             session,
+            profile: None,
+            queue: QueueType::Main,
+            image_layouts: Vec::new(),
+            capture: None,
         }
     }
 
@@ -466,23 +1117,101 @@ impl Session {
         let image = self.0.device.image_from_raw_mtl(raw_texture, width, height);
         // Expect client to do cleanup manually.
         let session = Weak::new();
-        Image(Arc::new(ImageInner { image, session }))
+        Image(Arc::new(ImageInner {
+            image,
+            layout: Mutex::new(LayoutState::default()),
+            id: 0,
+            session,
+        }))
     }
 }
 
 impl SessionInner {
+    /// Record device loss if the result reports it.
+    fn note_lost<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(Error::DeviceLost) = &result {
+            self.lost.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn alloc_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn is_capturing(&self) -> bool {
+        self.capture.lock().unwrap().is_some()
+    }
+
+    /// Add an event to the capture in progress, if any.
+    fn capture(&self, event: impl FnOnce() -> Event) {
+        if let Some(recorder) = self.capture.lock().unwrap().as_mut() {
+            recorder.push(event());
+        }
+    }
+
+    fn check_lost(&self) -> Result<(), Error> {
+        if self.lost.load(Ordering::Relaxed) {
+            Err(Error::DeviceLost)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Clean up a submitted command buffer.
     ///
-    /// This drops the resources used by the command buffer and also cleans up the command
-    /// buffer itself. Currently that means destroying it, but at some point we'll want to
-    /// be better at reuse.
+    /// This drops the resources used by the command buffer and also recycles the command
+    /// buffer itself.
     unsafe fn cleanup_submitted_cmd_buf(&self, item: SubmittedCmdBufInner) {
-        let _should_handle_err = self.device.destroy_cmd_buf(item.cmd_buf);
-        let _should_handle_err = self.device.destroy_fence(item.fence);
+        self.recycle_cmd_buf(item.queue, item.cmd_buf, item.fence);
 
         std::mem::drop(item.resources);
         if let Some(mut staging_cmd_buf) = item.staging_cmd_buf {
-            staging_cmd_buf.destroy(self);
+            staging_cmd_buf.recycle(self);
+        }
+    }
+
+    /// Clean up a submitted command buffer that can't be waited for.
+    ///
+    /// This is used when the device is lost, or waiting on the fence failed,
+    /// so the command buffer and fence are destroyed rather than recycled.
+    unsafe fn discard_submitted_cmd_buf(&self, item: SubmittedCmdBufInner) {
+        let _ = self.device.destroy_cmd_buf(item.cmd_buf);
+        let _ = self.device.destroy_fence(item.fence);
+        std::mem::drop(item.resources);
+        if let Some(mut staging_cmd_buf) = item.staging_cmd_buf {
+            if let (Some(cmd_buf), Some(fence)) =
+                (staging_cmd_buf.cmd_buf.take(), staging_cmd_buf.fence.take())
+            {
+                let _ = self.device.destroy_cmd_buf(cmd_buf);
+                let _ = self.device.destroy_fence(fence);
+            }
+        }
+    }
+
+    /// Return a command buffer to the pool, or destroy it if it can't be reused.
+    ///
+    /// The command buffer must not be pending, and the fence must be unsignaled.
+    unsafe fn recycle_cmd_buf(&self, queue: QueueType, mut cmd_buf: mux::CmdBuf, fence: Fence) {
+        if cmd_buf.reset() {
+            self.cmd_buf_pool
+                .lock()
+                .unwrap()
+                .push((queue, cmd_buf, fence));
+        } else {
+            let _should_handle_err = self.device.destroy_cmd_buf(cmd_buf);
+            let _should_handle_err = self.device.destroy_fence(fence);
+        }
+    }
+}
+
+impl Drop for SessionInner {
+    fn drop(&mut self) {
+        for (_, cmd_buf, fence) in self.cmd_buf_pool.get_mut().unwrap().drain(..) {
+            unsafe {
+                let _ = self.device.destroy_cmd_buf(cmd_buf);
+                let _ = self.device.destroy_fence(fence);
+            }
         }
     }
 }
@@ -492,6 +1221,11 @@ impl CmdBuf {
         self.cmd_buf.as_mut().unwrap()
     }
 
+    /// The queue the command buffer will be submitted to.
+    pub fn queue(&self) -> QueueType {
+        self.queue
+    }
+
     /// Begin recording into a command buffer.
     ///
     /// Always call this before encoding any actual work.
@@ -499,6 +1233,10 @@ impl CmdBuf {
     /// Discussion question: can this be subsumed?
     pub unsafe fn begin(&mut self) {
         self.cmd_buf().begin();
+        self.capture = match Weak::upgrade(&self.session) {
+            Some(session) if session.is_capturing() => Some(Vec::new()),
+            _ => None,
+        };
     }
 
     /// Finish recording into a command buffer.
@@ -515,8 +1253,21 @@ impl CmdBuf {
     }
 
     /// Begin a compute pass.
+    ///
+    /// When profiling, a labeled pass without explicit timer queries is
+    /// timed as a scope.
     pub unsafe fn begin_compute_pass(&mut self, desc: &ComputePassDescriptor) -> ComputePass {
-        self.cmd_buf().begin_compute_pass(desc);
+        let cmd_buf = self.cmd_buf.as_mut().unwrap();
+        match (&mut self.profile, desc.label) {
+            (Some(profile), Some(label)) if desc.timer_queries.is_none() => {
+                let desc = ComputePassDescriptor {
+                    timer_queries: profile.alloc_scope(label),
+                    label: desc.label,
+                };
+                cmd_buf.begin_compute_pass(&desc);
+            }
+            _ => cmd_buf.begin_compute_pass(desc),
+        }
         ComputePass { cmd_buf: self }
     }
 
@@ -526,6 +1277,7 @@ impl CmdBuf {
     /// that were written before this barrier.
     pub unsafe fn memory_barrier(&mut self) {
         self.cmd_buf().memory_barrier();
+        self.capture(&[], || Op::MemoryBarrier);
     }
 
     /// Insert a barrier for host access to buffers.
@@ -537,6 +1289,7 @@ impl CmdBuf {
     /// ("Host memory reads") for an explanation of this barrier.
     pub unsafe fn host_barrier(&mut self) {
         self.cmd_buf().memory_barrier();
+        self.capture(&[], || Op::MemoryBarrier);
     }
 
     /// Insert an image barrier, transitioning image layout.
@@ -547,6 +1300,9 @@ impl CmdBuf {
     ///
     /// Additionally, when writing to an image for the first time, it must be
     /// transitioned from an unknown layout to specify the layout.
+    ///
+    /// The source layout must be the current layout of the image. Prefer
+    /// [`transition`](Self::transition), which tracks it.
     pub unsafe fn image_barrier(
         &mut self,
         image: &Image,
@@ -555,6 +1311,66 @@ impl CmdBuf {
     ) {
         self.cmd_buf()
             .image_barrier(image.mux_image(), src_layout, dst_layout);
+        let ix = self.image_use(image, Some(src_layout));
+        self.set_layout(ix, dst_layout);
+        self.capture_image_barrier(image, src_layout, dst_layout);
+    }
+
+    /// Transition an image to a layout.
+    ///
+    /// The current layout of the image is tracked, across command buffers,
+    /// so only the new layout is given. Nothing is recorded if the image is
+    /// already in that layout.
+    ///
+    /// Copies, blits and dispatches transition the images they use, so this
+    /// is mostly needed for leaving an image in a particular layout, such as
+    /// `ImageLayout::Present` for a swapchain image. The layout must not be
+    /// `ImageLayout::Undefined`.
+    pub unsafe fn transition(&mut self, image: &Image, layout: ImageLayout) {
+        let ix = self.image_use(image, None);
+        let current = self.image_layouts[ix].current;
+        if current != layout {
+            self.cmd_buf()
+                .image_barrier(image.mux_image(), current, layout);
+            self.set_layout(ix, layout);
+            self.capture_image_barrier(image, current, layout);
+        }
+    }
+
+    /// Transfer ownership of a buffer between queues.
+    ///
+    /// The same transfer must be recorded on both the source queue (releasing
+    /// ownership) and the destination queue (acquiring it), with a semaphore
+    /// ordering the two submissions. When the queues share hardware, this is
+    /// a memory barrier.
+    pub unsafe fn buffer_queue_transfer(
+        &mut self,
+        buffer: &Buffer,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        self.cmd_buf()
+            .buffer_queue_transfer(buffer.mux_buffer(), src, dst);
+        self.capture(&[], || Op::MemoryBarrier);
+    }
+
+    /// Transfer ownership of an image between queues, with a layout transition.
+    ///
+    /// As with [`buffer_queue_transfer`](CmdBuf::buffer_queue_transfer), the
+    /// transfer must be recorded on both queues.
+    pub unsafe fn image_queue_transfer(
+        &mut self,
+        image: &Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        self.cmd_buf()
+            .image_queue_transfer(image.mux_image(), src_layout, dst_layout, src, dst);
+        let ix = self.image_use(image, Some(src_layout));
+        self.set_layout(ix, dst_layout);
+        self.capture_image_barrier(image, src_layout, dst_layout);
     }
 
     /// Clear the buffer.
@@ -562,6 +1378,10 @@ impl CmdBuf {
     /// When the size is not specified, it clears the whole buffer.
     pub unsafe fn clear_buffer(&mut self, buffer: &Buffer, size: Option<u64>) {
         self.cmd_buf().clear_buffer(buffer.mux_buffer(), size);
+        self.capture(&[], || Op::ClearBuffer {
+            buffer: buffer.0.id,
+            size,
+        });
     }
 
     /// Copy one buffer to another.
@@ -570,14 +1390,24 @@ impl CmdBuf {
     pub unsafe fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) {
         self.cmd_buf()
             .copy_buffer(src.mux_buffer(), dst.mux_buffer());
+        self.capture(&[], || Op::CopyBuffer {
+            src: src.0.id,
+            dst: dst.0.id,
+        });
     }
 
     /// Copy an image to a buffer.
     ///
-    /// The size of the image and buffer must match.
+    /// The size of the image and buffer must match. The image is
+    /// transitioned to `ImageLayout::BlitSrc` if needed.
     pub unsafe fn copy_image_to_buffer(&mut self, src: &Image, dst: &Buffer) {
+        self.transition(src, ImageLayout::BlitSrc);
         self.cmd_buf()
             .copy_image_to_buffer(src.mux_image(), dst.mux_buffer());
+        self.capture(&[src], || Op::CopyImageToBuffer {
+            src: src.0.id,
+            dst: dst.0.id,
+        });
         // TODO: change the backend signature to allow failure, as in "not
         // implemented" or "unaligned", and fall back to compute shader
         // submission.
@@ -585,10 +1415,16 @@ impl CmdBuf {
 
     /// Copy a buffer to an image.
     ///
-    /// The size of the image and buffer must match.
+    /// The size of the image and buffer must match. The image is
+    /// transitioned to `ImageLayout::BlitDst` if needed.
     pub unsafe fn copy_buffer_to_image(&mut self, src: &Buffer, dst: &Image) {
+        self.transition(dst, ImageLayout::BlitDst);
         self.cmd_buf()
             .copy_buffer_to_image(src.mux_buffer(), dst.mux_image());
+        self.capture(&[dst], || Op::CopyBufferToImage {
+            src: src.0.id,
+            dst: dst.0.id,
+        });
         // See above.
     }
 
@@ -599,8 +1435,118 @@ impl CmdBuf {
     ///
     /// Discussion question: we might have a specialized version of this
     /// function for copying to the swapchain image, and a separate type.
+    ///
+    /// The images are transitioned to `ImageLayout::BlitSrc` and
+    /// `ImageLayout::BlitDst` if needed.
     pub unsafe fn blit_image(&mut self, src: &Image, dst: &Image) {
+        self.transition(src, ImageLayout::BlitSrc);
+        self.transition(dst, ImageLayout::BlitDst);
         self.cmd_buf().blit_image(src.mux_image(), dst.mux_image());
+        self.capture(&[src, dst], || Op::BlitImage {
+            src: src.0.id,
+            dst: dst.0.id,
+        });
+    }
+
+    /// Copy a range of one buffer to another.
+    ///
+    /// The ranges must be within the buffers. On Metal, offsets and size
+    /// must be multiples of 4 bytes.
+    pub unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        self.cmd_buf().copy_buffer_region(
+            src.mux_buffer(),
+            src_offset,
+            dst.mux_buffer(),
+            dst_offset,
+            size,
+        );
+        self.capture(&[], || Op::CopyBufferRegion {
+            src: src.0.id,
+            src_offset,
+            dst: dst.0.id,
+            dst_offset,
+            size,
+        });
+    }
+
+    /// Copy a region of an image to a buffer.
+    ///
+    /// Rows of the region are written to the buffer as described by the
+    /// layout, which must fit in the buffer.
+    pub unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        self.transition(src, ImageLayout::BlitSrc);
+        self.cmd_buf().copy_image_region_to_buffer(
+            src.mux_image(),
+            region,
+            dst.mux_buffer(),
+            layout,
+        );
+        self.capture(&[src], || Op::CopyImageRegionToBuffer {
+            src: src.0.id,
+            region,
+            dst: dst.0.id,
+            layout,
+        });
+    }
+
+    /// Copy from a buffer to a region of an image.
+    ///
+    /// This can be used to update part of an image, such as an atlas.
+    pub unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        self.transition(dst, ImageLayout::BlitDst);
+        self.cmd_buf().copy_buffer_to_image_region(
+            src.mux_buffer(),
+            layout,
+            dst.mux_image(),
+            region,
+        );
+        self.capture(&[dst], || Op::CopyBufferToImageRegion {
+            src: src.0.id,
+            layout,
+            dst: dst.0.id,
+            region,
+        });
+    }
+
+    /// Copy a region of an image to another, without scaling.
+    ///
+    /// The images must have the same format, and the region must fit in both.
+    pub unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        self.transition(src, ImageLayout::BlitSrc);
+        self.transition(dst, ImageLayout::BlitDst);
+        self.cmd_buf()
+            .copy_image_region(src.mux_image(), region, dst.mux_image(), dst_origin);
+        self.capture(&[src, dst], || Op::CopyImageRegion {
+            src: src.0.id,
+            region,
+            dst: dst.0.id,
+            dst_origin,
+        });
     }
 
     /// Reset the query pool.
@@ -621,6 +1567,70 @@ impl CmdBuf {
         self.cmd_buf().finish_timestamps(pool);
     }
 
+    /// Start recording timer scopes.
+    ///
+    /// Up to `max_scopes` scopes are timed, counting both labeled compute
+    /// passes and scopes opened with [`begin_scope`][Self::begin_scope]. Call
+    /// [`finish_profile`][Self::finish_profile] before finishing the command
+    /// buffer.
+    ///
+    /// Without [`GpuInfo::has_timestamp_writes`], only labeled compute
+    /// passes are timed, relative to the start of the first one.
+    pub unsafe fn begin_profile(
+        &mut self,
+        session: &Session,
+        max_scopes: u32,
+    ) -> Result<(), Error> {
+        let timestamp_writes = session.gpu_info().has_timestamp_writes;
+        let pool = session.query_pool(1 + 2 * max_scopes)?;
+        self.cmd_buf().reset_query_pool(&pool);
+        if timestamp_writes {
+            self.cmd_buf().write_timestamp(&pool, 0);
+        }
+        self.profile = Some(ProfileRecorder::new(pool, timestamp_writes));
+        Ok(())
+    }
+
+    /// Begin a named timer scope.
+    ///
+    /// Scopes may be nested, and may contain compute passes, but are begun
+    /// and ended outside of them. This does nothing unless profiling, and
+    /// the scope isn't timed without [`GpuInfo::has_timestamp_writes`].
+    pub unsafe fn begin_scope(&mut self, label: &str) {
+        if let Some(profile) = &mut self.profile {
+            if let Some((pool, query)) = profile.push_scope(label) {
+                self.cmd_buf.as_mut().unwrap().write_timestamp(pool, query);
+            }
+        }
+    }
+
+    /// End the scope opened by the matching `begin_scope`.
+    pub unsafe fn end_scope(&mut self) {
+        if let Some(profile) = &mut self.profile {
+            if let Some((pool, query)) = profile.pop_scope() {
+                self.cmd_buf.as_mut().unwrap().write_timestamp(pool, query);
+            }
+        }
+    }
+
+    /// Stop recording timer scopes.
+    ///
+    /// Any open scopes are ended. The returned profile can be resolved once
+    /// the command buffer has completed.
+    pub unsafe fn finish_profile(&mut self) -> Option<PendingProfile> {
+        while self.profile.as_ref()?.depth() > 0 {
+            self.end_scope();
+        }
+        let profile = self.profile.take()?;
+        let cmd_buf = self.cmd_buf.as_mut().unwrap();
+        // Every query must be written for the pool to be read back.
+        for query in profile.unused_queries() {
+            cmd_buf.write_timestamp(profile.pool(), query);
+        }
+        cmd_buf.finish_timestamps(profile.pool());
+        Some(profile.finish())
+    }
+
     /// Begin a labeled section for debugging and profiling purposes.
     pub unsafe fn begin_debug_label(&mut self, label: &str) {
         self.cmd_buf().begin_debug_label(label);
@@ -642,6 +1652,149 @@ impl CmdBuf {
     pub fn add_resource(&mut self, resource: impl Into<RetainResource>) {
         self.resources.push(resource.into());
     }
+
+    /// Dispatch a compute shader, transitioning the images it binds.
+    unsafe fn dispatch_impl(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.transition_images(descriptor_set);
+        self.cmd_buf().dispatch(
+            &pipeline.pipeline,
+            &descriptor_set.descriptor_set,
+            workgroup_count,
+            workgroup_size,
+            push_constants,
+        );
+        self.capture(&[], || Op::Dispatch {
+            pipeline: pipeline.id,
+            set: descriptor_set.id,
+            workgroup_count,
+            workgroup_size,
+            push_constants: push_constants.to_vec(),
+        });
+    }
+
+    unsafe fn dispatch_indirect_impl(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.transition_images(descriptor_set);
+        self.cmd_buf().dispatch_indirect(
+            &pipeline.pipeline,
+            &descriptor_set.descriptor_set,
+            buffer.mux_buffer(),
+            offset,
+            workgroup_size,
+            push_constants,
+        );
+        self.capture(&[], || Op::DispatchIndirect {
+            pipeline: pipeline.id,
+            set: descriptor_set.id,
+            buffer: buffer.0.id,
+            offset,
+            workgroup_size,
+            push_constants: push_constants.to_vec(),
+        });
+    }
+
+    unsafe fn transition_images(&mut self, descriptor_set: &DescriptorSet) {
+        for (_, image, layout) in &descriptor_set.images {
+            self.transition(image, *layout);
+        }
+    }
+
+    /// Add a command to the capture in progress, if any.
+    ///
+    /// Commands on images that aren't captured, such as swapchain images,
+    /// are left out.
+    fn capture(&mut self, images: &[&Image], op: impl FnOnce() -> Op) {
+        if let Some(ops) = &mut self.capture {
+            if images.iter().all(|image| image.0.id != 0) {
+                ops.push(op());
+            }
+        }
+    }
+
+    fn capture_image_barrier(
+        &mut self,
+        image: &Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+    ) {
+        self.capture(&[image], || Op::ImageBarrier {
+            image: image.0.id,
+            src_layout,
+            dst_layout,
+        });
+    }
+
+    /// Find the use of an image, starting to track it if needed.
+    ///
+    /// A newly tracked image starts in the given layout, or else where the
+    /// most recently recorded command buffer left it.
+    fn image_use(&mut self, image: &Image, initial: Option<ImageLayout>) -> usize {
+        if let Some(ix) = self
+            .image_layouts
+            .iter()
+            .position(|image_use| Arc::ptr_eq(&image_use.image.0, &image.0))
+        {
+            return ix;
+        }
+        let initial = initial.unwrap_or_else(|| image.0.layout.lock().unwrap().recorded);
+        self.image_layouts.push(ImageUse {
+            image: image.clone(),
+            initial,
+            current: initial,
+        });
+        self.image_layouts.len() - 1
+    }
+
+    fn set_layout(&mut self, ix: usize, layout: ImageLayout) {
+        let image_use = &mut self.image_layouts[ix];
+        image_use.current = layout;
+        image_use.image.0.layout.lock().unwrap().recorded = layout;
+    }
+
+    /// The layout of an image after the commands recorded so far.
+    fn current_layout(&mut self, image: &Image) -> ImageLayout {
+        let ix = self.image_use(image, None);
+        self.image_layouts[ix].current
+    }
+
+    /// The transitions needed before the command buffer, to bring images to
+    /// the layouts it assumed.
+    fn layout_fixups(&self) -> Vec<(Image, ImageLayout, ImageLayout)> {
+        let mut fixups = Vec::new();
+        for image_use in &self.image_layouts {
+            let state = image_use.image.0.layout.lock().unwrap();
+            // Starting from undefined discards the contents, which is
+            // valid from any layout.
+            if image_use.initial != ImageLayout::Undefined && state.submitted != image_use.initial {
+                fixups.push((image_use.image.clone(), state.submitted, image_use.initial));
+            }
+        }
+        fixups
+    }
+
+    /// Commit the tracked layouts once the command buffer is submitted.
+    ///
+    /// The images are retained until the submission completes.
+    fn commit_layouts(&mut self) {
+        for image_use in self.image_layouts.drain(..) {
+            image_use.image.0.layout.lock().unwrap().submitted = image_use.current;
+            self.resources.push(RetainResource::Image(image_use.image));
+        }
+    }
 }
 
 impl SubmittedCmdBuf {
@@ -658,13 +1811,21 @@ impl SubmittedCmdBuf {
     /// [`add_resource`][`CmdBuf::add_resource`] will actually be dropped here.
     ///
     /// If the command buffer is still available for reuse, it is returned.
+    ///
+    /// If the device was lost, [`Error::DeviceLost`] is returned and the
+    /// session is marked as lost; see [`Session::is_lost`]. On any error, the
+    /// command buffer is destroyed along with its resources.
     pub fn wait(mut self) -> Result<Option<CmdBuf>, Error> {
         let mut item = self.0.take().unwrap();
         if let Some(session) = Weak::upgrade(&self.1) {
             unsafe {
-                session.device.wait_and_reset(vec![&mut item.fence])?;
+                let result = session.device.wait_and_reset(vec![&mut item.fence]);
+                if let Err(e) = session.note_lost(result) {
+                    session.discard_submitted_cmd_buf(item);
+                    return Err(e);
+                }
                 if let Some(mut staging_cmd_buf) = item.staging_cmd_buf {
-                    staging_cmd_buf.destroy(&session);
+                    staging_cmd_buf.recycle(&session);
                 }
                 if item.cmd_buf.reset() {
                     return Ok(Some(CmdBuf {
@@ -672,8 +1833,14 @@ impl SubmittedCmdBuf {
                         fence: Some(item.fence),
                         resources: Vec::new(),
                         session: std::mem::take(&mut self.1),
+                        profile: None,
+                        queue: item.queue,
+                        image_layouts: Vec::new(),
+                        capture: None,
                     }));
                 } else {
+                    let _ = session.device.destroy_cmd_buf(item.cmd_buf);
+                    let _ = session.device.destroy_fence(item.fence);
                     return Ok(None);
                 }
             }
@@ -687,21 +1854,52 @@ impl Drop for CmdBuf {
     fn drop(&mut self) {
         if let Some(session) = Weak::upgrade(&self.session) {
             unsafe {
-                self.destroy(&session);
+                self.recycle(&session);
             }
         }
     }
 }
 
 impl CmdBuf {
-    unsafe fn destroy(&mut self, session: &SessionInner) {
-        if let Some(cmd_buf) = self.cmd_buf.take() {
-            let _ = session.device.destroy_cmd_buf(cmd_buf);
-        }
-        if let Some(fence) = self.fence.take() {
-            let _ = session.device.destroy_fence(fence);
+    unsafe fn recycle(&mut self, session: &SessionInner) {
+        if let (Some(cmd_buf), Some(fence)) = (self.cmd_buf.take(), self.fence.take()) {
+            session.recycle_cmd_buf(self.queue, cmd_buf, fence);
         }
         self.resources.clear();
+        self.profile = None;
+        self.image_layouts.clear();
+        self.capture = None;
+    }
+}
+
+impl PooledQueryPool {
+    /// The number of queries in the pool.
+    pub fn n_queries(&self) -> u32 {
+        self.n_queries
+    }
+}
+
+impl Deref for PooledQueryPool {
+    type Target = QueryPool;
+
+    fn deref(&self) -> &QueryPool {
+        self.pool.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledQueryPool {
+    fn drop(&mut self) {
+        if let Some(session) = Weak::upgrade(&self.session) {
+            if let Some(pool) = self.pool.take() {
+                session
+                    .query_pool_pool
+                    .lock()
+                    .unwrap()
+                    .entry(self.n_queries)
+                    .or_default()
+                    .push(pool);
+            }
+        }
     }
 }
 
@@ -720,6 +1918,8 @@ impl<'a> ComputePass<'a> {
     ///
     /// Request a compute shader to be run, using the pipeline to specify the
     /// code, and the descriptor set to address the resources read and written.
+    /// Images in the descriptor set are transitioned to the layout they are
+    /// bound with, if needed.
     ///
     /// Both the workgroup count (number of workgroups) and the workgroup size
     /// (number of threads in a workgroup) must be specified here, though not
@@ -731,9 +1931,101 @@ impl<'a> ComputePass<'a> {
         workgroup_count: (u32, u32, u32),
         workgroup_size: (u32, u32, u32),
     ) {
-        self.cmd_buf
-            .cmd_buf()
-            .dispatch(pipeline, descriptor_set, workgroup_count, workgroup_size);
+        self.cmd_buf.dispatch_impl(
+            pipeline,
+            descriptor_set,
+            workgroup_count,
+            workgroup_size,
+            &[],
+        );
+    }
+
+    /// Dispatch a compute shader, with push constants.
+    ///
+    /// The pipeline must declare a `BindType::PushConstants` block of the
+    /// same size as `push_constants`; otherwise, nothing is recorded and an
+    /// error is returned.
+    pub unsafe fn dispatch_with_push_constants<T: Pod>(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: &T,
+    ) -> Result<(), Error> {
+        let push_constants = bytemuck::bytes_of(push_constants);
+        if push_constants.len() != pipeline.push_constant_size as usize {
+            return Err(format!(
+                "pipeline takes {} bytes of push constants, but {} were given",
+                pipeline.push_constant_size,
+                push_constants.len()
+            )
+            .into());
+        }
+        self.cmd_buf.dispatch_impl(
+            pipeline,
+            descriptor_set,
+            workgroup_count,
+            workgroup_size,
+            push_constants,
+        );
+        Ok(())
+    }
+
+    /// Dispatch a compute shader, reading the workgroup counts from a buffer.
+    ///
+    /// The buffer must have been created with `BufferUsage::INDIRECT`, and
+    /// hold three `u32` values at `offset`. When those are written by an
+    /// earlier dispatch, add a memory barrier in between.
+    pub unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+    ) {
+        self.cmd_buf.dispatch_indirect_impl(
+            pipeline,
+            descriptor_set,
+            buffer,
+            offset,
+            workgroup_size,
+            &[],
+        );
+    }
+
+    /// Dispatch a compute shader indirectly, with push constants.
+    ///
+    /// The push constants are checked as in
+    /// [`dispatch_with_push_constants`][Self::dispatch_with_push_constants].
+    pub unsafe fn dispatch_indirect_with_push_constants<T: Pod>(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &T,
+    ) -> Result<(), Error> {
+        let push_constants = bytemuck::bytes_of(push_constants);
+        if push_constants.len() != pipeline.push_constant_size as usize {
+            return Err(format!(
+                "pipeline takes {} bytes of push constants, but {} were given",
+                pipeline.push_constant_size,
+                push_constants.len()
+            )
+            .into());
+        }
+        self.cmd_buf.dispatch_indirect_impl(
+            pipeline,
+            descriptor_set,
+            buffer,
+            offset,
+            workgroup_size,
+            push_constants,
+        );
+        Ok(())
     }
 
     /// Add a memory barrier.
@@ -763,6 +2055,9 @@ impl<'a> ComputePass<'a> {
 impl Drop for BufferInner {
     fn drop(&mut self) {
         if let Some(session) = Weak::upgrade(&self.session) {
+            if let Some(bytes) = session.buffer_bytes.lock().unwrap().get_mut(&self.usage) {
+                *bytes -= self.buffer.size();
+            }
             unsafe {
                 let _ = session.device.destroy_buffer(&self.buffer);
             }
@@ -770,6 +2065,47 @@ impl Drop for BufferInner {
     }
 }
 
+impl Pipeline {
+    /// The subgroup size of the pipeline, when known.
+    ///
+    /// On Vulkan, this is the requested size, or `None` when no size was
+    /// requested or the request could not be honored. On Metal, it's the
+    /// SIMD-group width chosen for the pipeline.
+    pub fn subgroup_size(&self) -> Option<u32> {
+        self.pipeline.subgroup_size()
+    }
+}
+
+impl Drop for PipelineCacheInner {
+    fn drop(&mut self) {
+        if let Some(session) = Weak::upgrade(&self.session) {
+            unsafe {
+                let _ = session.device.destroy_pipeline_cache(&self.cache);
+            }
+        }
+    }
+}
+
+impl PipelineCache {
+    /// Whether the cache was initialized from saved data.
+    ///
+    /// This is false if no data was given, or if the data was rejected, either
+    /// by the header check or by the driver. Backends without pipeline caches
+    /// never restore data.
+    pub fn is_restored(&self) -> bool {
+        self.0.restored
+    }
+}
+
+impl Default for LayoutState {
+    fn default() -> Self {
+        LayoutState {
+            recorded: ImageLayout::Undefined,
+            submitted: ImageLayout::Undefined,
+        }
+    }
+}
+
 impl Drop for ImageInner {
     fn drop(&mut self) {
         if let Some(session) = Weak::upgrade(&self.session) {
@@ -786,12 +2122,36 @@ impl Image {
         &self.0.image
     }
 
+    /// The width of the image, in pixels.
+    pub fn width(&self) -> u32 {
+        self.0.image.size().0
+    }
+
+    /// The height of the image, in pixels.
+    pub fn height(&self) -> u32 {
+        self.0.image.size().1
+    }
+
+    /// The pixel format of the image.
+    pub fn format(&self) -> ImageFormat {
+        self.0.image.format()
+    }
+
+    /// The id of the image in captures.
+    ///
+    /// This is 0 for images that aren't captured, such as swapchain images.
+    pub fn capture_id(&self) -> u64 {
+        self.0.id
+    }
+
     /// Wrap a swapchain image so it can be exported to the hub level.
     /// Swapchain images don't need resource tracking (or at least we
     /// don't do it), so no session ref is needed.
     pub(crate) fn wrap_swapchain_image(image: mux::Image) -> Image {
         Image(Arc::new(ImageInner {
             image,
+            layout: Mutex::new(LayoutState::default()),
+            id: 0,
             session: Weak::new(),
         }))
     }
@@ -803,6 +2163,58 @@ impl Buffer {
         &self.0.buffer
     }
 
+    /// Get a slice of the buffer, for binding in a descriptor set.
+    ///
+    /// The slice must not be empty. The offset must be a multiple of
+    /// [`GpuInfo::buffer_offset_alignment`], and the size a multiple of 4.
+    pub fn slice(&self, range: impl RangeBounds<u64>) -> Result<BufferSlice, Error> {
+        let offset = match range.start_bound() {
+            Bound::Unbounded => Some(0),
+            Bound::Included(&s) => Some(s),
+            Bound::Excluded(&s) => s.checked_add(1),
+        };
+        let end = match range.end_bound() {
+            Bound::Unbounded => Some(self.size()),
+            Bound::Excluded(&s) => Some(s),
+            Bound::Included(&s) => s.checked_add(1),
+        };
+        let (offset, end) = match (offset, end) {
+            (Some(offset), Some(end)) => (offset, end),
+            _ => {
+                return Err(
+                    format!("slice out of bounds for buffer of size {}", self.size()).into(),
+                )
+            }
+        };
+        if offset >= end {
+            return Err(format!("slice {}..{} is empty", offset, end).into());
+        }
+        if end > self.size() {
+            return Err(format!(
+                "slice {}..{} out of bounds for buffer of size {}",
+                offset,
+                end,
+                self.size()
+            )
+            .into());
+        }
+        let alignment = self.0.offset_alignment;
+        if offset % alignment != 0 {
+            return Err(
+                format!("slice offset {} is not a multiple of {}", offset, alignment).into(),
+            );
+        }
+        let size = end - offset;
+        if size % 4 != 0 {
+            return Err(format!("slice size {} is not a multiple of 4", size).into());
+        }
+        Ok(BufferSlice {
+            buffer: self.clone(),
+            offset,
+            size,
+        })
+    }
+
     /// Write the buffer contents.
     ///
     /// The buffer must have been created with `MAP_WRITE` usage, and with
@@ -826,6 +2238,11 @@ impl Buffer {
             session
                 .device
                 .unmap_buffer(&self.0.buffer, 0, size, MapMode::Write)?;
+            session.capture(|| Event::WriteBuffer {
+                id: self.0.id,
+                offset: 0,
+                data: bytes.to_vec(),
+            });
         }
         // else session lost error?
         Ok(())
@@ -896,6 +2313,7 @@ impl Buffer {
                 buf_write,
                 session,
                 buffer: &self.0.buffer,
+                id: self.0.id,
                 offset,
                 size,
             };
@@ -960,33 +2378,96 @@ impl Buffer {
     pub fn size(&self) -> u64 {
         self.0.buffer.size()
     }
+
+    /// The usage flags the buffer was created with.
+    pub fn usage(&self) -> BufferUsage {
+        self.0.usage
+    }
+
+    /// The id of the buffer in captures.
+    pub fn capture_id(&self) -> u64 {
+        self.0.id
+    }
+}
+
+impl BufferSlice {
+    /// The buffer this is a slice of.
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    /// The offset of the slice, in bytes.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The size of the slice, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 }
 
 impl DescriptorSetBuilder {
     pub fn add_buffers<'a>(mut self, buffers: impl IntoRefs<'a, Buffer>) -> Self {
+        let buffers = buffers.into_refs().collect::<SmallVec<[_; 8]>>();
         let mux_buffers = buffers
-            .into_refs()
+            .iter()
             .map(|b| b.mux_buffer())
             .collect::<SmallVec<[_; 8]>>();
-        self.0.add_buffers(&mux_buffers);
+        self.builder.add_buffers(&mux_buffers);
+        self.n_buffers += mux_buffers.len() as u32;
+        self.buffers
+            .extend(buffers.iter().map(|b| BufferBinding::Whole(b.0.id)));
+        self
+    }
+
+    /// Add buffer slices.
+    ///
+    /// These take binding slots in sequence with buffers added by
+    /// [`add_buffers`](Self::add_buffers).
+    pub fn add_buffer_slices<'a>(mut self, slices: impl IntoRefs<'a, BufferSlice>) -> Self {
+        let slices = slices.into_refs().collect::<SmallVec<[_; 8]>>();
+        let mux_slices = slices
+            .iter()
+            .map(|s| (s.buffer.mux_buffer(), s.offset, s.size))
+            .collect::<SmallVec<[_; 8]>>();
+        self.builder.add_buffer_slices(&mux_slices);
+        self.n_buffers += mux_slices.len() as u32;
+        self.buffers
+            .extend(slices.iter().map(|s| BufferBinding::Slice {
+                buffer: s.buffer.0.id,
+                offset: s.offset,
+                size: s.size,
+            }));
         self
     }
 
     pub fn add_images<'a>(mut self, images: impl IntoRefs<'a, Image>) -> Self {
-        let mux_images = images
-            .into_refs()
-            .map(|i| i.mux_image())
-            .collect::<Vec<_>>();
-        self.0.add_images(&mux_images);
+        let images = images.into_refs().collect::<Vec<_>>();
+        let mux_images = images.iter().map(|i| i.mux_image()).collect::<Vec<_>>();
+        self.builder.add_images(&mux_images);
+        self.images.extend(images.into_iter().cloned());
         self
     }
 
     pub fn add_textures<'a>(mut self, images: impl IntoRefs<'a, Image>) -> Self {
-        let mux_images = images
-            .into_refs()
-            .map(|i| i.mux_image())
-            .collect::<Vec<_>>();
-        self.0.add_textures(&mux_images);
+        let images = images.into_refs().collect::<Vec<_>>();
+        let mux_images = images.iter().map(|i| i.mux_image()).collect::<Vec<_>>();
+        self.builder.add_textures(&mux_images);
+        self.textures.extend(images.into_iter().cloned());
+        self
+    }
+
+    /// Add images, to be read through the given sampler.
+    pub fn add_sampled_images<'a>(
+        mut self,
+        images: impl IntoRefs<'a, Image>,
+        sampler: &Sampler,
+    ) -> Self {
+        let images = images.into_refs().collect::<Vec<_>>();
+        let mux_images = images.iter().map(|i| i.mux_image()).collect::<Vec<_>>();
+        self.builder.add_sampled_images(&mux_images, sampler);
+        self.sampled_images.extend(images.into_iter().cloned());
         self
     }
 
@@ -995,7 +2476,55 @@ impl DescriptorSetBuilder {
         session: &Session,
         pipeline: &Pipeline,
     ) -> Result<DescriptorSet, Error> {
-        self.0.build(&session.0.device, pipeline)
+        let DescriptorSetBuilder {
+            builder,
+            n_buffers,
+            buffers,
+            images,
+            textures,
+            sampled_images,
+        } = self;
+        let descriptor_set = builder.build(&session.0.device, &pipeline.pipeline)?;
+        let id = session.0.alloc_id();
+        let ids = |images: &[Image]| images.iter().map(|i| i.0.id).collect();
+        let event = sampled_images
+            .is_empty()
+            .then(|| Event::CreateDescriptorSet {
+                id,
+                pipeline: pipeline.id,
+                buffers,
+                images: ids(&images),
+                textures: ids(&textures),
+            });
+        session.0.capture(|| {
+            event
+                .clone()
+                .unwrap_or_else(|| Event::Unsupported("descriptor set with sampled images".into()))
+        });
+        let history = session
+            .0
+            .registry
+            .lock()
+            .unwrap()
+            .add_object(event.into_iter().collect());
+        // Images take the slots after the buffers, in this order.
+        let storage = images.into_iter().chain(textures);
+        let images = storage
+            .map(|image| (image, ImageLayout::General))
+            .chain(
+                sampled_images
+                    .into_iter()
+                    .map(|image| (image, ImageLayout::ShaderRead)),
+            )
+            .zip(n_buffers..)
+            .map(|((image, layout), slot)| (slot, image, layout))
+            .collect();
+        Ok(DescriptorSet {
+            descriptor_set,
+            images,
+            id,
+            history,
+        })
     }
 }
 
@@ -1055,6 +2584,11 @@ impl<'a, T: Clone + Into<RetainResource>> From<&'a T> for RetainResource {
 
 impl<'a> Drop for BufWriteGuard<'a> {
     fn drop(&mut self) {
+        self.session.capture(|| Event::WriteBuffer {
+            id: self.id,
+            offset: self.offset,
+            data: self.buf_write.to_vec(),
+        });
         unsafe {
             let _ = self.session.device.unmap_buffer(
                 self.buffer,
@@ -1107,3 +2641,55 @@ impl<'a> BufReadGuard<'a> {
         bytemuck::cast_slice(self.bytes)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Session;
+    use crate::mux::Instance;
+    use crate::InstanceFlags;
+
+    fn cpu_session() -> Session {
+        let instance = Instance::new(InstanceFlags::CPU).unwrap();
+        let device = unsafe { instance.device().unwrap() };
+        Session::new(device)
+    }
+
+    #[test]
+    fn cmd_buf_recycled() {
+        let session = cpu_session();
+        let mut cmd_buf = session.cmd_buf().unwrap();
+        unsafe {
+            cmd_buf.begin();
+            cmd_buf.finish();
+            // The CPU backend runs the command buffer on submission, so its
+            // fence is signaled by the time it's dropped.
+            drop(session.run_cmd_buf(cmd_buf, &[], &[]).unwrap());
+        }
+        assert_eq!(session.0.pending.lock().unwrap().len(), 1);
+        assert!(session.0.cmd_buf_pool.lock().unwrap().is_empty());
+        // Polling returns the finished command buffer to the pool, and it is
+        // taken back out rather than a new one being allocated.
+        let _cmd_buf = session.cmd_buf().unwrap();
+        assert!(session.0.pending.lock().unwrap().is_empty());
+        assert!(session.0.cmd_buf_pool.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn query_pool_reused_by_size() {
+        let session = cpu_session();
+        let bucket_len = |n_queries| {
+            let pools = session.0.query_pool_pool.lock().unwrap();
+            pools.get(&n_queries).map_or(0, Vec::len)
+        };
+        drop(session.query_pool(4).unwrap());
+        assert_eq!(bucket_len(4), 1);
+        // A different size doesn't take from the bucket.
+        let pool8 = session.query_pool(8).unwrap();
+        assert_eq!(bucket_len(4), 1);
+        let pool4 = session.query_pool(4).unwrap();
+        assert_eq!(bucket_len(4), 0);
+        drop(pool8);
+        drop(pool4);
+        assert_eq!((bucket_len(4), bucket_len(8)), (1, 1));
+    }
+}
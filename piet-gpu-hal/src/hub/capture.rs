@@ -0,0 +1,1533 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Capture of the work of a session, and its replay.
+//!
+//! A capture is a stream of events: creation of resources, pipelines and
+//! descriptor sets, writes to buffers from the host, and the commands of
+//! each submitted command buffer. Objects are identified by ids assigned by
+//! the session. Shaders chosen with [`Session::choose_shader`] (as by
+//! `include_shader!`) are captured in all their forms, so a capture can be
+//! replayed on another backend.
+//!
+//! Objects that are live when a capture starts are recorded first, from a
+//! registry kept by the session: the contents of buffers and images are
+//! read back, and pipelines and descriptor sets keep the events that
+//! created them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::{
+    Buffer, BufferInner, CmdBuf, ComputePass, DescriptorSet, Image, ImageInner, Pipeline, Session,
+};
+use crate::pipeline_cache::Reader;
+use crate::{
+    BackendType, BindType, BufferImageLayout, BufferUsage, Error, ImageFormat, ImageLayout,
+    ImageRegion, PipelineOptions, ShaderCode, SpecConstant, SpecValue,
+};
+
+const MAGIC: &[u8; 4] = b"PGCS";
+
+/// The version of the format; bump when events change.
+const VERSION: u32 = 3;
+
+/// A capture in progress.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    events: Vec<Event>,
+    /// The forms of shaders chosen during the capture, by the address and
+    /// length of the chosen form.
+    shader_choices: Vec<((usize, usize), Vec<Shader>)>,
+}
+
+/// The live objects of a session, which a capture starts with.
+#[derive(Default)]
+pub(crate) struct Registry {
+    buffers: Vec<Weak<BufferInner>>,
+    images: Vec<Weak<ImageInner>>,
+    /// Pipelines and descriptor sets, in order of creation.
+    objects: Vec<Weak<Mutex<Vec<Event>>>>,
+}
+
+/// The events that recreate a pipeline or descriptor set.
+///
+/// This is empty for objects that can't be captured.
+pub(crate) type History = Arc<Mutex<Vec<Event>>>;
+
+/// The resources of a replayed capture.
+#[derive(Default)]
+pub struct Replay {
+    buffers: BTreeMap<u64, Buffer>,
+    images: BTreeMap<u64, Image>,
+}
+
+#[derive(Clone)]
+pub(crate) enum Event {
+    CreateBuffer {
+        id: u64,
+        size: u64,
+        usage: BufferUsage,
+    },
+    CreateImage {
+        id: u64,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    },
+    WriteBuffer {
+        id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// The contents of an image when the capture started, and its layout.
+    WriteImage {
+        id: u64,
+        layout: ImageLayout,
+        row_pitch: u32,
+        data: Vec<u8>,
+    },
+    CreatePipeline {
+        id: u64,
+        shaders: Vec<Shader>,
+        bind_types: Vec<BindType>,
+        constants: Vec<SpecConstant>,
+        subgroup_size: Option<u32>,
+    },
+    CreateDescriptorSet {
+        id: u64,
+        pipeline: u64,
+        buffers: Vec<BufferBinding>,
+        images: Vec<u64>,
+        textures: Vec<u64>,
+    },
+    UpdateBufferDescriptor {
+        set: u64,
+        index: u32,
+        buffer: u64,
+    },
+    UpdateImageDescriptor {
+        set: u64,
+        index: u32,
+        image: u64,
+    },
+    Submit(Vec<Op>),
+    /// Work that can't be captured; replay fails here.
+    Unsupported(String),
+}
+
+/// A form of a shader.
+#[derive(Clone)]
+pub(crate) enum Shader {
+    Spv(Vec<u8>),
+    Hlsl(String),
+    Dxil(Vec<u8>),
+    Msl(String),
+    Wgsl(String),
+}
+
+/// A buffer bound in a descriptor set.
+#[derive(Clone, Copy)]
+pub(crate) enum BufferBinding {
+    Whole(u64),
+    Slice { buffer: u64, offset: u64, size: u64 },
+}
+
+/// A command in a command buffer.
+///
+/// Host barriers are captured as memory barriers, and queue transfers as
+/// memory or image barriers, as replay runs on the main queue.
+#[derive(Clone)]
+pub(crate) enum Op {
+    Dispatch {
+        pipeline: u64,
+        set: u64,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: Vec<u8>,
+    },
+    DispatchIndirect {
+        pipeline: u64,
+        set: u64,
+        buffer: u64,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: Vec<u8>,
+    },
+    MemoryBarrier,
+    ImageBarrier {
+        image: u64,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+    },
+    ClearBuffer {
+        buffer: u64,
+        size: Option<u64>,
+    },
+    CopyBuffer {
+        src: u64,
+        dst: u64,
+    },
+    CopyBufferRegion {
+        src: u64,
+        src_offset: u64,
+        dst: u64,
+        dst_offset: u64,
+        size: u64,
+    },
+    CopyImageToBuffer {
+        src: u64,
+        dst: u64,
+    },
+    CopyBufferToImage {
+        src: u64,
+        dst: u64,
+    },
+    BlitImage {
+        src: u64,
+        dst: u64,
+    },
+    CopyImageRegionToBuffer {
+        src: u64,
+        region: ImageRegion,
+        dst: u64,
+        layout: BufferImageLayout,
+    },
+    CopyBufferToImageRegion {
+        src: u64,
+        layout: BufferImageLayout,
+        dst: u64,
+        region: ImageRegion,
+    },
+    CopyImageRegion {
+        src: u64,
+        region: ImageRegion,
+        dst: u64,
+        dst_origin: (u32, u32),
+    },
+}
+
+/// The state of a replay in progress.
+struct Replayer<'a> {
+    session: &'a Session,
+    replay: Replay,
+    pipelines: BTreeMap<u64, Pipeline>,
+    descriptor_sets: BTreeMap<u64, DescriptorSet>,
+}
+
+impl Session {
+    /// Start capturing the work of the session.
+    ///
+    /// The capture begins with the buffers, images, pipelines and
+    /// descriptor sets that are live, so work can be captured from a session
+    /// that is already set up. Their contents are read back on the main
+    /// queue, which waits for work submitted to it; work on other queues
+    /// must be complete. Pipelines created before the capture only have the
+    /// shader code they were created with.
+    ///
+    /// Until [`finish_capture`](Session::finish_capture), the creation of
+    /// objects, writes to buffers from the host, and the commands of
+    /// submitted command buffers are recorded. Command buffers must be
+    /// begun after the capture starts.
+    ///
+    /// Work on swapchain images and images from other APIs is left out, as
+    /// are timer queries and semaphores. Pipelines with CPU shaders and
+    /// descriptor sets with sampled images can't be captured, and the
+    /// capture fails to replay from the point they are used.
+    pub unsafe fn start_capture(&self) -> Result<(), Error> {
+        // Staging chunks created earlier aren't in the capture.
+        self.0.staging_ring.lock().unwrap().reset();
+        let recorder = Recorder {
+            events: self.snapshot()?,
+            ..Default::default()
+        };
+        *self.0.capture.lock().unwrap() = Some(recorder);
+        Ok(())
+    }
+
+    /// The events that recreate the live objects, with their contents.
+    unsafe fn snapshot(&self) -> Result<Vec<Event>, Error> {
+        let (buffers, images, objects) = self.0.registry.lock().unwrap().live();
+        let mut events = Vec::new();
+        let mut ids = BTreeSet::new();
+        for buffer in &buffers {
+            events.push(Event::CreateBuffer {
+                id: buffer.0.id,
+                size: buffer.size(),
+                usage: buffer.usage(),
+            });
+            ids.insert(buffer.0.id);
+        }
+        for image in &images {
+            events.push(Event::CreateImage {
+                id: image.0.id,
+                width: image.width(),
+                height: image.height(),
+                format: image.format(),
+            });
+            ids.insert(image.0.id);
+        }
+
+        let usage = BufferUsage::MAP_READ | BufferUsage::COPY_DST;
+        let mut cmd_buf = self.cmd_buf()?;
+        cmd_buf.begin();
+        cmd_buf.memory_barrier();
+        let mut buffer_reads = Vec::new();
+        for buffer in &buffers {
+            if buffer.size() == 0 {
+                continue;
+            }
+            // Backends can copy from buffers of any usage.
+            let readback = self.create_buffer(buffer.size(), usage)?;
+            cmd_buf.copy_buffer(buffer, &readback);
+            buffer_reads.push((buffer, readback));
+        }
+        let mut image_reads = Vec::new();
+        for image in &images {
+            // Images that were never written have no contents.
+            let layout = image.0.layout.lock().unwrap().recorded;
+            if layout == ImageLayout::Undefined {
+                continue;
+            }
+            let (width, height) = (image.width(), image.height());
+            let row_pitch = (width * image.format().bytes_per_pixel() + 255) & !255;
+            let readback = self.create_buffer(row_pitch as u64 * height as u64, usage)?;
+            let region = ImageRegion {
+                origin: (0, 0),
+                size: (width, height),
+            };
+            let buffer_layout = BufferImageLayout {
+                offset: 0,
+                row_pitch,
+            };
+            cmd_buf.copy_image_region_to_buffer(image, region, &readback, buffer_layout);
+            cmd_buf.transition(image, layout);
+            image_reads.push((image, layout, row_pitch, readback));
+        }
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        self.run_cmd_buf(cmd_buf, &[], &[])?.wait()?;
+        for (buffer, readback) in buffer_reads {
+            let mut data = Vec::new();
+            readback.read(&mut data)?;
+            data.truncate(buffer.size() as usize);
+            events.push(Event::WriteBuffer {
+                id: buffer.0.id,
+                offset: 0,
+                data,
+            });
+        }
+        for (image, layout, row_pitch, readback) in image_reads {
+            let mut data = Vec::new();
+            readback.read(&mut data)?;
+            events.push(Event::WriteImage {
+                id: image.0.id,
+                layout,
+                row_pitch,
+                data,
+            });
+        }
+
+        // Descriptor sets whose resources are gone are left out.
+        for history in objects {
+            let history = history.lock().unwrap();
+            if history
+                .iter()
+                .all(|event| event.uses().iter().all(|id| ids.contains(id)))
+            {
+                ids.extend(history.first().and_then(Event::created));
+                events.extend(history.iter().cloned());
+            }
+        }
+        Ok(events)
+    }
+
+    /// Finish capturing, returning the serialized capture.
+    ///
+    /// This is `None` if no capture was in progress.
+    pub fn finish_capture(&self) -> Option<Vec<u8>> {
+        let recorder = self.0.capture.lock().unwrap().take()?;
+        Some(recorder.finish())
+    }
+
+    /// Replay a capture.
+    ///
+    /// The captured work is run in order on the main queue, waiting for each
+    /// submission to complete. The capture may have been made on another
+    /// device or backend, as long as its pipelines have shader code for this
+    /// one.
+    pub unsafe fn replay(&self, capture: &[u8]) -> Result<Replay, Error> {
+        let mut reader = Reader(capture);
+        if reader.take(4) != Some(MAGIC) {
+            return Err("not a capture".into());
+        }
+        if reader.u32() != Some(VERSION) {
+            return Err("unsupported capture version".into());
+        }
+        let mut replayer = Replayer {
+            session: self,
+            replay: Default::default(),
+            pipelines: Default::default(),
+            descriptor_sets: Default::default(),
+        };
+        while !reader.0.is_empty() {
+            let event = Event::decode(&mut reader).ok_or("capture is truncated or corrupt")?;
+            replayer.event(event)?;
+        }
+        Ok(replayer.replay)
+    }
+}
+
+impl Recorder {
+    pub(crate) fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    /// Remember the forms of a shader, in case a pipeline is created from it.
+    pub(crate) fn note_shader_choice(
+        &mut self,
+        chosen: &ShaderCode,
+        spv: &[u8],
+        hlsl: &str,
+        dxil: &[u8],
+        msl: &str,
+    ) {
+        if let Some(key) = code_key(chosen) {
+            if !self.shader_choices.iter().any(|(k, _)| *k == key) {
+                let shaders = vec![
+                    Shader::Spv(spv.to_owned()),
+                    Shader::Hlsl(hlsl.to_owned()),
+                    Shader::Dxil(dxil.to_owned()),
+                    Shader::Msl(msl.to_owned()),
+                ];
+                self.shader_choices.push((key, shaders));
+            }
+        }
+    }
+
+    /// The forms of the shader of a pipeline.
+    ///
+    /// This is empty for CPU shaders.
+    pub(crate) fn shaders(&self, code: &ShaderCode) -> Vec<Shader> {
+        let key = code_key(code);
+        if let Some((_, shaders)) = self.shader_choices.iter().find(|(k, _)| Some(*k) == key) {
+            return shaders.clone();
+        }
+        shaders(code)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend_from_slice(MAGIC);
+        w.u32(VERSION);
+        for event in &self.events {
+            event.encode(&mut w);
+        }
+        w.0
+    }
+}
+
+/// The shader of a pipeline, in the form it was created with.
+///
+/// This is empty for CPU shaders.
+pub(crate) fn shaders(code: &ShaderCode) -> Vec<Shader> {
+    match code {
+        ShaderCode::Spv(spv) => vec![Shader::Spv(spv.to_vec())],
+        ShaderCode::Hlsl(hlsl) => vec![Shader::Hlsl(hlsl.to_string())],
+        ShaderCode::Dxil(dxil) => vec![Shader::Dxil(dxil.to_vec())],
+        ShaderCode::Msl(msl) => vec![Shader::Msl(msl.to_string())],
+        ShaderCode::Wgsl(wgsl) => vec![Shader::Wgsl(wgsl.to_string())],
+        ShaderCode::Cpu(_) => Vec::new(),
+    }
+}
+
+fn code_key(code: &ShaderCode) -> Option<(usize, usize)> {
+    match code {
+        ShaderCode::Spv(b) | ShaderCode::Dxil(b) => Some((b.as_ptr() as usize, b.len())),
+        ShaderCode::Hlsl(s) | ShaderCode::Msl(s) | ShaderCode::Wgsl(s) => {
+            Some((s.as_ptr() as usize, s.len()))
+        }
+        ShaderCode::Cpu(_) => None,
+    }
+}
+
+impl Registry {
+    pub(crate) fn add_buffer(&mut self, buffer: &Arc<BufferInner>) {
+        push_pruned(&mut self.buffers, Arc::downgrade(buffer));
+    }
+
+    pub(crate) fn add_image(&mut self, image: &Arc<ImageInner>) {
+        push_pruned(&mut self.images, Arc::downgrade(image));
+    }
+
+    /// Add a pipeline or descriptor set, given the events that create it.
+    pub(crate) fn add_object(&mut self, events: Vec<Event>) -> History {
+        let history = Arc::new(Mutex::new(events));
+        push_pruned(&mut self.objects, Arc::downgrade(&history));
+        history
+    }
+
+    fn live(&mut self) -> (Vec<Buffer>, Vec<Image>, Vec<History>) {
+        let buffers = self.buffers.iter().filter_map(Weak::upgrade);
+        let images = self.images.iter().filter_map(Weak::upgrade);
+        let objects = self.objects.iter().filter_map(Weak::upgrade);
+        (
+            buffers.map(Buffer).collect(),
+            images.map(Image).collect(),
+            objects.collect(),
+        )
+    }
+}
+
+/// Push to a list of weak references, dropping dead ones when it is full.
+///
+/// This keeps the list within twice the number of live objects.
+fn push_pruned<T>(list: &mut Vec<Weak<T>>, item: Weak<T>) {
+    if list.len() == list.capacity() {
+        list.retain(|item| item.strong_count() > 0);
+    }
+    list.push(item);
+}
+
+/// Record an update to a descriptor set, replacing an earlier one of the
+/// same slot.
+pub(crate) fn update_history(history: &History, event: &Event) {
+    let mut history = history.lock().unwrap();
+    if history.is_empty() {
+        return;
+    }
+    let slot = |event: &Event| match event {
+        Event::UpdateBufferDescriptor { index, .. } => Some((0, *index)),
+        Event::UpdateImageDescriptor { index, .. } => Some((1, *index)),
+        _ => None,
+    };
+    history.retain(|e| slot(e).is_none() || slot(e) != slot(event));
+    history.push(event.clone());
+}
+
+impl Replay {
+    /// The buffers, by the id of the original (see [`Buffer::capture_id`]).
+    pub fn buffers(&self) -> impl Iterator<Item = (u64, &Buffer)> {
+        self.buffers.iter().map(|(id, buffer)| (*id, buffer))
+    }
+
+    /// The images, by the id of the original (see [`Image::capture_id`]).
+    pub fn images(&self) -> impl Iterator<Item = (u64, &Image)> {
+        self.images.iter().map(|(id, image)| (*id, image))
+    }
+
+    /// Read back the contents of a buffer, by the id of the original.
+    pub unsafe fn read_buffer(&self, session: &Session, id: u64) -> Result<Vec<u8>, Error> {
+        let buffer = self.buffers.get(&id).ok_or("no buffer with that id")?;
+        let usage = BufferUsage::MAP_READ | BufferUsage::COPY_DST;
+        let readback = session.create_buffer(buffer.size(), usage)?;
+        let mut cmd_buf = session.cmd_buf()?;
+        cmd_buf.begin();
+        cmd_buf.copy_buffer(buffer, &readback);
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        session.run_cmd_buf(cmd_buf, &[], &[])?.wait()?;
+        let mut bytes = Vec::new();
+        readback.read(&mut bytes)?;
+        bytes.truncate(buffer.size() as usize);
+        Ok(bytes)
+    }
+}
+
+impl<'a> Replayer<'a> {
+    unsafe fn event(&mut self, event: Event) -> Result<(), Error> {
+        match event {
+            Event::CreateBuffer { id, size, usage } => {
+                // Replayed buffers are written and read back through copies.
+                let usage = usage | BufferUsage::COPY_SRC | BufferUsage::COPY_DST;
+                let buffer = self.session.create_buffer(size, usage)?;
+                self.replay.buffers.insert(id, buffer);
+            }
+            Event::CreateImage {
+                id,
+                width,
+                height,
+                format,
+            } => {
+                let image = self.session.create_image2d(width, height, format)?;
+                self.replay.images.insert(id, image);
+            }
+            Event::WriteBuffer { id, offset, data } => {
+                let buffer = self.buffer(id)?;
+                let usage = BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC;
+                let staging = self.session.create_buffer_init(&data, usage)?;
+                let mut cmd_buf = self.session.cmd_buf()?;
+                cmd_buf.begin();
+                cmd_buf.copy_buffer_region(&staging, 0, buffer, offset, data.len() as u64);
+                cmd_buf.finish();
+                self.session.run_cmd_buf(cmd_buf, &[], &[])?.wait()?;
+            }
+            Event::WriteImage {
+                id,
+                layout,
+                row_pitch,
+                data,
+            } => {
+                let image = self.image(id)?;
+                let usage = BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC;
+                let staging = self.session.create_buffer_init(&data, usage)?;
+                let buffer_layout = BufferImageLayout {
+                    offset: 0,
+                    row_pitch,
+                };
+                let region = ImageRegion {
+                    origin: (0, 0),
+                    size: (image.width(), image.height()),
+                };
+                let mut cmd_buf = self.session.cmd_buf()?;
+                cmd_buf.begin();
+                cmd_buf.copy_buffer_to_image_region(&staging, buffer_layout, image, region);
+                cmd_buf.transition(image, layout);
+                cmd_buf.finish();
+                self.session.run_cmd_buf(cmd_buf, &[], &[])?.wait()?;
+            }
+            Event::CreatePipeline {
+                id,
+                shaders,
+                bind_types,
+                constants,
+                subgroup_size,
+            } => {
+                let code = self.shader_code(&shaders).ok_or_else(|| {
+                    Error::from(format!(
+                        "pipeline {} has no shader code for the {:?} backend",
+                        id,
+                        self.session.backend_type()
+                    ))
+                })?;
+                let mut options = PipelineOptions::default().constants(&constants);
+                if let Some(size) = subgroup_size {
+                    options = options.subgroup_size(size);
+                }
+                let pipeline = self.session.create_compute_pipeline_with_options(
+                    code,
+                    &bind_types,
+                    &options,
+                )?;
+                self.pipelines.insert(id, pipeline);
+            }
+            Event::CreateDescriptorSet {
+                id,
+                pipeline,
+                buffers,
+                images,
+                textures,
+            } => {
+                let mut builder = self.session.descriptor_set_builder();
+                for binding in buffers {
+                    builder = match binding {
+                        BufferBinding::Whole(buffer) => {
+                            builder.add_buffers(&[self.buffer(buffer)?])
+                        }
+                        BufferBinding::Slice {
+                            buffer,
+                            offset,
+                            size,
+                        } => {
+                            let slice = self.buffer(buffer)?.slice(offset..offset + size)?;
+                            builder.add_buffer_slices(&[&slice])
+                        }
+                    };
+                }
+                let images = self.images(&images)?;
+                let textures = self.images(&textures)?;
+                let descriptor_set = builder
+                    .add_images(images)
+                    .add_textures(textures)
+                    .build(self.session, self.pipeline(pipeline)?)?;
+                self.descriptor_sets.insert(id, descriptor_set);
+            }
+            Event::UpdateBufferDescriptor { set, index, buffer } => {
+                let buffer = self.buffer(buffer)?.clone();
+                let session = self.session;
+                let set = self.descriptor_set_mut(set)?;
+                session.update_buffer_descriptor(set, index, &buffer);
+            }
+            Event::UpdateImageDescriptor { set, index, image } => {
+                let image = self.image(image)?.clone();
+                let session = self.session;
+                let set = self.descriptor_set_mut(set)?;
+                session.update_image_descriptor(set, index, &image);
+            }
+            Event::Submit(ops) => self.submit(&ops)?,
+            Event::Unsupported(what) => {
+                return Err(format!("capture includes unsupported work: {}", what).into())
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn submit(&self, ops: &[Op]) -> Result<(), Error> {
+        let mut cmd_buf = self.session.cmd_buf()?;
+        cmd_buf.begin();
+        let mut ops = ops.iter().peekable();
+        while let Some(op) = ops.next() {
+            if op.is_dispatch() {
+                let mut pass = cmd_buf.begin_compute_pass(&Default::default());
+                self.dispatch(&mut pass, op)?;
+                while let Some(op) = ops.next_if(|op| op.is_dispatch()) {
+                    self.dispatch(&mut pass, op)?;
+                }
+                pass.end();
+            } else {
+                self.command(&mut cmd_buf, op)?;
+            }
+        }
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        self.session.run_cmd_buf(cmd_buf, &[], &[])?.wait()?;
+        Ok(())
+    }
+
+    unsafe fn dispatch(&self, pass: &mut ComputePass, op: &Op) -> Result<(), Error> {
+        match op {
+            Op::Dispatch {
+                pipeline,
+                set,
+                workgroup_count,
+                workgroup_size,
+                push_constants,
+            } => pass.cmd_buf.dispatch_impl(
+                self.pipeline(*pipeline)?,
+                self.descriptor_set(*set)?,
+                *workgroup_count,
+                *workgroup_size,
+                push_constants,
+            ),
+            Op::DispatchIndirect {
+                pipeline,
+                set,
+                buffer,
+                offset,
+                workgroup_size,
+                push_constants,
+            } => pass.cmd_buf.dispatch_indirect_impl(
+                self.pipeline(*pipeline)?,
+                self.descriptor_set(*set)?,
+                self.buffer(*buffer)?,
+                *offset,
+                *workgroup_size,
+                push_constants,
+            ),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    unsafe fn command(&self, cmd_buf: &mut CmdBuf, op: &Op) -> Result<(), Error> {
+        match op {
+            Op::Dispatch { .. } | Op::DispatchIndirect { .. } => unreachable!(),
+            Op::MemoryBarrier => cmd_buf.memory_barrier(),
+            Op::ImageBarrier {
+                image,
+                src_layout,
+                dst_layout,
+            } => cmd_buf.image_barrier(self.image(*image)?, *src_layout, *dst_layout),
+            Op::ClearBuffer { buffer, size } => cmd_buf.clear_buffer(self.buffer(*buffer)?, *size),
+            Op::CopyBuffer { src, dst } => {
+                cmd_buf.copy_buffer(self.buffer(*src)?, self.buffer(*dst)?)
+            }
+            Op::CopyBufferRegion {
+                src,
+                src_offset,
+                dst,
+                dst_offset,
+                size,
+            } => cmd_buf.copy_buffer_region(
+                self.buffer(*src)?,
+                *src_offset,
+                self.buffer(*dst)?,
+                *dst_offset,
+                *size,
+            ),
+            Op::CopyImageToBuffer { src, dst } => {
+                cmd_buf.copy_image_to_buffer(self.image(*src)?, self.buffer(*dst)?)
+            }
+            Op::CopyBufferToImage { src, dst } => {
+                cmd_buf.copy_buffer_to_image(self.buffer(*src)?, self.image(*dst)?)
+            }
+            Op::BlitImage { src, dst } => cmd_buf.blit_image(self.image(*src)?, self.image(*dst)?),
+            Op::CopyImageRegionToBuffer {
+                src,
+                region,
+                dst,
+                layout,
+            } => cmd_buf.copy_image_region_to_buffer(
+                self.image(*src)?,
+                *region,
+                self.buffer(*dst)?,
+                *layout,
+            ),
+            Op::CopyBufferToImageRegion {
+                src,
+                layout,
+                dst,
+                region,
+            } => cmd_buf.copy_buffer_to_image_region(
+                self.buffer(*src)?,
+                *layout,
+                self.image(*dst)?,
+                *region,
+            ),
+            Op::CopyImageRegion {
+                src,
+                region,
+                dst,
+                dst_origin,
+            } => cmd_buf.copy_image_region(
+                self.image(*src)?,
+                *region,
+                self.image(*dst)?,
+                *dst_origin,
+            ),
+        }
+        Ok(())
+    }
+
+    /// Choose the form of a shader for the backend of the session.
+    fn shader_code<'s>(&self, shaders: &'s [Shader]) -> Option<ShaderCode<'s>> {
+        let backend = self.session.backend_type();
+        let native = shaders.iter().find_map(|shader| match (backend, shader) {
+            (BackendType::Vulkan, Shader::Spv(spv)) => Some(ShaderCode::Spv(spv)),
+            (BackendType::Dx12, Shader::Dxil(dxil)) if !dxil.is_empty() => {
+                Some(ShaderCode::Dxil(dxil))
+            }
+            (BackendType::Metal, Shader::Msl(msl)) => Some(ShaderCode::Msl(msl)),
+            _ => None,
+        });
+        native.or_else(|| {
+            shaders.iter().find_map(|shader| match (backend, shader) {
+                (BackendType::Dx12, Shader::Hlsl(hlsl)) => Some(ShaderCode::Hlsl(hlsl)),
+                (BackendType::Cpu, _) => None,
+                (_, Shader::Wgsl(wgsl)) => Some(ShaderCode::Wgsl(wgsl)),
+                _ => None,
+            })
+        })
+    }
+
+    fn buffer(&self, id: u64) -> Result<&Buffer, Error> {
+        self.replay
+            .buffers
+            .get(&id)
+            .ok_or_else(|| not_captured("buffer", id))
+    }
+
+    fn image(&self, id: u64) -> Result<&Image, Error> {
+        self.replay
+            .images
+            .get(&id)
+            .ok_or_else(|| not_captured("image", id))
+    }
+
+    fn images(&self, ids: &[u64]) -> Result<Vec<&Image>, Error> {
+        ids.iter().map(|id| self.image(*id)).collect()
+    }
+
+    fn pipeline(&self, id: u64) -> Result<&Pipeline, Error> {
+        self.pipelines
+            .get(&id)
+            .ok_or_else(|| not_captured("pipeline", id))
+    }
+
+    fn descriptor_set(&self, id: u64) -> Result<&DescriptorSet, Error> {
+        self.descriptor_sets
+            .get(&id)
+            .ok_or_else(|| not_captured("descriptor set", id))
+    }
+
+    fn descriptor_set_mut(&mut self, id: u64) -> Result<&mut DescriptorSet, Error> {
+        self.descriptor_sets
+            .get_mut(&id)
+            .ok_or_else(|| not_captured("descriptor set", id))
+    }
+}
+
+fn not_captured(what: &str, id: u64) -> Error {
+    format!("{} {} is not in the capture", what, id).into()
+}
+
+impl Event {
+    /// The id of the object this creates, if it is a pipeline or descriptor set.
+    fn created(&self) -> Option<u64> {
+        match self {
+            Event::CreatePipeline { id, .. } | Event::CreateDescriptorSet { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// The ids of the other objects a pipeline or descriptor set event uses.
+    fn uses(&self) -> Vec<u64> {
+        match self {
+            Event::CreateDescriptorSet {
+                pipeline,
+                buffers,
+                images,
+                textures,
+                ..
+            } => {
+                let buffers = buffers.iter().map(|binding| match binding {
+                    BufferBinding::Whole(buffer) => *buffer,
+                    BufferBinding::Slice { buffer, .. } => *buffer,
+                });
+                std::iter::once(*pipeline)
+                    .chain(buffers)
+                    .chain(images.iter().copied())
+                    .chain(textures.iter().copied())
+                    .collect()
+            }
+            Event::UpdateBufferDescriptor { buffer, .. } => vec![*buffer],
+            Event::UpdateImageDescriptor { image, .. } => vec![*image],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Op {
+    fn is_dispatch(&self) -> bool {
+        matches!(self, Op::Dispatch { .. } | Op::DispatchIndirect { .. })
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn bytes(&mut self, x: &[u8]) {
+        self.u64(x.len() as u64);
+        self.0.extend_from_slice(x);
+    }
+
+    fn ids(&mut self, ids: &[u64]) {
+        self.u32(ids.len() as u32);
+        for id in ids {
+            self.u64(*id);
+        }
+    }
+
+    fn pair(&mut self, (x, y): (u32, u32)) {
+        self.u32(x);
+        self.u32(y);
+    }
+
+    fn triple(&mut self, (x, y, z): (u32, u32, u32)) {
+        self.u32(x);
+        self.u32(y);
+        self.u32(z);
+    }
+
+    fn region(&mut self, region: ImageRegion) {
+        self.pair(region.origin);
+        self.pair(region.size);
+    }
+
+    fn buffer_layout(&mut self, layout: BufferImageLayout) {
+        self.u64(layout.offset);
+        self.u32(layout.row_pitch);
+    }
+
+    fn option(&mut self, x: Option<u64>) {
+        match x {
+            Some(x) => {
+                self.u8(1);
+                self.u64(x);
+            }
+            None => self.u8(0),
+        }
+    }
+}
+
+// Decoding helpers for the types written by `Writer`.
+
+fn read_string(r: &mut Reader) -> Option<String> {
+    String::from_utf8(r.bytes()?.to_vec()).ok()
+}
+
+fn read_ids(r: &mut Reader) -> Option<Vec<u64>> {
+    (0..r.u32()?).map(|_| r.u64()).collect()
+}
+
+fn read_pair(r: &mut Reader) -> Option<(u32, u32)> {
+    Some((r.u32()?, r.u32()?))
+}
+
+fn read_triple(r: &mut Reader) -> Option<(u32, u32, u32)> {
+    Some((r.u32()?, r.u32()?, r.u32()?))
+}
+
+fn read_region(r: &mut Reader) -> Option<ImageRegion> {
+    Some(ImageRegion {
+        origin: read_pair(r)?,
+        size: read_pair(r)?,
+    })
+}
+
+fn read_buffer_layout(r: &mut Reader) -> Option<BufferImageLayout> {
+    Some(BufferImageLayout {
+        offset: r.u64()?,
+        row_pitch: r.u32()?,
+    })
+}
+
+fn read_option(r: &mut Reader) -> Option<Option<u64>> {
+    match r.u8()? {
+        0 => Some(None),
+        1 => Some(Some(r.u64()?)),
+        _ => None,
+    }
+}
+
+const LAYOUTS: [ImageLayout; 6] = [
+    ImageLayout::Undefined,
+    ImageLayout::Present,
+    ImageLayout::BlitSrc,
+    ImageLayout::BlitDst,
+    ImageLayout::General,
+    ImageLayout::ShaderRead,
+];
+
+const FORMATS: [ImageFormat; 8] = [
+    ImageFormat::A8,
+    ImageFormat::Rgba8,
+    ImageFormat::Bgra8,
+    ImageFormat::Rgba8Srgb,
+    ImageFormat::Bgra8Srgb,
+    ImageFormat::Rgba16Float,
+    ImageFormat::R32Float,
+    ImageFormat::Rgba32Float,
+];
+
+fn index_of<T: PartialEq>(table: &[T], value: T) -> u8 {
+    table.iter().position(|x| *x == value).unwrap() as u8
+}
+
+fn read_layout(r: &mut Reader) -> Option<ImageLayout> {
+    LAYOUTS.get(r.u8()? as usize).copied()
+}
+
+fn encode_bind_type(w: &mut Writer, bind_type: BindType) {
+    match bind_type {
+        BindType::Buffer => w.u8(0),
+        BindType::BufReadOnly => w.u8(1),
+        BindType::Image => w.u8(2),
+        BindType::ImageRead => w.u8(3),
+        BindType::Uniform => w.u8(4),
+        BindType::SampledImage => w.u8(5),
+        BindType::PushConstants(size) => {
+            w.u8(6);
+            w.u32(size);
+        }
+    }
+}
+
+fn decode_bind_type(r: &mut Reader) -> Option<BindType> {
+    Some(match r.u8()? {
+        0 => BindType::Buffer,
+        1 => BindType::BufReadOnly,
+        2 => BindType::Image,
+        3 => BindType::ImageRead,
+        4 => BindType::Uniform,
+        5 => BindType::SampledImage,
+        6 => BindType::PushConstants(r.u32()?),
+        _ => return None,
+    })
+}
+
+fn encode_constant(w: &mut Writer, constant: &SpecConstant) {
+    w.u32(constant.id);
+    let tag = match constant.value {
+        SpecValue::Bool(_) => 0,
+        SpecValue::U32(_) => 1,
+        SpecValue::I32(_) => 2,
+        SpecValue::F32(_) => 3,
+    };
+    w.u8(tag);
+    w.u32(constant.value.to_bits());
+}
+
+fn decode_constant(r: &mut Reader) -> Option<SpecConstant> {
+    let id = r.u32()?;
+    let tag = r.u8()?;
+    let bits = r.u32()?;
+    let value = match tag {
+        0 => SpecValue::Bool(bits != 0),
+        1 => SpecValue::U32(bits),
+        2 => SpecValue::I32(bits as i32),
+        3 => SpecValue::F32(f32::from_bits(bits)),
+        _ => return None,
+    };
+    Some(SpecConstant { id, value })
+}
+
+impl Shader {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Shader::Spv(spv) => {
+                w.u8(0);
+                w.bytes(spv);
+            }
+            Shader::Hlsl(hlsl) => {
+                w.u8(1);
+                w.bytes(hlsl.as_bytes());
+            }
+            Shader::Dxil(dxil) => {
+                w.u8(2);
+                w.bytes(dxil);
+            }
+            Shader::Msl(msl) => {
+                w.u8(3);
+                w.bytes(msl.as_bytes());
+            }
+            Shader::Wgsl(wgsl) => {
+                w.u8(4);
+                w.bytes(wgsl.as_bytes());
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Option<Shader> {
+        Some(match r.u8()? {
+            0 => Shader::Spv(r.bytes()?.to_vec()),
+            1 => Shader::Hlsl(read_string(r)?),
+            2 => Shader::Dxil(r.bytes()?.to_vec()),
+            3 => Shader::Msl(read_string(r)?),
+            4 => Shader::Wgsl(read_string(r)?),
+            _ => return None,
+        })
+    }
+}
+
+impl Event {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Event::CreateBuffer { id, size, usage } => {
+                w.u8(0);
+                w.u64(*id);
+                w.u64(*size);
+                w.u32(usage.bits());
+            }
+            Event::CreateImage {
+                id,
+                width,
+                height,
+                format,
+            } => {
+                w.u8(1);
+                w.u64(*id);
+                w.pair((*width, *height));
+                w.u8(index_of(&FORMATS, *format));
+            }
+            Event::WriteBuffer { id, offset, data } => {
+                w.u8(2);
+                w.u64(*id);
+                w.u64(*offset);
+                w.bytes(data);
+            }
+            Event::CreatePipeline {
+                id,
+                shaders,
+                bind_types,
+                constants,
+                subgroup_size,
+            } => {
+                w.u8(3);
+                w.u64(*id);
+                w.u32(shaders.len() as u32);
+                for shader in shaders {
+                    shader.encode(w);
+                }
+                w.u32(bind_types.len() as u32);
+                for bind_type in bind_types {
+                    encode_bind_type(w, *bind_type);
+                }
+                w.u32(constants.len() as u32);
+                for constant in constants {
+                    encode_constant(w, constant);
+                }
+                w.option(subgroup_size.map(u64::from));
+            }
+            Event::CreateDescriptorSet {
+                id,
+                pipeline,
+                buffers,
+                images,
+                textures,
+            } => {
+                w.u8(4);
+                w.u64(*id);
+                w.u64(*pipeline);
+                w.u32(buffers.len() as u32);
+                for binding in buffers {
+                    match binding {
+                        BufferBinding::Whole(buffer) => {
+                            w.u8(0);
+                            w.u64(*buffer);
+                        }
+                        BufferBinding::Slice {
+                            buffer,
+                            offset,
+                            size,
+                        } => {
+                            w.u8(1);
+                            w.u64(*buffer);
+                            w.u64(*offset);
+                            w.u64(*size);
+                        }
+                    }
+                }
+                w.ids(images);
+                w.ids(textures);
+            }
+            Event::UpdateBufferDescriptor { set, index, buffer } => {
+                w.u8(5);
+                w.u64(*set);
+                w.u32(*index);
+                w.u64(*buffer);
+            }
+            Event::UpdateImageDescriptor { set, index, image } => {
+                w.u8(6);
+                w.u64(*set);
+                w.u32(*index);
+                w.u64(*image);
+            }
+            Event::Submit(ops) => {
+                w.u8(7);
+                w.u32(ops.len() as u32);
+                for op in ops {
+                    op.encode(w);
+                }
+            }
+            Event::Unsupported(what) => {
+                w.u8(8);
+                w.bytes(what.as_bytes());
+            }
+            Event::WriteImage {
+                id,
+                layout,
+                row_pitch,
+                data,
+            } => {
+                w.u8(9);
+                w.u64(*id);
+                w.u8(index_of(&LAYOUTS, *layout));
+                w.u32(*row_pitch);
+                w.bytes(data);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Option<Event> {
+        Some(match r.u8()? {
+            0 => Event::CreateBuffer {
+                id: r.u64()?,
+                size: r.u64()?,
+                usage: BufferUsage::from_bits(r.u32()?)?,
+            },
+            1 => {
+                let id = r.u64()?;
+                let (width, height) = read_pair(r)?;
+                let format = *FORMATS.get(r.u8()? as usize)?;
+                Event::CreateImage {
+                    id,
+                    width,
+                    height,
+                    format,
+                }
+            }
+            2 => Event::WriteBuffer {
+                id: r.u64()?,
+                offset: r.u64()?,
+                data: r.bytes()?.to_vec(),
+            },
+            3 => {
+                let id = r.u64()?;
+                let shaders = (0..r.u32()?)
+                    .map(|_| Shader::decode(r))
+                    .collect::<Option<_>>()?;
+                let bind_types = (0..r.u32()?)
+                    .map(|_| decode_bind_type(r))
+                    .collect::<Option<_>>()?;
+                let constants = (0..r.u32()?)
+                    .map(|_| decode_constant(r))
+                    .collect::<Option<_>>()?;
+                let subgroup_size = read_option(r)?.map(|size| size as u32);
+                Event::CreatePipeline {
+                    id,
+                    shaders,
+                    bind_types,
+                    constants,
+                    subgroup_size,
+                }
+            }
+            4 => {
+                let id = r.u64()?;
+                let pipeline = r.u64()?;
+                let buffers = (0..r.u32()?)
+                    .map(|_| match r.u8()? {
+                        0 => Some(BufferBinding::Whole(r.u64()?)),
+                        1 => Some(BufferBinding::Slice {
+                            buffer: r.u64()?,
+                            offset: r.u64()?,
+                            size: r.u64()?,
+                        }),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+                Event::CreateDescriptorSet {
+                    id,
+                    pipeline,
+                    buffers,
+                    images: read_ids(r)?,
+                    textures: read_ids(r)?,
+                }
+            }
+            5 => Event::UpdateBufferDescriptor {
+                set: r.u64()?,
+                index: r.u32()?,
+                buffer: r.u64()?,
+            },
+            6 => Event::UpdateImageDescriptor {
+                set: r.u64()?,
+                index: r.u32()?,
+                image: r.u64()?,
+            },
+            7 => {
+                let ops = (0..r.u32()?)
+                    .map(|_| Op::decode(r))
+                    .collect::<Option<_>>()?;
+                Event::Submit(ops)
+            }
+            8 => Event::Unsupported(read_string(r)?),
+            9 => Event::WriteImage {
+                id: r.u64()?,
+                layout: read_layout(r)?,
+                row_pitch: r.u32()?,
+                data: r.bytes()?.to_vec(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl Op {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Op::Dispatch {
+                pipeline,
+                set,
+                workgroup_count,
+                workgroup_size,
+                push_constants,
+            } => {
+                w.u8(0);
+                w.u64(*pipeline);
+                w.u64(*set);
+                w.triple(*workgroup_count);
+                w.triple(*workgroup_size);
+                w.bytes(push_constants);
+            }
+            Op::DispatchIndirect {
+                pipeline,
+                set,
+                buffer,
+                offset,
+                workgroup_size,
+                push_constants,
+            } => {
+                w.u8(1);
+                w.u64(*pipeline);
+                w.u64(*set);
+                w.u64(*buffer);
+                w.u64(*offset);
+                w.triple(*workgroup_size);
+                w.bytes(push_constants);
+            }
+            Op::MemoryBarrier => w.u8(2),
+            Op::ImageBarrier {
+                image,
+                src_layout,
+                dst_layout,
+            } => {
+                w.u8(3);
+                w.u64(*image);
+                w.u8(index_of(&LAYOUTS, *src_layout));
+                w.u8(index_of(&LAYOUTS, *dst_layout));
+            }
+            Op::ClearBuffer { buffer, size } => {
+                w.u8(4);
+                w.u64(*buffer);
+                w.option(*size);
+            }
+            Op::CopyBuffer { src, dst } => {
+                w.u8(5);
+                w.u64(*src);
+                w.u64(*dst);
+            }
+            Op::CopyBufferRegion {
+                src,
+                src_offset,
+                dst,
+                dst_offset,
+                size,
+            } => {
+                w.u8(6);
+                w.u64(*src);
+                w.u64(*src_offset);
+                w.u64(*dst);
+                w.u64(*dst_offset);
+                w.u64(*size);
+            }
+            Op::CopyImageToBuffer { src, dst } => {
+                w.u8(7);
+                w.u64(*src);
+                w.u64(*dst);
+            }
+            Op::CopyBufferToImage { src, dst } => {
+                w.u8(8);
+                w.u64(*src);
+                w.u64(*dst);
+            }
+            Op::BlitImage { src, dst } => {
+                w.u8(9);
+                w.u64(*src);
+                w.u64(*dst);
+            }
+            Op::CopyImageRegionToBuffer {
+                src,
+                region,
+                dst,
+                layout,
+            } => {
+                w.u8(10);
+                w.u64(*src);
+                w.region(*region);
+                w.u64(*dst);
+                w.buffer_layout(*layout);
+            }
+            Op::CopyBufferToImageRegion {
+                src,
+                layout,
+                dst,
+                region,
+            } => {
+                w.u8(11);
+                w.u64(*src);
+                w.buffer_layout(*layout);
+                w.u64(*dst);
+                w.region(*region);
+            }
+            Op::CopyImageRegion {
+                src,
+                region,
+                dst,
+                dst_origin,
+            } => {
+                w.u8(12);
+                w.u64(*src);
+                w.region(*region);
+                w.u64(*dst);
+                w.pair(*dst_origin);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Option<Op> {
+        Some(match r.u8()? {
+            0 => Op::Dispatch {
+                pipeline: r.u64()?,
+                set: r.u64()?,
+                workgroup_count: read_triple(r)?,
+                workgroup_size: read_triple(r)?,
+                push_constants: r.bytes()?.to_vec(),
+            },
+            1 => Op::DispatchIndirect {
+                pipeline: r.u64()?,
+                set: r.u64()?,
+                buffer: r.u64()?,
+                offset: r.u64()?,
+                workgroup_size: read_triple(r)?,
+                push_constants: r.bytes()?.to_vec(),
+            },
+            2 => Op::MemoryBarrier,
+            3 => Op::ImageBarrier {
+                image: r.u64()?,
+                src_layout: read_layout(r)?,
+                dst_layout: read_layout(r)?,
+            },
+            4 => Op::ClearBuffer {
+                buffer: r.u64()?,
+                size: read_option(r)?,
+            },
+            5 => Op::CopyBuffer {
+                src: r.u64()?,
+                dst: r.u64()?,
+            },
+            6 => Op::CopyBufferRegion {
+                src: r.u64()?,
+                src_offset: r.u64()?,
+                dst: r.u64()?,
+                dst_offset: r.u64()?,
+                size: r.u64()?,
+            },
+            7 => Op::CopyImageToBuffer {
+                src: r.u64()?,
+                dst: r.u64()?,
+            },
+            8 => Op::CopyBufferToImage {
+                src: r.u64()?,
+                dst: r.u64()?,
+            },
+            9 => Op::BlitImage {
+                src: r.u64()?,
+                dst: r.u64()?,
+            },
+            10 => Op::CopyImageRegionToBuffer {
+                src: r.u64()?,
+                region: read_region(r)?,
+                dst: r.u64()?,
+                layout: read_buffer_layout(r)?,
+            },
+            11 => Op::CopyBufferToImageRegion {
+                src: r.u64()?,
+                layout: read_buffer_layout(r)?,
+                dst: r.u64()?,
+                region: read_region(r)?,
+            },
+            12 => Op::CopyImageRegion {
+                src: r.u64()?,
+                region: read_region(r)?,
+                dst: r.u64()?,
+                dst_origin: read_pair(r)?,
+            },
+            _ => return None,
+        })
+    }
+}
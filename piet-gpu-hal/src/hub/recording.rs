@@ -0,0 +1,827 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! A safe layer for recording and submitting commands.
+//!
+//! Commands recorded here retain the resources they use until the GPU is
+//! done with them, barriers between commands that touch the same resource
+//! are inserted automatically, and images are transitioned to the layouts
+//! commands need. Misuse is reported as an error at recording time.
+//!
+//! The one unsafe step is creating a [`Kernel`], as the shader code must
+//! match the bind types and stay in bounds of the bound resources.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytemuck::Pod;
+
+use super::{
+    Buffer, CmdBuf, DescriptorSet, Image, Pipeline, RetainResource, Session, SubmittedCmdBuf,
+};
+use crate::{
+    mux, BackendType, BindType, BufferUsage, ComputePassDescriptor, Error, FormatCapabilities,
+    ImageLayout, PipelineOptions, SamplerParams, ShaderCode,
+};
+
+/// A compute pipeline, along with the types of its bindings.
+#[derive(Clone)]
+pub struct Kernel(Arc<KernelInner>);
+
+struct KernelInner {
+    pipeline: Pipeline,
+    /// The bind types that occupy binding slots.
+    bind_types: Vec<BindType>,
+    /// The size of the push constant block, or 0 if there is none.
+    push_constant_size: u32,
+}
+
+/// A resource to bind to a slot of a [`Kernel`].
+#[derive(Clone, Copy)]
+pub enum Binding<'a> {
+    /// A buffer, for a storage or uniform buffer slot.
+    Buffer(&'a Buffer),
+    /// A range of a buffer, for a storage or uniform buffer slot.
+    BufferSlice(&'a super::BufferSlice),
+    /// An image, for a storage image slot.
+    Image(&'a Image),
+    /// An image, read through a sampler with the given parameters.
+    SampledImage(&'a Image, SamplerParams),
+}
+
+/// Resources bound to the slots of a kernel.
+///
+/// This retains the kernel and the resources, and can be dispatched any
+/// number of times.
+#[derive(Clone)]
+pub struct Bindings(Arc<BindingsInner>);
+
+struct BindingsInner {
+    kernel: Kernel,
+    descriptor_set: DescriptorSet,
+    /// Bound buffers, and whether the kernel may write them.
+    buffers: Vec<(Buffer, bool)>,
+    /// Bound images, with the layout they need, and whether the kernel may
+    /// write them.
+    images: Vec<(Image, ImageLayout, bool)>,
+    _samplers: Vec<mux::Sampler>,
+}
+
+/// A command buffer being recorded through the safe layer.
+///
+/// Commands are recorded for the main queue. Resources used by commands are
+/// retained until the submission completes.
+pub struct Recording {
+    cmd_buf: CmdBuf,
+    session: Session,
+    in_pass: bool,
+    hazards: Hazards,
+    /// Shared with the readbacks of this recording.
+    done: Option<Arc<AtomicBool>>,
+}
+
+/// A submitted [`Recording`].
+pub struct Submission {
+    submitted: SubmittedCmdBuf,
+    done: Option<Arc<AtomicBool>>,
+}
+
+/// The contents of a buffer, copied for reading on the host.
+///
+/// The contents are available once the [`Submission`] of the recording
+/// that made the copy has been waited on.
+pub struct Readback {
+    buffer: Buffer,
+    size: u64,
+    done: Arc<AtomicBool>,
+}
+
+/// Resources accessed since the last barrier, identified by address.
+#[derive(Default)]
+struct Hazards {
+    reads: Vec<usize>,
+    writes: Vec<usize>,
+}
+
+#[derive(Clone, Copy)]
+struct Access {
+    id: usize,
+    write: bool,
+}
+
+impl Session {
+    /// Create a kernel from shader code.
+    ///
+    /// Storage and uniform buffer slots must come before storage image
+    /// slots, which must come before sampled image slots.
+    ///
+    /// # Safety
+    ///
+    /// The shader must declare the given bindings, and may only access
+    /// bound resources within their bounds.
+    pub unsafe fn create_kernel<'a>(
+        &self,
+        code: ShaderCode<'a>,
+        bind_types: &[BindType],
+    ) -> Result<Kernel, Error> {
+        self.create_kernel_with_options(code, bind_types, &Default::default())
+    }
+
+    /// Create a kernel from shader code, with pipeline options.
+    ///
+    /// # Safety
+    ///
+    /// See [`Session::create_kernel`].
+    pub unsafe fn create_kernel_with_options<'a>(
+        &self,
+        code: ShaderCode<'a>,
+        bind_types: &[BindType],
+        options: &PipelineOptions,
+    ) -> Result<Kernel, Error> {
+        let (slots, push_constant_size) = BindType::split_push_constants(bind_types)?;
+        let mut group = 0;
+        for bind_type in &slots {
+            let bind_group = match bind_type {
+                BindType::Buffer | BindType::BufReadOnly | BindType::Uniform => 0,
+                BindType::Image | BindType::ImageRead => 1,
+                _ => 2,
+            };
+            if bind_group < group {
+                return Err("buffer slots must precede image slots, which must \
+                    precede sampled image slots"
+                    .into());
+            }
+            group = bind_group;
+        }
+        let pipeline = self.create_compute_pipeline_with_options(code, bind_types, options)?;
+        Ok(Kernel(Arc::new(KernelInner {
+            pipeline,
+            bind_types: slots,
+            push_constant_size,
+        })))
+    }
+
+    /// Bind resources to the slots of a kernel.
+    ///
+    /// The resources must match the bind types of the kernel, and must have
+    /// been created with the usage the slot needs.
+    pub fn create_bindings(
+        &self,
+        kernel: &Kernel,
+        bindings: &[Binding],
+    ) -> Result<Bindings, Error> {
+        let bind_types = &kernel.0.bind_types;
+        if bindings.len() != bind_types.len() {
+            return Err(format!(
+                "kernel has {} slots, but {} resources were bound",
+                bind_types.len(),
+                bindings.len()
+            )
+            .into());
+        }
+        let mut builder = unsafe { self.descriptor_set_builder() };
+        let mut buffers = Vec::new();
+        let mut images: Vec<(Image, ImageLayout, bool)> = Vec::new();
+        let mut samplers = Vec::new();
+        for (slot, (binding, bind_type)) in bindings.iter().zip(bind_types).enumerate() {
+            match binding {
+                Binding::Buffer(buffer) => {
+                    check_buffer_slot(buffer, *bind_type, slot)?;
+                    builder = builder.add_buffers(&[*buffer]);
+                    buffers.push((Buffer::clone(buffer), *bind_type == BindType::Buffer));
+                }
+                Binding::BufferSlice(slice) => {
+                    check_buffer_slot(&slice.buffer, *bind_type, slot)?;
+                    builder = builder.add_buffer_slices(&[*slice]);
+                    buffers.push((slice.buffer.clone(), *bind_type == BindType::Buffer));
+                }
+                Binding::Image(image) => {
+                    if !matches!(bind_type, BindType::Image | BindType::ImageRead) {
+                        return Err(slot_mismatch(slot));
+                    }
+                    self.check_image_caps(image, FormatCapabilities::STORAGE)?;
+                    builder = builder.add_images(&[*image]);
+                    let write = *bind_type == BindType::Image;
+                    add_image_binding(&mut images, image, ImageLayout::General, write)?;
+                }
+                Binding::SampledImage(image, params) => {
+                    if *bind_type != BindType::SampledImage {
+                        return Err(slot_mismatch(slot));
+                    }
+                    self.check_image_caps(image, FormatCapabilities::SAMPLED)?;
+                    let sampler = unsafe { self.0.device.create_sampler(*params)? };
+                    builder = builder.add_sampled_images(&[*image], &sampler);
+                    samplers.push(sampler);
+                    add_image_binding(&mut images, image, ImageLayout::ShaderRead, false)?;
+                }
+            }
+        }
+        let descriptor_set = unsafe { builder.build(self, &kernel.0.pipeline)? };
+        Ok(Bindings(Arc::new(BindingsInner {
+            kernel: kernel.clone(),
+            descriptor_set,
+            buffers,
+            images,
+            _samplers: samplers,
+        })))
+    }
+
+    /// Start recording commands.
+    pub fn record(&self) -> Result<Recording, Error> {
+        let mut cmd_buf = self.cmd_buf()?;
+        unsafe {
+            cmd_buf.begin();
+        }
+        Ok(Recording {
+            cmd_buf,
+            session: self.clone(),
+            in_pass: false,
+            hazards: Default::default(),
+            done: None,
+        })
+    }
+
+    fn check_image_caps(&self, image: &Image, caps: FormatCapabilities) -> Result<(), Error> {
+        if self.format_capabilities(image.format()).contains(caps) {
+            Ok(())
+        } else {
+            Err(Error::unsupported(format!(
+                "{:?} for {:?} images",
+                caps,
+                image.format()
+            )))
+        }
+    }
+}
+
+fn add_image_binding(
+    images: &mut Vec<(Image, ImageLayout, bool)>,
+    image: &Image,
+    layout: ImageLayout,
+    write: bool,
+) -> Result<(), Error> {
+    match images.iter_mut().find(|(i, _, _)| i.id() == image.id()) {
+        Some((_, bound_layout, _)) if *bound_layout != layout => {
+            Err("image bound both as storage and sampled image".into())
+        }
+        Some((_, _, bound_write)) => {
+            *bound_write |= write;
+            Ok(())
+        }
+        None => {
+            images.push((image.clone(), layout, write));
+            Ok(())
+        }
+    }
+}
+
+impl Kernel {
+    /// The underlying pipeline.
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.0.pipeline
+    }
+}
+
+impl Bindings {
+    /// The kernel the resources are bound to.
+    pub fn kernel(&self) -> &Kernel {
+        &self.0.kernel
+    }
+}
+
+impl Recording {
+    /// Dispatch the kernel of the bindings.
+    ///
+    /// The workgroup size must be within the limits of the device.
+    pub fn dispatch(
+        &mut self,
+        bindings: &Bindings,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+    ) -> Result<(), Error> {
+        self.dispatch_impl(bindings, workgroup_count, workgroup_size, &[])
+    }
+
+    /// Dispatch the kernel of the bindings, with push constants.
+    ///
+    /// The kernel must declare a push constant block of the size of `T`.
+    pub fn dispatch_with_push_constants<T: Pod>(
+        &mut self,
+        bindings: &Bindings,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: &T,
+    ) -> Result<(), Error> {
+        let push_constants = bytemuck::bytes_of(push_constants);
+        self.dispatch_impl(bindings, workgroup_count, workgroup_size, push_constants)
+    }
+
+    /// Dispatch the kernel of the bindings, reading the workgroup counts from
+    /// a buffer.
+    ///
+    /// The buffer must have been created with `BufferUsage::INDIRECT`, and
+    /// hold three `u32` values at `offset`.
+    pub fn dispatch_indirect(
+        &mut self,
+        bindings: &Bindings,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+    ) -> Result<(), Error> {
+        self.dispatch_indirect_impl(bindings, buffer, offset, workgroup_size, &[])
+    }
+
+    /// Dispatch the kernel of the bindings indirectly, with push constants.
+    ///
+    /// The kernel must declare a push constant block of the size of `T`.
+    pub fn dispatch_indirect_with_push_constants<T: Pod>(
+        &mut self,
+        bindings: &Bindings,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &T,
+    ) -> Result<(), Error> {
+        let push_constants = bytemuck::bytes_of(push_constants);
+        self.dispatch_indirect_impl(bindings, buffer, offset, workgroup_size, push_constants)
+    }
+
+    /// Clear a buffer to zero.
+    ///
+    /// The buffer must have been created with `BufferUsage::CLEAR`.
+    pub fn clear_buffer(&mut self, buffer: &Buffer) -> Result<(), Error> {
+        check_usage(buffer, BufferUsage::CLEAR)?;
+        self.prepare_transfer(&[Access {
+            id: buffer.id(),
+            write: true,
+        }]);
+        self.cmd_buf.add_resource(buffer);
+        unsafe {
+            self.cmd_buf.clear_buffer(buffer, None);
+        }
+        Ok(())
+    }
+
+    /// Copy one buffer to another.
+    ///
+    /// When the buffers differ in size, the minimum of the sizes is used.
+    pub fn copy_buffer(&mut self, src: &Buffer, dst: &Buffer) -> Result<(), Error> {
+        check_usage(src, BufferUsage::COPY_SRC)?;
+        check_usage(dst, BufferUsage::COPY_DST)?;
+        self.prepare_transfer(&[
+            Access {
+                id: src.id(),
+                write: false,
+            },
+            Access {
+                id: dst.id(),
+                write: true,
+            },
+        ]);
+        self.cmd_buf.add_resource(src);
+        self.cmd_buf.add_resource(dst);
+        unsafe {
+            self.cmd_buf.copy_buffer(src, dst);
+        }
+        Ok(())
+    }
+
+    /// Copy an image to a buffer.
+    ///
+    /// The buffer must be large enough to hold the tightly packed rows of
+    /// the image.
+    pub fn copy_image_to_buffer(&mut self, src: &Image, dst: &Buffer) -> Result<(), Error> {
+        self.check_image_copy(src, dst)?;
+        check_usage(dst, BufferUsage::COPY_DST)?;
+        self.use_image(src, ImageLayout::BlitSrc);
+        self.prepare_transfer(&[
+            Access {
+                id: src.id(),
+                write: false,
+            },
+            Access {
+                id: dst.id(),
+                write: true,
+            },
+        ]);
+        self.cmd_buf.add_resource(src);
+        self.cmd_buf.add_resource(dst);
+        unsafe {
+            self.cmd_buf.copy_image_to_buffer(src, dst);
+        }
+        Ok(())
+    }
+
+    /// Copy a buffer to an image.
+    ///
+    /// The buffer must hold the tightly packed rows of the image.
+    pub fn copy_buffer_to_image(&mut self, src: &Buffer, dst: &Image) -> Result<(), Error> {
+        self.check_image_copy(dst, src)?;
+        check_usage(src, BufferUsage::COPY_SRC)?;
+        self.use_image(dst, ImageLayout::BlitDst);
+        self.prepare_transfer(&[
+            Access {
+                id: src.id(),
+                write: false,
+            },
+            Access {
+                id: dst.id(),
+                write: true,
+            },
+        ]);
+        self.cmd_buf.add_resource(src);
+        self.cmd_buf.add_resource(dst);
+        unsafe {
+            self.cmd_buf.copy_buffer_to_image(src, dst);
+        }
+        Ok(())
+    }
+
+    /// Copy an image to another, scaling if the sizes differ.
+    pub fn blit_image(&mut self, src: &Image, dst: &Image) -> Result<(), Error> {
+        if src.id() == dst.id() {
+            return Err("blit from an image to itself".into());
+        }
+        self.session
+            .check_image_caps(src, FormatCapabilities::BLIT)?;
+        self.session
+            .check_image_caps(dst, FormatCapabilities::BLIT)?;
+        self.use_image(src, ImageLayout::BlitSrc);
+        self.use_image(dst, ImageLayout::BlitDst);
+        self.prepare_transfer(&[
+            Access {
+                id: src.id(),
+                write: false,
+            },
+            Access {
+                id: dst.id(),
+                write: true,
+            },
+        ]);
+        self.cmd_buf.add_resource(src);
+        self.cmd_buf.add_resource(dst);
+        unsafe {
+            self.cmd_buf.blit_image(src, dst);
+        }
+        Ok(())
+    }
+
+    /// Transition an image to a layout.
+    ///
+    /// Layouts needed by commands are transitioned to automatically; this is
+    /// for leaving an image in a particular layout, such as
+    /// `ImageLayout::Present` for a swapchain image.
+    pub fn transition(&mut self, image: &Image, layout: ImageLayout) {
+        self.use_image(image, layout);
+        self.cmd_buf.add_resource(image);
+    }
+
+    /// Write data to a buffer, at a byte offset.
+    ///
+    /// The data is copied when the commands run, through a staging buffer.
+    /// The buffer must have been created with `BufferUsage::COPY_DST`, and
+    /// the offset and size of the data must be multiples of 4 bytes.
+    pub fn write_buffer<T: Pod>(
+        &mut self,
+        buffer: &Buffer,
+        offset: u64,
+        data: &[T],
+    ) -> Result<(), Error> {
+        let size = std::mem::size_of_val(data) as u64;
+        check_usage(buffer, BufferUsage::COPY_DST)?;
+        if offset % 4 != 0 || size % 4 != 0 {
+            return Err("buffer writes must be aligned to 4 bytes".into());
+        }
+        if offset + size > buffer.size() {
+            return Err("buffer write out of bounds".into());
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        let staging = self
+            .session
+            .create_buffer_init(data, BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC)?;
+        self.prepare_transfer(&[Access {
+            id: buffer.id(),
+            write: true,
+        }]);
+        unsafe {
+            self.cmd_buf
+                .copy_buffer_region(&staging, 0, buffer, offset, size);
+        }
+        self.cmd_buf.add_resource(staging);
+        self.cmd_buf.add_resource(buffer);
+        Ok(())
+    }
+
+    /// Copy the contents of a buffer for reading on the host.
+    ///
+    /// The buffer must have been created with `BufferUsage::COPY_SRC`.
+    pub fn read_buffer(&mut self, buffer: &Buffer) -> Result<Readback, Error> {
+        check_usage(buffer, BufferUsage::COPY_SRC)?;
+        let size = buffer.size();
+        let readback_buf = self
+            .session
+            .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)?;
+        self.copy_buffer(buffer, &readback_buf)?;
+        let done = self.done.get_or_insert_with(Default::default).clone();
+        Ok(Readback {
+            buffer: readback_buf,
+            size,
+            done,
+        })
+    }
+
+    /// Submit the recorded commands to the main queue.
+    pub fn submit(mut self) -> Result<Submission, Error> {
+        self.end_pass();
+        unsafe {
+            if self.done.is_some() {
+                self.cmd_buf.host_barrier();
+            }
+            self.cmd_buf.finish();
+        }
+        let submitted = unsafe { self.session.run_cmd_buf(self.cmd_buf, &[], &[])? };
+        Ok(Submission {
+            submitted,
+            done: self.done,
+        })
+    }
+
+    fn dispatch_impl(
+        &mut self,
+        bindings: &Bindings,
+        workgroup_count: (u32, u32, u32),
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) -> Result<(), Error> {
+        self.check_dispatch(bindings, workgroup_size, push_constants)?;
+        self.prepare_dispatch(bindings, &bindings_accesses(bindings));
+        unsafe {
+            self.cmd_buf.dispatch_impl(
+                &bindings.0.kernel.0.pipeline,
+                &bindings.0.descriptor_set,
+                workgroup_count,
+                workgroup_size,
+                push_constants,
+            );
+        }
+        Ok(())
+    }
+
+    fn dispatch_indirect_impl(
+        &mut self,
+        bindings: &Bindings,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) -> Result<(), Error> {
+        self.check_dispatch(bindings, workgroup_size, push_constants)?;
+        check_usage(buffer, BufferUsage::INDIRECT)?;
+        if offset % 4 != 0 || offset + 12 > buffer.size() {
+            return Err("indirect dispatch arguments out of bounds".into());
+        }
+        let mut accesses = bindings_accesses(bindings);
+        accesses.push(Access {
+            id: buffer.id(),
+            write: false,
+        });
+        self.prepare_dispatch(bindings, &accesses);
+        self.cmd_buf.add_resource(buffer);
+        unsafe {
+            self.cmd_buf.dispatch_indirect_impl(
+                &bindings.0.kernel.0.pipeline,
+                &bindings.0.descriptor_set,
+                buffer,
+                offset,
+                workgroup_size,
+                push_constants,
+            );
+        }
+        Ok(())
+    }
+
+    fn check_dispatch(
+        &self,
+        bindings: &Bindings,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) -> Result<(), Error> {
+        let push_constant_size = bindings.0.kernel.0.push_constant_size;
+        if push_constants.len() != push_constant_size as usize {
+            return Err(format!(
+                "kernel takes {} bytes of push constants, but {} were given",
+                push_constant_size,
+                push_constants.len()
+            )
+            .into());
+        }
+        let limits = &self.session.gpu_info().workgroup_limits;
+        let (x, y, z) = workgroup_size;
+        if x > limits.max_size[0]
+            || y > limits.max_size[1]
+            || z > limits.max_size[2]
+            || x * y * z > limits.max_invocations
+        {
+            return Err(
+                format!("workgroup size {:?} exceeds device limits", workgroup_size).into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Transition the bound images, insert barriers, and retain the bindings.
+    fn prepare_dispatch(&mut self, bindings: &Bindings, accesses: &[Access]) {
+        for (image, layout, _) in &bindings.0.images {
+            self.use_image(image, *layout);
+        }
+        self.sync(accesses);
+        if !self.in_pass {
+            unsafe {
+                self.cmd_buf
+                    .cmd_buf()
+                    .begin_compute_pass(&ComputePassDescriptor::default());
+            }
+            self.in_pass = true;
+        }
+        self.cmd_buf
+            .add_resource(RetainResource::Bindings(bindings.clone()));
+    }
+
+    fn prepare_transfer(&mut self, accesses: &[Access]) {
+        self.end_pass();
+        self.sync(accesses);
+    }
+
+    fn end_pass(&mut self) {
+        if self.in_pass {
+            unsafe {
+                self.cmd_buf.cmd_buf().end_compute_pass();
+            }
+            self.in_pass = false;
+        }
+    }
+
+    /// Insert a memory barrier if the accesses conflict with earlier ones.
+    fn sync(&mut self, accesses: &[Access]) {
+        if self.hazards.conflicts(accesses) {
+            unsafe {
+                self.cmd_buf.memory_barrier();
+            }
+            self.hazards = Default::default();
+        }
+        for access in accesses {
+            if access.write {
+                self.hazards.writes.push(access.id);
+            } else {
+                self.hazards.reads.push(access.id);
+            }
+        }
+    }
+
+    /// Transition an image to a layout, if it isn't in that layout already.
+    fn use_image(&mut self, image: &Image, layout: ImageLayout) {
+        if self.cmd_buf.current_layout(image) != layout {
+            self.end_pass();
+            unsafe {
+                self.cmd_buf.transition(image, layout);
+            }
+            // The layout transition synchronizes earlier accesses.
+            let id = image.id();
+            self.hazards.reads.retain(|r| *r != id);
+            self.hazards.writes.retain(|w| *w != id);
+        }
+    }
+
+    fn check_image_copy(&self, image: &Image, buffer: &Buffer) -> Result<(), Error> {
+        self.session
+            .check_image_caps(image, FormatCapabilities::COPY)?;
+        let row_bytes = image.width() * image.format().bytes_per_pixel();
+        if self.session.backend_type() == BackendType::Dx12 && row_bytes % 256 != 0 {
+            return Err(Error::unsupported(
+                "image copies with rows not a multiple of 256 bytes on DX12",
+            ));
+        }
+        if (row_bytes as u64) * (image.height() as u64) > buffer.size() {
+            return Err("buffer too small for image copy".into());
+        }
+        Ok(())
+    }
+}
+
+impl Hazards {
+    fn conflicts(&self, accesses: &[Access]) -> bool {
+        accesses.iter().any(|access| {
+            self.writes.contains(&access.id) || (access.write && self.reads.contains(&access.id))
+        })
+    }
+}
+
+impl Submission {
+    /// Wait for the submitted commands to complete.
+    ///
+    /// After this, the contents of readbacks made by the recording are
+    /// available.
+    pub fn wait(self) -> Result<(), Error> {
+        self.submitted.wait()?;
+        if let Some(done) = &self.done {
+            done.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+impl Readback {
+    /// The size of the contents, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read the contents.
+    ///
+    /// This fails if the submission hasn't been waited on, or the size of
+    /// the contents is not a multiple of the size of `T`.
+    pub fn read<T: Pod>(&self) -> Result<Vec<T>, Error> {
+        if !self.done.load(Ordering::Acquire) {
+            return Err("readback before its submission completed".into());
+        }
+        let item_size = std::mem::size_of::<T>() as u64;
+        if item_size == 0 || self.size % item_size != 0 {
+            return Err("readback size is not a multiple of the item size".into());
+        }
+        let mut bytes: Vec<u8> = Vec::new();
+        // Safety: the readback buffer is only used by the recording, which
+        // has completed.
+        unsafe {
+            self.buffer.read(&mut bytes)?;
+        }
+        let mut result = vec![T::zeroed(); (self.size / item_size) as usize];
+        bytemuck::cast_slice_mut(&mut result).copy_from_slice(&bytes[..self.size as usize]);
+        Ok(result)
+    }
+}
+
+/// Check that a buffer can be bound to a slot of the given type.
+fn check_buffer_slot(buffer: &Buffer, bind_type: BindType, slot: usize) -> Result<(), Error> {
+    match bind_type {
+        BindType::Buffer | BindType::BufReadOnly => check_usage(buffer, BufferUsage::STORAGE),
+        BindType::Uniform => check_usage(buffer, BufferUsage::UNIFORM),
+        _ => Err(slot_mismatch(slot)),
+    }
+}
+
+fn slot_mismatch(slot: usize) -> Error {
+    format!("resource bound to slot {} doesn't match its type", slot).into()
+}
+
+fn check_usage(buffer: &Buffer, usage: BufferUsage) -> Result<(), Error> {
+    if buffer.usage().contains(usage) {
+        Ok(())
+    } else {
+        Err(format!("buffer needs {:?} usage", usage).into())
+    }
+}
+
+fn bindings_accesses(bindings: &Bindings) -> Vec<Access> {
+    let buffers = bindings.0.buffers.iter().map(|(buffer, write)| Access {
+        id: buffer.id(),
+        write: *write,
+    });
+    let images = bindings.0.images.iter().map(|(image, _, write)| Access {
+        id: image.id(),
+        write: *write,
+    });
+    buffers.chain(images).collect()
+}
+
+impl Buffer {
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const u8 as usize
+    }
+}
+
+impl Image {
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const u8 as usize
+    }
+}
+
+impl From<Bindings> for RetainResource {
+    fn from(bindings: Bindings) -> Self {
+        RetainResource::Bindings(bindings)
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Uploads through a ring of staging buffers.
+
+use std::sync::Arc;
+
+use bytemuck::Pod;
+
+use super::{Buffer, CmdBuf, Image, Session};
+use crate::{BufWrite, BufferImageLayout, BufferUsage, Error, ImageRegion};
+
+/// The size of a staging chunk. Larger uploads get a buffer of their own.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+// The placement of image data in a buffer, as required by DX12 and
+// suitable for the other backends.
+const IMAGE_OFFSET_ALIGNMENT: u64 = 512;
+const ROW_PITCH_ALIGNMENT: u32 = 256;
+
+/// A ring of host-visible buffers for staging uploads.
+///
+/// Space in the current chunk is handed out in order. Command buffers
+/// retain the chunks they copy from, and once no submission holds on to a
+/// chunk (that is, their fences have signaled), it is reused.
+#[derive(Default)]
+pub(crate) struct StagingRing {
+    chunks: Vec<Buffer>,
+    /// The chunk being allocated from.
+    current: usize,
+    /// The start of the free space in the current chunk.
+    offset: u64,
+}
+
+impl Session {
+    /// Upload data to a buffer, at a byte offset.
+    ///
+    /// If the buffer has `MAP_WRITE` usage, it is written directly, right
+    /// away, so it must not be in use by pending work. Otherwise the data is
+    /// copied to a staging buffer, and the copy to the buffer is recorded on
+    /// the command buffer; then the buffer needs `COPY_DST` usage. Staging
+    /// space is reused once the command buffer completes.
+    ///
+    /// The offset and the size of the data must be multiples of 4 bytes.
+    pub unsafe fn upload<T: Pod>(
+        &self,
+        cmd_buf: &mut CmdBuf,
+        buffer: &Buffer,
+        offset: u64,
+        data: &[T],
+    ) -> Result<(), Error> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let size = bytes.len() as u64;
+        if offset % 4 != 0 || size % 4 != 0 {
+            return Err("uploads must be aligned to 4 bytes".into());
+        }
+        if !matches!(offset.checked_add(size), Some(end) if end <= buffer.size()) {
+            return Err(format!(
+                "upload of {} bytes at {} out of bounds for buffer of size {}",
+                size,
+                offset,
+                buffer.size()
+            )
+            .into());
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        if buffer.usage().contains(BufferUsage::MAP_WRITE) {
+            let mut mapped = buffer.map_write_impl(offset, size)?;
+            mapped.push_bytes(bytes);
+            return Ok(());
+        }
+        if !buffer.usage().contains(BufferUsage::COPY_DST) {
+            return Err("buffer needs MAP_WRITE or COPY_DST usage for uploads".into());
+        }
+        let (staging, staging_offset) =
+            self.staging_write(size, 4, |mapped| mapped.push_bytes(bytes))?;
+        cmd_buf.copy_buffer_region(&staging, staging_offset, buffer, offset, size);
+        cmd_buf.add_resource(staging);
+        Ok(())
+    }
+
+    /// Upload pixels to a region of an image.
+    ///
+    /// The data holds the rows of the region, tightly packed. It is copied
+    /// to a staging buffer, and the copy to the image is recorded on the
+    /// command buffer, which transitions the image to
+    /// `ImageLayout::BlitDst`. Staging space is reused once the command
+    /// buffer completes.
+    pub unsafe fn upload_image<T: Pod>(
+        &self,
+        cmd_buf: &mut CmdBuf,
+        image: &Image,
+        region: ImageRegion,
+        data: &[T],
+    ) -> Result<(), Error> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let (width, height) = region.size;
+        let in_bounds = |origin: u32, size: u32, limit: u32| matches!(origin.checked_add(size), Some(end) if end <= limit);
+        if !in_bounds(region.origin.0, width, image.width())
+            || !in_bounds(region.origin.1, height, image.height())
+        {
+            return Err(format!(
+                "region {:?} out of bounds for image of size {}x{}",
+                region,
+                image.width(),
+                image.height()
+            )
+            .into());
+        }
+        let row_bytes = width * image.format().bytes_per_pixel();
+        if bytes.len() as u64 != row_bytes as u64 * height as u64 {
+            return Err(format!(
+                "upload of {} bytes doesn't match a {}x{} region",
+                bytes.len(),
+                width,
+                height
+            )
+            .into());
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let row_pitch =
+            (row_bytes + ROW_PITCH_ALIGNMENT - 1) / ROW_PITCH_ALIGNMENT * ROW_PITCH_ALIGNMENT;
+        let size = row_pitch as u64 * height as u64;
+        let (staging, offset) = self.staging_write(size, IMAGE_OFFSET_ALIGNMENT, |mapped| {
+            for row in bytes.chunks(row_bytes as usize) {
+                mapped.push_bytes(row);
+                mapped.fill_zero((row_pitch - row_bytes) as usize);
+            }
+        })?;
+        let layout = BufferImageLayout { offset, row_pitch };
+        cmd_buf.copy_buffer_to_image_region(&staging, layout, image, region);
+        cmd_buf.add_resource(staging);
+        Ok(())
+    }
+
+    /// Allocate staging space and write to it, returning the buffer and the
+    /// offset in it.
+    ///
+    /// The ring stays locked while the space is mapped, as uploads from
+    /// other threads may map the same chunk, and its memory must not be
+    /// mapped twice at once.
+    unsafe fn staging_write(
+        &self,
+        size: u64,
+        alignment: u64,
+        write: impl FnOnce(&mut BufWrite),
+    ) -> Result<(Buffer, u64), Error> {
+        let usage = BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC;
+        if size > CHUNK_SIZE {
+            let buffer = self.create_buffer(size, usage)?;
+            write(&mut *buffer.map_write_impl(0, size)?);
+            return Ok((buffer, 0));
+        }
+        let mut ring = self.0.staging_ring.lock().unwrap();
+        let (chunk, offset) = self.staging_alloc(&mut ring, size, alignment)?;
+        write(&mut *chunk.map_write_impl(offset, size)?);
+        Ok((chunk, offset))
+    }
+
+    /// Allocate space in the staging ring, returning the chunk and the
+    /// offset in it.
+    fn staging_alloc(
+        &self,
+        ring: &mut StagingRing,
+        size: u64,
+        alignment: u64,
+    ) -> Result<(Buffer, u64), Error> {
+        let usage = BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC;
+        if let Some(chunk) = ring.chunks.get(ring.current) {
+            let offset = (ring.offset + alignment - 1) / alignment * alignment;
+            if offset + size <= chunk.size() {
+                let chunk = chunk.clone();
+                ring.offset = offset + size;
+                return Ok((chunk, offset));
+            }
+        }
+        // Move on to the next chunk no longer in use, or add one. The ring
+        // holds the only reference to a chunk once no command buffer does.
+        let n_chunks = ring.chunks.len();
+        let current = ring.current;
+        let free = (1..=n_chunks)
+            .map(|i| (current + i) % n_chunks)
+            .find(|&ix| Arc::strong_count(&ring.chunks[ix].0) == 1);
+        let ix = match free {
+            Some(ix) => ix,
+            None => {
+                ring.chunks.push(self.create_buffer(CHUNK_SIZE, usage)?);
+                n_chunks
+            }
+        };
+        ring.current = ix;
+        ring.offset = size;
+        Ok((ring.chunks[ix].clone(), 0))
+    }
+}
+
+impl StagingRing {
+    /// Drop the chunks, so that new ones are created.
+    ///
+    /// Chunks still used by pending command buffers are retained by them.
+    pub(crate) fn reset(&mut self) {
+        *self = Default::default();
+    }
+}
@@ -8,22 +8,31 @@ use bitflags::bitflags;
 mod backend;
 mod bestfit;
 mod bufwrite;
+mod cpu;
+mod error;
 mod hub;
 
 #[macro_use]
 mod macros;
 
 mod mux;
+mod pipeline_cache;
+mod profiler;
+mod translate;
 
 pub use crate::mux::{
-    DescriptorSet, Device, Fence, Instance, Pipeline, QueryPool, Sampler, Semaphore, ShaderCode,
-    Surface, Swapchain,
+    Device, Fence, Instance, QueryPool, Sampler, Semaphore, ShaderCode, Surface, Swapchain,
 };
 pub use bufwrite::BufWrite;
+pub use cpu::{CpuBinding, CpuBufGuard, CpuDispatch, CpuShader};
+pub use error::Error;
+pub use hub::{Binding, Bindings, Kernel, Readback, Recording, Submission};
 pub use hub::{
-    BufReadGuard, BufWriteGuard, Buffer, CmdBuf, ComputePass, DescriptorSetBuilder, Image,
-    RetainResource, Session, SubmittedCmdBuf,
+    BufReadGuard, BufWriteGuard, Buffer, BufferSlice, CmdBuf, ComputePass, DescriptorSet,
+    DescriptorSetBuilder, Image, Pipeline, PipelineCache, PooledQueryPool, Replay, RetainResource,
+    Session, SubmittedCmdBuf,
 };
+pub use profiler::{ChromeTrace, FrameProfile, PendingProfile, ProfileScope};
 
 // TODO: because these are conditionally included, "cargo fmt" does not
 // see them. Figure that out, possibly including running rustfmt manually.
@@ -38,18 +47,19 @@ mux_cfg! {
 #[cfg(target_os = "macos")]
 mod metal;
 
-/// The common error type for the crate.
-///
-/// This keeps things simple and can be expanded later.
-pub type Error = Box<dyn std::error::Error>;
-
 bitflags! {
     /// Options when creating an instance.
     #[derive(Default)]
     pub struct InstanceFlags: u32 {
         /// Prefer DX12 over Vulkan.
         const DX12 = 0x1;
-        // TODO: discrete vs integrated selection
+        /// Use the CPU backend, which runs ports of kernels on the host.
+        const CPU = 0x2;
+        /// Enable validation layers, when present.
+        ///
+        /// Messages from the layers are forwarded to the `log` crate. This
+        /// is always enabled in debug builds.
+        const VALIDATION = 0x4;
     }
 }
 
@@ -59,6 +69,57 @@ pub enum BackendType {
     Vulkan,
     Dx12,
     Metal,
+    Cpu,
+}
+
+/// A queue that command buffers are submitted to.
+///
+/// Work on different queues may run concurrently. Where the hardware has no
+/// dedicated queue of a type, work is submitted to the main queue instead.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum QueueType {
+    /// The queue for all kinds of work, including presentation.
+    Main,
+    /// A queue for compute work, running alongside the main queue.
+    Compute,
+    /// A queue for copies, often backed by a DMA engine.
+    ///
+    /// Only copies and barriers may be recorded for this queue, and timer
+    /// queries may not be supported.
+    Transfer,
+}
+
+/// The kind of physical device behind an adapter.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceType {
+    /// A GPU with its own memory, typically on a separate card.
+    Discrete,
+    /// A GPU sharing memory with the host, typically on the same die.
+    Integrated,
+    /// A GPU exposed through virtualization.
+    Virtual,
+    /// A software rasterizer such as lavapipe, SwiftShader or WARP.
+    Software,
+    /// The back-end doesn't report the device type.
+    Unknown,
+}
+
+/// Information about an adapter that can be opened as a device.
+///
+/// Adapters are listed by [`Instance::adapters`], and a specific one can
+/// be opened with [`Instance::device_from_adapter`].
+#[derive(Clone, Debug)]
+pub struct AdapterInfo {
+    /// The name of the adapter, as reported by the driver.
+    pub name: String,
+    /// The PCI vendor id, or 0 if not known.
+    pub vendor_id: u32,
+    /// The PCI device id, or 0 if not known.
+    pub device_id: u32,
+    pub device_type: DeviceType,
+    pub backend: BackendType,
+    /// The index of the adapter in the back-end's enumeration order.
+    pub(crate) index: usize,
 }
 
 /// An image layout state.
@@ -81,23 +142,149 @@ pub enum ImageLayout {
     ShaderRead,
 }
 
-/// The type of sampling for image lookup.
-///
-/// This could take a lot more params, such as filtering, repeat, behavior
-/// at edges, etc., but for now we'll keep it simple.
-#[derive(Copy, Clone, Debug)]
-pub enum SamplerParams {
+/// The parameters of a sampler, for image lookup.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SamplerParams {
+    /// Filtering for both magnification and minification.
+    pub filter: FilterMode,
+    /// Behavior for coordinates outside the image, for all axes.
+    pub address_mode: AddressMode,
+}
+
+/// The filtering of a sampler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterMode {
     Nearest,
     Linear,
 }
 
+/// The behavior of a sampler for coordinates outside the image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressMode {
+    /// Use the nearest edge texel.
+    ClampToEdge,
+    /// Use transparent black.
+    ClampToBorder,
+    /// Wrap around, for tiling.
+    Repeat,
+    /// Wrap around, flipping on every repeat.
+    MirrorRepeat,
+}
+
+impl SamplerParams {
+    /// Nearest-neighbor sampling, transparent outside the image.
+    pub const NEAREST: SamplerParams = SamplerParams {
+        filter: FilterMode::Nearest,
+        address_mode: AddressMode::ClampToBorder,
+    };
+
+    /// Bilinear sampling, transparent outside the image.
+    pub const LINEAR: SamplerParams = SamplerParams {
+        filter: FilterMode::Linear,
+        address_mode: AddressMode::ClampToBorder,
+    };
+
+    /// Change the address mode.
+    pub fn address_mode(mut self, address_mode: AddressMode) -> SamplerParams {
+        self.address_mode = address_mode;
+        self
+    }
+}
+
 /// Image format.
-#[derive(Copy, Clone, Debug)]
+///
+/// Not every format supports every use; see
+/// [`Session::format_capabilities`](crate::Session::format_capabilities).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ImageFormat {
     // 8 bit grayscale / alpha
     A8,
     // 8 bit per pixel RGBA
     Rgba8,
+    // 8 bit per pixel BGRA, the common swapchain format
+    Bgra8,
+    // 8 bit per pixel RGBA, sRGB encoded
+    Rgba8Srgb,
+    // 8 bit per pixel BGRA, sRGB encoded
+    Bgra8Srgb,
+    // 16 bit float per pixel RGBA
+    Rgba16Float,
+    // 32 bit float single channel
+    R32Float,
+    // 32 bit float per pixel RGBA
+    Rgba32Float,
+}
+
+impl ImageFormat {
+    /// The size of a pixel in bytes.
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            ImageFormat::A8 => 1,
+            ImageFormat::Rgba8
+            | ImageFormat::Bgra8
+            | ImageFormat::Rgba8Srgb
+            | ImageFormat::Bgra8Srgb
+            | ImageFormat::R32Float => 4,
+            ImageFormat::Rgba16Float => 8,
+            ImageFormat::Rgba32Float => 16,
+        }
+    }
+
+    /// Whether the format is sRGB encoded.
+    ///
+    /// Shaders see linear values when sampling these formats, but they
+    /// can't be bound as storage images.
+    pub fn is_srgb(self) -> bool {
+        matches!(self, ImageFormat::Rgba8Srgb | ImageFormat::Bgra8Srgb)
+    }
+}
+
+/// A rectangle of pixels in an image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageRegion {
+    /// The top left corner.
+    pub origin: (u32, u32),
+    /// The width and height.
+    pub size: (u32, u32),
+}
+
+/// The placement of image data in a buffer, for copies between the two.
+///
+/// For portability, the offset should be a multiple of 512 bytes and the
+/// row pitch a multiple of 256 bytes, as required by DX12.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferImageLayout {
+    /// The offset of the first pixel, in bytes.
+    pub offset: u64,
+    /// The distance between the starts of consecutive rows, in bytes.
+    pub row_pitch: u32,
+}
+
+/// The kind of handle used to share memory with other APIs or processes.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExternalHandleType {
+    /// An opaque fd, which can only be imported by the same driver and device.
+    OpaqueFd,
+    /// A Linux dma-buf, which can be shared with other drivers and APIs.
+    ///
+    /// Images shared as dma-bufs use linear tiling.
+    DmaBuf,
+}
+
+/// Memory shared with another API or process.
+///
+/// Importing the memory transfers ownership of the fd to the driver;
+/// otherwise, the holder is responsible for closing it.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug)]
+pub struct ExternalMemory {
+    pub fd: std::os::unix::io::RawFd,
+    pub handle_type: ExternalHandleType,
+    /// The size of the allocation, in bytes.
+    pub size: u64,
+    /// The placement of the pixels, for images with linear tiling.
+    pub layout: Option<BufferImageLayout>,
 }
 
 bitflags! {
@@ -111,8 +298,12 @@ bitflags! {
         const COPY_SRC = 0x4;
         /// The buffer can be copied to.
         const COPY_DST = 0x8;
+        /// The buffer can be bound to a compute shader as a uniform buffer.
+        const UNIFORM = 0x40;
         /// The buffer can be bound to a compute shader.
         const STORAGE = 0x80;
+        /// The buffer can hold arguments for indirect dispatch.
+        const INDIRECT = 0x100;
         /// The buffer can be used to store the results of queries.
         const QUERY_RESOLVE = 0x200;
         /// The buffer may be cleared.
@@ -121,6 +312,20 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The operations supported for images of a format.
+    pub struct FormatCapabilities: u32 {
+        /// Images can be sampled by shaders.
+        const SAMPLED = 0x1;
+        /// Images can be bound as storage images, for read and write.
+        const STORAGE = 0x2;
+        /// Images can be the source and destination of copies.
+        const COPY = 0x4;
+        /// Images can be the source and destination of `blit_image`.
+        const BLIT = 0x8;
+    }
+}
+
 /// The type of resource that will be bound to a slot in a shader.
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum BindType {
@@ -137,7 +342,60 @@ pub enum BindType {
     /// but the `--hlsl-nonwritable-uav-texture-as-srv` option to
     /// spirv-cross (marked as unstable) would do so.
     ImageRead,
-    // TODO: Uniform, Sampler, maybe others
+    /// A uniform buffer.
+    ///
+    /// Uniform buffers are read only and limited in size (the guaranteed
+    /// minimum is 16k), but can be faster for small amounts of data read by
+    /// all invocations. The buffer needs to be created with
+    /// `BufferUsage::UNIFORM`.
+    Uniform,
+    /// An image read through a sampler (a combined image/sampler).
+    ///
+    /// The image needs to be in `ImageLayout::ShaderRead` layout.
+    SampledImage,
+    /// A block of push constants, with the given size in bytes.
+    ///
+    /// Push constants don't take up a binding slot, and a pipeline can have
+    /// at most one block. The size must be a multiple of 4 and no more than
+    /// 128 bytes. The values are given with each dispatch.
+    ///
+    /// On DX12 the block is mapped to root constants, and on Metal it is set
+    /// with `setBytes`; in both cases the register or buffer index is the one
+    /// following the last binding.
+    PushConstants(u32),
+}
+
+impl BindType {
+    /// Separate the push constant block from the other bindings.
+    ///
+    /// Returns the bindings that occupy slots, and the size of the push
+    /// constant block in bytes (0 if there is none). It's an error to have
+    /// more than one block, or a block of an unsupported size.
+    pub(crate) fn split_push_constants(
+        bind_types: &[BindType],
+    ) -> Result<(Vec<BindType>, u32), Error> {
+        let mut push_constant_size = None;
+        let mut bindings = Vec::with_capacity(bind_types.len());
+        for bind_type in bind_types {
+            match *bind_type {
+                BindType::PushConstants(_) if push_constant_size.is_some() => {
+                    return Err("a pipeline can have at most one push constant block".into());
+                }
+                BindType::PushConstants(size) => {
+                    if size == 0 || size % 4 != 0 || size > 128 {
+                        return Err(format!(
+                            "push constant size {} is not a multiple of 4 from 4 to 128",
+                            size
+                        )
+                        .into());
+                    }
+                    push_constant_size = Some(size);
+                }
+                bind_type => bindings.push(bind_type),
+            }
+        }
+        Ok((bindings, push_constant_size.unwrap_or(0)))
+    }
 }
 
 /// Whether to map a buffer in read or write mode.
@@ -155,18 +413,118 @@ pub struct GpuInfo {
     pub has_descriptor_indexing: bool,
     /// The GPU supports subgroups.
     ///
-    /// Right now, this just checks for basic subgroup capability (as
-    /// required in Vulkan 1.1), and we should have finer grained
-    /// queries for shuffles, etc.
+    /// This is set when basic subgroup operations are available in compute
+    /// shaders; see `subgroup_features` for the other operations.
     pub has_subgroups: bool,
+    /// The subgroup operations available in compute shaders.
+    pub subgroup_features: SubgroupFeatures,
     /// Limits on workgroup size for compute shaders.
     pub workgroup_limits: WorkgroupLimits,
-    /// Info about subgroup size control, if available.
+    /// The range of subgroup sizes, if known.
     pub subgroup_size: Option<SubgroupSize>,
     /// The GPU supports a real, grown-ass memory model.
     pub has_memory_model: bool,
     /// Whether staging buffers should be used.
     pub use_staging_buffers: bool,
+    /// The name of the adapter the device was created from.
+    pub adapter_name: String,
+    /// The alignment, in bytes, required for the offset of a bound buffer slice.
+    pub buffer_offset_alignment: u64,
+    /// Timestamps can be written between compute passes.
+    ///
+    /// Otherwise, timer queries are only written at the boundaries of
+    /// compute passes, as with Metal's stage boundary counters.
+    pub has_timestamp_writes: bool,
+    /// The memory heaps available to the device.
+    pub memory_heaps: Vec<MemoryHeap>,
+}
+
+/// A heap of memory available to the device.
+#[derive(Clone, Debug)]
+pub struct MemoryHeap {
+    /// The size of the heap, in bytes.
+    pub size: u64,
+    /// The heap is local to the device, rather than system memory.
+    pub device_local: bool,
+}
+
+/// The current budget and usage of a memory heap.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryBudget {
+    /// The amount of memory the process can use without degraded performance,
+    /// in bytes.
+    ///
+    /// This changes as other processes allocate and free memory.
+    pub budget: u64,
+    /// The amount of memory the process is using, in bytes.
+    pub usage: u64,
+}
+
+/// A report of memory use.
+///
+/// See [`Session::memory_report`].
+#[derive(Clone, Debug)]
+pub struct MemoryReport {
+    /// The memory heaps available to the device.
+    pub heaps: Vec<MemoryHeap>,
+    /// The budget and usage of each heap, in the same order as `heaps`.
+    ///
+    /// This is `None` when the backend can't query it.
+    pub budgets: Option<Vec<MemoryBudget>>,
+    /// The size of live buffers created by the session, in bytes, for each
+    /// combination of usage flags.
+    pub buffer_bytes: Vec<(BufferUsage, u64)>,
+}
+
+impl MemoryReport {
+    /// The remaining budget of the device local heaps, in bytes, if known.
+    pub fn device_local_available(&self) -> Option<u64> {
+        let budgets = self.budgets.as_ref()?;
+        Some(
+            self.heaps
+                .iter()
+                .zip(budgets)
+                .filter(|(heap, _)| heap.device_local)
+                .map(|(_, budget)| budget.budget.saturating_sub(budget.usage))
+                .sum(),
+        )
+    }
+
+    /// The total size of live buffers created by the session, in bytes.
+    pub fn total_buffer_bytes(&self) -> u64 {
+        self.buffer_bytes.iter().map(|(_, bytes)| bytes).sum()
+    }
+}
+
+/// Statistics of the pooled buffer allocator.
+///
+/// See [`Session::enable_memory_pool`].
+#[derive(Clone, Debug, Default)]
+pub struct MemoryPoolStats {
+    /// The number of memory blocks.
+    pub n_blocks: usize,
+    /// The total size of the memory blocks, in bytes.
+    pub block_bytes: u64,
+    /// The number of live sub-allocations.
+    pub n_allocations: usize,
+    /// The total size of live sub-allocations, in bytes.
+    pub allocated_bytes: u64,
+    /// The sum over blocks of the largest free range in each, in bytes.
+    pub largest_free_bytes: u64,
+}
+
+impl MemoryPoolStats {
+    /// The fraction of free memory that is not in the largest free range of its block.
+    ///
+    /// This is 0 when each block's free memory is contiguous.
+    pub fn fragmentation(&self) -> f64 {
+        let free_bytes = self.block_bytes - self.allocated_bytes;
+        if free_bytes == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_bytes as f64 / free_bytes as f64
+        }
+    }
 }
 
 /// The range of subgroup sizes supported by a back-end, when available.
@@ -178,6 +536,34 @@ pub struct GpuInfo {
 pub struct SubgroupSize {
     pub min: u32,
     pub max: u32,
+    /// Pipelines can request a size in the range, with
+    /// [`PipelineOptions::subgroup_size`].
+    pub control: bool,
+}
+
+bitflags! {
+    /// Subgroup operations supported in compute shaders.
+    ///
+    /// These follow the categories of Vulkan subgroup operations.
+    #[derive(Default)]
+    pub struct SubgroupFeatures: u32 {
+        /// Electing an invocation, and subgroup barriers.
+        const BASIC = 0x1;
+        /// Votes: all, any, and all equal.
+        const VOTE = 0x2;
+        /// Reductions and scans, such as add and max.
+        const ARITHMETIC = 0x4;
+        /// Ballots and broadcasts.
+        const BALLOT = 0x8;
+        /// Shuffles by index or xor mask.
+        const SHUFFLE = 0x10;
+        /// Shuffles up or down by a delta.
+        const SHUFFLE_RELATIVE = 0x20;
+        /// Operations on clusters within the subgroup.
+        const CLUSTERED = 0x40;
+        /// Operations within quads.
+        const QUAD = 0x80;
+    }
 }
 
 /// The range of workgroup sizes supported by a back-end.
@@ -193,19 +579,130 @@ pub struct WorkgroupLimits {
 /// Options for creating a compute pass.
 #[derive(Default)]
 pub struct ComputePassDescriptor<'a> {
-    // Maybe label should go here? It does in wgpu and wgpu_hal.
     /// Timer query parameters.
     ///
     /// To record timer queries for a compute pass, set the query pool, start
     /// query index, and end query index here. The indices must be less than
     /// the size of the query pool.
     timer_queries: Option<(&'a QueryPool, u32, u32)>,
+    /// The name of the pass, used for profiling.
+    label: Option<&'a str>,
 }
 
 impl<'a> ComputePassDescriptor<'a> {
     pub fn timer(pool: &'a QueryPool, start_query: u32, end_query: u32) -> ComputePassDescriptor {
         ComputePassDescriptor {
             timer_queries: Some((pool, start_query, end_query)),
+            label: None,
         }
     }
+
+    /// Label the pass.
+    ///
+    /// When the command buffer is being profiled, a labeled pass is timed
+    /// automatically, unless explicit timer queries are given.
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = Some(label);
+        self
+    }
+}
+
+/// Options for creating a compute pipeline.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PipelineOptions<'a> {
+    /// Values for specialization constants.
+    constants: &'a [SpecConstant],
+    /// The requested subgroup size.
+    subgroup_size: Option<u32>,
+}
+
+impl<'a> PipelineOptions<'a> {
+    /// Specialize the shader with constant values.
+    ///
+    /// Constants are identified by id, following the conventions of
+    /// spirv-cross: they are specialization constants in SPIR-V, function
+    /// constants in MSL, and `SPIRV_CROSS_CONSTANT_ID_<id>` defines in HLSL.
+    /// Shaders precompiled to DXIL can't be specialized.
+    pub fn constants(mut self, constants: &'a [SpecConstant]) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    /// Request a subgroup size for the pipeline.
+    ///
+    /// The request takes effect when the size is a power of two in the range
+    /// reported by [`GpuInfo::subgroup_size`], and that range has `control`
+    /// set, which currently requires Vulkan with subgroup size control;
+    /// [`Pipeline::subgroup_size`] reports whether it did.
+    ///
+    /// DX12 doesn't support the request yet. It needs the `WaveSize`
+    /// attribute of Shader Model 6.6, but HLSL is compiled with FXC, which
+    /// only targets Shader Model 5.1. Emitting the attribute is left until
+    /// HLSL can be compiled with DXC. Until then, the request is ignored, and
+    /// a DXIL shader can still declare `WaveSize` itself.
+    pub fn subgroup_size(mut self, size: u32) -> Self {
+        self.subgroup_size = Some(size);
+        self
+    }
+}
+
+/// The value of a specialization constant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpecConstant {
+    pub id: u32,
+    pub value: SpecValue,
+}
+
+impl SpecConstant {
+    pub fn new(id: u32, value: impl Into<SpecValue>) -> SpecConstant {
+        SpecConstant {
+            id,
+            value: value.into(),
+        }
+    }
+}
+
+/// A scalar value for a specialization constant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpecValue {
+    Bool(bool),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+}
+
+impl SpecValue {
+    /// The 32-bit representation of the value, as used by SPIR-V.
+    pub fn to_bits(self) -> u32 {
+        match self {
+            SpecValue::Bool(b) => b as u32,
+            SpecValue::U32(u) => u,
+            SpecValue::I32(i) => i as u32,
+            SpecValue::F32(f) => f.to_bits(),
+        }
+    }
+}
+
+impl From<bool> for SpecValue {
+    fn from(b: bool) -> SpecValue {
+        SpecValue::Bool(b)
+    }
+}
+
+impl From<u32> for SpecValue {
+    fn from(u: u32) -> SpecValue {
+        SpecValue::U32(u)
+    }
+}
+
+impl From<i32> for SpecValue {
+    fn from(i: i32) -> SpecValue {
+        SpecValue::I32(i)
+    }
+}
+
+impl From<f32> for SpecValue {
+    fn from(f: f32) -> SpecValue {
+        SpecValue::F32(f)
+    }
 }
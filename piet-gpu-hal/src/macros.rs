@@ -41,6 +41,7 @@ macro_rules! mux_enum {
         Vk($vk:ty),
         Dx12($dx12:ty),
         Mtl($mtl:ty),
+        Cpu($cpu:ty),
     } ) => {
         $(#[$outer])* $v enum $name {
             #[cfg(not(target_os="macos"))]
@@ -49,6 +50,7 @@ macro_rules! mux_enum {
             Dx12($dx12),
             #[cfg(target_os="macos")]
             Mtl($mtl),
+            Cpu($cpu),
         }
 
         impl $name {
@@ -120,6 +122,7 @@ macro_rules! mux_enum {
                 fn mtl(&self) -> &$mtl {
                     match self {
                         $name::Mtl(x) => x,
+                        _ => panic!("downcast error")
                     }
                 }
             }
@@ -129,6 +132,7 @@ macro_rules! mux_enum {
                 fn mtl_mut(&mut self) -> &mut $mtl {
                     match self {
                         $name::Mtl(x) => x,
+                        _ => panic!("downcast error")
                     }
                 }
             }
@@ -138,9 +142,32 @@ macro_rules! mux_enum {
                 fn mtl_owned(self) -> $mtl {
                     match self {
                         $name::Mtl(x) => x,
+                        _ => panic!("downcast error")
                     }
                 }
             }
+
+            #[allow(unused)]
+            pub(crate) fn cpu(&self) -> &$cpu {
+                match self {
+                    $name::Cpu(x) => x,
+                    _ => panic!("downcast error")
+                }
+            }
+            #[allow(unused)]
+            fn cpu_mut(&mut self) -> &mut $cpu {
+                match self {
+                    $name::Cpu(x) => x,
+                    _ => panic!("downcast error")
+                }
+            }
+            #[allow(unused)]
+            fn cpu_owned(self) -> $cpu {
+                match self {
+                    $name::Cpu(x) => x,
+                    _ => panic!("downcast error")
+                }
+            }
         }
     };
 }
@@ -154,6 +181,7 @@ macro_rules! mux_device_enum {
                 Vk(<$crate::vulkan::VkDevice as $crate::backend::Device>::$assoc_type),
                 Dx12(<$crate::dx12::Dx12Device as $crate::backend::Device>::$assoc_type),
                 Mtl(<$crate::metal::MtlDevice as $crate::backend::Device>::$assoc_type),
+                Cpu(<$crate::cpu::CpuDevice as $crate::backend::Device>::$assoc_type),
             }
         }
     }
@@ -167,6 +195,7 @@ macro_rules! mux_match {
         $vkname:ident::Vk($vkvar:ident) => $vkblock: block
         $dx12name:ident::Dx12($dx12var:ident) => $dx12block: block
         $mtlname:ident::Mtl($mtlvar:ident) => $mtlblock: block
+        $cpuname:ident::Cpu($cpuvar:ident) => $cpublock: block
     ) => {
         match $e {
             #[cfg(not(target_os="macos"))]
@@ -175,6 +204,7 @@ macro_rules! mux_match {
             $dx12name::Dx12($dx12var) => $dx12block
             #[cfg(target_os="macos")]
             $mtlname::Mtl($mtlvar) => $mtlblock
+            $cpuname::Cpu($cpuvar) => $cpublock
         }
     };
 
@@ -182,16 +212,23 @@ macro_rules! mux_match {
         $vkname:ident::Vk($vkvar:ident) => $vkblock: expr,
         $dx12name:ident::Dx12($dx12var:ident) => $dx12block: expr,
         $mtlname:ident::Mtl($mtlvar:ident) => $mtlblock: expr,
+        $cpuname:ident::Cpu($cpuvar:ident) => $cpublock: expr,
     ) => {
         $crate::mux_match! { $e;
             $vkname::Vk($vkvar) => { $vkblock }
             $dx12name::Dx12($dx12var) => { $dx12block }
             $mtlname::Mtl($mtlvar) => { $mtlblock }
+            $cpuname::Cpu($cpuvar) => { $cpublock }
         }
     };
 }
 
 /// A convenience macro for selecting a shader from included files.
+///
+/// An optional third argument gives a CPU port of the shader (a
+/// [`CpuShader`][crate::CpuShader]), used when running on the CPU backend.
+/// Without it, creating a pipeline on the CPU backend fails with
+/// [`Error::Unsupported`][crate::Error::Unsupported].
 #[macro_export]
 macro_rules! include_shader {
     ( $device:expr, $path_base:expr) => {
@@ -202,4 +239,12 @@ macro_rules! include_shader {
             include_str!(concat!($path_base, ".msl")),
         )
     };
+
+    ( $device:expr, $path_base:expr, $cpu:expr) => {
+        if $device.backend_type() == $crate::BackendType::Cpu {
+            $crate::ShaderCode::Cpu($cpu)
+        } else {
+            $crate::include_shader!($device, $path_base)
+        }
+    };
 }
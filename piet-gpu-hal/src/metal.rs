@@ -14,6 +14,7 @@
 //
 // Also licensed under MIT license, at your choice.
 
+mod archive;
 mod clear;
 mod timer;
 mod util;
@@ -34,11 +35,15 @@ use metal::{CGFloat, CommandBufferRef, MTLFeatureSet};
 use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
 
 use crate::{
-    BufferUsage, ComputePassDescriptor, Error, GpuInfo, ImageFormat, MapMode, WorkgroupLimits,
+    AdapterInfo, AddressMode, BackendType, BufferImageLayout, BufferUsage, ComputePassDescriptor,
+    DeviceType, Error, FilterMode, FormatCapabilities, GpuInfo, ImageFormat, ImageRegion, MapMode,
+    MemoryBudget, MemoryHeap, PipelineOptions, QueueType, SamplerParams, SpecValue,
+    SubgroupFeatures, WorkgroupLimits,
 };
 
 use util::*;
 
+use self::archive::BinaryArchive;
 use self::timer::{CounterSampleBuffer, CounterSet, TimeCalibration};
 
 pub struct MtlInstance;
@@ -72,6 +77,7 @@ pub struct MtlSwapchain {
     drawable: Mutex<Option<metal::MetalDrawable>>,
     n_drawables: usize,
     drawable_ix: usize,
+    format: ImageFormat,
 }
 
 #[derive(Clone)]
@@ -83,8 +89,9 @@ pub struct Buffer {
 #[derive(Clone)]
 pub struct Image {
     texture: metal::Texture,
-    width: u32,
-    height: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
 }
 
 // This is the way gfx-hal does it, but a more Vulkan-like strategy would be
@@ -119,13 +126,31 @@ pub struct QueryPool {
 
 pub struct Pipeline(metal::ComputePipelineState);
 
+impl Pipeline {
+    /// The SIMD-group width of the pipeline.
+    pub fn thread_execution_width(&self) -> u32 {
+        self.0.thread_execution_width() as u32
+    }
+}
+
+pub struct PipelineCache {
+    /// The binary archive, or `None` if the OS doesn't support them.
+    archive: Option<Mutex<BinaryArchive>>,
+}
+
 #[derive(Default)]
 pub struct DescriptorSetBuilder(DescriptorSet);
 
 #[derive(Default)]
 pub struct DescriptorSet {
-    buffers: Vec<Buffer>,
+    /// Buffers with their offset.
+    buffers: Vec<(Buffer, u64)>,
     images: Vec<Image>,
+    /// Samplers for sampled images, with their binding index.
+    ///
+    /// With `--msl-decoration-binding`, the sampler of a combined image/sampler
+    /// has the same index as its texture.
+    samplers: Vec<(u64, metal::SamplerState)>,
 }
 
 struct Helpers {
@@ -179,8 +204,6 @@ impl MtlInstance {
         Some(MtlSurface { layer: metal_layer })
     }
 
-    // TODO might do some enumeration of devices
-
     pub fn device(&self) -> Result<MtlDevice, Error> {
         if let Some(device) = metal::Device::system_default() {
             let cmd_queue = device.new_command_queue();
@@ -190,14 +213,65 @@ impl MtlInstance {
         }
     }
 
+    pub fn adapters(&self) -> Result<Vec<AdapterInfo>, Error> {
+        let adapters = metal::Device::all()
+            .iter()
+            .enumerate()
+            .map(|(index, device)| {
+                // Metal doesn't report PCI ids, and Apple silicon GPUs are integrated
+                // even though they don't claim to be low power.
+                let device_type = if device.is_low_power() || device.has_unified_memory() {
+                    DeviceType::Integrated
+                } else {
+                    DeviceType::Discrete
+                };
+                AdapterInfo {
+                    name: device.name().into(),
+                    vendor_id: 0,
+                    device_id: 0,
+                    device_type,
+                    backend: BackendType::Metal,
+                    index,
+                }
+            })
+            .collect();
+        Ok(adapters)
+    }
+
+    pub fn device_from_adapter(&self, adapter: &AdapterInfo) -> Result<MtlDevice, Error> {
+        let device = metal::Device::all()
+            .into_iter()
+            .nth(adapter.index)
+            .ok_or("adapter not found")?;
+        let cmd_queue = device.new_command_queue();
+        Ok(MtlDevice::new_from_raw_mtl(device, cmd_queue))
+    }
+
     pub unsafe fn swapchain(
         &self,
         _width: usize,
         _height: usize,
         device: &MtlDevice,
         surface: &MtlSurface,
+        formats: &[ImageFormat],
     ) -> Result<MtlSwapchain, Error> {
         surface.layer.set_device(&device.device);
+        // These are the formats supported by CAMetalLayer that we can
+        // express; Rgba8 is stored as BGRA (see `mtl_format`).
+        let format = formats
+            .iter()
+            .copied()
+            .find(|format| {
+                matches!(
+                    format,
+                    ImageFormat::Bgra8
+                        | ImageFormat::Rgba8
+                        | ImageFormat::Bgra8Srgb
+                        | ImageFormat::Rgba16Float
+                )
+            })
+            .unwrap_or(ImageFormat::Bgra8);
+        surface.layer.set_pixel_format(mtl_format(format));
         let n_drawables = surface.layer.maximum_drawable_count() as usize;
         Ok(MtlSwapchain {
             layer: surface.layer.to_owned(),
@@ -205,6 +279,7 @@ impl MtlInstance {
             drawable: Default::default(),
             n_drawables,
             drawable_ix: 0,
+            format,
         })
     }
 }
@@ -221,11 +296,48 @@ impl MtlDevice {
             } else {
                 !device.is_low_power()
             };
+        // Metal doesn't expose heaps; the working set the device can use
+        // without degraded performance is the closest match.
+        let working_set_size: u64 = unsafe {
+            let device: &metal::DeviceRef = &device;
+            msg_send![device, recommendedMaxWorkingSetSize]
+        };
+        // Timer stuff
+        let timer_set = CounterSet::get_timer_counter_set(&device);
+        let counter_style = if timer_set.is_some() {
+            if device.supports_counter_sampling(metal::MTLCounterSamplingPoint::AtStageBoundary) {
+                CounterStyle::Stage
+            } else if device
+                .supports_counter_sampling(metal::MTLCounterSamplingPoint::AtDispatchBoundary)
+            {
+                CounterStyle::Command
+            } else {
+                CounterStyle::None
+            }
+        } else {
+            CounterStyle::None
+        };
+        // SIMD-group functions, including reductions, are available from
+        // MSL 2.1 on macOS. The width depends on the pipeline, so there's no
+        // device-wide range.
+        let has_subgroups = is_mac && version.at_least(10, 14);
+        let subgroup_features = if has_subgroups {
+            SubgroupFeatures::BASIC
+                | SubgroupFeatures::VOTE
+                | SubgroupFeatures::ARITHMETIC
+                | SubgroupFeatures::BALLOT
+                | SubgroupFeatures::SHUFFLE
+                | SubgroupFeatures::SHUFFLE_RELATIVE
+                | SubgroupFeatures::QUAD
+        } else {
+            SubgroupFeatures::empty()
+        };
         // TODO: these are conservative; we need to derive these from
         // supports_feature_set queries.
         let gpu_info = GpuInfo {
             has_descriptor_indexing: false,
-            has_subgroups: false,
+            has_subgroups,
+            subgroup_features,
             subgroup_size: None,
             // The workgroup limits are taken from the minimum of a desktop installation;
             // we don't support iOS right now, but in case of testing on those devices it might
@@ -236,25 +348,19 @@ impl MtlDevice {
             },
             has_memory_model: false,
             use_staging_buffers,
+            adapter_name: device.name().into(),
+            // Buffers in the constant address space need 256 byte offsets on macOS.
+            buffer_offset_alignment: 256,
+            // Stage boundary counters can only be sampled by compute passes.
+            has_timestamp_writes: counter_style != CounterStyle::Stage,
+            memory_heaps: vec![MemoryHeap {
+                size: working_set_size,
+                device_local: true,
+            }],
         };
         let helpers = Arc::new(Helpers {
             clear_pipeline: clear::make_clear_pipeline(&device),
         });
-        // Timer stuff
-        let timer_set = CounterSet::get_timer_counter_set(&device);
-        let counter_style = if timer_set.is_some() {
-            if device.supports_counter_sampling(metal::MTLCounterSamplingPoint::AtStageBoundary) {
-                CounterStyle::Stage
-            } else if device
-                .supports_counter_sampling(metal::MTLCounterSamplingPoint::AtDispatchBoundary)
-            {
-                CounterStyle::Command
-            } else {
-                CounterStyle::None
-            }
-        } else {
-            CounterStyle::None
-        };
         MtlDevice {
             device,
             cmd_queue: Arc::new(Mutex::new(cmd_queue)),
@@ -280,10 +386,13 @@ impl MtlDevice {
     }
 
     pub fn image_from_raw_mtl(&self, texture: metal::Texture, width: u32, height: u32) -> Image {
+        // Textures in formats we don't know about can still be blitted.
+        let format = image_format(texture.pixel_format()).unwrap_or(ImageFormat::Bgra8);
         Image {
             texture,
             width,
             height,
+            format,
         }
     }
 }
@@ -307,14 +416,25 @@ impl crate::backend::Device for MtlDevice {
 
     type DescriptorSetBuilder = DescriptorSetBuilder;
 
-    type Sampler = ();
+    type Sampler = metal::SamplerState;
 
     type ShaderSource = str;
 
+    type PipelineCache = PipelineCache;
+
     fn query_gpu_info(&self) -> crate::GpuInfo {
         self.gpu_info.clone()
     }
 
+    fn query_memory_budget(&self) -> Option<Vec<MemoryBudget>> {
+        let device: &metal::DeviceRef = &self.device;
+        let usage: NSUInteger = unsafe { msg_send![device, currentAllocatedSize] };
+        Some(vec![MemoryBudget {
+            budget: self.gpu_info.memory_heaps[0].size,
+            usage: usage as u64,
+        }])
+    }
+
     fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Self::Buffer, Error> {
         let options = if usage.contains(BufferUsage::MAP_READ) {
             metal::MTLResourceOptions::StorageModeShared
@@ -325,6 +445,10 @@ impl crate::backend::Device for MtlDevice {
         } else {
             metal::MTLResourceOptions::StorageModePrivate
         };
+        // Metal returns nil rather than an error for buffers that are too big.
+        if size > self.device.max_buffer_length() {
+            return Err(Error::OutOfDeviceMemory);
+        }
         let buffer = self.device.new_buffer(size, options);
         Ok(Buffer { buffer, size })
     }
@@ -335,6 +459,17 @@ impl crate::backend::Device for MtlDevice {
         Ok(())
     }
 
+    fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities {
+        // All our formats can be sampled and copied. Shader writes to sRGB
+        // textures are only supported on some iOS GPUs, so don't count on them.
+        let mut capabilities =
+            FormatCapabilities::SAMPLED | FormatCapabilities::COPY | FormatCapabilities::BLIT;
+        if !format.is_srgb() {
+            capabilities |= FormatCapabilities::STORAGE;
+        }
+        capabilities
+    }
+
     unsafe fn create_image2d(
         &self,
         width: u32,
@@ -347,17 +482,21 @@ impl crate::backend::Device for MtlDevice {
         // These are defaults so don't need to be explicitly set.
         //desc.set_depth(1);
         //desc.set_mipmap_level_count(1);
-        let mtl_format = match format {
-            ImageFormat::A8 => metal::MTLPixelFormat::R8Unorm,
-            ImageFormat::Rgba8 => metal::MTLPixelFormat::BGRA8Unorm,
-        };
-        desc.set_pixel_format(mtl_format);
-        desc.set_usage(metal::MTLTextureUsage::ShaderRead | metal::MTLTextureUsage::ShaderWrite);
+        desc.set_pixel_format(mtl_format(format));
+        let mut usage = metal::MTLTextureUsage::ShaderRead;
+        if self
+            .format_capabilities(format)
+            .contains(FormatCapabilities::STORAGE)
+        {
+            usage |= metal::MTLTextureUsage::ShaderWrite;
+        }
+        desc.set_usage(usage);
         let texture = self.device.new_texture(&desc);
         Ok(Image {
             texture,
             width,
             height,
+            format,
         })
     }
 
@@ -370,16 +509,76 @@ impl crate::backend::Device for MtlDevice {
         &self,
         code: &Self::ShaderSource,
         _bind_types: &[crate::BindType],
+        options: &PipelineOptions,
+        cache: Option<&PipelineCache>,
     ) -> Result<Self::Pipeline, Error> {
-        let options = metal::CompileOptions::new();
-        let library = self.device.new_library_with_source(code, &options)?;
-        let function = library.get_function("main0", None)?;
-        let pipeline = self
+        let compile_options = metal::CompileOptions::new();
+        let library = self
             .device
-            .new_compute_pipeline_state_with_function(&function)?;
+            .new_library_with_source(code, &compile_options)
+            .map_err(|log| Error::ShaderCompile { log })?;
+        let constants = function_constants(options);
+        // Shaders translated by spirv-cross have the entry point renamed to
+        // `main0`; other translators differ, so fall back to the only function.
+        let function = match library.get_function("main0", constants.clone()) {
+            Ok(function) => function,
+            Err(e) => match library.function_names().as_slice() {
+                [name] => library.get_function(name, constants)?,
+                _ => return Err(e.into()),
+            },
+        };
+        let archive = cache.and_then(|cache| cache.archive.as_ref());
+        let pipeline = if let Some(archive) = archive {
+            let descriptor = metal::ComputePipelineDescriptor::new();
+            descriptor.set_compute_function(Some(&function));
+            archive.lock().unwrap().add_compute_pipeline(&descriptor)?;
+            self.device.new_compute_pipeline_state(&descriptor)?
+        } else {
+            self.device
+                .new_compute_pipeline_state_with_function(&function)?
+        };
         Ok(Pipeline(pipeline))
     }
 
+    fn pipeline_cache_key(&self) -> Vec<u8> {
+        // Drivers are updated along with the OS.
+        let version = NSOperatingSystemVersion::get();
+        let mut key = self.device.name().as_bytes().to_vec();
+        for v in [version.major, version.minor, version.patch] {
+            key.extend_from_slice(&(v as u64).to_le_bytes());
+        }
+        key
+    }
+
+    unsafe fn create_pipeline_cache(&self, data: &[u8]) -> Result<(PipelineCache, bool), Error> {
+        if !BinaryArchive::is_supported(&self.device) {
+            return Ok((PipelineCache { archive: None }, false));
+        }
+        let (archive, restored) = match BinaryArchive::new(&self.device, data) {
+            Ok(archive) => (archive, !data.is_empty()),
+            Err(e) if !data.is_empty() => {
+                log::info!("ignoring saved binary archive: {}", e);
+                (BinaryArchive::new(&self.device, &[])?, false)
+            }
+            Err(e) => return Err(e),
+        };
+        let cache = PipelineCache {
+            archive: Some(Mutex::new(archive)),
+        };
+        Ok((cache, restored))
+    }
+
+    unsafe fn pipeline_cache_data(&self, cache: &PipelineCache) -> Result<Vec<u8>, Error> {
+        match &cache.archive {
+            Some(archive) => archive.lock().unwrap().serialize(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    unsafe fn destroy_pipeline_cache(&self, _cache: &PipelineCache) -> Result<(), Error> {
+        Ok(())
+    }
+
     unsafe fn descriptor_set_builder(&self) -> Self::DescriptorSetBuilder {
         DescriptorSetBuilder::default()
     }
@@ -390,7 +589,7 @@ impl crate::backend::Device for MtlDevice {
         index: u32,
         buf: &Self::Buffer,
     ) {
-        ds.buffers[index as usize] = buf.clone();
+        ds.buffers[index as usize] = (buf.clone(), 0);
     }
 
     unsafe fn update_image_descriptor(
@@ -402,7 +601,7 @@ impl crate::backend::Device for MtlDevice {
         ds.images[index as usize - ds.buffers.len()] = image.clone();
     }
 
-    fn create_cmd_buf(&self) -> Result<Self::CmdBuf, Error> {
+    fn create_cmd_buf(&self, _queue: QueueType) -> Result<Self::CmdBuf, Error> {
         let cmd_queue = self.cmd_queue.lock().unwrap();
         // A discussion about autorelease pools.
         //
@@ -474,6 +673,7 @@ impl crate::backend::Device for MtlDevice {
         _wait_semaphores: &[&Self::Semaphore],
         _signal_semaphores: &[&Self::Semaphore],
         fence: Option<&mut Self::Fence>,
+        _queue: QueueType,
     ) -> Result<(), Error> {
         unsafe fn add_scheduled_handler(
             cmd_buf: &metal::CommandBufferRef,
@@ -560,9 +760,9 @@ impl crate::backend::Device for MtlDevice {
                 Fence::Idle => (),
                 Fence::CmdBufPending(cmd_buf) => {
                     cmd_buf.wait_until_completed();
-                    // TODO: this would be a good place to check errors, currently
-                    // dropped on the floor.
+                    let result = cmd_buf_error(cmd_buf);
                     *fence = Fence::Idle;
+                    result?;
                 }
             }
         }
@@ -572,14 +772,33 @@ impl crate::backend::Device for MtlDevice {
     unsafe fn get_fence_status(&self, fence: &mut Self::Fence) -> Result<bool, Error> {
         match fence {
             Fence::Idle => Ok(true),
-            Fence::CmdBufPending(cmd_buf) => {
-                Ok(cmd_buf.status() == metal::MTLCommandBufferStatus::Completed)
-            }
+            Fence::CmdBufPending(cmd_buf) => match cmd_buf.status() {
+                metal::MTLCommandBufferStatus::Completed => Ok(true),
+                metal::MTLCommandBufferStatus::Error => cmd_buf_error(cmd_buf).map(|_| true),
+                _ => Ok(false),
+            },
         }
     }
 
-    unsafe fn create_sampler(&self, params: crate::SamplerParams) -> Result<Self::Sampler, Error> {
-        todo!()
+    unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Self::Sampler, Error> {
+        let filter = match params.filter {
+            FilterMode::Nearest => metal::MTLSamplerMinMagFilter::Nearest,
+            FilterMode::Linear => metal::MTLSamplerMinMagFilter::Linear,
+        };
+        let address_mode = match params.address_mode {
+            AddressMode::ClampToEdge => metal::MTLSamplerAddressMode::ClampToEdge,
+            AddressMode::ClampToBorder => metal::MTLSamplerAddressMode::ClampToBorderColor,
+            AddressMode::Repeat => metal::MTLSamplerAddressMode::Repeat,
+            AddressMode::MirrorRepeat => metal::MTLSamplerAddressMode::MirrorRepeat,
+        };
+        let desc = metal::SamplerDescriptor::new();
+        desc.set_min_filter(filter);
+        desc.set_mag_filter(filter);
+        desc.set_address_mode_s(address_mode);
+        desc.set_address_mode_t(address_mode);
+        desc.set_address_mode_r(address_mode);
+        desc.set_border_color(metal::MTLSamplerBorderColor::TransparentBlack);
+        Ok(self.device.new_sampler(&desc))
     }
 }
 
@@ -660,18 +879,16 @@ impl crate::backend::CmdBuf<MtlDevice> for CmdBuf {
         descriptor_set: &DescriptorSet,
         workgroup_count: (u32, u32, u32),
         workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
     ) {
-        let encoder = self.compute_command_encoder();
-        encoder.set_compute_pipeline_state(&pipeline.0);
-        let mut buf_ix = 0;
-        for buffer in &descriptor_set.buffers {
-            encoder.set_buffer(buf_ix, Some(&buffer.buffer), 0);
-            buf_ix += 1;
-        }
-        let mut img_ix = buf_ix;
-        for image in &descriptor_set.images {
-            encoder.set_texture(img_ix, Some(&image.texture));
-            img_ix += 1;
+        let (encoder, next_ix) = self.bind_compute(pipeline, descriptor_set);
+        if !push_constants.is_empty() {
+            // The push constant block takes the index following the last binding.
+            encoder.set_bytes(
+                next_ix,
+                push_constants.len() as u64,
+                push_constants.as_ptr() as *const _,
+            );
         }
         let workgroup_count = metal::MTLSize {
             width: workgroup_count.0 as u64,
@@ -686,6 +903,31 @@ impl crate::backend::CmdBuf<MtlDevice> for CmdBuf {
         encoder.dispatch_thread_groups(workgroup_count, workgroup_size);
     }
 
+    unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        let (encoder, next_ix) = self.bind_compute(pipeline, descriptor_set);
+        if !push_constants.is_empty() {
+            encoder.set_bytes(
+                next_ix,
+                push_constants.len() as u64,
+                push_constants.as_ptr() as *const _,
+            );
+        }
+        let workgroup_size = metal::MTLSize {
+            width: workgroup_size.0 as u64,
+            height: workgroup_size.1 as u64,
+            depth: workgroup_size.2 as u64,
+        };
+        encoder.dispatch_thread_groups_indirect(&buffer.buffer, offset, workgroup_size);
+    }
+
     unsafe fn end_compute_pass(&mut self) {
         // TODO: might validate that we are in a compute encoder state
         self.flush_encoder();
@@ -724,8 +966,12 @@ impl crate::backend::CmdBuf<MtlDevice> for CmdBuf {
 
     unsafe fn copy_image_to_buffer(&mut self, src: &Image, dst: &Buffer) {
         let encoder = self.blit_command_encoder();
-        assert_eq!(dst.size, (src.width as u64) * (src.height as u64) * 4);
-        let bytes_per_row = (src.width * 4) as NSUInteger;
+        let bpp = src.format.bytes_per_pixel();
+        assert_eq!(
+            dst.size,
+            (src.width as u64) * (src.height as u64) * bpp as u64
+        );
+        let bytes_per_row = (src.width * bpp) as NSUInteger;
         let src_size = metal::MTLSize {
             width: src.width as NSUInteger,
             height: src.height as NSUInteger,
@@ -748,8 +994,12 @@ impl crate::backend::CmdBuf<MtlDevice> for CmdBuf {
 
     unsafe fn copy_buffer_to_image(&mut self, src: &Buffer, dst: &Image) {
         let encoder = self.blit_command_encoder();
-        assert_eq!(src.size, (dst.width as u64) * (dst.height as u64) * 4);
-        let bytes_per_row = (dst.width * 4) as NSUInteger;
+        let bpp = dst.format.bytes_per_pixel();
+        assert_eq!(
+            src.size,
+            (dst.width as u64) * (dst.height as u64) * bpp as u64
+        );
+        let bytes_per_row = (dst.width * bpp) as NSUInteger;
         let src_size = metal::MTLSize {
             width: dst.width as NSUInteger,
             height: dst.height as NSUInteger,
@@ -791,33 +1041,108 @@ impl crate::backend::CmdBuf<MtlDevice> for CmdBuf {
         );
     }
 
+    unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        let encoder = self.blit_command_encoder();
+        encoder.copy_from_buffer(&src.buffer, src_offset, &dst.buffer, dst_offset, size);
+    }
+
+    unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        let encoder = self.blit_command_encoder();
+        let bytes_per_row = layout.row_pitch as NSUInteger;
+        encoder.copy_from_texture_to_buffer(
+            &src.texture,
+            0,
+            0,
+            mtl_origin(region.origin),
+            mtl_size(region.size),
+            &dst.buffer,
+            layout.offset,
+            bytes_per_row,
+            bytes_per_row * region.size.1 as NSUInteger,
+            metal::MTLBlitOption::empty(),
+        );
+    }
+
+    unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        let encoder = self.blit_command_encoder();
+        let bytes_per_row = layout.row_pitch as NSUInteger;
+        encoder.copy_from_buffer_to_texture(
+            &src.buffer,
+            layout.offset,
+            bytes_per_row,
+            bytes_per_row * region.size.1 as NSUInteger,
+            mtl_size(region.size),
+            &dst.texture,
+            0,
+            0,
+            mtl_origin(region.origin),
+            metal::MTLBlitOption::empty(),
+        );
+    }
+
+    unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        let encoder = self.blit_command_encoder();
+        encoder.copy_from_texture(
+            &src.texture,
+            0,
+            0,
+            mtl_origin(region.origin),
+            mtl_size(region.size),
+            &dst.texture,
+            0,
+            0,
+            mtl_origin(dst_origin),
+        );
+    }
+
     unsafe fn reset_query_pool(&mut self, pool: &QueryPool) {
         let mut calibration = pool.calibration.lock().unwrap();
         *calibration = Some(self.time_calibration.clone());
     }
 
     unsafe fn write_timestamp(&mut self, pool: &QueryPool, query: u32) {
+        // Stage boundary counters are only sampled by compute passes, which
+        // is reported as `GpuInfo::has_timestamp_writes`.
+        if self.counter_style != CounterStyle::Command {
+            return;
+        }
         if let Some(buf) = &pool.counter_sample_buf {
             if matches!(self.cur_encoder, Encoder::None) {
                 self.cur_encoder =
                     Encoder::Compute(self.cmd_buf.new_compute_command_encoder().to_owned(), None);
             }
             let sample_index = query as NSUInteger;
-            if self.counter_style == CounterStyle::Command {
-                match &self.cur_encoder {
-                    Encoder::Compute(e, _) => {
-                        let () = msg_send![e.as_ptr(), sampleCountersInBuffer: buf.id() atSampleIndex: sample_index withBarrier: true];
-                    }
-                    Encoder::None => unreachable!(),
-                    _ => todo!(),
-                }
-            } else if self.counter_style == CounterStyle::Stage {
-                match &self.cur_encoder {
-                    Encoder::Compute(_e, _) => {
-                        println!("write_timestamp is not supported for stage-style encoders");
-                    }
-                    _ => (),
+            match &self.cur_encoder {
+                Encoder::Compute(e, _) => {
+                    let () = msg_send![e.as_ptr(), sampleCountersInBuffer: buf.id() atSampleIndex: sample_index withBarrier: true];
                 }
+                Encoder::None => unreachable!(),
+                _ => todo!(),
             }
         }
     }
@@ -837,6 +1162,32 @@ impl CmdBuf {
         }
     }
 
+    /// Bind the pipeline and descriptor set to the compute encoder.
+    ///
+    /// Also returns the buffer index following the last binding.
+    fn bind_compute(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+    ) -> (&metal::ComputeCommandEncoder, NSUInteger) {
+        let encoder = self.compute_command_encoder();
+        encoder.set_compute_pipeline_state(&pipeline.0);
+        let mut buf_ix = 0;
+        for (buffer, offset) in &descriptor_set.buffers {
+            encoder.set_buffer(buf_ix, Some(&buffer.buffer), *offset);
+            buf_ix += 1;
+        }
+        let mut img_ix = buf_ix;
+        for image in &descriptor_set.images {
+            encoder.set_texture(img_ix, Some(&image.texture));
+            img_ix += 1;
+        }
+        for (sampler_ix, sampler) in &descriptor_set.samplers {
+            encoder.set_sampler_state(*sampler_ix, Some(sampler));
+        }
+        (encoder, img_ix)
+    }
+
     fn blit_command_encoder(&mut self) -> &metal::BlitCommandEncoder {
         if !matches!(self.cur_encoder, Encoder::Blit(_)) {
             self.flush_encoder();
@@ -867,7 +1218,18 @@ impl CmdBuf {
 
 impl crate::backend::DescriptorSetBuilder<MtlDevice> for DescriptorSetBuilder {
     fn add_buffers(&mut self, buffers: &[&Buffer]) {
-        self.0.buffers.extend(buffers.iter().copied().cloned());
+        self.0
+            .buffers
+            .extend(buffers.iter().map(|b| ((*b).clone(), 0)));
+    }
+
+    fn add_buffer_slices(&mut self, slices: &[(&Buffer, u64, u64)]) {
+        // Metal doesn't bound the size of a buffer binding.
+        self.0.buffers.extend(
+            slices
+                .iter()
+                .map(|(b, offset, _size)| ((*b).clone(), *offset)),
+        );
     }
 
     fn add_images(&mut self, images: &[&Image]) {
@@ -878,6 +1240,14 @@ impl crate::backend::DescriptorSetBuilder<MtlDevice> for DescriptorSetBuilder {
         self.add_images(images);
     }
 
+    fn add_sampled_images(&mut self, images: &[&Image], sampler: &metal::SamplerState) {
+        for image in images {
+            let ix = (self.0.buffers.len() + self.0.images.len()) as u64;
+            self.0.samplers.push((ix, sampler.clone()));
+            self.0.images.push((*image).clone());
+        }
+    }
+
     unsafe fn build(
         self,
         _device: &MtlDevice,
@@ -905,9 +1275,14 @@ impl MtlSwapchain {
             texture,
             width: size.width.round() as u32,
             height: size.height.round() as u32,
+            format: self.format,
         }
     }
 
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
     pub unsafe fn present(
         &self,
         _image_idx: usize,
@@ -928,6 +1303,93 @@ impl MtlSwapchain {
     }
 }
 
+/// Map the error of a completed command buffer, if any.
+unsafe fn cmd_buf_error(cmd_buf: &metal::CommandBuffer) -> Result<(), Error> {
+    if cmd_buf.status() != metal::MTLCommandBufferStatus::Error {
+        return Ok(());
+    }
+    let error: id = msg_send![cmd_buf.as_ptr(), error];
+    let code: NSInteger = msg_send![error, code];
+    // Values of MTLCommandBufferError.
+    match code {
+        // Timeout, PageFault, DeviceRemoved
+        2 | 3 | 11 => Err(Error::DeviceLost),
+        // OutOfMemory
+        8 => Err(Error::OutOfDeviceMemory),
+        _ => Err(format!("command buffer failed with error code {}", code).into()),
+    }
+}
+
+fn mtl_format(format: ImageFormat) -> metal::MTLPixelFormat {
+    match format {
+        ImageFormat::A8 => metal::MTLPixelFormat::R8Unorm,
+        // Drawables have no RGBA format, so Rgba8 images are stored as BGRA
+        // so they can be copied to the swapchain.
+        ImageFormat::Rgba8 | ImageFormat::Bgra8 => metal::MTLPixelFormat::BGRA8Unorm,
+        ImageFormat::Rgba8Srgb => metal::MTLPixelFormat::RGBA8Unorm_sRGB,
+        ImageFormat::Bgra8Srgb => metal::MTLPixelFormat::BGRA8Unorm_sRGB,
+        ImageFormat::Rgba16Float => metal::MTLPixelFormat::RGBA16Float,
+        ImageFormat::R32Float => metal::MTLPixelFormat::R32Float,
+        ImageFormat::Rgba32Float => metal::MTLPixelFormat::RGBA32Float,
+    }
+}
+
+fn image_format(format: metal::MTLPixelFormat) -> Option<ImageFormat> {
+    match format {
+        metal::MTLPixelFormat::R8Unorm => Some(ImageFormat::A8),
+        metal::MTLPixelFormat::BGRA8Unorm => Some(ImageFormat::Bgra8),
+        metal::MTLPixelFormat::RGBA8Unorm_sRGB => Some(ImageFormat::Rgba8Srgb),
+        metal::MTLPixelFormat::BGRA8Unorm_sRGB => Some(ImageFormat::Bgra8Srgb),
+        metal::MTLPixelFormat::RGBA16Float => Some(ImageFormat::Rgba16Float),
+        metal::MTLPixelFormat::R32Float => Some(ImageFormat::R32Float),
+        metal::MTLPixelFormat::RGBA32Float => Some(ImageFormat::Rgba32Float),
+        _ => None,
+    }
+}
+
+/// Function constant values for specializing a shader, if any.
+fn function_constants(options: &PipelineOptions) -> Option<metal::FunctionConstantValues> {
+    if options.constants.is_empty() {
+        return None;
+    }
+    let values = metal::FunctionConstantValues::new();
+    for constant in options.constants {
+        // Bools are a single byte in MSL; other types are 32 bits.
+        let bool_value;
+        let bits = constant.value.to_bits();
+        let (ptr, ty) = match constant.value {
+            SpecValue::Bool(b) => {
+                bool_value = b;
+                (
+                    &bool_value as *const bool as *const _,
+                    metal::MTLDataType::Bool,
+                )
+            }
+            SpecValue::U32(_) => (&bits as *const u32 as *const _, metal::MTLDataType::UInt),
+            SpecValue::I32(_) => (&bits as *const u32 as *const _, metal::MTLDataType::Int),
+            SpecValue::F32(_) => (&bits as *const u32 as *const _, metal::MTLDataType::Float),
+        };
+        values.set_constant_value_at_index(ptr, ty, constant.id as NSUInteger);
+    }
+    Some(values)
+}
+
+fn mtl_origin(origin: (u32, u32)) -> metal::MTLOrigin {
+    metal::MTLOrigin {
+        x: origin.0 as NSUInteger,
+        y: origin.1 as NSUInteger,
+        z: 0,
+    }
+}
+
+fn mtl_size(size: (u32, u32)) -> metal::MTLSize {
+    metal::MTLSize {
+        width: size.0 as NSUInteger,
+        height: size.1 as NSUInteger,
+        depth: 1,
+    }
+}
+
 #[repr(C)]
 struct NSOperatingSystemVersion {
     major: NSInteger,
@@ -0,0 +1,150 @@
+// Copyright 2021 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Pipeline caching with binary archives.
+//!
+//! Binary archives are loaded from and serialized to files, so the data
+//! goes through temporary files.
+
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cocoa_foundation::base::id;
+use metal::{ComputePipelineDescriptorRef, DeviceRef};
+use objc::rc::autoreleasepool;
+use objc::runtime::{BOOL, YES};
+use objc::{class, msg_send, sel, sel_impl};
+
+use crate::Error;
+
+pub struct BinaryArchive {
+    id: id,
+    /// The file the archive was loaded from, which is removed on drop.
+    path: Option<PathBuf>,
+}
+
+impl Drop for BinaryArchive {
+    fn drop(&mut self) {
+        unsafe {
+            let () = msg_send![self.id, release];
+        }
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl BinaryArchive {
+    /// Whether the device supports binary archives (macOS 11, iOS 14).
+    pub fn is_supported(device: &DeviceRef) -> bool {
+        unsafe {
+            let supported: BOOL = msg_send![
+                device,
+                respondsToSelector: sel!(newBinaryArchiveWithDescriptor:error:)
+            ];
+            supported == YES
+        }
+    }
+
+    /// Create a binary archive, loading serialized data if not empty.
+    pub fn new(device: &DeviceRef, data: &[u8]) -> Result<BinaryArchive, Error> {
+        let path = if data.is_empty() {
+            None
+        } else {
+            let path = temp_path();
+            std::fs::write(&path, data).map_err(|e| Error::Backend(Box::new(e)))?;
+            Some(path)
+        };
+        autoreleasepool(|| unsafe {
+            let descriptor: id = msg_send![class!(MTLBinaryArchiveDescriptor), new];
+            if let Some(path) = &path {
+                let () = msg_send![descriptor, setUrl: file_url(path)];
+            }
+            let mut error: id = null_mut();
+            let archive: id =
+                msg_send![device, newBinaryArchiveWithDescriptor: descriptor error: &mut error];
+            let () = msg_send![descriptor, release];
+            if archive.is_null() {
+                if let Some(path) = &path {
+                    let _ = std::fs::remove_file(path);
+                }
+                return Err(ns_error(error, "could not create binary archive"));
+            }
+            Ok(BinaryArchive { id: archive, path })
+        })
+    }
+
+    /// Add the pipeline to the archive, and use the archive when creating it.
+    pub fn add_compute_pipeline(
+        &self,
+        descriptor: &ComputePipelineDescriptorRef,
+    ) -> Result<(), Error> {
+        autoreleasepool(|| unsafe {
+            let archives: id = msg_send![class!(NSArray), arrayWithObject: self.id];
+            let () = msg_send![descriptor, setBinaryArchives: archives];
+            let mut error: id = null_mut();
+            let success: BOOL = msg_send![
+                self.id,
+                addComputePipelineFunctionsWithDescriptor: descriptor
+                error: &mut error
+            ];
+            if success != YES {
+                return Err(ns_error(error, "could not add pipeline to binary archive"));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        let path = temp_path();
+        let result = autoreleasepool(|| unsafe {
+            let mut error: id = null_mut();
+            let success: BOOL =
+                msg_send![self.id, serializeToURL: file_url(&path) error: &mut error];
+            if success != YES {
+                return Err(ns_error(error, "could not serialize binary archive"));
+            }
+            std::fs::read(&path).map_err(|e| Error::Backend(Box::new(e)))
+        });
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+}
+
+fn temp_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let name = format!("piet-gpu-{}-{}.metallib", std::process::id(), n);
+    std::env::temp_dir().join(name)
+}
+
+/// Create an autoreleased file URL.
+unsafe fn file_url(path: &Path) -> id {
+    let path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+    let path: id = msg_send![class!(NSString), stringWithUTF8String: path.as_ptr()];
+    msg_send![class!(NSURL), fileURLWithPath: path]
+}
+
+unsafe fn ns_error(error: id, context: &str) -> Error {
+    if error.is_null() {
+        return context.into();
+    }
+    let description: id = msg_send![error, localizedDescription];
+    let description = CStr::from_ptr(msg_send![description, UTF8String]);
+    format!("{}: {}", context, description.to_string_lossy()).into()
+}
@@ -16,6 +16,9 @@
 
 //! A multiplexer module that selects a back-end at runtime.
 
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+
 use smallvec::SmallVec;
 
 mux_cfg! {
@@ -33,12 +36,26 @@ mux_cfg! {
 use crate::backend::CmdBuf as CmdBufTrait;
 use crate::backend::DescriptorSetBuilder as DescriptorSetBuilderTrait;
 use crate::backend::Device as DeviceTrait;
+use crate::cpu;
+use crate::translate;
 use crate::BackendType;
 use crate::BindType;
+use crate::BufferImageLayout;
 use crate::ComputePassDescriptor;
+use crate::CpuShader;
+use crate::FormatCapabilities;
 use crate::ImageFormat;
+use crate::ImageRegion;
 use crate::MapMode;
+use crate::MemoryBudget;
+use crate::MemoryPoolStats;
+use crate::PipelineOptions;
+use crate::QueueType;
+use crate::SamplerParams;
+use crate::{AdapterInfo, DeviceType};
 use crate::{BufferUsage, Error, GpuInfo, ImageLayout, InstanceFlags};
+#[cfg(target_os = "linux")]
+use crate::{ExternalHandleType, ExternalMemory};
 
 mux_enum! {
     /// An instance, selected from multiple backends.
@@ -46,6 +63,7 @@ mux_enum! {
         Vk(vulkan::VkInstance),
         Dx12(dx12::Dx12Instance),
         Mtl(metal::MtlInstance),
+        Cpu(cpu::CpuInstance),
     }
 }
 
@@ -55,6 +73,7 @@ mux_enum! {
         Vk(vulkan::VkDevice),
         Dx12(dx12::Dx12Device),
         Mtl(metal::MtlDevice),
+        Cpu(cpu::CpuDevice),
     }
 }
 
@@ -64,6 +83,7 @@ mux_enum! {
         Vk(vulkan::VkSurface),
         Dx12(dx12::Dx12Surface),
         Mtl(metal::MtlSurface),
+        Cpu(cpu::CpuSurface),
     }
 }
 
@@ -73,6 +93,7 @@ mux_enum! {
         Vk(vulkan::VkSwapchain),
         Dx12(dx12::Dx12Swapchain),
         Mtl(metal::MtlSwapchain),
+        Cpu(cpu::CpuSwapchain),
     }
 }
 
@@ -100,6 +121,9 @@ mux_device_enum! {
 /// An object for recording timer queries.
 QueryPool }
 mux_device_enum! { Sampler }
+mux_device_enum! {
+/// A cache of compiled pipelines.
+PipelineCache }
 
 /// The code for a shader, either as source or intermediate representation.
 pub enum ShaderCode<'a> {
@@ -111,6 +135,12 @@ pub enum ShaderCode<'a> {
     Dxil(&'a [u8]),
     /// Metal Shading Language (source)
     Msl(&'a str),
+    /// WGSL (source), translated to the language of the backend
+    ///
+    /// This requires the `wgsl` feature.
+    Wgsl(&'a str),
+    /// A Rust port of the shader, for the CPU backend
+    Cpu(CpuShader),
 }
 
 impl Instance {
@@ -122,17 +152,24 @@ impl Instance {
     /// When no surface is given, the instance is suitable for compute-only
     /// work.
     pub fn new(flags: InstanceFlags) -> Result<Instance, Error> {
+        if flags.contains(InstanceFlags::CPU) {
+            return cpu::CpuInstance::new().map(Instance::Cpu);
+        }
         let mut backends = [BackendType::Vulkan, BackendType::Dx12];
         if flags.contains(InstanceFlags::DX12) {
             backends.swap(0, 1);
         }
+        // The error from the last backend tried, reported if none succeed.
+        #[allow(unused_mut)]
+        let mut error = Error::unsupported("GPU backend");
         for backend in backends {
             if backend == BackendType::Vulkan {
                 mux_cfg! {
                     #[cfg(vk)]
                     {
-                        if let Ok(instance) = vulkan::VkInstance::new() {
-                            return Ok(Instance::Vk(instance));
+                        match vulkan::VkInstance::new(flags.contains(InstanceFlags::VALIDATION)) {
+                            Ok(instance) => return Ok(Instance::Vk(instance)),
+                            Err(e) => error = e,
                         }
                     }
                 }
@@ -141,8 +178,9 @@ impl Instance {
                 mux_cfg! {
                     #[cfg(dx12)]
                     {
-                        if let Ok(instance) = dx12::Dx12Instance::new() {
-                            return Ok(Instance::Dx12(instance))
+                        match dx12::Dx12Instance::new(flags.contains(InstanceFlags::VALIDATION)) {
+                            Ok(instance) => return Ok(Instance::Dx12(instance)),
+                            Err(e) => error = e,
                         }
                     }
                 }
@@ -151,13 +189,13 @@ impl Instance {
         mux_cfg! {
             #[cfg(mtl)]
             {
-                if let Ok(instance) = metal::MtlInstance::new() {
-                    return Ok(Instance::Mtl(instance));
+                match metal::MtlInstance::new() {
+                    Ok(instance) => return Ok(Instance::Mtl(instance)),
+                    Err(e) => error = e,
                 }
             }
         }
-        // TODO plumb creation errors through.
-        Err("No suitable instances found".into())
+        Err(error)
     }
 
     /// Create a surface from the specified window handle.
@@ -169,6 +207,7 @@ impl Instance {
             Instance::Vk(i) => i.surface(window_handle).map(Surface::Vk),
             Instance::Dx12(i) => i.surface(window_handle).map(Surface::Dx12),
             Instance::Mtl(i) => i.surface(window_handle).map(Surface::Mtl),
+            Instance::Cpu(i) => i.surface(window_handle).map(Surface::Cpu),
         }
     }
 
@@ -182,6 +221,54 @@ impl Instance {
             Instance::Vk(i) => i.device().map(Device::Vk),
             Instance::Dx12(i) => i.device().map(Device::Dx12),
             Instance::Mtl(i) => i.device().map(Device::Mtl),
+            Instance::Cpu(i) => i.device().map(Device::Cpu),
+        }
+    }
+
+    /// List the adapters that a device can be created from.
+    ///
+    /// Only adapters of the selected back-end are listed.
+    pub fn adapters(&self) -> Result<Vec<AdapterInfo>, Error> {
+        mux_match! { self;
+            Instance::Vk(i) => i.adapters(),
+            Instance::Dx12(i) => i.adapters(),
+            Instance::Mtl(i) => i.adapters(),
+            Instance::Cpu(i) => i.adapters(),
+        }
+    }
+
+    /// Create a device from a specific adapter, as returned by [`Instance::adapters`].
+    pub unsafe fn device_from_adapter(&self, adapter: &AdapterInfo) -> Result<Device, Error> {
+        if adapter.backend != self.backend_type() {
+            return Err("adapter belongs to a different backend".into());
+        }
+        mux_match! { self;
+            Instance::Vk(i) => i.device_from_adapter(adapter).map(Device::Vk),
+            Instance::Dx12(i) => i.device_from_adapter(adapter).map(Device::Dx12),
+            Instance::Mtl(i) => i.device_from_adapter(adapter).map(Device::Mtl),
+            Instance::Cpu(i) => i.device_from_adapter(adapter).map(Device::Cpu),
+        }
+    }
+
+    /// Create a device, preferring an adapter of the given type.
+    ///
+    /// If no adapter of that type is available, this falls back to the
+    /// same choice as [`Instance::device`].
+    pub unsafe fn device_with_preference(&self, device_type: DeviceType) -> Result<Device, Error> {
+        let adapters = self.adapters()?;
+        match adapters.iter().find(|a| a.device_type == device_type) {
+            Some(adapter) => self.device_from_adapter(adapter),
+            None => self.device(),
+        }
+    }
+
+    /// The backend of this instance.
+    pub fn backend_type(&self) -> BackendType {
+        mux_match! { self;
+            Instance::Vk(_i) => BackendType::Vulkan,
+            Instance::Dx12(_i) => BackendType::Dx12,
+            Instance::Mtl(_i) => BackendType::Metal,
+            Instance::Cpu(_i) => BackendType::Cpu,
         }
     }
 
@@ -197,17 +284,35 @@ impl Instance {
         height: usize,
         device: &Device,
         surface: &Surface,
+    ) -> Result<Swapchain, Error> {
+        self.swapchain_with_formats(width, height, device, surface, &[])
+    }
+
+    /// Create a swapchain, preferring the given image formats in order.
+    ///
+    /// If the surface supports none of the formats, the platform default is
+    /// used; check [`Swapchain::format`] for the format chosen.
+    pub unsafe fn swapchain_with_formats(
+        &self,
+        width: usize,
+        height: usize,
+        device: &Device,
+        surface: &Surface,
+        formats: &[ImageFormat],
     ) -> Result<Swapchain, Error> {
         mux_match! { self;
             Instance::Vk(i) => i
-                .swapchain(width, height, device.vk(), surface.vk())
+                .swapchain(width, height, device.vk(), surface.vk(), formats)
                 .map(Swapchain::Vk),
             Instance::Dx12(i) => i
-                .swapchain(width, height, device.dx12(), surface.dx12())
+                .swapchain(width, height, device.dx12(), surface.dx12(), formats)
                 .map(Swapchain::Dx12),
             Instance::Mtl(i) => i
-                .swapchain(width, height, device.mtl(), surface.mtl())
+                .swapchain(width, height, device.mtl(), surface.mtl(), formats)
                 .map(Swapchain::Mtl),
+            Instance::Cpu(i) => i
+                .swapchain(width, height, device.cpu(), surface.cpu(), formats)
+                .map(Swapchain::Cpu),
         }
     }
 }
@@ -229,9 +334,7 @@ impl Device {
 
     #[cfg(target_os = "macos")]
     pub fn cmd_buf_from_raw_mtl(&self, raw_cmd_buf: &::metal::CommandBufferRef) -> CmdBuf {
-        // Note: this will cause problems if we support multiple back-ends on mac. But it will
-        // be a compile error;
-        let Device::Mtl(d) = self;
+        let d = self.mtl();
         CmdBuf::Mtl(d.cmd_buf_from_raw_mtl(raw_cmd_buf.to_owned()))
     }
 
@@ -242,17 +345,160 @@ impl Device {
         width: u32,
         height: u32,
     ) -> Image {
-        // Note: this will cause problems if we support multiple back-ends on mac. But it will
-        // be a compile error;
-        let Device::Mtl(d) = self;
+        let d = self.mtl();
         Image::Mtl(d.image_from_raw_mtl(raw_texture.to_owned(), width, height))
     }
 
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_buffer_exportable(
+        &self,
+        size: u64,
+        usage: BufferUsage,
+        handle_type: ExternalHandleType,
+    ) -> Result<Buffer, Error> {
+        match self {
+            Device::Vk(d) => d
+                .create_buffer_exportable(size, usage, handle_type)
+                .map(Buffer::Vk),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_image2d_exportable(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        handle_type: ExternalHandleType,
+    ) -> Result<Image, Error> {
+        match self {
+            Device::Vk(d) => d
+                .create_image2d_exportable(width, height, format, handle_type)
+                .map(Image::Vk),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_buffer(
+        &self,
+        buffer: &Buffer,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        match self {
+            Device::Vk(d) => d.export_buffer(buffer.vk(), handle_type),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_image(
+        &self,
+        image: &Image,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        match self {
+            Device::Vk(d) => d.export_image(image.vk(), handle_type),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_buffer(
+        &self,
+        memory: ExternalMemory,
+        size: u64,
+        usage: BufferUsage,
+    ) -> Result<Buffer, Error> {
+        match self {
+            Device::Vk(d) => d.import_buffer(memory, size, usage).map(Buffer::Vk),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_image2d(
+        &self,
+        memory: ExternalMemory,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Image, Error> {
+        match self {
+            Device::Vk(d) => d
+                .import_image2d(memory, width, height, format)
+                .map(Image::Vk),
+            _ => Err(Error::unsupported("external memory")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_semaphore_exportable(&self) -> Result<Semaphore, Error> {
+        match self {
+            Device::Vk(d) => d.create_semaphore_exportable().map(Semaphore::Vk),
+            _ => Err(Error::unsupported("external semaphores")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_semaphore(&self, semaphore: &Semaphore) -> Result<RawFd, Error> {
+        match self {
+            Device::Vk(d) => d.export_semaphore(*semaphore.vk()),
+            _ => Err(Error::unsupported("external semaphores")),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_semaphore(&self, fd: RawFd) -> Result<Semaphore, Error> {
+        match self {
+            Device::Vk(d) => d.import_semaphore(fd).map(Semaphore::Vk),
+            _ => Err(Error::unsupported("external semaphores")),
+        }
+    }
+
     pub fn query_gpu_info(&self) -> GpuInfo {
         mux_match! { self;
             Device::Vk(d) => d.query_gpu_info(),
             Device::Dx12(d) => d.query_gpu_info(),
             Device::Mtl(d) => d.query_gpu_info(),
+            Device::Cpu(d) => d.query_gpu_info(),
+        }
+    }
+
+    pub fn enable_memory_pool(&self, block_size: u64) -> Result<(), Error> {
+        mux_match! { self;
+            Device::Vk(d) => d.enable_memory_pool(block_size),
+            Device::Dx12(d) => d.enable_memory_pool(block_size),
+            Device::Mtl(d) => d.enable_memory_pool(block_size),
+            Device::Cpu(d) => d.enable_memory_pool(block_size),
+        }
+    }
+
+    pub fn memory_pool_stats(&self) -> MemoryPoolStats {
+        mux_match! { self;
+            Device::Vk(d) => d.memory_pool_stats(),
+            Device::Dx12(d) => d.memory_pool_stats(),
+            Device::Mtl(d) => d.memory_pool_stats(),
+            Device::Cpu(d) => d.memory_pool_stats(),
+        }
+    }
+
+    pub fn query_memory_budget(&self) -> Option<Vec<MemoryBudget>> {
+        mux_match! { self;
+            Device::Vk(d) => d.query_memory_budget(),
+            Device::Dx12(d) => d.query_memory_budget(),
+            Device::Mtl(d) => d.query_memory_budget(),
+            Device::Cpu(d) => d.query_memory_budget(),
+        }
+    }
+
+    pub unsafe fn trim_memory_pool(&self) {
+        mux_match! { self;
+            Device::Vk(d) => d.trim_memory_pool(),
+            Device::Dx12(d) => d.trim_memory_pool(),
+            Device::Mtl(d) => d.trim_memory_pool(),
+            Device::Cpu(d) => d.trim_memory_pool(),
         }
     }
 
@@ -261,6 +507,7 @@ impl Device {
             Device::Vk(d) => d.create_buffer(size, usage).map(Buffer::Vk),
             Device::Dx12(d) => d.create_buffer(size, usage).map(Buffer::Dx12),
             Device::Mtl(d) => d.create_buffer(size, usage).map(Buffer::Mtl),
+            Device::Cpu(d) => d.create_buffer(size, usage).map(Buffer::Cpu),
         }
     }
 
@@ -269,6 +516,16 @@ impl Device {
             Device::Vk(d) => d.destroy_buffer(buffer.vk()),
             Device::Dx12(d) => d.destroy_buffer(buffer.dx12()),
             Device::Mtl(d) => d.destroy_buffer(buffer.mtl()),
+            Device::Cpu(d) => d.destroy_buffer(buffer.cpu()),
+        }
+    }
+
+    pub fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities {
+        mux_match! { self;
+            Device::Vk(d) => d.format_capabilities(format),
+            Device::Dx12(d) => d.format_capabilities(format),
+            Device::Mtl(d) => d.format_capabilities(format),
+            Device::Cpu(d) => d.format_capabilities(format),
         }
     }
 
@@ -282,6 +539,7 @@ impl Device {
             Device::Vk(d) => d.create_image2d(width, height, format).map(Image::Vk),
             Device::Dx12(d) => d.create_image2d(width, height, format).map(Image::Dx12),
             Device::Mtl(d) => d.create_image2d(width, height, format).map(Image::Mtl),
+            Device::Cpu(d) => d.create_image2d(width, height, format).map(Image::Cpu),
         }
     }
 
@@ -290,6 +548,7 @@ impl Device {
             Device::Vk(d) => d.destroy_image(image.vk()),
             Device::Dx12(d) => d.destroy_image(image.dx12()),
             Device::Mtl(d) => d.destroy_image(image.mtl()),
+            Device::Cpu(d) => d.destroy_image(image.cpu()),
         }
     }
 
@@ -298,6 +557,7 @@ impl Device {
             Device::Vk(d) => d.create_fence(signaled).map(Fence::Vk),
             Device::Dx12(d) => d.create_fence(signaled).map(Fence::Dx12),
             Device::Mtl(d) => d.create_fence(signaled).map(Fence::Mtl),
+            Device::Cpu(d) => d.create_fence(signaled).map(Fence::Cpu),
         }
     }
 
@@ -306,6 +566,7 @@ impl Device {
             Device::Vk(d) => d.destroy_fence(fence.vk_owned()),
             Device::Dx12(d) => d.destroy_fence(fence.dx12_owned()),
             Device::Mtl(d) => d.destroy_fence(fence.mtl_owned()),
+            Device::Cpu(d) => d.destroy_fence(fence.cpu_owned()),
         }
     }
 
@@ -333,6 +594,13 @@ impl Device {
                     .collect::<Vec<_>>();
                 d.wait_and_reset(fences)
             }
+            Device::Cpu(d) => {
+                let fences = fences
+                    .into_iter()
+                    .map(|f| f.cpu_mut())
+                    .collect::<Vec<_>>();
+                d.wait_and_reset(fences)
+            }
         }
     }
 
@@ -341,6 +609,16 @@ impl Device {
             Device::Vk(d) => d.get_fence_status(fence.vk_mut()),
             Device::Dx12(d) => d.get_fence_status(fence.dx12_mut()),
             Device::Mtl(d) => d.get_fence_status(fence.mtl_mut()),
+            Device::Cpu(d) => d.get_fence_status(fence.cpu_mut()),
+        }
+    }
+
+    pub unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Sampler, Error> {
+        mux_match! { self;
+            Device::Vk(d) => d.create_sampler(params).map(Sampler::Vk),
+            Device::Dx12(d) => d.create_sampler(params).map(Sampler::Dx12),
+            Device::Mtl(d) => d.create_sampler(params).map(Sampler::Mtl),
+            Device::Cpu(d) => d.create_sampler(params).map(Sampler::Cpu),
         }
     }
 
@@ -349,6 +627,7 @@ impl Device {
             Device::Vk(d) => d.create_semaphore().map(Semaphore::Vk),
             Device::Dx12(d) => d.create_semaphore().map(Semaphore::Dx12),
             Device::Mtl(d) => d.create_semaphore().map(Semaphore::Mtl),
+            Device::Cpu(d) => d.create_semaphore().map(Semaphore::Cpu),
         }
     }
 
@@ -356,7 +635,21 @@ impl Device {
         &self,
         code: ShaderCode<'a>,
         bind_types: &[BindType],
+        options: &PipelineOptions,
+        cache: Option<&PipelineCache>,
     ) -> Result<Pipeline, Error> {
+        let translated;
+        let code = match code {
+            ShaderCode::Wgsl(wgsl) => {
+                // Translated code doesn't follow the spirv-cross conventions.
+                if !options.constants.is_empty() {
+                    return Err(Error::unsupported("specialization constants in WGSL"));
+                }
+                translated = translate::wgsl(self.backend_type(), wgsl, bind_types)?;
+                translated.shader_code()
+            }
+            code => code,
+        };
         mux_match! { self;
             Device::Vk(d) => {
                 let shader_code = match code {
@@ -364,18 +657,35 @@ impl Device {
                     // Panic or return "incompatible shader" error here?
                     _ => panic!("Vulkan backend requires shader code in SPIR-V format"),
                 };
-                d.create_compute_pipeline(shader_code, bind_types)
-                    .map(Pipeline::Vk)
+                d.create_compute_pipeline(
+                    shader_code,
+                    bind_types,
+                    options,
+                    cache.map(PipelineCache::vk),
+                )
+                .map(Pipeline::Vk)
             }
             Device::Dx12(d) => {
+                let compiled;
                 let shader_code = match code {
-                    //ShaderCode::Hlsl(hlsl) => hlsl,
+                    ShaderCode::Hlsl(hlsl) => {
+                        compiled = d.compile_hlsl(hlsl, options)?;
+                        &compiled
+                    }
+                    ShaderCode::Dxil(_) if !options.constants.is_empty() => {
+                        return Err(Error::unsupported("specialization constants in DXIL"));
+                    }
                     ShaderCode::Dxil(dxil) => dxil,
                     // Panic or return "incompatible shader" error here?
-                    _ => panic!("DX12 backend requires shader code in DXIL format"),
+                    _ => panic!("DX12 backend requires shader code in HLSL or DXIL format"),
                 };
-                d.create_compute_pipeline(shader_code, bind_types)
-                    .map(Pipeline::Dx12)
+                d.create_compute_pipeline(
+                    shader_code,
+                    bind_types,
+                    options,
+                    cache.map(PipelineCache::dx12),
+                )
+                .map(Pipeline::Dx12)
             }
             Device::Mtl(d) => {
                 let shader_code = match code {
@@ -383,17 +693,84 @@ impl Device {
                     // Panic or return "incompatible shader" error here?
                     _ => panic!("Metal backend requires shader code in MSL format"),
                 };
-                d.create_compute_pipeline(shader_code, bind_types)
-                    .map(Pipeline::Mtl)
+                d.create_compute_pipeline(
+                    shader_code,
+                    bind_types,
+                    options,
+                    cache.map(PipelineCache::mtl),
+                )
+                .map(Pipeline::Mtl)
+            }
+            Device::Cpu(d) => {
+                let shader_code = match code {
+                    ShaderCode::Cpu(cpu) => cpu,
+                    // Many shaders don't have CPU ports yet, so make this recoverable.
+                    _ => return Err(Error::unsupported("CPU kernel")),
+                };
+                d.create_compute_pipeline(
+                    &shader_code,
+                    bind_types,
+                    options,
+                    cache.map(PipelineCache::cpu),
+                )
+                .map(Pipeline::Cpu)
             }
         }
     }
 
+    pub fn pipeline_cache_key(&self) -> Vec<u8> {
+        mux_match! { self;
+            Device::Vk(d) => d.pipeline_cache_key(),
+            Device::Dx12(d) => d.pipeline_cache_key(),
+            Device::Mtl(d) => d.pipeline_cache_key(),
+            Device::Cpu(d) => d.pipeline_cache_key(),
+        }
+    }
+
+    pub unsafe fn create_pipeline_cache(
+        &self,
+        data: &[u8],
+    ) -> Result<(PipelineCache, bool), Error> {
+        mux_match! { self;
+            Device::Vk(d) => d
+                .create_pipeline_cache(data)
+                .map(|(cache, restored)| (PipelineCache::Vk(cache), restored)),
+            Device::Dx12(d) => d
+                .create_pipeline_cache(data)
+                .map(|(cache, restored)| (PipelineCache::Dx12(cache), restored)),
+            Device::Mtl(d) => d
+                .create_pipeline_cache(data)
+                .map(|(cache, restored)| (PipelineCache::Mtl(cache), restored)),
+            Device::Cpu(d) => d
+                .create_pipeline_cache(data)
+                .map(|(cache, restored)| (PipelineCache::Cpu(cache), restored)),
+        }
+    }
+
+    pub unsafe fn pipeline_cache_data(&self, cache: &PipelineCache) -> Result<Vec<u8>, Error> {
+        mux_match! { self;
+            Device::Vk(d) => d.pipeline_cache_data(cache.vk()),
+            Device::Dx12(d) => d.pipeline_cache_data(cache.dx12()),
+            Device::Mtl(d) => d.pipeline_cache_data(cache.mtl()),
+            Device::Cpu(d) => d.pipeline_cache_data(cache.cpu()),
+        }
+    }
+
+    pub unsafe fn destroy_pipeline_cache(&self, cache: &PipelineCache) -> Result<(), Error> {
+        mux_match! { self;
+            Device::Vk(d) => d.destroy_pipeline_cache(cache.vk()),
+            Device::Dx12(d) => d.destroy_pipeline_cache(cache.dx12()),
+            Device::Mtl(d) => d.destroy_pipeline_cache(cache.mtl()),
+            Device::Cpu(d) => d.destroy_pipeline_cache(cache.cpu()),
+        }
+    }
+
     pub unsafe fn descriptor_set_builder(&self) -> DescriptorSetBuilder {
         mux_match! { self;
             Device::Vk(d) => DescriptorSetBuilder::Vk(d.descriptor_set_builder()),
             Device::Dx12(d) => DescriptorSetBuilder::Dx12(d.descriptor_set_builder()),
             Device::Mtl(d) => DescriptorSetBuilder::Mtl(d.descriptor_set_builder()),
+            Device::Cpu(d) => DescriptorSetBuilder::Cpu(d.descriptor_set_builder()),
         }
     }
 
@@ -407,6 +784,7 @@ impl Device {
             Device::Vk(d) => d.update_buffer_descriptor(ds.vk_mut(), index, buffer.vk()),
             Device::Dx12(d) => d.update_buffer_descriptor(ds.dx12_mut(), index, buffer.dx12()),
             Device::Mtl(d) => d.update_buffer_descriptor(ds.mtl_mut(), index, buffer.mtl()),
+            Device::Cpu(d) => d.update_buffer_descriptor(ds.cpu_mut(), index, buffer.cpu()),
         }
     }
 
@@ -420,14 +798,25 @@ impl Device {
             Device::Vk(d) => d.update_image_descriptor(ds.vk_mut(), index, image.vk()),
             Device::Dx12(d) => d.update_image_descriptor(ds.dx12_mut(), index, image.dx12()),
             Device::Mtl(d) => d.update_image_descriptor(ds.mtl_mut(), index, image.mtl()),
+            Device::Cpu(d) => d.update_image_descriptor(ds.cpu_mut(), index, image.cpu()),
         }
     }
 
-    pub fn create_cmd_buf(&self) -> Result<CmdBuf, Error> {
+    pub fn create_cmd_buf(&self, queue: QueueType) -> Result<CmdBuf, Error> {
         mux_match! { self;
-            Device::Vk(d) => d.create_cmd_buf().map(CmdBuf::Vk),
-            Device::Dx12(d) => d.create_cmd_buf().map(CmdBuf::Dx12),
-            Device::Mtl(d) => d.create_cmd_buf().map(CmdBuf::Mtl),
+            Device::Vk(d) => d.create_cmd_buf(queue).map(CmdBuf::Vk),
+            Device::Dx12(d) => d.create_cmd_buf(queue).map(CmdBuf::Dx12),
+            Device::Mtl(d) => d.create_cmd_buf(queue).map(CmdBuf::Mtl),
+            Device::Cpu(d) => d.create_cmd_buf(queue).map(CmdBuf::Cpu),
+        }
+    }
+
+    pub fn has_dedicated_queue(&self, queue: QueueType) -> bool {
+        mux_match! { self;
+            Device::Vk(d) => d.has_dedicated_queue(queue),
+            Device::Dx12(d) => d.has_dedicated_queue(queue),
+            Device::Mtl(d) => d.has_dedicated_queue(queue),
+            Device::Cpu(d) => d.has_dedicated_queue(queue),
         }
     }
 
@@ -436,6 +825,7 @@ impl Device {
             Device::Vk(d) => d.destroy_cmd_buf(cmd_buf.vk_owned()),
             Device::Dx12(d) => d.destroy_cmd_buf(cmd_buf.dx12_owned()),
             Device::Mtl(d) => d.destroy_cmd_buf(cmd_buf.mtl_owned()),
+            Device::Cpu(d) => d.destroy_cmd_buf(cmd_buf.cpu_owned()),
         }
     }
 
@@ -444,6 +834,7 @@ impl Device {
             Device::Vk(d) => d.create_query_pool(n_queries).map(QueryPool::Vk),
             Device::Dx12(d) => d.create_query_pool(n_queries).map(QueryPool::Dx12),
             Device::Mtl(d) => d.create_query_pool(n_queries).map(QueryPool::Mtl),
+            Device::Cpu(d) => d.create_query_pool(n_queries).map(QueryPool::Cpu),
         }
     }
 
@@ -452,6 +843,7 @@ impl Device {
             Device::Vk(d) => d.fetch_query_pool(pool.vk()),
             Device::Dx12(d) => d.fetch_query_pool(pool.dx12()),
             Device::Mtl(d) => d.fetch_query_pool(pool.mtl()),
+            Device::Cpu(d) => d.fetch_query_pool(pool.cpu()),
         }
     }
 
@@ -461,6 +853,7 @@ impl Device {
         wait_semaphores: &[&Semaphore],
         signal_semaphores: &[&Semaphore],
         fence: Option<&mut Fence>,
+        queue: QueueType,
     ) -> Result<(), Error> {
         mux_match! { self;
             Device::Vk(d) => d.run_cmd_bufs(
@@ -479,6 +872,7 @@ impl Device {
                     .map(Semaphore::vk)
                     .collect::<SmallVec<[_; 4]>>(),
                 fence.map(Fence::vk_mut),
+                queue,
             ),
             Device::Dx12(d) => d.run_cmd_bufs(
                 &cmd_bufs
@@ -496,6 +890,7 @@ impl Device {
                     .map(Semaphore::dx12)
                     .collect::<SmallVec<[_; 4]>>(),
                 fence.map(Fence::dx12_mut),
+                queue,
             ),
             Device::Mtl(d) => d.run_cmd_bufs(
                 &cmd_bufs
@@ -513,6 +908,25 @@ impl Device {
                     .map(Semaphore::mtl)
                     .collect::<SmallVec<[_; 4]>>(),
                 fence.map(Fence::mtl_mut),
+                queue,
+            ),
+            Device::Cpu(d) => d.run_cmd_bufs(
+                &cmd_bufs
+                    .iter()
+                    .map(|c| c.cpu())
+                    .collect::<SmallVec<[_; 4]>>(),
+                &wait_semaphores
+                    .iter()
+                    .copied()
+                    .map(Semaphore::cpu)
+                    .collect::<SmallVec<[_; 4]>>(),
+                &signal_semaphores
+                    .iter()
+                    .copied()
+                    .map(Semaphore::cpu)
+                    .collect::<SmallVec<[_; 4]>>(),
+                fence.map(Fence::cpu_mut),
+                queue,
             ),
         }
     }
@@ -528,6 +942,7 @@ impl Device {
             Device::Vk(d) => d.map_buffer(buffer.vk(), offset, size, mode),
             Device::Dx12(d) => d.map_buffer(buffer.dx12(), offset, size, mode),
             Device::Mtl(d) => d.map_buffer(buffer.mtl(), offset, size, mode),
+            Device::Cpu(d) => d.map_buffer(buffer.cpu(), offset, size, mode),
         }
     }
 
@@ -542,6 +957,7 @@ impl Device {
             Device::Vk(d) => d.unmap_buffer(buffer.vk(), offset, size, mode),
             Device::Dx12(d) => d.unmap_buffer(buffer.dx12(), offset, size, mode),
             Device::Mtl(d) => d.unmap_buffer(buffer.mtl(), offset, size, mode),
+            Device::Cpu(d) => d.unmap_buffer(buffer.cpu(), offset, size, mode),
         }
     }
 
@@ -557,6 +973,10 @@ impl Device {
             Device::Vk(_d) => ShaderCode::Spv(_spv),
             Device::Dx12(_d) => ShaderCode::Dxil(_dxil),
             Device::Mtl(_d) => ShaderCode::Msl(_msl),
+            // There's no CPU kernel among the choices, so pipeline creation
+            // fails with `Error::Unsupported`. Use the three-argument form of
+            // `include_shader!` to provide one.
+            Device::Cpu(_d) => ShaderCode::Spv(_spv),
         }
     }
 
@@ -565,6 +985,7 @@ impl Device {
             Device::Vk(_d) => BackendType::Vulkan,
             Device::Dx12(_d) => BackendType::Dx12,
             Device::Mtl(_d) => BackendType::Metal,
+            Device::Cpu(_d) => BackendType::Cpu,
         }
     }
 }
@@ -593,6 +1014,43 @@ impl DescriptorSetBuilder {
                     .map(Buffer::mtl)
                     .collect::<SmallVec<[_; 8]>>(),
             ),
+            DescriptorSetBuilder::Cpu(x) => x.add_buffers(
+                &buffers
+                    .iter()
+                    .copied()
+                    .map(Buffer::cpu)
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
+        }
+    }
+
+    /// Add buffer slices, each given as (buffer, offset, size) in bytes.
+    pub fn add_buffer_slices(&mut self, slices: &[(&Buffer, u64, u64)]) {
+        mux_match! { self;
+            DescriptorSetBuilder::Vk(x) => x.add_buffer_slices(
+                &slices
+                    .iter()
+                    .map(|(b, offset, size)| (b.vk(), *offset, *size))
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
+            DescriptorSetBuilder::Dx12(x) => x.add_buffer_slices(
+                &slices
+                    .iter()
+                    .map(|(b, offset, size)| (b.dx12(), *offset, *size))
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
+            DescriptorSetBuilder::Mtl(x) => x.add_buffer_slices(
+                &slices
+                    .iter()
+                    .map(|(b, offset, size)| (b.mtl(), *offset, *size))
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
+            DescriptorSetBuilder::Cpu(x) => x.add_buffer_slices(
+                &slices
+                    .iter()
+                    .map(|(b, offset, size)| (b.cpu(), *offset, *size))
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
         }
     }
 
@@ -619,6 +1077,13 @@ impl DescriptorSetBuilder {
                     .map(Image::mtl)
                     .collect::<SmallVec<[_; 8]>>(),
             ),
+            DescriptorSetBuilder::Cpu(x) => x.add_images(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::cpu)
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
         }
     }
 
@@ -645,6 +1110,50 @@ impl DescriptorSetBuilder {
                     .map(Image::mtl)
                     .collect::<SmallVec<[_; 8]>>(),
             ),
+            DescriptorSetBuilder::Cpu(x) => x.add_textures(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::cpu)
+                    .collect::<SmallVec<[_; 8]>>(),
+            ),
+        }
+    }
+
+    pub fn add_sampled_images(&mut self, images: &[&Image], sampler: &Sampler) {
+        mux_match! { self;
+            DescriptorSetBuilder::Vk(x) => x.add_sampled_images(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::vk)
+                    .collect::<SmallVec<[_; 8]>>(),
+                sampler.vk(),
+            ),
+            DescriptorSetBuilder::Dx12(x) => x.add_sampled_images(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::dx12)
+                    .collect::<SmallVec<[_; 8]>>(),
+                sampler.dx12(),
+            ),
+            DescriptorSetBuilder::Mtl(x) => x.add_sampled_images(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::mtl)
+                    .collect::<SmallVec<[_; 8]>>(),
+                sampler.mtl(),
+            ),
+            DescriptorSetBuilder::Cpu(x) => x.add_sampled_images(
+                &images
+                    .iter()
+                    .copied()
+                    .map(Image::cpu)
+                    .collect::<SmallVec<[_; 8]>>(),
+                sampler.cpu(),
+            ),
         }
     }
 
@@ -662,6 +1171,9 @@ impl DescriptorSetBuilder {
             DescriptorSetBuilder::Mtl(x) => x
                 .build(device.mtl(), pipeline.mtl())
                 .map(DescriptorSet::Mtl),
+            DescriptorSetBuilder::Cpu(x) => x
+                .build(device.cpu(), pipeline.cpu())
+                .map(DescriptorSet::Cpu),
         }
     }
 }
@@ -672,6 +1184,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.begin(),
             CmdBuf::Dx12(c) => c.begin(),
             CmdBuf::Mtl(c) => c.begin(),
+            CmdBuf::Cpu(c) => c.begin(),
         }
     }
 
@@ -680,6 +1193,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.flush(),
             CmdBuf::Dx12(c) => c.flush(),
             CmdBuf::Mtl(c) => c.flush(),
+            CmdBuf::Cpu(c) => c.flush(),
         }
     }
 
@@ -688,6 +1202,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.finish(),
             CmdBuf::Dx12(c) => c.finish(),
             CmdBuf::Mtl(c) => c.finish(),
+            CmdBuf::Cpu(c) => c.finish(),
         }
     }
 
@@ -696,6 +1211,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.reset(),
             CmdBuf::Dx12(c) => c.reset(),
             CmdBuf::Mtl(c) => c.reset(),
+            CmdBuf::Cpu(c) => c.reset(),
         }
     }
 
@@ -704,6 +1220,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.begin_compute_pass(desc),
             CmdBuf::Dx12(c) => c.begin_compute_pass(desc),
             CmdBuf::Mtl(c) => c.begin_compute_pass(desc),
+            CmdBuf::Cpu(c) => c.begin_compute_pass(desc),
         }
     }
 
@@ -721,11 +1238,30 @@ impl CmdBuf {
         descriptor_set: &DescriptorSet,
         workgroup_count: (u32, u32, u32),
         workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
     ) {
         mux_match! { self;
-            CmdBuf::Vk(c) => c.dispatch(pipeline.vk(), descriptor_set.vk(), workgroup_count, workgroup_size),
-            CmdBuf::Dx12(c) => c.dispatch(pipeline.dx12(), descriptor_set.dx12(), workgroup_count, workgroup_size),
-            CmdBuf::Mtl(c) => c.dispatch(pipeline.mtl(), descriptor_set.mtl(), workgroup_count, workgroup_size),
+            CmdBuf::Vk(c) => c.dispatch(pipeline.vk(), descriptor_set.vk(), workgroup_count, workgroup_size, push_constants),
+            CmdBuf::Dx12(c) => c.dispatch(pipeline.dx12(), descriptor_set.dx12(), workgroup_count, workgroup_size, push_constants),
+            CmdBuf::Mtl(c) => c.dispatch(pipeline.mtl(), descriptor_set.mtl(), workgroup_count, workgroup_size, push_constants),
+            CmdBuf::Cpu(c) => c.dispatch(pipeline.cpu(), descriptor_set.cpu(), workgroup_count, workgroup_size, push_constants),
+        }
+    }
+
+    pub unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.dispatch_indirect(pipeline.vk(), descriptor_set.vk(), buffer.vk(), offset, workgroup_size, push_constants),
+            CmdBuf::Dx12(c) => c.dispatch_indirect(pipeline.dx12(), descriptor_set.dx12(), buffer.dx12(), offset, workgroup_size, push_constants),
+            CmdBuf::Mtl(c) => c.dispatch_indirect(pipeline.mtl(), descriptor_set.mtl(), buffer.mtl(), offset, workgroup_size, push_constants),
+            CmdBuf::Cpu(c) => c.dispatch_indirect(pipeline.cpu(), descriptor_set.cpu(), buffer.cpu(), offset, workgroup_size, push_constants),
         }
     }
 
@@ -734,6 +1270,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.end_compute_pass(),
             CmdBuf::Dx12(c) => c.end_compute_pass(),
             CmdBuf::Mtl(c) => c.end_compute_pass(),
+            CmdBuf::Cpu(c) => c.end_compute_pass(),
         }
     }
 
@@ -742,6 +1279,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.memory_barrier(),
             CmdBuf::Dx12(c) => c.memory_barrier(),
             CmdBuf::Mtl(c) => c.memory_barrier(),
+            CmdBuf::Cpu(c) => c.memory_barrier(),
         }
     }
 
@@ -750,6 +1288,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.host_barrier(),
             CmdBuf::Dx12(c) => c.host_barrier(),
             CmdBuf::Mtl(c) => c.host_barrier(),
+            CmdBuf::Cpu(c) => c.host_barrier(),
         }
     }
 
@@ -763,6 +1302,37 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.image_barrier(image.vk(), src_layout, dst_layout),
             CmdBuf::Dx12(c) => c.image_barrier(image.dx12(), src_layout, dst_layout),
             CmdBuf::Mtl(c) => c.image_barrier(image.mtl(), src_layout, dst_layout),
+            CmdBuf::Cpu(c) => c.image_barrier(image.cpu(), src_layout, dst_layout),
+        }
+    }
+
+    pub unsafe fn buffer_queue_transfer(
+        &mut self,
+        buffer: &Buffer,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.buffer_queue_transfer(buffer.vk(), src, dst),
+            CmdBuf::Dx12(c) => c.buffer_queue_transfer(buffer.dx12(), src, dst),
+            CmdBuf::Mtl(c) => c.buffer_queue_transfer(buffer.mtl(), src, dst),
+            CmdBuf::Cpu(c) => c.buffer_queue_transfer(buffer.cpu(), src, dst),
+        }
+    }
+
+    pub unsafe fn image_queue_transfer(
+        &mut self,
+        image: &Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.image_queue_transfer(image.vk(), src_layout, dst_layout, src, dst),
+            CmdBuf::Dx12(c) => c.image_queue_transfer(image.dx12(), src_layout, dst_layout, src, dst),
+            CmdBuf::Mtl(c) => c.image_queue_transfer(image.mtl(), src_layout, dst_layout, src, dst),
+            CmdBuf::Cpu(c) => c.image_queue_transfer(image.cpu(), src_layout, dst_layout, src, dst),
         }
     }
 
@@ -771,6 +1341,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.clear_buffer(buffer.vk(), size),
             CmdBuf::Dx12(c) => c.clear_buffer(buffer.dx12(), size),
             CmdBuf::Mtl(c) => c.clear_buffer(buffer.mtl(), size),
+            CmdBuf::Cpu(c) => c.clear_buffer(buffer.cpu(), size),
         }
     }
 
@@ -779,6 +1350,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.copy_buffer(src.vk(), dst.vk()),
             CmdBuf::Dx12(c) => c.copy_buffer(src.dx12(), dst.dx12()),
             CmdBuf::Mtl(c) => c.copy_buffer(src.mtl(), dst.mtl()),
+            CmdBuf::Cpu(c) => c.copy_buffer(src.cpu(), dst.cpu()),
         }
     }
 
@@ -787,6 +1359,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.copy_image_to_buffer(src.vk(), dst.vk()),
             CmdBuf::Dx12(c) => c.copy_image_to_buffer(src.dx12(), dst.dx12()),
             CmdBuf::Mtl(c) => c.copy_image_to_buffer(src.mtl(), dst.mtl()),
+            CmdBuf::Cpu(c) => c.copy_image_to_buffer(src.cpu(), dst.cpu()),
         }
     }
 
@@ -795,6 +1368,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.copy_buffer_to_image(src.vk(), dst.vk()),
             CmdBuf::Dx12(c) => c.copy_buffer_to_image(src.dx12(), dst.dx12()),
             CmdBuf::Mtl(c) => c.copy_buffer_to_image(src.mtl(), dst.mtl()),
+            CmdBuf::Cpu(c) => c.copy_buffer_to_image(src.cpu(), dst.cpu()),
         }
     }
 
@@ -803,6 +1377,68 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.blit_image(src.vk(), dst.vk()),
             CmdBuf::Dx12(c) => c.blit_image(src.dx12(), dst.dx12()),
             CmdBuf::Mtl(c) => c.blit_image(src.mtl(), dst.mtl()),
+            CmdBuf::Cpu(c) => c.blit_image(src.cpu(), dst.cpu()),
+        }
+    }
+
+    pub unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.copy_buffer_region(src.vk(), src_offset, dst.vk(), dst_offset, size),
+            CmdBuf::Dx12(c) => c.copy_buffer_region(src.dx12(), src_offset, dst.dx12(), dst_offset, size),
+            CmdBuf::Mtl(c) => c.copy_buffer_region(src.mtl(), src_offset, dst.mtl(), dst_offset, size),
+            CmdBuf::Cpu(c) => c.copy_buffer_region(src.cpu(), src_offset, dst.cpu(), dst_offset, size),
+        }
+    }
+
+    pub unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.copy_image_region_to_buffer(src.vk(), region, dst.vk(), layout),
+            CmdBuf::Dx12(c) => c.copy_image_region_to_buffer(src.dx12(), region, dst.dx12(), layout),
+            CmdBuf::Mtl(c) => c.copy_image_region_to_buffer(src.mtl(), region, dst.mtl(), layout),
+            CmdBuf::Cpu(c) => c.copy_image_region_to_buffer(src.cpu(), region, dst.cpu(), layout),
+        }
+    }
+
+    pub unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.copy_buffer_to_image_region(src.vk(), layout, dst.vk(), region),
+            CmdBuf::Dx12(c) => c.copy_buffer_to_image_region(src.dx12(), layout, dst.dx12(), region),
+            CmdBuf::Mtl(c) => c.copy_buffer_to_image_region(src.mtl(), layout, dst.mtl(), region),
+            CmdBuf::Cpu(c) => c.copy_buffer_to_image_region(src.cpu(), layout, dst.cpu(), region),
+        }
+    }
+
+    pub unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        mux_match! { self;
+            CmdBuf::Vk(c) => c.copy_image_region(src.vk(), region, dst.vk(), dst_origin),
+            CmdBuf::Dx12(c) => c.copy_image_region(src.dx12(), region, dst.dx12(), dst_origin),
+            CmdBuf::Mtl(c) => c.copy_image_region(src.mtl(), region, dst.mtl(), dst_origin),
+            CmdBuf::Cpu(c) => c.copy_image_region(src.cpu(), region, dst.cpu(), dst_origin),
         }
     }
 
@@ -811,6 +1447,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.reset_query_pool(pool.vk()),
             CmdBuf::Dx12(c) => c.reset_query_pool(pool.dx12()),
             CmdBuf::Mtl(c) => c.reset_query_pool(pool.mtl()),
+            CmdBuf::Cpu(c) => c.reset_query_pool(pool.cpu()),
         }
     }
 
@@ -819,6 +1456,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.write_timestamp(pool.vk(), query),
             CmdBuf::Dx12(c) => c.write_timestamp(pool.dx12(), query),
             CmdBuf::Mtl(c) => c.write_timestamp(pool.mtl(), query),
+            CmdBuf::Cpu(c) => c.write_timestamp(pool.cpu(), query),
         }
     }
 
@@ -827,6 +1465,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.finish_timestamps(pool.vk()),
             CmdBuf::Dx12(c) => c.finish_timestamps(pool.dx12()),
             CmdBuf::Mtl(c) => c.finish_timestamps(pool.mtl()),
+            CmdBuf::Cpu(c) => c.finish_timestamps(pool.cpu()),
         }
     }
 
@@ -835,6 +1474,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.begin_debug_label(label),
             CmdBuf::Dx12(c) => c.begin_debug_label(label),
             CmdBuf::Mtl(c) => c.begin_debug_label(label),
+            CmdBuf::Cpu(c) => c.begin_debug_label(label),
         }
     }
 
@@ -843,6 +1483,7 @@ impl CmdBuf {
             CmdBuf::Vk(c) => c.end_debug_label(),
             CmdBuf::Dx12(c) => c.end_debug_label(),
             CmdBuf::Mtl(c) => c.end_debug_label(),
+            CmdBuf::Cpu(c) => c.end_debug_label(),
         }
     }
 }
@@ -853,6 +1494,46 @@ impl Buffer {
             Buffer::Vk(b) => b.size,
             Buffer::Dx12(b) => b.size,
             Buffer::Mtl(b) => b.size,
+            Buffer::Cpu(b) => b.size,
+        }
+    }
+}
+
+impl Image {
+    /// The width and height of the image.
+    pub fn size(&self) -> (u32, u32) {
+        mux_match! { self;
+            Image::Vk(i) => (i.extent.width, i.extent.height),
+            Image::Dx12(i) => i.size,
+            Image::Mtl(i) => (i.width, i.height),
+            Image::Cpu(i) => (i.width, i.height),
+        }
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        mux_match! { self;
+            Image::Vk(i) => i.format,
+            Image::Dx12(i) => i.format,
+            Image::Mtl(i) => i.format,
+            Image::Cpu(i) => i.format,
+        }
+    }
+}
+
+impl Pipeline {
+    /// The subgroup size of the pipeline, when known.
+    ///
+    /// On Vulkan, this is the requested size, or `None` when no size was
+    /// requested or the request could not be honored. On Metal, it's the
+    /// SIMD-group width chosen for the pipeline. On DX12, it's always `None`,
+    /// as requests aren't supported there yet (see
+    /// [`PipelineOptions::subgroup_size`]).
+    pub fn subgroup_size(&self) -> Option<u32> {
+        mux_match! { self;
+            Pipeline::Vk(p) => p.subgroup_size,
+            Pipeline::Dx12(_p) => None,
+            Pipeline::Mtl(p) => Some(p.thread_execution_width()),
+            Pipeline::Cpu(_p) => None,
         }
     }
 }
@@ -872,6 +1553,17 @@ impl Swapchain {
                 let (idx, sem) = s.next()?;
                 Ok((idx, Semaphore::Mtl(sem)))
             }
+            Swapchain::Cpu(s) => { match *s {} }
+        }
+    }
+
+    /// The format of the swapchain images.
+    pub fn format(&self) -> ImageFormat {
+        mux_match! { self;
+            Swapchain::Vk(s) => s.format(),
+            Swapchain::Dx12(s) => s.format(),
+            Swapchain::Mtl(s) => s.format(),
+            Swapchain::Cpu(s) => match *s {},
         }
     }
 
@@ -884,6 +1576,7 @@ impl Swapchain {
             Swapchain::Vk(s) => Image::Vk(s.image(idx)),
             Swapchain::Dx12(s) => Image::Dx12(s.image(idx)),
             Swapchain::Mtl(s) => Image::Mtl(s.image(idx)),
+            Swapchain::Cpu(s) => match *s {},
         }
     }
 
@@ -917,6 +1610,7 @@ impl Swapchain {
                     .map(Semaphore::mtl)
                     .collect::<SmallVec<[_; 4]>>(),
             ),
+            Swapchain::Cpu(s) => match *s {},
         }
     }
 }
@@ -0,0 +1,177 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! The serialized form of pipeline caches.
+//!
+//! The data produced by the backend is wrapped in a header that identifies
+//! the backend, device and driver version, followed by a checksum. Data that
+//! doesn't match the current device is rejected before it reaches the driver,
+//! as some drivers don't cope well with stale or corrupt caches.
+
+use std::convert::TryInto;
+
+use crate::BackendType;
+
+const MAGIC: &[u8; 4] = b"PGPC";
+
+/// The version of the format; bump when the header or cache contents change.
+const VERSION: u32 = 1;
+
+/// Wrap backend cache data with the header.
+pub(crate) fn encode(backend: BackendType, key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(24 + key.len() + data.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&backend_id(backend).to_le_bytes());
+    bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(&hash(data).to_le_bytes());
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Validate the header, returning the backend cache data.
+///
+/// The error describes why the data was rejected.
+pub(crate) fn decode<'a>(
+    backend: BackendType,
+    key: &[u8],
+    bytes: &'a [u8],
+) -> Result<&'a [u8], &'static str> {
+    let mut reader = Reader(bytes);
+    if reader.take(4) != Some(MAGIC) {
+        return Err("not a pipeline cache");
+    }
+    if reader.u32() != Some(VERSION) {
+        return Err("unsupported version");
+    }
+    if reader.u32() != Some(backend_id(backend)) {
+        return Err("created by a different backend");
+    }
+    let key_len = reader.u32().ok_or("truncated")?;
+    if reader.take(key_len as usize) != Some(key) {
+        return Err("created by a different device or driver version");
+    }
+    let checksum = reader.u64().ok_or("truncated")?;
+    let data = reader.0;
+    if hash(data) != checksum {
+        return Err("checksum mismatch");
+    }
+    Ok(data)
+}
+
+/// A 64-bit FNV-1a hash.
+///
+/// This is stable across builds, unlike the standard library hasher.
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &b in data {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn backend_id(backend: BackendType) -> u32 {
+    match backend {
+        BackendType::Vulkan => 1,
+        BackendType::Dx12 => 2,
+        BackendType::Metal => 3,
+        BackendType::Cpu => 4,
+    }
+}
+
+/// A reader of little-endian binary data.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if n > self.0.len() {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Read a byte string, prefixed by its length.
+    pub(crate) fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(len.try_into().ok()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode};
+    use crate::BackendType;
+
+    const KEY: &[u8] = b"device";
+    const DATA: &[u8] = b"pipelines";
+
+    #[test]
+    fn round_trip() {
+        let bytes = encode(BackendType::Vulkan, KEY, DATA);
+        assert_eq!(decode(BackendType::Vulkan, KEY, &bytes), Ok(DATA));
+        let bytes = encode(BackendType::Vulkan, KEY, &[]);
+        assert_eq!(decode(BackendType::Vulkan, KEY, &bytes), Ok(&[][..]));
+    }
+
+    #[test]
+    fn mismatch() {
+        let bytes = encode(BackendType::Vulkan, KEY, DATA);
+        assert!(decode(BackendType::Dx12, KEY, &bytes).is_err());
+        assert!(decode(BackendType::Vulkan, b"other", &bytes).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = encode(BackendType::Vulkan, KEY, DATA);
+        for len in 0..bytes.len() {
+            assert!(
+                decode(BackendType::Vulkan, KEY, &bytes[..len]).is_err(),
+                "truncated to {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted() {
+        let bytes = encode(BackendType::Vulkan, KEY, DATA);
+        for i in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            assert!(
+                decode(BackendType::Vulkan, KEY, &corrupted).is_err(),
+                "corrupted byte {}",
+                i
+            );
+        }
+    }
+}
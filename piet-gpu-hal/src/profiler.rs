@@ -0,0 +1,329 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Named timer scopes, resolved from timer queries.
+//!
+//! Recording is started on a command buffer with
+//! [`begin_profile`][crate::CmdBuf::begin_profile]. From then on, scopes and
+//! labeled compute passes are allocated query slots automatically. After the
+//! command buffer completes, the [`PendingProfile`] is resolved into a
+//! [`FrameProfile`], and frames can be collected into a [`ChromeTrace`].
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::hub::{PooledQueryPool, Session};
+use crate::{Error, QueryPool};
+
+/// The state of timer scopes while recording a command buffer.
+pub(crate) struct ProfileRecorder {
+    pool: PooledQueryPool,
+    /// The next unused query; query 0 is the start of the command buffer.
+    next_query: u32,
+    /// Timestamps can be written between compute passes. Otherwise, only
+    /// labeled passes are timed, and query 0 is the start of the first.
+    timestamp_writes: bool,
+    scopes: Vec<ScopeQueries>,
+    /// The open scopes, or `None` for scopes that didn't get queries.
+    stack: Vec<Option<usize>>,
+}
+
+struct ScopeQueries {
+    label: String,
+    depth: u32,
+    start: u32,
+    end: u32,
+}
+
+/// Timer scopes of a command buffer that has not yet completed.
+///
+/// Resolve this after waiting for the submitted command buffer.
+pub struct PendingProfile {
+    pool: PooledQueryPool,
+    scopes: Vec<ScopeQueries>,
+}
+
+/// The resolved timings of the scopes in one command buffer.
+#[derive(Clone, Debug, Default)]
+pub struct FrameProfile {
+    /// The scopes, in the order they were begun.
+    pub scopes: Vec<ProfileScope>,
+}
+
+/// The timing of a single scope.
+#[derive(Clone, Debug)]
+pub struct ProfileScope {
+    pub label: String,
+    /// The nesting depth, 0 for outermost scopes.
+    pub depth: u32,
+    /// Start time in seconds, relative to the start of the command buffer,
+    /// or of its first timed pass without timestamp writes.
+    pub start: f64,
+    /// End time in seconds, on the same timeline as `start`.
+    pub end: f64,
+}
+
+/// A trace in the Chrome trace event format.
+///
+/// The resulting file can be loaded into chrome://tracing or Perfetto.
+/// Frames are laid out end to end, as the time between submissions isn't
+/// measured.
+#[derive(Default)]
+pub struct ChromeTrace {
+    events: Vec<(usize, f64, ProfileScope)>,
+    n_frames: usize,
+    /// The start time of the next frame, in seconds.
+    time: f64,
+}
+
+impl ProfileRecorder {
+    /// Start recording into the pool.
+    ///
+    /// The caller is responsible for resetting the pool and, with
+    /// `timestamp_writes`, writing the initial timestamp to query 0.
+    pub(crate) fn new(pool: PooledQueryPool, timestamp_writes: bool) -> ProfileRecorder {
+        ProfileRecorder {
+            pool,
+            next_query: if timestamp_writes { 1 } else { 0 },
+            timestamp_writes,
+            scopes: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn pool(&self) -> &QueryPool {
+        &self.pool
+    }
+
+    /// Allocate queries for a new scope, returning the pool and query indices.
+    ///
+    /// Returns `None` when the pool is exhausted; the scope is then not timed.
+    pub(crate) fn alloc_scope(&mut self, label: &str) -> Option<(&QueryPool, u32, u32)> {
+        if self.next_query + 2 > self.pool.n_queries() {
+            return None;
+        }
+        let start = self.next_query;
+        self.next_query += 2;
+        self.scopes.push(ScopeQueries {
+            label: label.into(),
+            depth: self.stack.len() as u32,
+            start,
+            end: start + 1,
+        });
+        Some((&self.pool, start, start + 1))
+    }
+
+    /// Begin a scope that is closed explicitly, returning the start query.
+    ///
+    /// Without timestamp writes, these scopes are not timed.
+    pub(crate) fn push_scope(&mut self, label: &str) -> Option<(&QueryPool, u32)> {
+        let ix = self.scopes.len();
+        let has_queries = self.timestamp_writes && self.alloc_scope(label).is_some();
+        self.stack.push(if has_queries { Some(ix) } else { None });
+        if has_queries {
+            Some((&self.pool, self.scopes[ix].start))
+        } else {
+            None
+        }
+    }
+
+    /// End the innermost open scope, returning the end query.
+    pub(crate) fn pop_scope(&mut self) -> Option<(&QueryPool, u32)> {
+        let ix = self.stack.pop()??;
+        Some((&self.pool, self.scopes[ix].end))
+    }
+
+    /// The number of open scopes.
+    pub(crate) fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// The queries that were not used, which need to be written before readback.
+    ///
+    /// This is empty without timestamp writes, as they can't be written.
+    pub(crate) fn unused_queries(&self) -> std::ops::Range<u32> {
+        if self.timestamp_writes {
+            self.next_query..self.pool.n_queries()
+        } else {
+            0..0
+        }
+    }
+
+    pub(crate) fn finish(self) -> PendingProfile {
+        PendingProfile {
+            pool: self.pool,
+            scopes: self.scopes,
+        }
+    }
+}
+
+impl PendingProfile {
+    /// Read back the timer queries.
+    ///
+    /// The command buffer that recorded the scopes must have completed.
+    pub unsafe fn resolve(self, session: &Session) -> Result<FrameProfile, Error> {
+        // This has the first timestamp subtracted, so index 0 is query 1.
+        let times = session.fetch_query_pool(&self.pool)?;
+        // Backends without timer support return no timestamps.
+        let time = |query: u32| match query {
+            0 if !times.is_empty() => Some(0.0),
+            _ => times.get(query as usize - 1).copied(),
+        };
+        let scopes = self
+            .scopes
+            .into_iter()
+            .filter_map(|scope| {
+                let start = time(scope.start)?;
+                let end = time(scope.end)?;
+                Some(ProfileScope {
+                    label: scope.label,
+                    depth: scope.depth,
+                    start,
+                    end,
+                })
+            })
+            .collect();
+        Ok(FrameProfile { scopes })
+    }
+}
+
+impl FrameProfile {
+    /// The first scope with the given label.
+    pub fn get(&self, label: &str) -> Option<&ProfileScope> {
+        self.scopes.iter().find(|scope| scope.label == label)
+    }
+
+    /// The duration in seconds of the first scope with the given label.
+    pub fn duration(&self, label: &str) -> Option<f64> {
+        self.get(label).map(ProfileScope::duration)
+    }
+
+    /// The end time of the last scope to complete.
+    pub fn end(&self) -> f64 {
+        self.scopes
+            .iter()
+            .map(|scope| scope.end)
+            .fold(0.0, f64::max)
+    }
+}
+
+impl ProfileScope {
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+impl ChromeTrace {
+    pub fn new() -> ChromeTrace {
+        Default::default()
+    }
+
+    /// Append the scopes of a frame to the trace.
+    pub fn add_frame(&mut self, frame: &FrameProfile) {
+        for scope in &frame.scopes {
+            self.events.push((self.n_frames, self.time, scope.clone()));
+        }
+        self.n_frames += 1;
+        self.time += frame.end();
+    }
+
+    /// Write the trace as JSON.
+    pub fn write(&self, mut w: impl Write) -> io::Result<()> {
+        writeln!(w, "{{\"traceEvents\":[")?;
+        for (i, (frame, offset, scope)) in self.events.iter().enumerate() {
+            let sep = if i + 1 < self.events.len() { "," } else { "" };
+            writeln!(
+                w,
+                "{{\"name\":\"{}\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\
+                \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"frame\":{},\"depth\":{}}}}}{}",
+                json_escape(&scope.label),
+                (offset + scope.start) * 1e6,
+                scope.duration() * 1e6,
+                frame,
+                scope.depth,
+                sep
+            )?;
+        }
+        writeln!(w, "],\"displayTimeUnit\":\"ms\"}}")
+    }
+
+    /// Write the trace to a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.write(io::BufWriter::new(file))
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(result, "\\u{:04x}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_escape, ChromeTrace, FrameProfile, ProfileScope};
+
+    fn scope(label: &str, depth: u32, start: f64, end: f64) -> ProfileScope {
+        ProfileScope {
+            label: label.into(),
+            depth,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(json_escape("a \"b\" \\c"), "a \\\"b\\\" \\\\c");
+        assert_eq!(json_escape("tab\tnl\n"), "tab\\u0009nl\\u000a");
+        assert_eq!(json_escape("\u{e9}\u{1f600}"), "\u{e9}\u{1f600}");
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let frame = FrameProfile {
+            scopes: vec![
+                scope("outer", 0, 0.0, 0.002),
+                scope("\"inner\"", 1, 0.001, 0.0015),
+            ],
+        };
+        let mut trace = ChromeTrace::new();
+        trace.add_frame(&frame);
+        trace.add_frame(&frame);
+        let mut out = Vec::new();
+        trace.write(&mut out).unwrap();
+        let expected = "{\"traceEvents\":[
+{\"name\":\"outer\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":0.000,\"dur\":2000.000,\"args\":{\"frame\":0,\"depth\":0}},
+{\"name\":\"\\\"inner\\\"\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":1000.000,\"dur\":500.000,\"args\":{\"frame\":0,\"depth\":1}},
+{\"name\":\"outer\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":2000.000,\"dur\":2000.000,\"args\":{\"frame\":1,\"depth\":0}},
+{\"name\":\"\\\"inner\\\"\",\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":0,\"tid\":0,\"ts\":3000.000,\"dur\":500.000,\"args\":{\"frame\":1,\"depth\":1}}
+],\"displayTimeUnit\":\"ms\"}
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Translation of WGSL shaders to the shading language of the backend.
+//!
+//! This uses naga, so shaders can be written without the offline toolchain
+//! (glslangValidator, spirv-cross and DXC). It's enabled with the `wgsl`
+//! feature.
+//!
+//! The shader must have a single compute entry point. Bindings are in group
+//! 0, numbered in the order of the bind types given at pipeline creation,
+//! and a push constant block follows the bindings, matching the resource
+//! layout of the backends.
+
+use crate::{BackendType, BindType, Error, ShaderCode};
+
+/// A shader translated for the backend.
+#[cfg_attr(not(feature = "wgsl"), allow(dead_code))]
+pub(crate) enum Translated {
+    Spv(Vec<u8>),
+    Hlsl(String),
+    Msl(String),
+}
+
+impl Translated {
+    pub(crate) fn shader_code(&self) -> ShaderCode<'_> {
+        match self {
+            Translated::Spv(spv) => ShaderCode::Spv(spv),
+            Translated::Hlsl(hlsl) => ShaderCode::Hlsl(hlsl),
+            Translated::Msl(msl) => ShaderCode::Msl(msl),
+        }
+    }
+}
+
+#[cfg(not(feature = "wgsl"))]
+pub(crate) fn wgsl(
+    _backend: BackendType,
+    _source: &str,
+    _bind_types: &[BindType],
+) -> Result<Translated, Error> {
+    Err(Error::unsupported(
+        "WGSL shaders (enable the `wgsl` feature)",
+    ))
+}
+
+#[cfg(feature = "wgsl")]
+pub(crate) fn wgsl(
+    backend: BackendType,
+    source: &str,
+    bind_types: &[BindType],
+) -> Result<Translated, Error> {
+    use naga::back::{hlsl, msl, spv};
+    use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+    let mut module = naga::front::wgsl::parse_str(source).map_err(|e| Error::ShaderCompile {
+        log: e.emit_to_string(source),
+    })?;
+    // The backends look for an entry point named `main`.
+    match module.entry_points.as_mut_slice() {
+        [entry_point] if entry_point.stage == naga::ShaderStage::Compute => {
+            entry_point.name = "main".into();
+        }
+        _ => {
+            return Err(Error::ShaderCompile {
+                log: "expected a single compute entry point".into(),
+            })
+        }
+    }
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(compile_error)?;
+    match backend {
+        BackendType::Vulkan => {
+            let words = spv::write_vec(&module, &info, &spv::Options::default(), None)
+                .map_err(compile_error)?;
+            Ok(Translated::Spv(bytemuck::cast_slice(&words).to_vec()))
+        }
+        BackendType::Dx12 => {
+            // Registers are taken from the binding numbers, as in the root
+            // signature built by the backend.
+            let options = hlsl::Options {
+                shader_model: hlsl::ShaderModel::V5_1,
+                fake_missing_bindings: true,
+                ..Default::default()
+            };
+            let mut hlsl = String::new();
+            let reflection = hlsl::Writer::new(&mut hlsl, &options)
+                .write(&module, &info)
+                .map_err(compile_error)?;
+            match reflection.entry_point_names.into_iter().next() {
+                Some(Ok(name)) if name == "main" => Ok(Translated::Hlsl(hlsl)),
+                Some(Err(e)) => Err(compile_error(e)),
+                _ => Err(Error::ShaderCompile {
+                    log: "entry point was renamed in HLSL".into(),
+                }),
+            }
+        }
+        BackendType::Metal => {
+            // Buffers and textures share the slot numbering, and the push
+            // constant block takes the slot following the last binding.
+            let (bind_types, _) = BindType::split_push_constants(bind_types)?;
+            let mut resources = msl::BindingMap::default();
+            for i in 0..bind_types.len() {
+                let slot = Some(i as u8);
+                let binding = naga::ResourceBinding {
+                    group: 0,
+                    binding: i as u32,
+                };
+                let target = msl::BindTarget {
+                    buffer: slot,
+                    texture: slot,
+                    mutable: true,
+                    ..Default::default()
+                };
+                resources.insert(binding, target);
+            }
+            let cs = msl::PerStageResources {
+                resources,
+                push_constant_buffer: Some(bind_types.len() as u8),
+                ..Default::default()
+            };
+            let options = msl::Options {
+                lang_version: (2, 0),
+                per_stage_map: msl::PerStageMap {
+                    cs,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let pipeline_options = msl::PipelineOptions::default();
+            let (msl, _) = msl::write_string(&module, &info, &options, &pipeline_options)
+                .map_err(compile_error)?;
+            Ok(Translated::Msl(msl))
+        }
+        BackendType::Cpu => Err(Error::unsupported("CPU kernel")),
+    }
+}
+
+/// Report an error with its chain of causes.
+#[cfg(feature = "wgsl")]
+fn compile_error(e: impl std::error::Error) -> Error {
+    let mut log = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        log.push_str(": ");
+        log.push_str(&e.to_string());
+        source = e.source();
+    }
+    Error::ShaderCompile { log }
+}
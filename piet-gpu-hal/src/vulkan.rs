@@ -1,10 +1,12 @@
 //! Vulkan implemenation of HAL trait.
 
+mod pool;
+
 use std::borrow::Cow;
 use std::convert::TryInto;
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use ash::extensions::{ext::DebugUtils, khr};
 use ash::vk::DebugUtilsLabelEXT;
@@ -14,9 +16,13 @@ use smallvec::SmallVec;
 
 use crate::backend::Device as DeviceTrait;
 use crate::{
-    BindType, BufferUsage, ComputePassDescriptor, Error, GpuInfo, ImageFormat, ImageLayout,
-    MapMode, SamplerParams, SubgroupSize, WorkgroupLimits,
+    AdapterInfo, AddressMode, BackendType, BindType, BufferImageLayout, BufferUsage,
+    ComputePassDescriptor, DeviceType, Error, FilterMode, FormatCapabilities, GpuInfo, ImageFormat,
+    ImageLayout, ImageRegion, MapMode, MemoryBudget, MemoryHeap, MemoryPoolStats, PipelineOptions,
+    QueueType, SamplerParams, SubgroupFeatures, SubgroupSize, WorkgroupLimits,
 };
+#[cfg(target_os = "linux")]
+use crate::{ExternalHandleType, ExternalMemory};
 
 pub struct VkInstance {
     /// Retain the dynamic lib.
@@ -30,12 +36,56 @@ pub struct VkInstance {
 
 pub struct VkDevice {
     device: Arc<RawDevice>,
+    /// Retained for physical device queries.
+    instance: Instance,
     physical_device: vk::PhysicalDevice,
     device_mem_props: vk::PhysicalDeviceMemoryProperties,
-    queue: vk::Queue,
-    qfi: u32,
+    queues: Queues,
     timestamp_period: f32,
     gpu_info: GpuInfo,
+    /// Identifies the device and driver, for validating pipeline cache data.
+    pipeline_cache_key: Vec<u8>,
+    /// The header of compatible pipeline cache data, following its length.
+    pipeline_cache_header: Vec<u8>,
+    /// The pool for buffer memory, if enabled.
+    memory_pool: Mutex<Option<pool::MemoryPool>>,
+    external: ExternalFns,
+    /// The device supports `VK_EXT_memory_budget`.
+    has_memory_budget: bool,
+}
+
+/// Extensions for sharing memory and semaphores through fds, when supported.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct ExternalFns {
+    memory_fd: Option<khr::ExternalMemoryFd>,
+    has_dma_buf: bool,
+    semaphore_fd: Option<khr::ExternalSemaphoreFd>,
+}
+
+/// How the memory of a resource is shared outside of the device.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Clone, Copy)]
+enum ExternalAlloc {
+    /// Allocate memory that can be exported with the handle type.
+    Export(vk::ExternalMemoryHandleTypeFlags),
+    /// Import the memory of an fd, with its allocation size and, for linear
+    /// images, the layout of the pixels in it.
+    Import(
+        vk::ExternalMemoryHandleTypeFlags,
+        i32,
+        u64,
+        Option<BufferImageLayout>,
+    ),
+}
+
+/// The queues of a device, with their queue family indices.
+///
+/// Queue types with no dedicated family alias the main queue.
+#[derive(Clone, Copy)]
+struct Queues {
+    main: (vk::Queue, u32),
+    compute: (vk::Queue, u32),
+    transfer: (vk::Queue, u32),
 }
 
 struct RawDevice {
@@ -58,6 +108,7 @@ pub struct VkSwapchain {
     acquisition_semaphores: Vec<vk::Semaphore>, // same length as `images`
     images: Vec<vk::Image>,
     extent: vk::Extent2D,
+    format: ImageFormat,
 }
 
 /// A handle to a buffer.
@@ -67,6 +118,8 @@ pub struct VkSwapchain {
 pub struct Buffer {
     buffer: vk::Buffer,
     buffer_memory: vk::DeviceMemory,
+    /// The range of a pooled block, if the memory is not dedicated.
+    allocation: Option<pool::Allocation>,
     // TODO: there should probably be a Buffer trait and this should be a method.
     pub size: u64,
 }
@@ -75,13 +128,17 @@ pub struct Image {
     image: vk::Image,
     image_memory: vk::DeviceMemory,
     image_view: vk::ImageView,
-    extent: vk::Extent3D,
+    pub extent: vk::Extent3D,
+    pub format: ImageFormat,
 }
 
 pub struct Pipeline {
     pipeline: vk::Pipeline,
     descriptor_set_layout: vk::DescriptorSetLayout,
     pipeline_layout: vk::PipelineLayout,
+    bind_types: Vec<BindType>,
+    /// The required subgroup size, if one was requested and supported.
+    pub subgroup_size: Option<u32>,
 }
 
 pub struct DescriptorSet {
@@ -92,6 +149,8 @@ pub struct CmdBuf {
     cmd_buf: vk::CommandBuffer,
     cmd_pool: vk::CommandPool,
     device: Arc<RawDevice>,
+    /// Used to resolve queue family indices for ownership transfers.
+    queues: Queues,
     end_query: Option<(vk::QueryPool, u32)>,
 }
 
@@ -104,10 +163,11 @@ pub struct QueryPool {
 pub struct MemFlags(vk::MemoryPropertyFlags);
 
 pub struct DescriptorSetBuilder {
-    buffers: Vec<vk::Buffer>,
+    /// Buffers with their offset and range.
+    buffers: Vec<(vk::Buffer, u64, u64)>,
     images: Vec<vk::ImageView>,
     textures: Vec<vk::ImageView>,
-    // TODO: we had a sampler here, might need it again
+    sampled_images: Vec<(vk::ImageView, vk::Sampler)>,
 }
 
 struct Extensions {
@@ -141,9 +201,22 @@ unsafe extern "system" fn vulkan_debug_callback(
         CStr::from_ptr(callback_data.p_message).to_string_lossy()
     };
 
-    println!(
-        "{:?}:\n{:?} [{} ({})] : {}\n",
-        message_severity, message_type, message_id_name, message_id_number, message,
+    let level = if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        log::Level::Error
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        log::Level::Warn
+    } else if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        log::Level::Info
+    } else {
+        log::Level::Trace
+    };
+    log::log!(
+        level,
+        "{:?} [{} ({})] : {}",
+        message_type,
+        message_id_name,
+        message_id_number,
+        message,
     );
 
     vk::FALSE
@@ -154,20 +227,23 @@ impl VkInstance {
     ///
     /// There's more to be done to make this suitable for integration with other
     /// systems, but for now the goal is to make things simple.
-    pub fn new() -> Result<VkInstance, Error> {
+    ///
+    /// The validation layer is enabled if `validation` is set, or in debug builds.
+    pub fn new(validation: bool) -> Result<VkInstance, Error> {
         unsafe {
             let app_name = CString::new("VkToy").unwrap();
             let entry = Entry::new()?;
+            let validation = validation || cfg!(debug_assertions);
 
             let mut layers = Layers::new(entry.enumerate_instance_layer_properties()?);
-            if cfg!(debug_assertions) {
+            if validation {
                 layers
                     .try_add(CStr::from_bytes_with_nul(b"VK_LAYER_KHRONOS_validation\0").unwrap());
             }
 
             let mut exts = Extensions::new(entry.enumerate_instance_extension_properties()?);
             let mut has_debug_ext = false;
-            if cfg!(debug_assertions) {
+            if validation {
                 has_debug_ext = exts.try_add(DebugUtils::name());
             }
 
@@ -224,7 +300,8 @@ impl VkInstance {
                 let dbg_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
                     .message_severity(
                         vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
-                            | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+                            | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                            | vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
                     )
                     .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
                     .pfn_user_callback(Some(vulkan_debug_callback));
@@ -275,7 +352,56 @@ impl VkInstance {
         let devices = self.instance.enumerate_physical_devices()?;
         let (pdevice, qfi) =
             choose_device(&self.instance, &devices).ok_or("no suitable device")?;
+        self.create_device(pdevice, qfi)
+    }
+
+    /// List the physical devices that are suitable for creating a device.
+    pub fn adapters(&self) -> Result<Vec<AdapterInfo>, Error> {
+        unsafe {
+            let devices = self.instance.enumerate_physical_devices()?;
+            let mut adapters = Vec::new();
+            for (index, pdevice) in devices.iter().enumerate() {
+                if choose_queue_family(&self.instance, *pdevice).is_none() {
+                    continue;
+                }
+                let props = self.instance.get_physical_device_properties(*pdevice);
+                let device_type = match props.device_type {
+                    vk::PhysicalDeviceType::DISCRETE_GPU => DeviceType::Discrete,
+                    vk::PhysicalDeviceType::INTEGRATED_GPU => DeviceType::Integrated,
+                    vk::PhysicalDeviceType::VIRTUAL_GPU => DeviceType::Virtual,
+                    vk::PhysicalDeviceType::CPU => DeviceType::Software,
+                    _ => DeviceType::Unknown,
+                };
+                adapters.push(AdapterInfo {
+                    name: device_name(&props),
+                    vendor_id: props.vendor_id,
+                    device_id: props.device_id,
+                    device_type,
+                    backend: BackendType::Vulkan,
+                    index,
+                });
+            }
+            Ok(adapters)
+        }
+    }
+
+    /// Create a device from a specific adapter.
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as [`VkInstance::device`] apply.
+    pub unsafe fn device_from_adapter(&self, adapter: &AdapterInfo) -> Result<VkDevice, Error> {
+        let devices = self.instance.enumerate_physical_devices()?;
+        let pdevice = *devices.get(adapter.index).ok_or("adapter not found")?;
+        let qfi = choose_queue_family(&self.instance, pdevice).ok_or("adapter not suitable")?;
+        self.create_device(pdevice, qfi)
+    }
 
+    unsafe fn create_device(
+        &self,
+        pdevice: vk::PhysicalDevice,
+        qfi: u32,
+    ) -> Result<VkDevice, Error> {
         let mut has_descriptor_indexing = false;
         let vk1_1 = self.vk_version >= vk::make_api_version(0, 1, 1, 0);
         let mut features2 = vk::PhysicalDeviceFeatures2::builder();
@@ -295,11 +421,37 @@ impl VkInstance {
                 && descriptor_indexing_features.runtime_descriptor_array == vk::TRUE;
         }
 
+        let queue_props = self
+            .instance
+            .get_physical_device_queue_family_properties(pdevice);
+        let compute_qfi = find_queue_family(
+            &queue_props,
+            vk::QueueFlags::COMPUTE,
+            vk::QueueFlags::GRAPHICS,
+        )
+        .unwrap_or(qfi);
+        let transfer_qfi = find_queue_family(
+            &queue_props,
+            vk::QueueFlags::TRANSFER,
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+        )
+        .unwrap_or(qfi);
+        let mut families: SmallVec<[u32; 3]> = SmallVec::new();
+        for family in [qfi, compute_qfi, transfer_qfi] {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
         let queue_priorities = [1.0];
-        let queue_create_infos = [vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(qfi)
-            .queue_priorities(&queue_priorities)
-            .build()];
+        let queue_create_infos = families
+            .iter()
+            .map(|family| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(*family)
+                    .queue_priorities(&queue_priorities)
+                    .build()
+            })
+            .collect::<SmallVec<[_; 3]>>();
 
         let mut descriptor_indexing = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
             .shader_storage_image_array_non_uniform_indexing(true)
@@ -315,8 +467,26 @@ impl VkInstance {
             extensions.try_add(vk::KhrMaintenance3Fn::name());
             extensions.try_add(vk::ExtDescriptorIndexingFn::name());
         }
-        let has_subgroup_size = vk1_1 && extensions.try_add(vk::ExtSubgroupSizeControlFn::name());
+        let has_subgroup_size_ext =
+            vk1_1 && extensions.try_add(vk::ExtSubgroupSizeControlFn::name());
+        let mut subgroup_size_features =
+            vk::PhysicalDeviceSubgroupSizeControlFeaturesEXT::default();
+        if has_subgroup_size_ext {
+            let mut features =
+                vk::PhysicalDeviceFeatures2::builder().push_next(&mut subgroup_size_features);
+            self.instance
+                .get_physical_device_features2(pdevice, &mut features);
+        }
+        let has_subgroup_size = subgroup_size_features.subgroup_size_control == vk::TRUE;
         let has_memory_model = vk1_1 && extensions.try_add(vk::KhrVulkanMemoryModelFn::name());
+        // External memory and semaphores are core in 1.1; only the fd
+        // handle types need extensions.
+        let has_external_memory_fd = vk1_1 && extensions.try_add(khr::ExternalMemoryFd::name());
+        let has_dma_buf =
+            has_external_memory_fd && extensions.try_add(vk::ExtExternalMemoryDmaBufFn::name());
+        let has_external_semaphore_fd =
+            vk1_1 && extensions.try_add(khr::ExternalSemaphoreFd::name());
+        let has_memory_budget = vk1_1 && extensions.try_add(vk::ExtMemoryBudgetFn::name());
         let mut create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_create_infos)
             .enabled_extension_names(extensions.as_ptrs());
@@ -333,12 +503,40 @@ impl VkInstance {
         if has_descriptor_indexing {
             create_info = create_info.push_next(&mut descriptor_indexing);
         }
+        let mut set_subgroup_size_features =
+            vk::PhysicalDeviceSubgroupSizeControlFeaturesEXT::builder().subgroup_size_control(true);
+        if has_subgroup_size {
+            create_info = create_info.push_next(&mut set_subgroup_size_features);
+        }
         let device = self.instance.create_device(pdevice, &create_info, None)?;
+        let external = ExternalFns {
+            memory_fd: if has_external_memory_fd {
+                Some(khr::ExternalMemoryFd::new(&self.instance, &device))
+            } else {
+                None
+            },
+            has_dma_buf,
+            semaphore_fd: if has_external_semaphore_fd {
+                Some(khr::ExternalSemaphoreFd::new(&self.instance, &device))
+            } else {
+                None
+            },
+        };
 
         let device_mem_props = self.instance.get_physical_device_memory_properties(pdevice);
 
         let queue_index = 0;
-        let queue = device.get_device_queue(qfi, queue_index);
+        let queues = Queues {
+            main: (device.get_device_queue(qfi, queue_index), qfi),
+            compute: (
+                device.get_device_queue(compute_qfi, queue_index),
+                compute_qfi,
+            ),
+            transfer: (
+                device.get_device_queue(transfer_qfi, queue_index),
+                transfer_qfi,
+            ),
+        };
 
         let device = Arc::new(RawDevice {
             device,
@@ -347,19 +545,44 @@ impl VkInstance {
 
         let props = self.instance.get_physical_device_properties(pdevice);
         let timestamp_period = props.limits.timestamp_period;
-        let subgroup_size = if has_subgroup_size {
-            let mut subgroup_props = vk::PhysicalDeviceSubgroupSizeControlPropertiesEXT::default();
+        let mut subgroup_props = vk::PhysicalDeviceSubgroupProperties::default();
+        let mut subgroup_size_props = vk::PhysicalDeviceSubgroupSizeControlPropertiesEXT::default();
+        if vk1_1 {
             let mut properties =
                 vk::PhysicalDeviceProperties2::builder().push_next(&mut subgroup_props);
+            if has_subgroup_size {
+                properties = properties.push_next(&mut subgroup_size_props);
+            }
             self.instance
                 .get_physical_device_properties2(pdevice, &mut properties);
+        }
+        let subgroup_size = if has_subgroup_size
+            && subgroup_size_props
+                .required_subgroup_size_stages
+                .contains(vk::ShaderStageFlags::COMPUTE)
+        {
             Some(SubgroupSize {
-                min: subgroup_props.min_subgroup_size,
-                max: subgroup_props.max_subgroup_size,
+                min: subgroup_size_props.min_subgroup_size,
+                max: subgroup_size_props.max_subgroup_size,
+                control: true,
+            })
+        } else if subgroup_props.subgroup_size != 0 {
+            Some(SubgroupSize {
+                min: subgroup_props.subgroup_size,
+                max: subgroup_props.subgroup_size,
+                control: false,
             })
         } else {
             None
         };
+        let subgroup_features = if subgroup_props
+            .supported_stages
+            .contains(vk::ShaderStageFlags::COMPUTE)
+        {
+            subgroup_features(subgroup_props.supported_operations)
+        } else {
+            SubgroupFeatures::empty()
+        };
 
         // The question of when and when not to use staging buffers is complex, and this
         // is only a first approximation. Basically, it *must* be false when buffers can
@@ -369,8 +592,7 @@ impl VkInstance {
         // I'm still investigating what should be done in systems with Resizable BAR.
         let use_staging_buffers = props.device_type != vk::PhysicalDeviceType::INTEGRATED_GPU;
 
-        // TODO: finer grained query of specific subgroup info.
-        let has_subgroups = vk1_1;
+        let has_subgroups = subgroup_features.contains(SubgroupFeatures::BASIC);
 
         let workgroup_limits = WorkgroupLimits {
             max_invocations: props.limits.max_compute_work_group_invocations,
@@ -380,20 +602,52 @@ impl VkInstance {
         let gpu_info = GpuInfo {
             has_descriptor_indexing,
             has_subgroups,
+            subgroup_features,
             subgroup_size,
             workgroup_limits,
             has_memory_model,
             use_staging_buffers,
+            adapter_name: device_name(&props),
+            buffer_offset_alignment: props
+                .limits
+                .min_storage_buffer_offset_alignment
+                .max(props.limits.min_uniform_buffer_offset_alignment),
+            has_timestamp_writes: true,
+            memory_heaps: device_mem_props.memory_heaps
+                [..device_mem_props.memory_heap_count as usize]
+                .iter()
+                .map(|heap| MemoryHeap {
+                    size: heap.size,
+                    device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                })
+                .collect(),
         };
 
+        let mut pipeline_cache_key = Vec::new();
+        pipeline_cache_key.extend_from_slice(&props.vendor_id.to_le_bytes());
+        pipeline_cache_key.extend_from_slice(&props.device_id.to_le_bytes());
+        pipeline_cache_key.extend_from_slice(&props.driver_version.to_le_bytes());
+        pipeline_cache_key.extend_from_slice(&props.pipeline_cache_uuid);
+        let mut pipeline_cache_header = Vec::new();
+        let header_version = vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32;
+        pipeline_cache_header.extend_from_slice(&header_version.to_le_bytes());
+        pipeline_cache_header.extend_from_slice(&props.vendor_id.to_le_bytes());
+        pipeline_cache_header.extend_from_slice(&props.device_id.to_le_bytes());
+        pipeline_cache_header.extend_from_slice(&props.pipeline_cache_uuid);
+
         Ok(VkDevice {
             device,
+            instance: self.instance.clone(),
             physical_device: pdevice,
             device_mem_props,
-            qfi,
-            queue,
+            queues,
             timestamp_period,
             gpu_info,
+            pipeline_cache_key,
+            pipeline_cache_header,
+            memory_pool: Mutex::new(None),
+            external,
+            has_memory_budget,
         })
     }
 
@@ -403,23 +657,30 @@ impl VkInstance {
         height: usize,
         device: &VkDevice,
         surface: &VkSurface,
+        formats: &[ImageFormat],
     ) -> Result<VkSwapchain, Error> {
-        let formats = surface
+        let surface_formats = surface
             .surface_fn
             .get_physical_device_surface_formats(device.physical_device, surface.surface)?;
-        let surface_format = formats
-            .iter()
-            .map(|surface_fmt| match surface_fmt.format {
-                vk::Format::UNDEFINED => {
-                    vk::SurfaceFormatKHR {
-                        format: vk::Format::B8G8R8A8_UNORM, // most common format on desktop
-                        color_space: surface_fmt.color_space,
-                    }
-                }
-                _ => *surface_fmt,
-            })
-            .next()
-            .ok_or("no surface format found")?;
+        let first = surface_formats.first().ok_or("no surface format found")?;
+        let surface_format = if first.format == vk::Format::UNDEFINED {
+            // The surface accepts any format.
+            vk::SurfaceFormatKHR {
+                format: vk_format(formats.first().copied().unwrap_or(ImageFormat::Bgra8)),
+                color_space: first.color_space,
+            }
+        } else {
+            formats
+                .iter()
+                .find_map(|format| {
+                    let vk_format = vk_format(*format);
+                    surface_formats.iter().find(|sf| sf.format == vk_format)
+                })
+                .copied()
+                .unwrap_or(*first)
+        };
+        // Formats we don't know about are still usable as blit destinations.
+        let format = image_format(surface_format.format).unwrap_or(ImageFormat::Rgba8);
 
         let capabilities = surface
             .surface_fn
@@ -478,65 +739,115 @@ impl VkInstance {
             swapchain,
             swapchain_fn,
 
-            present_queue: device.queue,
+            present_queue: device.queues.main.0,
 
             images,
             acquisition_semaphores,
             acquisition_idx: 0,
             extent,
+            format,
         })
     }
 }
 
-impl crate::backend::Device for VkDevice {
-    type Buffer = Buffer;
-    type Image = Image;
-    type CmdBuf = CmdBuf;
-    type DescriptorSet = DescriptorSet;
-    type Pipeline = Pipeline;
-    type QueryPool = QueryPool;
-    type Fence = vk::Fence;
-    type Semaphore = vk::Semaphore;
-    type DescriptorSetBuilder = DescriptorSetBuilder;
-    type Sampler = vk::Sampler;
-    type ShaderSource = [u8];
-
-    fn query_gpu_info(&self) -> GpuInfo {
-        self.gpu_info.clone()
+impl Queues {
+    fn get(&self, queue: QueueType) -> (vk::Queue, u32) {
+        match queue {
+            QueueType::Main => self.main,
+            QueueType::Compute => self.compute,
+            QueueType::Transfer => self.transfer,
+        }
     }
+}
 
-    fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Buffer, Error> {
-        unsafe {
-            let device = &self.device.device;
-            let mut vk_usage = vk::BufferUsageFlags::empty();
-            if usage.contains(BufferUsage::STORAGE) {
-                vk_usage |= vk::BufferUsageFlags::STORAGE_BUFFER;
+impl Drop for VkDevice {
+    fn drop(&mut self) {
+        if let Some(pool) = self.memory_pool.get_mut().unwrap().as_mut() {
+            unsafe {
+                // The blocks may still be in use by submitted command buffers.
+                let _ = self.device.device.device_wait_idle();
+                pool.destroy(&self.device.device);
             }
-            if usage.contains(BufferUsage::COPY_SRC) {
-                vk_usage |= vk::BufferUsageFlags::TRANSFER_SRC;
-            }
-            if usage.contains(BufferUsage::COPY_DST) {
-                vk_usage |= vk::BufferUsageFlags::TRANSFER_DST;
-            }
-            let buffer = device.create_buffer(
-                &vk::BufferCreateInfo::builder()
-                    .size(size)
-                    .usage(
-                        vk::BufferUsageFlags::STORAGE_BUFFER
-                            | vk::BufferUsageFlags::TRANSFER_SRC
-                            | vk::BufferUsageFlags::TRANSFER_DST,
-                    )
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                None,
-            )?;
-            let mem_requirements = device.get_buffer_memory_requirements(buffer);
-            let mem_flags = memory_property_flags_for_usage(usage);
-            let mem_type = find_memory_type(
-                mem_requirements.memory_type_bits,
+        }
+    }
+}
+
+impl VkDevice {
+    unsafe fn create_buffer_impl(
+        &self,
+        size: u64,
+        usage: BufferUsage,
+        external: Option<ExternalAlloc>,
+    ) -> Result<Buffer, Error> {
+        let device = &self.device.device;
+        let mut vk_usage = vk::BufferUsageFlags::empty();
+        if usage.contains(BufferUsage::STORAGE) {
+            vk_usage |= vk::BufferUsageFlags::STORAGE_BUFFER;
+        }
+        if usage.contains(BufferUsage::UNIFORM) {
+            vk_usage |= vk::BufferUsageFlags::UNIFORM_BUFFER;
+        }
+        if usage.contains(BufferUsage::INDIRECT) {
+            vk_usage |= vk::BufferUsageFlags::INDIRECT_BUFFER;
+        }
+        if usage.contains(BufferUsage::COPY_SRC) {
+            vk_usage |= vk::BufferUsageFlags::TRANSFER_SRC;
+        }
+        if usage.contains(BufferUsage::COPY_DST) {
+            vk_usage |= vk::BufferUsageFlags::TRANSFER_DST;
+        }
+        let mut external_info = vk::ExternalMemoryBufferCreateInfo::builder();
+        let mut create_info = vk::BufferCreateInfo::builder();
+        if let Some(external) = external {
+            external_info = external_info.handle_types(external.handle_type());
+            create_info = create_info.push_next(&mut external_info);
+        }
+        let buffer = device.create_buffer(
+            &create_info
+                .size(size)
+                // Callers don't reliably request storage and copy usage yet,
+                // so those are always enabled.
+                .usage(
+                    vk_usage
+                        | vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::TRANSFER_SRC
+                        | vk::BufferUsageFlags::TRANSFER_DST,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            None,
+        )?;
+        let mem_requirements = device.get_buffer_memory_requirements(buffer);
+        let mem_flags = memory_property_flags_for_usage(usage);
+        if let Some(external) = external {
+            let buffer_memory = self.allocate_external_memory(
+                external,
+                &mem_requirements,
                 mem_flags,
-                &self.device_mem_props,
-            )
-            .unwrap(); // TODO: proper error
+                vk::MemoryDedicatedAllocateInfo::builder().buffer(buffer),
+            )?;
+            device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+            return Ok(Buffer {
+                buffer,
+                buffer_memory,
+                allocation: None,
+                size,
+            });
+        }
+        let mem_type = find_memory_type(
+            mem_requirements.memory_type_bits,
+            mem_flags,
+            &self.device_mem_props,
+        )
+        .ok_or_else(|| Error::unsupported("memory type for buffer usage"))?;
+        let host_visible = mem_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let allocation = match self.memory_pool.lock().unwrap().as_mut() {
+            Some(pool) => pool.alloc(device, mem_type, host_visible, &mem_requirements)?,
+            None => None,
+        };
+        let buffer_memory = if let Some(allocation) = &allocation {
+            device.bind_buffer_memory(buffer, allocation.memory, allocation.offset)?;
+            allocation.memory
+        } else {
             let buffer_memory = device.allocate_memory(
                 &vk::MemoryAllocateInfo::builder()
                     .allocation_size(mem_requirements.size)
@@ -544,76 +855,118 @@ impl crate::backend::Device for VkDevice {
                 None,
             )?;
             device.bind_buffer_memory(buffer, buffer_memory, 0)?;
-            Ok(Buffer {
-                buffer,
-                buffer_memory,
-                size,
-            })
-        }
-    }
-
-    unsafe fn destroy_buffer(&self, buffer: &Self::Buffer) -> Result<(), Error> {
-        let device = &self.device.device;
-        device.destroy_buffer(buffer.buffer, None);
-        device.free_memory(buffer.buffer_memory, None);
-        Ok(())
+            buffer_memory
+        };
+        Ok(Buffer {
+            buffer,
+            buffer_memory,
+            allocation,
+            size,
+        })
     }
 
-    unsafe fn create_image2d(
+    unsafe fn create_image_impl(
         &self,
         width: u32,
         height: u32,
         format: ImageFormat,
-    ) -> Result<Self::Image, Error> {
+        external: Option<ExternalAlloc>,
+    ) -> Result<Image, Error> {
         let device = &self.device.device;
         let extent = vk::Extent3D {
             width,
             height,
             depth: 1,
         };
-        // TODO: maybe want to fine-tune these for different use cases, especially because we'll
-        // want to add sampling for images and so on.
-        let usage = vk::ImageUsageFlags::STORAGE
-            | vk::ImageUsageFlags::TRANSFER_SRC
-            | vk::ImageUsageFlags::TRANSFER_DST;
-        let vk_format = match format {
-            ImageFormat::A8 => vk::Format::R8_UNORM,
-            ImageFormat::Rgba8 => vk::Format::R8G8B8A8_UNORM,
+        // Images shared as dma-bufs need a layout other APIs understand.
+        let dma_buf = Some(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let tiling = if external.map(|external| external.handle_type()) == dma_buf {
+            vk::ImageTiling::LINEAR
+        } else {
+            vk::ImageTiling::OPTIMAL
         };
+        // Enable every usage the format supports; in particular, sRGB formats
+        // generally can't be storage images.
+        let capabilities = self.tiling_capabilities(format, tiling);
+        if capabilities.is_empty() {
+            return Err(Error::unsupported(format!("image format {:?}", format)));
+        }
+        let mut usage = vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST;
+        if capabilities.contains(FormatCapabilities::STORAGE) {
+            usage |= vk::ImageUsageFlags::STORAGE;
+        }
+        if capabilities.contains(FormatCapabilities::SAMPLED) {
+            usage |= vk::ImageUsageFlags::SAMPLED;
+        }
+        let vk_format = vk_format(format);
+        let mut external_info = vk::ExternalMemoryImageCreateInfo::builder();
+        let mut create_info = vk::ImageCreateInfo::builder();
+        if let Some(external) = external {
+            external_info = external_info.handle_types(external.handle_type());
+            create_info = create_info.push_next(&mut external_info);
+        }
         let image = device.create_image(
-            &vk::ImageCreateInfo::builder()
+            &create_info
                 .image_type(vk::ImageType::TYPE_2D)
                 .format(vk_format)
                 .extent(extent)
                 .mip_levels(1)
                 .array_layers(1)
                 .samples(vk::SampleCountFlags::TYPE_1)
-                .tiling(vk::ImageTiling::OPTIMAL)
+                .tiling(tiling)
                 .initial_layout(vk::ImageLayout::UNDEFINED)
                 .usage(usage)
                 .sharing_mode(vk::SharingMode::EXCLUSIVE),
             None,
         )?;
+        // Check the layout before importing, as the driver owns the fd once
+        // the memory is imported.
+        if let Some(ExternalAlloc::Import(.., Some(expected))) = external {
+            let layout = self.subresource_layout(image);
+            if layout != expected {
+                device.destroy_image(image, None);
+                return Err(Error::unsupported(format!(
+                    "imported image layout {:?}, expected {:?}",
+                    expected, layout
+                )));
+            }
+        }
         let mem_requirements = device.get_image_memory_requirements(image);
         let mem_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
-        let mem_type = find_memory_type(
-            mem_requirements.memory_type_bits,
-            mem_flags,
-            &self.device_mem_props,
-        )
-        .unwrap(); // TODO: proper error
-        let image_memory = device.allocate_memory(
-            &vk::MemoryAllocateInfo::builder()
-                .allocation_size(mem_requirements.size)
-                .memory_type_index(mem_type),
-            None,
-        )?;
+        let image_memory = if let Some(external) = external {
+            let memory = self.allocate_external_memory(
+                external,
+                &mem_requirements,
+                mem_flags,
+                vk::MemoryDedicatedAllocateInfo::builder().image(image),
+            );
+            match memory {
+                Ok(memory) => memory,
+                Err(e) => {
+                    device.destroy_image(image, None);
+                    return Err(e);
+                }
+            }
+        } else {
+            let mem_type = find_memory_type(
+                mem_requirements.memory_type_bits,
+                mem_flags,
+                &self.device_mem_props,
+            )
+            .ok_or_else(|| Error::unsupported("memory type for image"))?;
+            device.allocate_memory(
+                &vk::MemoryAllocateInfo::builder()
+                    .allocation_size(mem_requirements.size)
+                    .memory_type_index(mem_type),
+                None,
+            )?
+        };
         device.bind_image_memory(image, image_memory, 0)?;
         let image_view = device.create_image_view(
             &vk::ImageViewCreateInfo::builder()
                 .view_type(vk::ImageViewType::TYPE_2D)
                 .image(image)
-                .format(vk::Format::R8G8B8A8_UNORM)
+                .format(vk_format)
                 .subresource_range(vk::ImageSubresourceRange {
                     aspect_mask: vk::ImageAspectFlags::COLOR,
                     base_mip_level: 0,
@@ -635,9 +988,428 @@ impl crate::backend::Device for VkDevice {
             image_memory,
             image_view,
             extent,
+            format,
+        })
+    }
+
+    fn tiling_capabilities(
+        &self,
+        format: ImageFormat,
+        tiling: vk::ImageTiling,
+    ) -> FormatCapabilities {
+        let props = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, vk_format(format))
+        };
+        let features = if tiling == vk::ImageTiling::LINEAR {
+            props.linear_tiling_features
+        } else {
+            props.optimal_tiling_features
+        };
+        let mut capabilities = FormatCapabilities::empty();
+        if features.is_empty() {
+            return capabilities;
+        }
+        // Transfer support is implied in Vulkan 1.0, and only reported with
+        // maintenance1, so assume it for any supported format.
+        capabilities |= FormatCapabilities::COPY;
+        if features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE) {
+            capabilities |= FormatCapabilities::SAMPLED;
+        }
+        if features.contains(vk::FormatFeatureFlags::STORAGE_IMAGE) {
+            capabilities |= FormatCapabilities::STORAGE;
+        }
+        if features.contains(vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST) {
+            capabilities |= FormatCapabilities::BLIT;
+        }
+        capabilities
+    }
+
+    /// Allocate dedicated memory for a resource shared outside the device.
+    unsafe fn allocate_external_memory(
+        &self,
+        external: ExternalAlloc,
+        mem_requirements: &vk::MemoryRequirements,
+        mem_flags: vk::MemoryPropertyFlags,
+        mut dedicated_info: vk::MemoryDedicatedAllocateInfoBuilder,
+    ) -> Result<vk::DeviceMemory, Error> {
+        let device = &self.device.device;
+        let memory_fd = self
+            .external
+            .memory_fd
+            .as_ref()
+            .ok_or_else(|| Error::unsupported("external memory"))?;
+        let handle_type = external.handle_type();
+        let mut memory_type_bits = mem_requirements.memory_type_bits;
+        let mut allocation_size = mem_requirements.size;
+        let mut export_info = vk::ExportMemoryAllocateInfo::builder().handle_types(handle_type);
+        let mut import_info = vk::ImportMemoryFdInfoKHR::builder().handle_type(handle_type);
+        if let ExternalAlloc::Import(_, fd, size, _) = external {
+            if size < mem_requirements.size {
+                return Err("imported memory is too small".into());
+            }
+            allocation_size = size;
+            // Opaque fds are only valid for memory types of the exporting device.
+            if handle_type == vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT {
+                memory_type_bits &= memory_fd
+                    .get_memory_fd_properties_khr(handle_type, fd)?
+                    .memory_type_bits;
+            }
+            import_info = import_info.fd(fd);
+        }
+        let mem_type = find_memory_type(memory_type_bits, mem_flags, &self.device_mem_props)
+            .ok_or_else(|| Error::unsupported("memory type for external memory"))?;
+        let mut allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(allocation_size)
+            .memory_type_index(mem_type)
+            .push_next(&mut dedicated_info);
+        allocate_info = match external {
+            ExternalAlloc::Export(_) => allocate_info.push_next(&mut export_info),
+            ExternalAlloc::Import(..) => allocate_info.push_next(&mut import_info),
+        };
+        Ok(device.allocate_memory(&allocate_info, None)?)
+    }
+
+    /// The handle type for sharing memory, if the device supports it.
+    #[cfg(target_os = "linux")]
+    fn external_handle_type(
+        &self,
+        handle_type: ExternalHandleType,
+    ) -> Result<vk::ExternalMemoryHandleTypeFlags, Error> {
+        let supported = match handle_type {
+            ExternalHandleType::OpaqueFd => self.external.memory_fd.is_some(),
+            ExternalHandleType::DmaBuf => self.external.has_dma_buf,
+        };
+        if !supported {
+            return Err(Error::unsupported(format!(
+                "{:?} external memory",
+                handle_type
+            )));
+        }
+        Ok(match handle_type {
+            ExternalHandleType::OpaqueFd => vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD,
+            ExternalHandleType::DmaBuf => vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT,
+        })
+    }
+
+    /// Create a buffer whose memory can be exported.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_buffer_exportable(
+        &self,
+        size: u64,
+        usage: BufferUsage,
+        handle_type: ExternalHandleType,
+    ) -> Result<Buffer, Error> {
+        let handle_type = self.external_handle_type(handle_type)?;
+        self.create_buffer_impl(size, usage, Some(ExternalAlloc::Export(handle_type)))
+    }
+
+    /// Create an image whose memory can be exported.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_image2d_exportable(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+        handle_type: ExternalHandleType,
+    ) -> Result<Image, Error> {
+        let handle_type = self.external_handle_type(handle_type)?;
+        self.create_image_impl(
+            width,
+            height,
+            format,
+            Some(ExternalAlloc::Export(handle_type)),
+        )
+    }
+
+    /// Export the memory of a buffer created with
+    /// [`create_buffer_exportable`](VkDevice::create_buffer_exportable).
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_buffer(
+        &self,
+        buffer: &Buffer,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        let size = self
+            .device
+            .device
+            .get_buffer_memory_requirements(buffer.buffer)
+            .size;
+        let fd = self.export_memory(buffer.buffer_memory, handle_type)?;
+        Ok(ExternalMemory {
+            fd,
+            handle_type,
+            size,
+            layout: None,
         })
     }
 
+    /// Export the memory of an image created with
+    /// [`create_image2d_exportable`](VkDevice::create_image2d_exportable).
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_image(
+        &self,
+        image: &Image,
+        handle_type: ExternalHandleType,
+    ) -> Result<ExternalMemory, Error> {
+        let size = self
+            .device
+            .device
+            .get_image_memory_requirements(image.image)
+            .size;
+        let layout = self.linear_image_layout(image, handle_type);
+        let fd = self.export_memory(image.image_memory, handle_type)?;
+        Ok(ExternalMemory {
+            fd,
+            handle_type,
+            size,
+            layout,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn export_memory(
+        &self,
+        memory: vk::DeviceMemory,
+        handle_type: ExternalHandleType,
+    ) -> Result<i32, Error> {
+        let vk_handle_type = self.external_handle_type(handle_type)?;
+        let memory_fd = self.external.memory_fd.as_ref().unwrap();
+        Ok(memory_fd.get_memory_fd(
+            &vk::MemoryGetFdInfoKHR::builder()
+                .memory(memory)
+                .handle_type(vk_handle_type),
+        )?)
+    }
+
+    /// The placement of the pixels of a linear image, as shared in a dma-buf.
+    #[cfg(target_os = "linux")]
+    unsafe fn linear_image_layout(
+        &self,
+        image: &Image,
+        handle_type: ExternalHandleType,
+    ) -> Option<BufferImageLayout> {
+        if handle_type != ExternalHandleType::DmaBuf {
+            return None;
+        }
+        Some(self.subresource_layout(image.image))
+    }
+
+    /// The placement of the pixels of an image with linear tiling.
+    unsafe fn subresource_layout(&self, image: vk::Image) -> BufferImageLayout {
+        let layout = self.device.device.get_image_subresource_layout(
+            image,
+            vk::ImageSubresource {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                array_layer: 0,
+            },
+        );
+        BufferImageLayout {
+            offset: layout.offset,
+            row_pitch: layout.row_pitch as u32,
+        }
+    }
+
+    /// Create a buffer using imported memory.
+    ///
+    /// On success, the fd is owned by the driver.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_buffer(
+        &self,
+        memory: ExternalMemory,
+        size: u64,
+        usage: BufferUsage,
+    ) -> Result<Buffer, Error> {
+        let handle_type = self.external_handle_type(memory.handle_type)?;
+        let import = ExternalAlloc::Import(handle_type, memory.fd, memory.size, None);
+        self.create_buffer_impl(size, usage, Some(import))
+    }
+
+    /// Create an image using imported memory.
+    ///
+    /// For dma-bufs, the layout of the memory must match the layout this
+    /// device chooses for a linear image. On success, the fd is owned by
+    /// the driver. The layout is checked before the memory is imported, so
+    /// a mismatch leaves the fd with the caller.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_image2d(
+        &self,
+        memory: ExternalMemory,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Image, Error> {
+        let handle_type = self.external_handle_type(memory.handle_type)?;
+        let layout = match memory.handle_type {
+            ExternalHandleType::DmaBuf => memory.layout,
+            ExternalHandleType::OpaqueFd => None,
+        };
+        let import = ExternalAlloc::Import(handle_type, memory.fd, memory.size, layout);
+        self.create_image_impl(width, height, format, Some(import))
+    }
+
+    /// Create a semaphore that can be exported as an opaque fd.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn create_semaphore_exportable(&self) -> Result<vk::Semaphore, Error> {
+        if self.external.semaphore_fd.is_none() {
+            return Err(Error::unsupported("external semaphores"));
+        }
+        let mut export_info = vk::ExportSemaphoreCreateInfo::builder()
+            .handle_types(vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD);
+        Ok(self.device.device.create_semaphore(
+            &vk::SemaphoreCreateInfo::builder().push_next(&mut export_info),
+            None,
+        )?)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn export_semaphore(&self, semaphore: vk::Semaphore) -> Result<i32, Error> {
+        let semaphore_fd = self
+            .external
+            .semaphore_fd
+            .as_ref()
+            .ok_or_else(|| Error::unsupported("external semaphores"))?;
+        Ok(semaphore_fd.get_semaphore_fd(
+            &vk::SemaphoreGetFdInfoKHR::builder()
+                .semaphore(semaphore)
+                .handle_type(vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD),
+        )?)
+    }
+
+    /// Create a semaphore from an opaque fd.
+    ///
+    /// On success, the fd is owned by the driver.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn import_semaphore(&self, fd: i32) -> Result<vk::Semaphore, Error> {
+        let semaphore_fd = self
+            .external
+            .semaphore_fd
+            .as_ref()
+            .ok_or_else(|| Error::unsupported("external semaphores"))?;
+        let semaphore = self.create_semaphore()?;
+        let result = semaphore_fd.import_semaphore_fd(
+            &vk::ImportSemaphoreFdInfoKHR::builder()
+                .semaphore(semaphore)
+                .handle_type(vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD)
+                .fd(fd),
+        );
+        if let Err(e) = result {
+            self.device.device.destroy_semaphore(semaphore, None);
+            return Err(e.into());
+        }
+        Ok(semaphore)
+    }
+}
+
+impl ExternalAlloc {
+    fn handle_type(&self) -> vk::ExternalMemoryHandleTypeFlags {
+        match *self {
+            ExternalAlloc::Export(handle_type) => handle_type,
+            ExternalAlloc::Import(handle_type, ..) => handle_type,
+        }
+    }
+}
+
+impl crate::backend::Device for VkDevice {
+    type Buffer = Buffer;
+    type Image = Image;
+    type CmdBuf = CmdBuf;
+    type DescriptorSet = DescriptorSet;
+    type Pipeline = Pipeline;
+    type QueryPool = QueryPool;
+    type Fence = vk::Fence;
+    type Semaphore = vk::Semaphore;
+    type DescriptorSetBuilder = DescriptorSetBuilder;
+    type Sampler = vk::Sampler;
+    type ShaderSource = [u8];
+    type PipelineCache = vk::PipelineCache;
+
+    fn query_gpu_info(&self) -> GpuInfo {
+        self.gpu_info.clone()
+    }
+
+    fn enable_memory_pool(&self, block_size: u64) -> Result<(), Error> {
+        let mut memory_pool = self.memory_pool.lock().unwrap();
+        let new_pool = pool::MemoryPool::new(block_size);
+        match memory_pool.as_ref() {
+            Some(pool) if pool.block_size() != new_pool.block_size() => {
+                return Err(format!(
+                    "memory pool already enabled with block size {}",
+                    pool.block_size()
+                )
+                .into());
+            }
+            Some(_) => (),
+            None => *memory_pool = Some(new_pool),
+        }
+        Ok(())
+    }
+
+    fn memory_pool_stats(&self) -> MemoryPoolStats {
+        match self.memory_pool.lock().unwrap().as_ref() {
+            Some(pool) => pool.stats(),
+            None => MemoryPoolStats::default(),
+        }
+    }
+
+    fn query_memory_budget(&self) -> Option<Vec<MemoryBudget>> {
+        if !self.has_memory_budget {
+            return None;
+        }
+        let mut budget_props = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut mem_props =
+            vk::PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget_props);
+        unsafe {
+            self.instance
+                .get_physical_device_memory_properties2(self.physical_device, &mut mem_props);
+        }
+        let heap_count = mem_props.memory_properties.memory_heap_count as usize;
+        Some(
+            budget_props.heap_budget[..heap_count]
+                .iter()
+                .zip(&budget_props.heap_usage)
+                .map(|(&budget, &usage)| MemoryBudget { budget, usage })
+                .collect(),
+        )
+    }
+
+    unsafe fn trim_memory_pool(&self) {
+        if let Some(pool) = self.memory_pool.lock().unwrap().as_mut() {
+            pool.trim(&self.device.device);
+        }
+    }
+
+    fn create_buffer(&self, size: u64, usage: BufferUsage) -> Result<Buffer, Error> {
+        unsafe { self.create_buffer_impl(size, usage, None) }
+    }
+
+    unsafe fn destroy_buffer(&self, buffer: &Self::Buffer) -> Result<(), Error> {
+        let device = &self.device.device;
+        device.destroy_buffer(buffer.buffer, None);
+        if let Some(allocation) = &buffer.allocation {
+            if let Some(pool) = self.memory_pool.lock().unwrap().as_mut() {
+                pool.free(allocation);
+            }
+        } else {
+            device.free_memory(buffer.buffer_memory, None);
+        }
+        Ok(())
+    }
+
+    fn format_capabilities(&self, format: ImageFormat) -> FormatCapabilities {
+        self.tiling_capabilities(format, vk::ImageTiling::OPTIMAL)
+    }
+
+    unsafe fn create_image2d(
+        &self,
+        width: u32,
+        height: u32,
+        format: ImageFormat,
+    ) -> Result<Self::Image, Error> {
+        self.create_image_impl(width, height, format, None)
+    }
+
     unsafe fn destroy_image(&self, image: &Self::Image) -> Result<(), Error> {
         let device = &self.device.device;
         device.destroy_image(image.image, None);
@@ -683,19 +1455,18 @@ impl crate::backend::Device for VkDevice {
         &self,
         code: &[u8],
         bind_types: &[BindType],
+        options: &PipelineOptions,
+        cache: Option<&vk::PipelineCache>,
     ) -> Result<Pipeline, Error> {
         let device = &self.device.device;
+        let (bind_types, push_constant_size) = BindType::split_push_constants(bind_types)?;
         let bindings = bind_types
             .iter()
             .enumerate()
             .map(|(i, bind_type)| {
-                let descriptor_type = match bind_type {
-                    BindType::Buffer | BindType::BufReadOnly => vk::DescriptorType::STORAGE_BUFFER,
-                    BindType::Image | BindType::ImageRead => vk::DescriptorType::STORAGE_IMAGE,
-                };
                 vk::DescriptorSetLayoutBinding::builder()
                     .binding(i.try_into().unwrap())
-                    .descriptor_type(descriptor_type)
+                    .descriptor_type(descriptor_type(*bind_type))
                     .descriptor_count(1)
                     .stage_flags(vk::ShaderStageFlags::COMPUTE)
                     .build()
@@ -712,22 +1483,59 @@ impl crate::backend::Device for VkDevice {
         let compute_shader_module = device
             .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&code_u32), None)?;
         let entry_name = CString::new("main").unwrap();
+        let push_constant_ranges = [vk::PushConstantRange::builder()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .offset(0)
+            .size(push_constant_size)
+            .build()];
+        let n_push_constant_ranges = if push_constant_size > 0 { 1 } else { 0 };
         let pipeline_layout = device.create_pipeline_layout(
-            &vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_set_layouts),
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&descriptor_set_layouts)
+                .push_constant_ranges(&push_constant_ranges[..n_push_constant_ranges]),
             None,
         )?;
 
+        // Every constant is 32 bits, including bools.
+        let spec_data = options
+            .constants
+            .iter()
+            .flat_map(|c| c.value.to_bits().to_ne_bytes())
+            .collect::<Vec<u8>>();
+        let spec_entries = options
+            .constants
+            .iter()
+            .enumerate()
+            .map(|(i, c)| vk::SpecializationMapEntry {
+                constant_id: c.id,
+                offset: i as u32 * 4,
+                size: 4,
+            })
+            .collect::<Vec<_>>();
+        let spec_info = vk::SpecializationInfo::builder()
+            .map_entries(&spec_entries)
+            .data(&spec_data);
+        let subgroup_size = options.subgroup_size.filter(|size| {
+            self.gpu_info.subgroup_size.as_ref().map_or(false, |range| {
+                range.control && size.is_power_of_two() && (range.min..=range.max).contains(size)
+            })
+        });
+        let mut required_subgroup_size =
+            vk::PipelineShaderStageRequiredSubgroupSizeCreateInfoEXT::builder();
+        let mut stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(compute_shader_module)
+            .name(&entry_name)
+            .specialization_info(&spec_info);
+        if let Some(size) = subgroup_size {
+            required_subgroup_size = required_subgroup_size.required_subgroup_size(size);
+            stage = stage.push_next(&mut required_subgroup_size);
+        }
         let pipeline = device
             .create_compute_pipelines(
-                vk::PipelineCache::null(),
+                cache.copied().unwrap_or_default(),
                 &[vk::ComputePipelineCreateInfo::builder()
-                    .stage(
-                        vk::PipelineShaderStageCreateInfo::builder()
-                            .stage(vk::ShaderStageFlags::COMPUTE)
-                            .module(compute_shader_module)
-                            .name(&entry_name)
-                            .build(),
-                    )
+                    .stage(stage.build())
                     .layout(pipeline_layout)
                     .build()],
                 None,
@@ -737,14 +1545,46 @@ impl crate::backend::Device for VkDevice {
             pipeline,
             pipeline_layout,
             descriptor_set_layout,
+            bind_types,
+            subgroup_size,
         })
     }
 
+    fn pipeline_cache_key(&self) -> Vec<u8> {
+        self.pipeline_cache_key.clone()
+    }
+
+    unsafe fn create_pipeline_cache(
+        &self,
+        data: &[u8],
+    ) -> Result<(vk::PipelineCache, bool), Error> {
+        let device = &self.device.device;
+        let cache = device.create_pipeline_cache(
+            &vk::PipelineCacheCreateInfo::builder().initial_data(data),
+            None,
+        )?;
+        // The driver ignores data with a header that doesn't match the
+        // device, without reporting it.
+        let header_len = 4 + self.pipeline_cache_header.len();
+        let restored = data.get(4..header_len) == Some(&self.pipeline_cache_header[..]);
+        Ok((cache, restored))
+    }
+
+    unsafe fn pipeline_cache_data(&self, cache: &vk::PipelineCache) -> Result<Vec<u8>, Error> {
+        Ok(self.device.device.get_pipeline_cache_data(*cache)?)
+    }
+
+    unsafe fn destroy_pipeline_cache(&self, cache: &vk::PipelineCache) -> Result<(), Error> {
+        self.device.device.destroy_pipeline_cache(*cache, None);
+        Ok(())
+    }
+
     unsafe fn descriptor_set_builder(&self) -> DescriptorSetBuilder {
         DescriptorSetBuilder {
             buffers: Vec::new(),
             images: Vec::new(),
             textures: Vec::new(),
+            sampled_images: Vec::new(),
         }
     }
 
@@ -791,13 +1631,18 @@ impl crate::backend::Device for VkDevice {
         );
     }
 
-    fn create_cmd_buf(&self) -> Result<CmdBuf, Error> {
+    fn has_dedicated_queue(&self, queue: QueueType) -> bool {
+        queue == QueueType::Main || self.queues.get(queue).1 != self.queues.main.1
+    }
+
+    fn create_cmd_buf(&self, queue: QueueType) -> Result<CmdBuf, Error> {
         unsafe {
             let device = &self.device.device;
+            let (_, qfi) = self.queues.get(queue);
             let cmd_pool = device.create_command_pool(
                 &vk::CommandPoolCreateInfo::builder()
                     .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-                    .queue_family_index(self.qfi),
+                    .queue_family_index(qfi),
                 None,
             )?;
             let cmd_buf = device.allocate_command_buffers(
@@ -810,6 +1655,7 @@ impl crate::backend::Device for VkDevice {
                 cmd_buf,
                 cmd_pool,
                 device: self.device.clone(),
+                queues: self.queues,
                 end_query: None,
             })
         }
@@ -858,6 +1704,7 @@ impl crate::backend::Device for VkDevice {
         wait_semaphores: &[&Self::Semaphore],
         signal_semaphores: &[&Self::Semaphore],
         fence: Option<&mut Self::Fence>,
+        queue: QueueType,
     ) -> Result<(), Error> {
         let device = &self.device.device;
 
@@ -884,7 +1731,7 @@ impl crate::backend::Device for VkDevice {
             .copied()
             .collect::<SmallVec<[_; 2]>>();
         device.queue_submit(
-            self.queue,
+            self.queues.get(queue).0,
             &[vk::SubmitInfo::builder()
                 .command_buffers(&cmd_bufs)
                 .wait_semaphores(&wait_semaphores)
@@ -903,6 +1750,15 @@ impl crate::backend::Device for VkDevice {
         size: u64,
         _mode: MapMode,
     ) -> Result<*mut u8, Error> {
+        if let Some(allocation) = &buffer.allocation {
+            // Pooled host visible blocks are persistently mapped.
+            let mut pool = self.memory_pool.lock().unwrap();
+            let ptr = pool
+                .as_mut()
+                .and_then(|pool| pool.mapped_ptr(allocation))
+                .ok_or("buffer is not host visible")?;
+            return Ok(ptr.add(offset as usize));
+        }
         let device = &self.device.device;
         let buf = device.map_memory(
             buffer.buffer_memory,
@@ -920,24 +1776,32 @@ impl crate::backend::Device for VkDevice {
         _size: u64,
         _mode: MapMode,
     ) -> Result<(), Error> {
-        self.device.device.unmap_memory(buffer.buffer_memory);
+        if buffer.allocation.is_none() {
+            self.device.device.unmap_memory(buffer.buffer_memory);
+        }
         Ok(())
     }
 
     unsafe fn create_sampler(&self, params: SamplerParams) -> Result<Self::Sampler, Error> {
         let device = &self.device.device;
-        let filter = match params {
-            SamplerParams::Linear => vk::Filter::LINEAR,
-            SamplerParams::Nearest => vk::Filter::NEAREST,
+        let filter = match params.filter {
+            FilterMode::Linear => vk::Filter::LINEAR,
+            FilterMode::Nearest => vk::Filter::NEAREST,
+        };
+        let address_mode = match params.address_mode {
+            AddressMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+            AddressMode::ClampToBorder => vk::SamplerAddressMode::CLAMP_TO_BORDER,
+            AddressMode::Repeat => vk::SamplerAddressMode::REPEAT,
+            AddressMode::MirrorRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
         };
         let sampler = device.create_sampler(
             &vk::SamplerCreateInfo::builder()
                 .mag_filter(filter)
                 .min_filter(filter)
                 .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
-                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
-                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .address_mode_u(address_mode)
+                .address_mode_v(address_mode)
+                .address_mode_w(address_mode)
                 .mip_lod_bias(0.0)
                 .compare_op(vk::CompareOp::NEVER)
                 .min_lod(0.0)
@@ -970,7 +1834,11 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
     unsafe fn flush(&mut self) {}
 
     unsafe fn reset(&mut self) -> bool {
-        true
+        self.end_query = None;
+        self.device
+            .device
+            .reset_command_buffer(self.cmd_buf, vk::CommandBufferResetFlags::empty())
+            .is_ok()
     }
 
     unsafe fn begin_compute_pass(&mut self, desc: &ComputePassDescriptor) {
@@ -989,6 +1857,7 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
         descriptor_set: &DescriptorSet,
         workgroup_count: (u32, u32, u32),
         _workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
     ) {
         let device = &self.device.device;
         device.cmd_bind_pipeline(
@@ -1004,6 +1873,15 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
             &[descriptor_set.descriptor_set],
             &[],
         );
+        if !push_constants.is_empty() {
+            device.cmd_push_constants(
+                self.cmd_buf,
+                pipeline.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
         device.cmd_dispatch(
             self.cmd_buf,
             workgroup_count.0,
@@ -1012,6 +1890,41 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
         );
     }
 
+    unsafe fn dispatch_indirect(
+        &mut self,
+        pipeline: &Pipeline,
+        descriptor_set: &DescriptorSet,
+        buffer: &Buffer,
+        offset: u64,
+        _workgroup_size: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        let device = &self.device.device;
+        device.cmd_bind_pipeline(
+            self.cmd_buf,
+            vk::PipelineBindPoint::COMPUTE,
+            pipeline.pipeline,
+        );
+        device.cmd_bind_descriptor_sets(
+            self.cmd_buf,
+            vk::PipelineBindPoint::COMPUTE,
+            pipeline.pipeline_layout,
+            0,
+            &[descriptor_set.descriptor_set],
+            &[],
+        );
+        if !push_constants.is_empty() {
+            device.cmd_push_constants(
+                self.cmd_buf,
+                pipeline.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
+        device.cmd_dispatch_indirect(self.cmd_buf, buffer.buffer, offset);
+    }
+
     unsafe fn end_compute_pass(&mut self) {
         if let Some((pool, end)) = self.end_query.take() {
             self.write_timestamp_raw(pool, end);
@@ -1082,6 +1995,74 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
         );
     }
 
+    unsafe fn buffer_queue_transfer(&mut self, buffer: &Buffer, src: QueueType, dst: QueueType) {
+        let (_, src_qfi) = self.queues.get(src);
+        let (_, dst_qfi) = self.queues.get(dst);
+        if src_qfi == dst_qfi {
+            self.memory_barrier();
+            return;
+        }
+        let device = &self.device.device;
+        device.cmd_pipeline_barrier(
+            self.cmd_buf,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[vk::BufferMemoryBarrier::builder()
+                .buffer(buffer.buffer)
+                .offset(0)
+                .size(vk::WHOLE_SIZE)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .src_queue_family_index(src_qfi)
+                .dst_queue_family_index(dst_qfi)
+                .build()],
+            &[],
+        );
+    }
+
+    unsafe fn image_queue_transfer(
+        &mut self,
+        image: &Image,
+        src_layout: ImageLayout,
+        dst_layout: ImageLayout,
+        src: QueueType,
+        dst: QueueType,
+    ) {
+        let (_, src_qfi) = self.queues.get(src);
+        let (_, dst_qfi) = self.queues.get(dst);
+        if src_qfi == dst_qfi {
+            self.image_barrier(image, src_layout, dst_layout);
+            return;
+        }
+        let device = &self.device.device;
+        device.cmd_pipeline_barrier(
+            self.cmd_buf,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[vk::ImageMemoryBarrier::builder()
+                .image(image.image)
+                .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                .old_layout(map_image_layout(src_layout))
+                .new_layout(map_image_layout(dst_layout))
+                .src_queue_family_index(src_qfi)
+                .dst_queue_family_index(dst_qfi)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: vk::REMAINING_MIP_LEVELS,
+                    base_array_layer: 0,
+                    layer_count: vk::REMAINING_MIP_LEVELS,
+                })
+                .build()],
+        );
+    }
+
     unsafe fn clear_buffer(&mut self, buffer: &Buffer, size: Option<u64>) {
         let device = &self.device.device;
         let size = size.unwrap_or(vk::WHOLE_SIZE);
@@ -1187,6 +2168,103 @@ impl crate::backend::CmdBuf<VkDevice> for CmdBuf {
         );
     }
 
+    unsafe fn copy_buffer_region(
+        &mut self,
+        src: &Buffer,
+        src_offset: u64,
+        dst: &Buffer,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        let device = &self.device.device;
+        device.cmd_copy_buffer(
+            self.cmd_buf,
+            src.buffer,
+            dst.buffer,
+            &[vk::BufferCopy {
+                src_offset,
+                dst_offset,
+                size,
+            }],
+        );
+    }
+
+    unsafe fn copy_image_region_to_buffer(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Buffer,
+        layout: BufferImageLayout,
+    ) {
+        let device = &self.device.device;
+        device.cmd_copy_image_to_buffer(
+            self.cmd_buf,
+            src.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            dst.buffer,
+            &[buffer_image_copy(src.format, region, layout)],
+        );
+    }
+
+    unsafe fn copy_buffer_to_image_region(
+        &mut self,
+        src: &Buffer,
+        layout: BufferImageLayout,
+        dst: &Image,
+        region: ImageRegion,
+    ) {
+        let device = &self.device.device;
+        device.cmd_copy_buffer_to_image(
+            self.cmd_buf,
+            src.buffer,
+            dst.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[buffer_image_copy(dst.format, region, layout)],
+        );
+    }
+
+    unsafe fn copy_image_region(
+        &mut self,
+        src: &Image,
+        region: ImageRegion,
+        dst: &Image,
+        dst_origin: (u32, u32),
+    ) {
+        let device = &self.device.device;
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        device.cmd_copy_image(
+            self.cmd_buf,
+            src.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            dst.image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::ImageCopy {
+                src_subresource: subresource,
+                src_offset: vk::Offset3D {
+                    x: region.origin.0 as i32,
+                    y: region.origin.1 as i32,
+                    z: 0,
+                },
+                dst_subresource: subresource,
+                dst_offset: vk::Offset3D {
+                    x: dst_origin.0 as i32,
+                    y: dst_origin.1 as i32,
+                    z: 0,
+                },
+                extent: vk::Extent3D {
+                    width: region.size.0,
+                    height: region.size.1,
+                    depth: 1,
+                },
+            }],
+        );
+    }
+
     unsafe fn reset_query_pool(&mut self, pool: &QueryPool) {
         let device = &self.device.device;
         device.cmd_reset_query_pool(self.cmd_buf, pool.pool, 0, pool.n_queries);
@@ -1227,7 +2305,16 @@ impl CmdBuf {
 
 impl crate::backend::DescriptorSetBuilder<VkDevice> for DescriptorSetBuilder {
     fn add_buffers(&mut self, buffers: &[&Buffer]) {
-        self.buffers.extend(buffers.iter().map(|b| b.buffer));
+        self.buffers
+            .extend(buffers.iter().map(|b| (b.buffer, 0, vk::WHOLE_SIZE)));
+    }
+
+    fn add_buffer_slices(&mut self, slices: &[(&Buffer, u64, u64)]) {
+        self.buffers.extend(
+            slices
+                .iter()
+                .map(|(b, offset, size)| (b.buffer, *offset, *size)),
+        );
     }
 
     fn add_images(&mut self, images: &[&Image]) {
@@ -1238,33 +2325,24 @@ impl crate::backend::DescriptorSetBuilder<VkDevice> for DescriptorSetBuilder {
         self.textures.extend(images.iter().map(|i| i.image_view));
     }
 
+    fn add_sampled_images(&mut self, images: &[&Image], sampler: &vk::Sampler) {
+        self.sampled_images
+            .extend(images.iter().map(|i| (i.image_view, *sampler)));
+    }
+
     unsafe fn build(self, device: &VkDevice, pipeline: &Pipeline) -> Result<DescriptorSet, Error> {
         let device = &device.device.device;
-        let mut descriptor_pool_sizes = Vec::new();
-        if !self.buffers.is_empty() {
-            descriptor_pool_sizes.push(
-                vk::DescriptorPoolSize::builder()
-                    .ty(vk::DescriptorType::STORAGE_BUFFER)
-                    .descriptor_count(self.buffers.len() as u32)
-                    .build(),
-            );
-        }
-        if !self.images.is_empty() {
-            descriptor_pool_sizes.push(
-                vk::DescriptorPoolSize::builder()
-                    .ty(vk::DescriptorType::STORAGE_IMAGE)
-                    .descriptor_count(self.images.len() as u32)
-                    .build(),
-            );
-        }
-        if !self.textures.is_empty() {
-            descriptor_pool_sizes.push(
+        // Duplicate descriptor types are fine here; the counts are summed.
+        let descriptor_pool_sizes = pipeline
+            .bind_types
+            .iter()
+            .map(|bind_type| {
                 vk::DescriptorPoolSize::builder()
-                    .ty(vk::DescriptorType::STORAGE_IMAGE)
-                    .descriptor_count(self.textures.len() as u32)
-                    .build(),
-            );
-        }
+                    .ty(descriptor_type(*bind_type))
+                    .descriptor_count(1)
+                    .build()
+            })
+            .collect::<Vec<_>>();
         let descriptor_pool = device.create_descriptor_pool(
             &vk::DescriptorPoolCreateInfo::builder()
                 .pool_sizes(&descriptor_pool_sizes)
@@ -1282,16 +2360,21 @@ impl crate::backend::DescriptorSetBuilder<VkDevice> for DescriptorSetBuilder {
             .unwrap();
         let mut binding = 0;
         // Maybe one call to update_descriptor_sets with an array of descriptor_writes?
-        for buf in &self.buffers {
+        for (buf, offset, range) in &self.buffers {
+            let bind_type = pipeline.bind_types.get(binding as usize);
+            let descriptor_type = match bind_type {
+                Some(BindType::Uniform) => vk::DescriptorType::UNIFORM_BUFFER,
+                _ => vk::DescriptorType::STORAGE_BUFFER,
+            };
             device.update_descriptor_sets(
                 &[vk::WriteDescriptorSet::builder()
                     .dst_set(descriptor_sets[0])
                     .dst_binding(binding)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_type(descriptor_type)
                     .buffer_info(&[vk::DescriptorBufferInfo::builder()
                         .buffer(*buf)
-                        .offset(0)
-                        .range(vk::WHOLE_SIZE)
+                        .offset(*offset)
+                        .range(*range)
                         .build()])
                     .build()],
                 &[],
@@ -1331,6 +2414,22 @@ impl crate::backend::DescriptorSetBuilder<VkDevice> for DescriptorSetBuilder {
             );
             binding += 1;
         }
+        for (image, sampler) in &self.sampled_images {
+            device.update_descriptor_sets(
+                &[vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_sets[0])
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&[vk::DescriptorImageInfo::builder()
+                        .sampler(*sampler)
+                        .image_view(*image)
+                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                        .build()])
+                    .build()],
+                &[],
+            );
+            binding += 1;
+        }
         Ok(DescriptorSet {
             descriptor_set: descriptor_sets[0],
         })
@@ -1361,9 +2460,14 @@ impl VkSwapchain {
                 height: self.extent.height,
                 depth: 1,
             },
+            format: self.format,
         }
     }
 
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
     pub unsafe fn present(
         &self,
         image_idx: usize,
@@ -1447,21 +2551,114 @@ unsafe fn choose_device(
     instance: &Instance,
     devices: &[vk::PhysicalDevice],
 ) -> Option<(vk::PhysicalDevice, u32)> {
-    for pdevice in devices {
-        let props = instance.get_physical_device_queue_family_properties(*pdevice);
-        for (ix, info) in props.iter().enumerate() {
-            // Select a device that supports both compute and graphics workloads.
-            // This function used to check for surface compatibility but that was removed
-            // to allow device creation without an instantiated surface. This follows from
-            // both Metal and DX12 which do not require such validation. It might be worth
-            // exposing this to the user in a future device enumeration API, which would
-            // also allow selection between discrete and integrated devices.
-            if info.queue_flags.contains(vk::QueueFlags::COMPUTE | vk::QueueFlags::GRAPHICS) {
-                return Some((*pdevice, ix as u32));
-            }
+    devices.iter().find_map(|pdevice| {
+        choose_queue_family(instance, *pdevice).map(|qfi| (*pdevice, qfi))
+    })
+}
+
+unsafe fn choose_queue_family(instance: &Instance, pdevice: vk::PhysicalDevice) -> Option<u32> {
+    let props = instance.get_physical_device_queue_family_properties(pdevice);
+    // Select a queue family that supports both compute and graphics workloads.
+    // This function used to check for surface compatibility but that was removed
+    // to allow device creation without an instantiated surface. This follows from
+    // both Metal and DX12 which do not require such validation.
+    props
+        .iter()
+        .position(|info| {
+            info.queue_flags
+                .contains(vk::QueueFlags::COMPUTE | vk::QueueFlags::GRAPHICS)
+        })
+        .map(|ix| ix as u32)
+}
+
+/// Find a queue family with the required capabilities and none of the
+/// excluded ones, to get a queue that runs independently of the main one.
+fn find_queue_family(
+    props: &[vk::QueueFamilyProperties],
+    required: vk::QueueFlags,
+    excluded: vk::QueueFlags,
+) -> Option<u32> {
+    props
+        .iter()
+        .position(|info| {
+            info.queue_count > 0
+                && info.queue_flags.contains(required)
+                && !info.queue_flags.intersects(excluded)
+        })
+        .map(|ix| ix as u32)
+}
+
+fn subgroup_features(operations: vk::SubgroupFeatureFlags) -> SubgroupFeatures {
+    let mut features = SubgroupFeatures::empty();
+    let mapping = [
+        (vk::SubgroupFeatureFlags::BASIC, SubgroupFeatures::BASIC),
+        (vk::SubgroupFeatureFlags::VOTE, SubgroupFeatures::VOTE),
+        (
+            vk::SubgroupFeatureFlags::ARITHMETIC,
+            SubgroupFeatures::ARITHMETIC,
+        ),
+        (vk::SubgroupFeatureFlags::BALLOT, SubgroupFeatures::BALLOT),
+        (vk::SubgroupFeatureFlags::SHUFFLE, SubgroupFeatures::SHUFFLE),
+        (
+            vk::SubgroupFeatureFlags::SHUFFLE_RELATIVE,
+            SubgroupFeatures::SHUFFLE_RELATIVE,
+        ),
+        (
+            vk::SubgroupFeatureFlags::CLUSTERED,
+            SubgroupFeatures::CLUSTERED,
+        ),
+        (vk::SubgroupFeatureFlags::QUAD, SubgroupFeatures::QUAD),
+    ];
+    for (vk_flag, feature) in mapping {
+        if operations.contains(vk_flag) {
+            features |= feature;
         }
     }
-    None
+    features
+}
+
+fn device_name(props: &vk::PhysicalDeviceProperties) -> String {
+    unsafe { CStr::from_ptr(props.device_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn descriptor_type(bind_type: BindType) -> vk::DescriptorType {
+    match bind_type {
+        BindType::Buffer | BindType::BufReadOnly => vk::DescriptorType::STORAGE_BUFFER,
+        BindType::Image | BindType::ImageRead => vk::DescriptorType::STORAGE_IMAGE,
+        BindType::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+        BindType::SampledImage => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        BindType::PushConstants(_) => unreachable!("push constants have no descriptor"),
+    }
+}
+
+impl From<vk::Result> for Error {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => Error::OutOfDeviceMemory,
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY => Error::OutOfHostMemory,
+            vk::Result::ERROR_DEVICE_LOST => Error::DeviceLost,
+            vk::Result::ERROR_SURFACE_LOST_KHR => Error::SurfaceLost,
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Error::SurfaceOutdated,
+            _ => Error::Backend(Box::new(result)),
+        }
+    }
+}
+
+impl From<ash::InstanceError> for Error {
+    fn from(e: ash::InstanceError) -> Self {
+        match e {
+            ash::InstanceError::VkError(result) => result.into(),
+            _ => Error::Backend(Box::new(e)),
+        }
+    }
+}
+
+impl From<ash::LoadingError> for Error {
+    fn from(e: ash::LoadingError) -> Self {
+        Error::Backend(Box::new(e))
+    }
 }
 
 fn memory_property_flags_for_usage(usage: BufferUsage) -> vk::MemoryPropertyFlags {
@@ -1501,6 +2698,33 @@ fn convert_u32_vec(src: &[u8]) -> Vec<u32> {
         .collect()
 }
 
+fn vk_format(format: ImageFormat) -> vk::Format {
+    match format {
+        ImageFormat::A8 => vk::Format::R8_UNORM,
+        ImageFormat::Rgba8 => vk::Format::R8G8B8A8_UNORM,
+        ImageFormat::Bgra8 => vk::Format::B8G8R8A8_UNORM,
+        ImageFormat::Rgba8Srgb => vk::Format::R8G8B8A8_SRGB,
+        ImageFormat::Bgra8Srgb => vk::Format::B8G8R8A8_SRGB,
+        ImageFormat::Rgba16Float => vk::Format::R16G16B16A16_SFLOAT,
+        ImageFormat::R32Float => vk::Format::R32_SFLOAT,
+        ImageFormat::Rgba32Float => vk::Format::R32G32B32A32_SFLOAT,
+    }
+}
+
+fn image_format(format: vk::Format) -> Option<ImageFormat> {
+    match format {
+        vk::Format::R8_UNORM => Some(ImageFormat::A8),
+        vk::Format::R8G8B8A8_UNORM => Some(ImageFormat::Rgba8),
+        vk::Format::B8G8R8A8_UNORM => Some(ImageFormat::Bgra8),
+        vk::Format::R8G8B8A8_SRGB => Some(ImageFormat::Rgba8Srgb),
+        vk::Format::B8G8R8A8_SRGB => Some(ImageFormat::Bgra8Srgb),
+        vk::Format::R16G16B16A16_SFLOAT => Some(ImageFormat::Rgba16Float),
+        vk::Format::R32_SFLOAT => Some(ImageFormat::R32Float),
+        vk::Format::R32G32B32A32_SFLOAT => Some(ImageFormat::Rgba32Float),
+        _ => None,
+    }
+}
+
 fn map_image_layout(layout: ImageLayout) -> vk::ImageLayout {
     match layout {
         ImageLayout::Undefined => vk::ImageLayout::UNDEFINED,
@@ -1511,3 +2735,32 @@ fn map_image_layout(layout: ImageLayout) -> vk::ImageLayout {
         ImageLayout::ShaderRead => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
     }
 }
+
+fn buffer_image_copy(
+    format: ImageFormat,
+    region: ImageRegion,
+    layout: BufferImageLayout,
+) -> vk::BufferImageCopy {
+    vk::BufferImageCopy {
+        buffer_offset: layout.offset,
+        // Vulkan measures the row length in texels rather than bytes.
+        buffer_row_length: layout.row_pitch / format.bytes_per_pixel(),
+        buffer_image_height: 0, // tight packing
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D {
+            x: region.origin.0 as i32,
+            y: region.origin.1 as i32,
+            z: 0,
+        },
+        image_extent: vk::Extent3D {
+            width: region.size.0,
+            height: region.size.1,
+            depth: 1,
+        },
+    }
+}
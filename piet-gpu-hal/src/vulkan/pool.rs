@@ -0,0 +1,199 @@
+// Copyright 2021 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Sub-allocation of buffer memory from larger blocks.
+
+use std::convert::TryInto;
+
+use ash::{vk, Device};
+
+use crate::bestfit::BestFit;
+use crate::{Error, MemoryPoolStats};
+
+/// The granularity of sub-allocations, in bytes.
+///
+/// This covers the alignment requirements of buffers on common hardware;
+/// buffers needing more are given a dedicated allocation.
+const GRANULE: u64 = 256;
+
+/// A pool of memory blocks, with buffers sub-allocated from them.
+///
+/// Each block has a single memory type and is either mapped or not, so
+/// buffers with different usage (host visible or device local) end up in
+/// different blocks.
+pub struct MemoryPool {
+    block_size: u64,
+    blocks: Vec<Block>,
+}
+
+struct Block {
+    memory: vk::DeviceMemory,
+    mem_type: u32,
+    host_visible: bool,
+    /// The persistent mapping of the block, for host visible memory.
+    ///
+    /// Vulkan doesn't allow memory to be mapped more than once, so the
+    /// whole block is mapped when created.
+    mapped: Option<*mut u8>,
+    allocator: BestFit,
+    n_allocations: usize,
+    allocated_granules: u32,
+}
+
+// The mapped pointer is owned by the block, and only handed out through
+// `MemoryPool::mapped_ptr`.
+unsafe impl Send for Block {}
+
+/// A range of a pooled block.
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    n_granules: u32,
+}
+
+impl MemoryPool {
+    pub fn new(block_size: u64) -> MemoryPool {
+        let block_size = (block_size + GRANULE - 1) / GRANULE * GRANULE;
+        MemoryPool {
+            block_size,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// The size of each block, in bytes.
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Allocate memory for a buffer.
+    ///
+    /// Returns `None` when the requirements can't be met by a block, in which
+    /// case the caller should make a dedicated allocation.
+    pub unsafe fn alloc(
+        &mut self,
+        device: &Device,
+        mem_type: u32,
+        host_visible: bool,
+        requirements: &vk::MemoryRequirements,
+    ) -> Result<Option<Allocation>, Error> {
+        if requirements.size > self.block_size || GRANULE % requirements.alignment != 0 {
+            return Ok(None);
+        }
+        let n_granules = ((requirements.size + GRANULE - 1) / GRANULE).try_into()?;
+        for block in &mut self.blocks {
+            if block.mem_type == mem_type && block.host_visible == host_visible {
+                if let Some(ix) = block.allocator.alloc(n_granules) {
+                    return Ok(Some(block.commit(ix, n_granules)));
+                }
+            }
+        }
+        let memory = device.allocate_memory(
+            &vk::MemoryAllocateInfo::builder()
+                .allocation_size(self.block_size)
+                .memory_type_index(mem_type),
+            None,
+        )?;
+        let mapped = if host_visible {
+            let ptr = device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?;
+            Some(ptr as *mut u8)
+        } else {
+            None
+        };
+        let mut block = Block {
+            memory,
+            mem_type,
+            host_visible,
+            mapped,
+            allocator: BestFit::new((self.block_size / GRANULE).try_into()?),
+            n_allocations: 0,
+            allocated_granules: 0,
+        };
+        let ix = block.allocator.alloc(n_granules).unwrap();
+        let allocation = block.commit(ix, n_granules);
+        self.blocks.push(block);
+        Ok(Some(allocation))
+    }
+
+    pub fn free(&mut self, allocation: &Allocation) {
+        if let Some(block) = self.find_block(allocation.memory) {
+            let ix = (allocation.offset / GRANULE) as u32;
+            block.allocator.free(ix, allocation.n_granules);
+            block.n_allocations -= 1;
+            block.allocated_granules -= allocation.n_granules;
+        }
+    }
+
+    /// The host pointer to the start of an allocation.
+    pub fn mapped_ptr(&mut self, allocation: &Allocation) -> Option<*mut u8> {
+        let mapped = self.find_block(allocation.memory)?.mapped?;
+        unsafe { Some(mapped.add(allocation.offset as usize)) }
+    }
+
+    /// Free all blocks with no live allocations.
+    pub unsafe fn trim(&mut self, device: &Device) {
+        self.blocks.retain(|block| {
+            if block.n_allocations > 0 {
+                return true;
+            }
+            if block.mapped.is_some() {
+                device.unmap_memory(block.memory);
+            }
+            device.free_memory(block.memory, None);
+            false
+        });
+    }
+
+    /// Free all blocks, whether or not they have live allocations.
+    ///
+    /// This is called when the device is dropped; no buffers in the pool may
+    /// be used afterwards.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for block in self.blocks.drain(..) {
+            if block.mapped.is_some() {
+                device.unmap_memory(block.memory);
+            }
+            device.free_memory(block.memory, None);
+        }
+    }
+
+    pub fn stats(&self) -> MemoryPoolStats {
+        let mut stats = MemoryPoolStats::default();
+        for block in &self.blocks {
+            stats.n_blocks += 1;
+            stats.block_bytes += self.block_size;
+            stats.n_allocations += block.n_allocations;
+            stats.allocated_bytes += block.allocated_granules as u64 * GRANULE;
+            stats.largest_free_bytes += block.allocator.largest_free() as u64 * GRANULE;
+        }
+        stats
+    }
+
+    fn find_block(&mut self, memory: vk::DeviceMemory) -> Option<&mut Block> {
+        self.blocks.iter_mut().find(|block| block.memory == memory)
+    }
+}
+
+impl Block {
+    fn commit(&mut self, ix: u32, n_granules: u32) -> Allocation {
+        self.n_allocations += 1;
+        self.allocated_granules += n_granules;
+        Allocation {
+            memory: self.memory,
+            offset: ix as u64 * GRANULE,
+            n_granules,
+        }
+    }
+}
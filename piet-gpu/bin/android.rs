@@ -130,7 +130,8 @@ impl GfxState {
             if self.current_frame >= NUM_FRAMES {
                 let stats = self
                     .render_driver
-                    .get_timing_stats(&self.session, frame_idx);
+                    .get_timing_stats(&self.session, frame_idx)
+                    .unwrap();
                 info_string = stats.short_summary();
                 println!("{}", info_string);
             }
@@ -151,9 +152,8 @@ impl GfxState {
             let cmd_buf = target.cmd_buf;
 
             // Image -> Swapchain
-            cmd_buf.image_barrier(&swap_image, ImageLayout::Undefined, ImageLayout::BlitDst);
             cmd_buf.blit_image(target.image, &swap_image);
-            cmd_buf.image_barrier(&swap_image, ImageLayout::BlitDst, ImageLayout::Present);
+            cmd_buf.transition(&swap_image, ImageLayout::Present);
 
             self.render_driver
                 .submit(
@@ -216,6 +216,8 @@ fn trace_ptcl(buf: &[u32]) {
 }
 
 fn main() -> Result<(), Error> {
+    // Validation messages from the GPU backends are logged.
+    env_logger::init();
     let matches = App::new("piet-gpu test")
         .arg(Arg::with_name("INPUT").index(1))
         .arg(Arg::with_name("flip").short("f").long("flip"))
@@ -225,11 +227,32 @@ fn main() -> Result<(), Error> {
                 .long("scale")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Write GPU timings as a Chrome trace to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pipeline-cache")
+                .long("pipeline-cache")
+                .help("Load compiled pipelines from this file, and save them to it")
+                .takes_value(true),
+        )
         .get_matches();
     let instance = Instance::new(InstanceFlags::default())?;
     unsafe {
         let device = instance.device()?;
         let session = Session::new(device);
+        let cache_path = matches.value_of("pipeline-cache");
+        let pipeline_cache = match cache_path {
+            Some(path) => {
+                let data = std::fs::read(path).ok();
+                Some(session.create_pipeline_cache(data.as_deref())?)
+            }
+            None => None,
+        };
+        session.set_pipeline_cache(pipeline_cache.as_ref());
 
         let mut ctx = PietGpuRenderContext::new();
         if let Some(input) = matches.value_of("INPUT") {
@@ -250,22 +273,35 @@ fn main() -> Result<(), Error> {
             test_scenes::render_blend_grid(&mut ctx);
         }
 
+        let start = std::time::Instant::now();
         let renderer = Renderer::new(&session, WIDTH, HEIGHT, 1)?;
+        println!("renderer creation time: {:?}", start.elapsed());
+        if let (Some(path), Some(cache)) = (cache_path, &pipeline_cache) {
+            let data = session.serialize_pipeline_cache(cache)?;
+            std::fs::write(path, data).unwrap();
+        }
         let mut render_driver = RenderDriver::new(&session, 1, renderer);
         let start = std::time::Instant::now();
         render_driver.upload_render_ctx(&session, &mut ctx)?;
         let image_usage = BufferUsage::MAP_READ | BufferUsage::COPY_DST;
         let image_buf = session.create_buffer((WIDTH * HEIGHT * 4) as u64, image_usage)?;
 
+        if matches.is_present("trace") {
+            render_driver.start_trace();
+        }
         render_driver.run_coarse(&session)?;
         let target = render_driver.record_fine(&session)?;
         target
             .cmd_buf
             .copy_image_to_buffer(target.image, &image_buf);
         render_driver.submit(&session, &[], &[])?;
-        render_driver.wait(&session);
+        render_driver.wait(&session)?;
         println!("elapsed = {:?}", start.elapsed());
-        render_driver.get_timing_stats(&session, 0).print_summary();
+        render_driver.get_timing_stats(&session, 0)?.print_summary();
+        if let Some(path) = matches.value_of("trace") {
+            let trace = render_driver.finish_trace().unwrap();
+            trace.save(path).unwrap();
+        }
 
         let mut img_data: Vec<u8> = Default::default();
         // Note: because png can use a `&[u8]` slice, we could avoid an extra copy
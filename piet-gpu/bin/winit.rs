@@ -17,7 +17,9 @@ const NUM_FRAMES: usize = 2;
 const WIDTH: usize = 2048;
 const HEIGHT: usize = 1536;
 
-fn main() -> Result<(), Error> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Validation messages from the GPU backends are logged.
+    env_logger::init();
     let matches = App::new("piet-gpu test")
         .arg(Arg::with_name("INPUT").index(1))
         .arg(Arg::with_name("flip").short("f").long("flip"))
@@ -103,7 +105,7 @@ fn main() -> Result<(), Error> {
                     let frame_idx = current_frame % NUM_FRAMES;
 
                     if current_frame >= NUM_FRAMES {
-                        let stats = render_driver.get_timing_stats(&session, frame_idx);
+                        let stats = render_driver.get_timing_stats(&session, frame_idx).unwrap();
                         info_string = stats.short_summary();
                     }
 
@@ -162,13 +164,8 @@ fn main() -> Result<(), Error> {
                     let cmd_buf = target.cmd_buf;
 
                     // Image -> Swapchain
-                    cmd_buf.image_barrier(
-                        &swap_image,
-                        ImageLayout::Undefined,
-                        ImageLayout::BlitDst,
-                    );
                     cmd_buf.blit_image(target.image, &swap_image);
-                    cmd_buf.image_barrier(&swap_image, ImageLayout::BlitDst, ImageLayout::Present);
+                    cmd_buf.transition(&swap_image, ImageLayout::Present);
                     render_driver
                         .submit(
                             &session,
@@ -185,7 +182,9 @@ fn main() -> Result<(), Error> {
                     current_frame += 1;
                 }
                 Event::LoopDestroyed => {
-                    render_driver.wait_all(&session);
+                    if let Err(e) = render_driver.wait_all(&session) {
+                        println!("error in waiting: {}", e);
+                    }
                 }
                 _ => (),
             }
@@ -23,7 +23,7 @@ use piet::{ImageFormat, RenderContext};
 
 use piet_gpu_hal::{
     include_shader, BindType, Buffer, BufferUsage, CmdBuf, ComputePassDescriptor, DescriptorSet,
-    Error, Image, ImageLayout, Pipeline, QueryPool, Session,
+    Error, Image, ImageLayout, ImageRegion, Pipeline, Session,
 };
 
 pub use pico_svg::PicoSvg;
@@ -54,6 +54,7 @@ pub fn dump_k1_data(k1_buf: &[u32]) {
     }
 }
 
+#[derive(Clone)]
 pub struct RenderConfig {
     width: usize,
     height: usize,
@@ -61,13 +62,14 @@ pub struct RenderConfig {
 }
 
 // Should we just use the enum from piet-gpu-hal?
+#[derive(Clone, Copy)]
 pub enum PixelFormat {
     A8,
     Rgba8,
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 pub(crate) struct MemoryHeader {
     mem_offset: u32,
     mem_error: u32,
@@ -97,6 +99,13 @@ pub struct Renderer {
     width: usize,
     height: usize,
 
+    // The configuration the renderer was created with, for recreation.
+    config: RenderConfig,
+    n_bufs: usize,
+
+    // Uploads go through the staging buffers of the session.
+    session: Session,
+
     pub image_dev: Image, // resulting image
 
     // TODO: two changes needed here. First, if we're fencing on the coarse
@@ -105,15 +114,17 @@ pub struct Renderer {
     // copying). Second, there should be a staging buffer for discrete cards.
     scene_bufs: Vec<Buffer>,
 
-    memory_buf_host: Vec<Buffer>,
     memory_buf_dev: Buffer,
     memory_buf_readback: Buffer,
 
-    // Staging buffers
-    config_bufs: Vec<Buffer>,
     // Device config buf
     config_buf: Buffer,
 
+    // The values uploaded to the config and memory buffers at the start of
+    // the pipeline, for each scene buffer.
+    configs: Vec<Config>,
+    memory_headers: Vec<MemoryHeader>,
+
     blend_buf: Buffer,
 
     // New element pipeline
@@ -155,7 +166,8 @@ pub struct Renderer {
     // Keep a reference to the image so that it is not destroyed.
     _bg_image: Image,
 
-    gradient_bufs: Vec<Buffer>,
+    // The gradient ramps of each scene, uploaded to the gradient image.
+    ramp_data: Vec<Vec<u32>>,
     gradients: Image,
 }
 
@@ -175,14 +187,11 @@ impl RenderConfig {
 }
 
 impl Renderer {
-    /// The number of query pool entries needed to run the renderer.
-    pub const QUERY_POOL_SIZE: u32 = Self::COARSE_QUERY_POOL_SIZE + Self::FINE_QUERY_POOL_SIZE;
+    /// The number of timer scopes recorded by the coarse pipeline.
+    pub const COARSE_SCOPES: u32 = 5;
 
-    /// The number of query pool entries needed to run the coarse pipeline.
-    pub const COARSE_QUERY_POOL_SIZE: u32 = 10;
-
-    /// The number of query pool entries needed to run the fine pipeline.
-    pub const FINE_QUERY_POOL_SIZE: u32 = 2;
+    /// The number of timer scopes recorded by the fine pipeline.
+    pub const FINE_SCOPES: u32 = 1;
 
     pub unsafe fn new(
         session: &Session,
@@ -195,6 +204,9 @@ impl Renderer {
     }
 
     /// Create a new renderer.
+    ///
+    /// The CPU backend isn't supported yet, as the kernels have no CPU ports;
+    /// there, this fails with `Error::Unsupported`.
     pub unsafe fn new_from_config(
         session: &Session,
         config: RenderConfig,
@@ -228,21 +240,6 @@ impl Renderer {
 
         const CONFIG_BUFFER_SIZE: u64 = std::mem::size_of::<Config>() as u64;
         let config_buf = session.create_buffer(CONFIG_BUFFER_SIZE, dev).unwrap();
-        let config_bufs = (0..n_bufs)
-            .map(|_| {
-                session
-                    .create_buffer(CONFIG_BUFFER_SIZE, usage_upload)
-                    .unwrap()
-            })
-            .collect();
-
-        let memory_buf_host = (0..n_bufs)
-            .map(|_| {
-                session
-                    .create_buffer(std::mem::size_of::<MemoryHeader>() as u64, usage_upload)
-                    .unwrap()
-            })
-            .collect();
         let target_dependent_size =
             (width / TILE_W) as u64 * (height / TILE_H) as u64 * PTCL_INITIAL_ALLOC as u64;
         let memory_buf_dev =
@@ -333,15 +330,6 @@ impl Renderer {
             .collect::<Result<Vec<_>, _>>()?;
         let bg_image = Self::make_test_bg_image(&session);
 
-        const GRADIENT_BUF_SIZE: usize =
-            crate::gradient::N_GRADIENTS * crate::gradient::N_SAMPLES * 4;
-        let gradient_bufs = (0..n_bufs)
-            .map(|_| {
-                session
-                    .create_buffer(GRADIENT_BUF_SIZE as u64, usage_upload)
-                    .unwrap()
-            })
-            .collect();
         let gradients = Self::make_gradient_image(&session);
 
         let k4_code = match config.format {
@@ -370,12 +358,15 @@ impl Renderer {
         Ok(Renderer {
             width,
             height,
+            config,
+            n_bufs,
+            session: session.clone(),
             scene_bufs,
-            memory_buf_host,
             memory_buf_dev,
             memory_buf_readback,
             config_buf,
-            config_bufs,
+            configs: vec![Default::default(); n_bufs],
+            memory_headers: vec![Default::default(); n_bufs],
             blend_buf,
             image_dev,
             element_code,
@@ -404,11 +395,26 @@ impl Renderer {
             n_pathtag: 0,
             n_clip: 0,
             _bg_image: bg_image,
-            gradient_bufs,
+            ramp_data: vec![Vec::new(); n_bufs],
             gradients,
         })
     }
 
+    /// Create a renderer with the same configuration on another session.
+    ///
+    /// This is how to recover from device loss: the pipelines and buffers of
+    /// this renderer belong to the lost device, so they are all recreated.
+    /// It fails if this renderer's session isn't lost, or if the new one is.
+    pub unsafe fn recreate(&self, session: &Session) -> Result<Self, Error> {
+        if !self.session.is_lost() {
+            return Err("renderer recreated while its session is not lost".into());
+        }
+        if session.is_lost() {
+            return Err(Error::DeviceLost);
+        }
+        Self::new_from_config(session, self.config.clone(), self.n_bufs)
+    }
+
     /// Convert the scene in the render context to GPU resources.
     ///
     /// At present, this requires that any command buffer submission has completed.
@@ -427,17 +433,9 @@ impl Renderer {
                 let mut mapped_scene = self.scene_bufs[buf_ix].map_write(..)?;
                 render_ctx.write_scene(&mut mapped_scene);
             }
-
-            // Upload gradient data.
-            let ramp_data = render_ctx.get_ramp_data();
-            if !ramp_data.is_empty() {
-                assert!(
-                    self.gradient_bufs[buf_ix].size() as usize
-                        >= std::mem::size_of_val(&*ramp_data)
-                );
-                self.gradient_bufs[buf_ix].write(&ramp_data)?;
-            }
         }
+        self.ramp_data[buf_ix] = render_ctx.get_ramp_data();
+        assert!(self.ramp_data[buf_ix].len() <= gradient::N_GRADIENTS * gradient::N_SAMPLES);
         Ok(())
     }
 
@@ -454,16 +452,9 @@ impl Renderer {
                 let mut mapped_scene = self.scene_bufs[buf_ix].map_write(..)?;
                 scene.write_scene(&mut mapped_scene);
             }
-
-            // Upload gradient data.
-            if !scene.ramp_data.is_empty() {
-                assert!(
-                    self.gradient_bufs[buf_ix].size() as usize
-                        >= std::mem::size_of_val(&*scene.ramp_data)
-                );
-                self.gradient_bufs[buf_ix].write(scene.ramp_data)?;
-            }
         }
+        self.ramp_data[buf_ix] = scene.ramp_data.to_vec();
+        assert!(self.ramp_data[buf_ix].len() <= gradient::N_GRADIENTS * gradient::N_SAMPLES);
         Ok(())
     }
 
@@ -479,14 +470,14 @@ impl Renderer {
         self.n_clip = stats.n_clip;
         let (mut config, alloc) = stats.config(self.width, self.height);
         config.mem_size = self.memory_buf_size() as u32;
-        self.config_bufs[buf_ix].write(&[config])?;
+        self.configs[buf_ix] = config;
         let mem_header = MemoryHeader {
             mem_offset: alloc as u32,
             mem_error: 0,
             blend_offset: 0,
         };
         // Note: we could skip doing this on realloc, but probably not worth the bother
-        self.memory_buf_host[buf_ix].write(&[mem_header])?;
+        self.memory_headers[buf_ix] = mem_header;
         Ok(())
     }
 
@@ -496,31 +487,34 @@ impl Renderer {
     }
 
     /// Record the coarse part of a render pipeline.
-    pub unsafe fn record_coarse(
-        &self,
-        cmd_buf: &mut CmdBuf,
-        query_pool: &QueryPool,
-        buf_ix: usize,
-    ) {
-        cmd_buf.copy_buffer(&self.config_bufs[buf_ix], &self.config_buf);
-        cmd_buf.copy_buffer(&self.memory_buf_host[buf_ix], &self.memory_buf_dev);
+    ///
+    /// The compute passes are labeled, so they are timed if the command
+    /// buffer is being profiled.
+    pub unsafe fn record_coarse(&self, cmd_buf: &mut CmdBuf, buf_ix: usize) -> Result<(), Error> {
+        let session = &self.session;
+        session.upload(cmd_buf, &self.config_buf, 0, &[self.configs[buf_ix]])?;
+        session.upload(
+            cmd_buf,
+            &self.memory_buf_dev,
+            0,
+            &[self.memory_headers[buf_ix]],
+        )?;
         cmd_buf.memory_barrier();
-        cmd_buf.image_barrier(
-            &self.image_dev,
-            ImageLayout::Undefined,
-            ImageLayout::General,
-        );
         // TODO: make gradient upload optional, only if it's changed
-        cmd_buf.image_barrier(
-            &self.gradients,
-            ImageLayout::Undefined,
-            ImageLayout::BlitDst,
-        );
-        cmd_buf.copy_buffer_to_image(&self.gradient_bufs[buf_ix], &self.gradients);
-        cmd_buf.image_barrier(&self.gradients, ImageLayout::BlitDst, ImageLayout::General);
-        cmd_buf.reset_query_pool(&query_pool);
+        let ramp_data = &self.ramp_data[buf_ix];
+        if !ramp_data.is_empty() {
+            let region = ImageRegion {
+                origin: (0, 0),
+                size: (
+                    gradient::N_SAMPLES as u32,
+                    (ramp_data.len() / gradient::N_SAMPLES) as u32,
+                ),
+            };
+            session.upload_image(cmd_buf, &self.gradients, region, ramp_data)?;
+        }
         cmd_buf.begin_debug_label("Element bounding box calculation");
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(&query_pool, 0, 1));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("element"));
         self.element_stage.record(
             &mut pass,
             &self.element_code,
@@ -532,7 +526,8 @@ impl Renderer {
         pass.end();
         cmd_buf.end_debug_label();
         cmd_buf.memory_barrier();
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(&query_pool, 2, 3));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("binning"));
         pass.begin_debug_label("Clip bounding box calculation");
         self.clip_binding
             .record(&mut pass, &self.clip_code, self.n_clip as u32);
@@ -557,7 +552,8 @@ impl Renderer {
         pass.end();
         cmd_buf.begin_debug_label("Path flattening");
         cmd_buf.memory_barrier();
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(&query_pool, 4, 5));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("path_coarse"));
         pass.dispatch(
             &self.path_pipeline,
             &self.path_ds,
@@ -568,7 +564,8 @@ impl Renderer {
         cmd_buf.end_debug_label();
         cmd_buf.memory_barrier();
         cmd_buf.begin_debug_label("Backdrop propagation");
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(&query_pool, 6, 7));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("backdrop"));
         pass.dispatch(
             &self.backdrop_pipeline,
             &self.backdrop_ds,
@@ -577,10 +574,10 @@ impl Renderer {
         );
         pass.end();
         cmd_buf.end_debug_label();
-        // TODO: redo query accounting
         cmd_buf.memory_barrier();
         cmd_buf.begin_debug_label("Coarse raster");
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(&query_pool, 8, 9));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("coarse"));
         pass.dispatch(
             &self.coarse_pipeline,
             &self.coarse_ds[buf_ix],
@@ -594,21 +591,13 @@ impl Renderer {
         pass.end();
         cmd_buf.end_debug_label();
         cmd_buf.memory_barrier();
+        Ok(())
     }
 
-    pub unsafe fn record_fine(
-        &self,
-        cmd_buf: &mut CmdBuf,
-        query_pool: &QueryPool,
-        query_start: u32,
-    ) {
-        cmd_buf.reset_query_pool(&query_pool);
+    pub unsafe fn record_fine(&self, cmd_buf: &mut CmdBuf) {
         cmd_buf.begin_debug_label("Fine raster");
-        let mut pass = cmd_buf.begin_compute_pass(&ComputePassDescriptor::timer(
-            &query_pool,
-            query_start,
-            query_start + 1,
-        ));
+        let mut pass =
+            cmd_buf.begin_compute_pass(&ComputePassDescriptor::default().label("kernel4"));
         pass.dispatch(
             &self.k4_pipeline,
             &self.k4_ds,
@@ -622,7 +611,6 @@ impl Renderer {
         pass.end();
         cmd_buf.end_debug_label();
         cmd_buf.memory_barrier();
-        cmd_buf.image_barrier(&self.image_dev, ImageLayout::General, ImageLayout::BlitSrc);
     }
 
     pub unsafe fn record_readback(&self, cmd_buf: &mut CmdBuf) {
@@ -633,9 +621,10 @@ impl Renderer {
     /// Record a render pipeline.
     ///
     /// This *assumes* the buffers are adequately sized.
-    pub unsafe fn record(&self, cmd_buf: &mut CmdBuf, query_pool: &QueryPool, buf_ix: usize) {
-        self.record_coarse(cmd_buf, query_pool, buf_ix);
-        self.record_fine(cmd_buf, query_pool, 10);
+    pub unsafe fn record(&self, cmd_buf: &mut CmdBuf, buf_ix: usize) -> Result<(), Error> {
+        self.record_coarse(cmd_buf, buf_ix)?;
+        self.record_fine(cmd_buf);
+        Ok(())
     }
 
     pub fn make_image(
@@ -649,17 +638,18 @@ impl Renderer {
             if format != ImageFormat::RgbaPremul {
                 return Err("unsupported image format".into());
             }
-            let buffer = session.create_buffer_init(&buf, BufferUsage::COPY_SRC)?;
             const RGBA: piet_gpu_hal::ImageFormat = piet_gpu_hal::ImageFormat::Rgba8;
             let image = session.create_image2d(width.try_into()?, height.try_into()?, RGBA)?;
+            let region = ImageRegion {
+                origin: (0, 0),
+                size: (image.width(), image.height()),
+            };
             let mut cmd_buf = session.cmd_buf()?;
             cmd_buf.begin();
-            cmd_buf.image_barrier(&image, ImageLayout::Undefined, ImageLayout::BlitDst);
-            cmd_buf.copy_buffer_to_image(&buffer, &image);
-            cmd_buf.image_barrier(&image, ImageLayout::BlitDst, ImageLayout::General);
+            session.upload_image(&mut cmd_buf, &image, region, buf)?;
+            cmd_buf.transition(&image, ImageLayout::General);
             cmd_buf.finish();
-            // Make sure not to drop the buffer and image until the command buffer completes.
-            cmd_buf.add_resource(&buffer);
+            // Make sure not to drop the image until the command buffer completes.
             cmd_buf.add_resource(&image);
             let _ = session.run_cmd_buf(cmd_buf, &[], &[]);
             // We let the session reclaim the fence.
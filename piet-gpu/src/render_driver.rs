@@ -15,7 +15,10 @@
 // Also licensed under MIT license, at your choice.
 
 use bytemuck::Pod;
-use piet_gpu_hal::{CmdBuf, Error, Image, QueryPool, Semaphore, Session, SubmittedCmdBuf};
+use piet_gpu_hal::{
+    ChromeTrace, CmdBuf, Error, FrameProfile, Image, PendingProfile, Semaphore, Session,
+    SubmittedCmdBuf,
+};
 
 use crate::{EncodedSceneRef, MemoryHeader, PietGpuRenderContext, Renderer, SceneStats};
 
@@ -29,6 +32,8 @@ pub struct RenderDriver {
     buf_ix: usize,
     /// The index of a pending fine rasterization submission.
     pending: Option<usize>,
+    /// The trace being recorded, if any.
+    trace: Option<ChromeTrace>,
 }
 
 pub struct TargetState<'a> {
@@ -36,16 +41,17 @@ pub struct TargetState<'a> {
     pub image: &'a Image,
 }
 
+/// Timings of the stages of a frame.
 #[derive(Default, Debug)]
 pub struct TimingStats {
-    coarse: Vec<f64>,
-    fine: Vec<f64>,
+    coarse: FrameProfile,
+    fine: FrameProfile,
 }
 
 struct RenderFrame {
     cmd_buf: CmdBufState,
-    coarse_query_pool: QueryPool,
-    fine_query_pool: QueryPool,
+    /// Timer scopes of the submitted fine rasterization.
+    fine_profile: Option<PendingProfile>,
     timing_stats: TimingStats,
 }
 
@@ -58,34 +64,45 @@ enum CmdBufState {
 impl RenderDriver {
     /// Create new render driver.
     ///
-    /// Should probably be fallible.
-    ///
     /// We can get n from the renderer as well.
-    pub fn new(session: &Session, n: usize, renderer: Renderer) -> RenderDriver {
-        let frames = (0..n)
-            .map(|_| {
-                // Maybe should allocate here so it doesn't happen on first frame?
-                let cmd_buf = CmdBufState::default();
-                let coarse_query_pool =
-                    session.create_query_pool(Renderer::COARSE_QUERY_POOL_SIZE)?;
-                let fine_query_pool = session.create_query_pool(Renderer::FINE_QUERY_POOL_SIZE)?;
-                Ok(RenderFrame {
-                    cmd_buf,
-                    coarse_query_pool,
-                    fine_query_pool,
-                    timing_stats: TimingStats::default(),
-                })
-            })
-            .collect::<Result<_, Error>>()
-            .unwrap();
+    pub fn new(_session: &Session, n: usize, renderer: Renderer) -> RenderDriver {
+        let frames = Self::create_frames(n);
         RenderDriver {
             frames,
             renderer,
             buf_ix: 0,
             pending: None,
+            trace: None,
         }
     }
 
+    fn create_frames(n: usize) -> Vec<RenderFrame> {
+        (0..n)
+            .map(|_| {
+                // Maybe should allocate here so it doesn't happen on first frame?
+                let cmd_buf = CmdBufState::default();
+                RenderFrame {
+                    cmd_buf,
+                    fine_profile: None,
+                    timing_stats: TimingStats::default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Recreate all GPU resources on a new session.
+    ///
+    /// Call this after the device is lost (see [`Session::is_lost`]), with a
+    /// session for a newly created device. The scene must be uploaded again.
+    pub fn recreate(&mut self, session: &Session) -> Result<(), Error> {
+        let renderer = unsafe { self.renderer.recreate(session)? };
+        self.frames = Self::create_frames(self.frames.len());
+        self.renderer = renderer;
+        self.buf_ix = 0;
+        self.pending = None;
+        Ok(())
+    }
+
     pub fn upload_render_ctx(
         &mut self,
         session: &Session,
@@ -122,7 +139,7 @@ impl RenderDriver {
             if let Some(pending) = self.pending.take() {
                 // There might be a fine rasterization task that binds the memory buffer
                 // still in flight.
-                self.frames[pending].cmd_buf.wait();
+                self.frames[pending].cmd_buf.wait()?;
             }
             unsafe {
                 self.renderer.realloc_memory(session, estimated_needed)?;
@@ -137,17 +154,17 @@ impl RenderDriver {
         let cmd_buf = frame.cmd_buf.cmd_buf(session)?;
         unsafe {
             cmd_buf.begin();
-            // TODO: probably want to return query results as well
-            self.renderer
-                .record_coarse(cmd_buf, &frame.coarse_query_pool, self.buf_ix);
+            cmd_buf.begin_profile(session, Renderer::COARSE_SCOPES)?;
+            self.renderer.record_coarse(cmd_buf, self.buf_ix)?;
             self.renderer.record_readback(cmd_buf);
-            let cmd_buf = frame.cmd_buf.cmd_buf(session)?;
-            cmd_buf.finish_timestamps(&frame.coarse_query_pool);
+            let profile = cmd_buf.finish_profile();
             cmd_buf.host_barrier();
             cmd_buf.finish();
             frame.cmd_buf.submit(session, &[], &[])?;
-            frame.cmd_buf.wait();
-            frame.timing_stats.coarse = session.fetch_query_pool(&frame.coarse_query_pool)?;
+            frame.cmd_buf.wait()?;
+            if let Some(profile) = profile {
+                frame.timing_stats.coarse = profile.resolve(session)?;
+            }
             let mut result = Vec::new();
             // TODO: consider read method for single POD value
             self.renderer.memory_buf_readback.read(&mut result)?;
@@ -187,8 +204,8 @@ impl RenderDriver {
         let cmd_buf = frame.cmd_buf.cmd_buf(session)?;
         unsafe {
             cmd_buf.begin();
-            self.renderer
-                .record_fine(cmd_buf, &frame.fine_query_pool, 0);
+            cmd_buf.begin_profile(session, Renderer::FINE_SCOPES)?;
+            self.renderer.record_fine(cmd_buf);
         }
         let image = &self.renderer.image_dev;
         Ok(TargetState { cmd_buf, image })
@@ -204,7 +221,7 @@ impl RenderDriver {
         let frame = &mut self.frames[self.buf_ix];
         let cmd_buf = frame.cmd_buf.cmd_buf(session)?;
         unsafe {
-            cmd_buf.finish_timestamps(&frame.fine_query_pool);
+            frame.fine_profile = cmd_buf.finish_profile();
             cmd_buf.host_barrier();
             cmd_buf.finish();
             frame
@@ -215,19 +232,28 @@ impl RenderDriver {
         Ok(())
     }
 
-    unsafe fn wait_frame(&mut self, session: &Session, buf_ix: usize) {
+    unsafe fn wait_frame(&mut self, session: &Session, buf_ix: usize) -> Result<(), Error> {
         let frame = &mut self.frames[buf_ix];
-        frame.cmd_buf.wait();
-        if let Ok(stats) = session.fetch_query_pool(&frame.fine_query_pool) {
-            frame.timing_stats.fine = stats;
+        frame.cmd_buf.wait()?;
+        if let Some(profile) = frame.fine_profile.take() {
+            frame.timing_stats.fine = profile.resolve(session)?;
+            if let Some(trace) = &mut self.trace {
+                trace.add_frame(&frame.timing_stats.coarse);
+                trace.add_frame(&frame.timing_stats.fine);
+            }
         }
         if self.pending == Some(buf_ix) {
             self.pending = None;
         }
+        Ok(())
     }
 
-    pub unsafe fn wait(&mut self, session: &Session) {
-        self.wait_frame(session, self.buf_ix);
+    /// Wait for the current frame to complete.
+    ///
+    /// This fails with [`Error::DeviceLost`] if the device was lost, in which
+    /// case the driver can be moved to a new session with [`Self::recreate`].
+    pub unsafe fn wait(&mut self, session: &Session) -> Result<(), Error> {
+        self.wait_frame(session, self.buf_ix)
     }
 
     /// Move to the next buffer.
@@ -235,17 +261,37 @@ impl RenderDriver {
         self.buf_ix = (self.buf_ix + 1) % self.frames.len()
     }
 
-    pub unsafe fn get_timing_stats(&mut self, session: &Session, buf_ix: usize) -> &TimingStats {
-        self.wait_frame(session, buf_ix);
-        &self.frames[buf_ix].timing_stats
+    pub unsafe fn get_timing_stats(
+        &mut self,
+        session: &Session,
+        buf_ix: usize,
+    ) -> Result<&TimingStats, Error> {
+        self.wait_frame(session, buf_ix)?;
+        Ok(&self.frames[buf_ix].timing_stats)
+    }
+
+    /// Start recording the timings of each frame into a trace.
+    ///
+    /// A frame is added to the trace when its timing stats are resolved.
+    pub fn start_trace(&mut self) {
+        self.trace = Some(ChromeTrace::new());
+    }
+
+    /// Stop recording a trace, returning it.
+    pub fn finish_trace(&mut self) -> Option<ChromeTrace> {
+        self.trace.take()
     }
 
-    pub fn wait_all(&mut self, session: &Session) {
+    /// Wait for all frames to complete.
+    ///
+    /// On error, the remaining frames are not waited for.
+    pub fn wait_all(&mut self, session: &Session) -> Result<(), Error> {
         for buf_ix in 0..self.frames.len() {
             unsafe {
-                self.wait_frame(session, buf_ix);
+                self.wait_frame(session, buf_ix)?;
             }
         }
+        Ok(())
     }
 }
 
@@ -264,7 +310,7 @@ impl CmdBufState {
             return Ok(cmd_buf);
         }
         if let CmdBufState::Submitted(submitted) = std::mem::take(self) {
-            if let Ok(Some(cmd_buf)) = submitted.wait() {
+            if let Some(cmd_buf) = submitted.wait()? {
                 *self = CmdBufState::Ready(cmd_buf);
             }
         }
@@ -293,40 +339,41 @@ impl CmdBufState {
         }
     }
 
-    fn wait(&mut self) {
+    fn wait(&mut self) -> Result<(), Error> {
         if matches!(self, CmdBufState::Submitted(_)) {
             if let CmdBufState::Submitted(submitted) = std::mem::take(self) {
-                if let Ok(Some(cmd_buf)) = submitted.wait() {
+                if let Some(cmd_buf) = submitted.wait()? {
                     *self = CmdBufState::Ready(cmd_buf);
                 }
             }
         }
+        Ok(())
     }
 }
 
 impl TimingStats {
+    /// The timings of the coarse pipeline.
+    pub fn coarse(&self) -> &FrameProfile {
+        &self.coarse
+    }
+
+    /// The timings of fine rasterization.
+    pub fn fine(&self) -> &FrameProfile {
+        &self.fine
+    }
+
     pub fn print_summary(&self) {
-        let ts = &self.coarse;
-        println!("Element time: {:.3}ms", ts[0] * 1e3);
-        println!("Clip + bin + tile time: {:.3}ms", (ts[2] - ts[1]) * 1e3);
-        println!("Coarse path time: {:.3}ms", (ts[4] - ts[2]) * 1e3);
-        println!("Backdrop time: {:.3}ms", (ts[6] - ts[5]) * 1e3);
-        println!("Coarse raster kernel time: {:.3}ms", (ts[8] - ts[7]) * 1e3);
-        println!("Fine kernel time: {:.3}ms", self.fine[0] * 1e3);
+        for scope in self.coarse.scopes.iter().chain(&self.fine.scopes) {
+            println!("{} time: {:.3}ms", scope.label, scope.duration() * 1e3);
+        }
     }
 
     pub fn short_summary(&self) -> String {
-        let ts = &self.coarse;
-        let el = ts[0] * 1e3;
-        let cl = (ts[2] - ts[1]) * 1e3;
-        let cp = (ts[4] - ts[3]) * 1e3;
-        let bd = (ts[6] - ts[5]) * 1e3;
-        let cr = (ts[8] - ts[7]) * 1e3;
-        let fr = self.fine[0] * 1e3;
-        let total = el + cl + cp + bd + cr + fr;
-        format!(
-            "{:.3}ms :: el:{:.3}ms|cl:{:.3}ms|cp:{:.3}ms|bd:{:.3}ms|cr:{:.3}ms|fr:{:.3}ms",
-            total, el, cl, cp, bd, cr, fr
-        )
+        let scopes = self.coarse.scopes.iter().chain(&self.fine.scopes);
+        let total: f64 = scopes.clone().map(|scope| scope.duration()).sum();
+        let stages = scopes
+            .map(|scope| format!("{}:{:.3}ms", scope.label, scope.duration() * 1e3))
+            .collect::<Vec<_>>();
+        format!("{:.3}ms :: {}", total * 1e3, stages.join("|"))
     }
 }
@@ -0,0 +1,112 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for capture and replay of command streams.
+
+use piet_gpu_hal::{BackendType, BufferUsage, ImageFormat};
+
+use crate::clear::{ClearCode, ClearStage};
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const SIZE: u32 = 64;
+
+/// Capture uploads and copies, then replay them and compare the results.
+pub unsafe fn run_capture_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("capture and replay");
+    // Objects created before the capture are in it, with their contents.
+    // CPU shaders can't be captured, so there's no dispatch on that backend.
+    let n_elements = 1024;
+    let out_buf = runner
+        .session
+        .create_buffer(n_elements * 4, BufferUsage::STORAGE)
+        .unwrap();
+    let clear = (runner.backend_type() != BackendType::Cpu).then(|| {
+        let code = ClearCode::new(runner);
+        let stage = ClearStage::new_with_value(runner, n_elements, 0x42);
+        let binding = stage.bind(runner, &code, &out_buf);
+        (code, stage, binding)
+    });
+    let session = &runner.session;
+    let data: Vec<u32> = (0..SIZE * SIZE)
+        .map(|i| i.wrapping_mul(0x9e37_79b9))
+        .collect();
+    let src_buf = session
+        .create_buffer_init(&data, BufferUsage::COPY_SRC)
+        .unwrap();
+    let image = session
+        .create_image2d(SIZE, SIZE, ImageFormat::Rgba8)
+        .unwrap();
+    let mut cmd_buf = session.cmd_buf().unwrap();
+    cmd_buf.begin();
+    cmd_buf.copy_buffer_to_image(&src_buf, &image);
+    cmd_buf.finish();
+    session
+        .run_cmd_buf(cmd_buf, &[], &[])
+        .unwrap()
+        .wait()
+        .unwrap();
+    let size = src_buf.size();
+    drop(src_buf);
+
+    session.start_capture().unwrap();
+    let image_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let usage = BufferUsage::MAP_READ | BufferUsage::MAP_WRITE | BufferUsage::CLEAR;
+    let mut clear_buf = session.create_buffer(64, usage).unwrap();
+    clear_buf.write(&[7u32; 16]).unwrap();
+    let mut cmd_buf = session.cmd_buf().unwrap();
+    cmd_buf.begin();
+    cmd_buf.copy_image_to_buffer(&image, &image_buf);
+    cmd_buf.clear_buffer(&clear_buf, Some(32));
+    if let Some((code, stage, binding)) = &clear {
+        let mut pass = cmd_buf.begin_compute_pass(&Default::default());
+        stage.record(&mut pass, code, binding);
+        pass.end();
+    }
+    cmd_buf.host_barrier();
+    cmd_buf.finish();
+    session
+        .run_cmd_buf(cmd_buf, &[], &[])
+        .unwrap()
+        .wait()
+        .unwrap();
+    let capture = session.finish_capture().unwrap();
+
+    let replay = match session.replay(&capture) {
+        Ok(replay) => replay,
+        Err(e) => {
+            result.fail(format!("replay failed: {}", e));
+            return result;
+        }
+    };
+    for buf in [&image_buf, &clear_buf] {
+        let mut expected: Vec<u8> = Vec::new();
+        buf.read(&mut expected).unwrap();
+        let replayed = replay.read_buffer(session, buf.capture_id()).unwrap();
+        if replayed != expected {
+            result.fail(format!("buffer {} differs on replay", buf.capture_id()));
+        }
+    }
+    if clear.is_some() {
+        let replayed = replay.read_buffer(session, out_buf.capture_id()).unwrap();
+        if replayed.chunks(4).any(|x| x != 0x42u32.to_le_bytes()) {
+            result.fail("dispatch with a pipeline created before the capture differs");
+        }
+    }
+    result
+}
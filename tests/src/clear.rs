@@ -16,14 +16,16 @@
 
 //! Utilities (and a benchmark) for clearing buffers with compute shaders.
 
-use piet_gpu_hal::{include_shader, BindType, BufferUsage, ComputePass, DescriptorSet};
-use piet_gpu_hal::{Buffer, Pipeline};
+use piet_gpu_hal::{
+    include_shader, BindType, BufferUsage, ComputePass, CpuDispatch, DescriptorSet,
+};
+use piet_gpu_hal::{Buffer, BufferSlice, Pipeline};
 
 use crate::config::Config;
 use crate::runner::Runner;
 use crate::test_result::TestResult;
 
-const WG_SIZE: u64 = 256;
+pub const WG_SIZE: u64 = 256;
 
 /// The shader code for clearing buffers.
 pub struct ClearCode {
@@ -73,7 +75,7 @@ pub unsafe fn run_clear_test(runner: &mut Runner, config: &Config) -> TestResult
 
 impl ClearCode {
     pub unsafe fn new(runner: &mut Runner) -> ClearCode {
-        let code = include_shader!(&runner.session, "../shader/gen/clear");
+        let code = include_shader!(&runner.session, "../shader/gen/clear", clear_cpu);
         let pipeline = runner
             .session
             .create_compute_pipeline(code, &[BindType::BufReadOnly, BindType::Buffer])
@@ -108,6 +110,23 @@ impl ClearStage {
         ClearBinding { descriptor_set }
     }
 
+    /// Bind a slice of a buffer as the output.
+    pub unsafe fn bind_slice(
+        &self,
+        runner: &mut Runner,
+        code: &ClearCode,
+        out_slice: &BufferSlice,
+    ) -> ClearBinding {
+        let session = &runner.session;
+        let descriptor_set = session
+            .descriptor_set_builder()
+            .add_buffers(&[&self.config_buf])
+            .add_buffer_slices(&[out_slice])
+            .build(session, &code.pipeline)
+            .unwrap();
+        ClearBinding { descriptor_set }
+    }
+
     pub unsafe fn record(&self, pass: &mut ComputePass, code: &ClearCode, bindings: &ClearBinding) {
         let n_workgroups = (self.n_elements + WG_SIZE - 1) / WG_SIZE;
         // An issue: for clearing large buffers (>16M), we need to check the
@@ -125,7 +144,17 @@ impl ClearStage {
     }
 }
 
+/// A port of the clear shader to the CPU backend.
+pub fn clear_cpu(dispatch: &CpuDispatch) {
+    let config = dispatch.bindings[0].as_buf();
+    let (size, value) = (config[0] as usize, config[1]);
+    let mut data = dispatch.bindings[1].as_buf();
+    for x in data.iter_mut().take(size) {
+        *x = value;
+    }
+}
+
 // Verify that the data is cleared.
-fn verify(data: &[u32]) -> Option<usize> {
+pub fn verify(data: &[u32]) -> Option<usize> {
     data.iter().position(|val| *val != 0x42)
 }
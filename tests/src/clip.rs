@@ -20,7 +20,7 @@ use bytemuck::{Pod, Zeroable};
 use rand::Rng;
 
 use piet_gpu::stages::{self, ClipBinding, ClipCode, DrawMonoid};
-use piet_gpu_hal::{BufWrite, BufferUsage};
+use piet_gpu_hal::{BackendType, BufWrite, BufferUsage};
 
 use crate::{Config, Runner, TestResult};
 
@@ -40,6 +40,10 @@ struct PathBbox {
 
 pub unsafe fn clip_test(runner: &mut Runner, config: &Config) -> TestResult {
     let mut result = TestResult::new("clip");
+    if runner.backend_type() == BackendType::Cpu {
+        result.skip("No CPU port of shaders");
+        return result;
+    }
     let n_clip: u64 = config.size.choose(1 << 8, 1 << 12, 1 << 16);
     let data = ClipData::new(n_clip);
     let stage_config = data.get_config();
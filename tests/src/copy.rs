@@ -0,0 +1,117 @@
+// Copyright 2021 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for copies of buffer and image regions.
+
+use piet_gpu_hal::{BufferImageLayout, BufferUsage, ImageFormat, ImageLayout, ImageRegion};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+// A width of 64 RGBA texels gives a row pitch that is aligned for DX12.
+const SIZE: u32 = 64;
+const ROW_PITCH: u32 = SIZE * 4;
+
+const REGION: ImageRegion = ImageRegion {
+    origin: (8, 16),
+    size: (16, 8),
+};
+
+// Offsets into the readback buffer, in bytes.
+const BUF_OFFSET: u64 = 0;
+const REGION_OFFSET: u64 = 512;
+const IMAGE_REGION_OFFSET: u64 = REGION_OFFSET + (ROW_PITCH * REGION.size.1) as u64;
+const READBACK_SIZE: u64 = IMAGE_REGION_OFFSET + (ROW_PITCH * REGION.size.1) as u64;
+
+// The range copied from the source buffer, in words.
+const BUF_START: usize = 100;
+const BUF_LEN: usize = 16;
+
+pub unsafe fn run_copy_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("copy regions");
+    let session = &runner.session;
+    // Each texel holds its own index.
+    let data: Vec<u32> = (0..SIZE * SIZE).collect();
+    let src_buf = session
+        .create_buffer_init(&data, BufferUsage::COPY_SRC)
+        .unwrap();
+    let readback = session
+        .create_buffer(READBACK_SIZE, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let image = session
+        .create_image2d(SIZE, SIZE, ImageFormat::Rgba8)
+        .unwrap();
+    let image2 = session
+        .create_image2d(SIZE, SIZE, ImageFormat::Rgba8)
+        .unwrap();
+    let mut commands = runner.commands();
+    let cmd_buf = &mut commands.cmd_buf;
+    cmd_buf.copy_buffer_region(
+        &src_buf,
+        (BUF_START * 4) as u64,
+        &readback,
+        BUF_OFFSET,
+        (BUF_LEN * 4) as u64,
+    );
+    cmd_buf.image_barrier(&image, ImageLayout::Undefined, ImageLayout::BlitDst);
+    cmd_buf.image_barrier(&image2, ImageLayout::Undefined, ImageLayout::BlitDst);
+    cmd_buf.copy_buffer_to_image(&src_buf, &image);
+    cmd_buf.image_barrier(&image, ImageLayout::BlitDst, ImageLayout::BlitSrc);
+    let layout = BufferImageLayout {
+        offset: REGION_OFFSET,
+        row_pitch: ROW_PITCH,
+    };
+    cmd_buf.copy_image_region_to_buffer(&image, REGION, &readback, layout);
+    cmd_buf.copy_image_region(&image, REGION, &image2, (0, 0));
+    cmd_buf.image_barrier(&image2, ImageLayout::BlitDst, ImageLayout::BlitSrc);
+    let region2 = ImageRegion {
+        origin: (0, 0),
+        size: REGION.size,
+    };
+    let layout2 = BufferImageLayout {
+        offset: IMAGE_REGION_OFFSET,
+        row_pitch: ROW_PITCH,
+    };
+    cmd_buf.copy_image_region_to_buffer(&image2, region2, &readback, layout2);
+    runner.submit(commands);
+
+    let mut dst: Vec<u32> = Vec::new();
+    readback.read(&mut dst).unwrap();
+    let buf_words = &dst[BUF_OFFSET as usize / 4..][..BUF_LEN];
+    if let Some(i) = (0..BUF_LEN).find(|&i| buf_words[i] != (BUF_START + i) as u32) {
+        result.fail(format!("buffer region mismatch at {}", i));
+    } else if let Some(failure) = verify_region(&dst, REGION_OFFSET) {
+        result.fail(format!("image to buffer mismatch at {:?}", failure));
+    } else if let Some(failure) = verify_region(&dst, IMAGE_REGION_OFFSET) {
+        result.fail(format!("image to image mismatch at {:?}", failure));
+    }
+    result
+}
+
+/// Verify a copy of `REGION` written with `ROW_PITCH` at the given offset.
+fn verify_region(data: &[u32], offset: u64) -> Option<(u32, u32)> {
+    let words_per_row = (ROW_PITCH / 4) as usize;
+    for y in 0..REGION.size.1 {
+        for x in 0..REGION.size.0 {
+            let ix = offset as usize / 4 + y as usize * words_per_row + x as usize;
+            let expected = (REGION.origin.1 + y) * SIZE + REGION.origin.0 + x;
+            if data[ix] != expected {
+                return Some((x, y));
+            }
+        }
+    }
+    None
+}
@@ -16,7 +16,7 @@
 
 //! Tests for the piet-gpu draw object stage.
 
-use piet_gpu_hal::{BufWrite, BufferUsage};
+use piet_gpu_hal::{BackendType, BufWrite, BufferUsage};
 use rand::{seq::SliceRandom, Rng};
 
 use crate::{Config, Runner, TestResult};
@@ -47,6 +47,10 @@ struct DrawTestData {
 
 pub unsafe fn draw_test(runner: &mut Runner, config: &Config) -> TestResult {
     let mut result = TestResult::new("draw");
+    if runner.backend_type() == BackendType::Cpu {
+        result.skip("No CPU port of shaders");
+        return result;
+    }
     // TODO: implement large scan and set large to 1 << 24
     let n_tag: u64 = config.size.choose(1 << 12, 1 << 20, 1 << 22);
     let data = DrawTestData::new(n_tag);
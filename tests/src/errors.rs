@@ -0,0 +1,48 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for the error variants reported by the session.
+
+use piet_gpu_hal::{BufferUsage, Error};
+
+use crate::formats::FORMATS;
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Check that failures are reported with their specific variant, rather than
+/// as `Error::Backend`.
+pub unsafe fn run_error_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("error variants");
+    let session = &runner.session;
+    // Far more memory than any device or host has.
+    match session.create_buffer(1 << 52, BufferUsage::STORAGE) {
+        Err(Error::OutOfDeviceMemory | Error::OutOfHostMemory) => (),
+        Err(e) => result.fail(format!("oversized buffer: {:?}", e)),
+        Ok(_) => result.fail("oversized buffer succeeded"),
+    }
+    // Not all devices have a format without capabilities.
+    let unsupported = FORMATS
+        .iter()
+        .find(|format| session.format_capabilities(**format).is_empty());
+    if let Some(&format) = unsupported {
+        match session.create_image2d(16, 16, format) {
+            Err(Error::Unsupported { .. }) => (),
+            Err(e) => result.fail(format!("{:?} image: {:?}", format, e)),
+            Ok(_) => result.fail(format!("{:?} image succeeded", format)),
+        }
+    }
+    result
+}
@@ -0,0 +1,79 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for sharing memory with other APIs and processes.
+
+use std::os::unix::io::FromRawFd;
+
+use piet_gpu_hal::{BufferUsage, Error, ExternalHandleType};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Share a buffer's memory through an fd and read it back through the import.
+pub unsafe fn run_external_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("external memory");
+    let session = &runner.session;
+    let data: Vec<u32> = (0..1024).map(|i| i * 7 + 2).collect();
+    let src_buf = session
+        .create_buffer_init(&data, BufferUsage::COPY_SRC)
+        .unwrap();
+    let size = src_buf.size();
+    let usage = BufferUsage::COPY_SRC | BufferUsage::COPY_DST;
+    let handle_type = ExternalHandleType::OpaqueFd;
+    let exported = match session.create_buffer_exportable(size, usage, handle_type) {
+        Ok(buf) => buf,
+        Err(Error::Unsupported { feature }) => {
+            result.skip(format!("{} not supported", feature));
+            return result;
+        }
+        Err(e) => {
+            result.fail(format!("error creating buffer: {}", e));
+            return result;
+        }
+    };
+    let memory = match session.export_buffer(&exported, handle_type) {
+        Ok(memory) => memory,
+        Err(e) => {
+            result.fail(format!("error exporting buffer: {}", e));
+            return result;
+        }
+    };
+    let fd = memory.fd;
+    let imported = match session.import_buffer(memory, size, usage) {
+        Ok(buf) => buf,
+        Err(e) => {
+            // The fd wasn't imported, so it's still ours to close.
+            drop(std::fs::File::from_raw_fd(fd));
+            result.fail(format!("error importing buffer: {}", e));
+            return result;
+        }
+    };
+    let dst_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let mut commands = runner.commands();
+    commands.cmd_buf.copy_buffer(&src_buf, &exported);
+    commands.cmd_buf.memory_barrier();
+    commands.cmd_buf.copy_buffer(&imported, &dst_buf);
+    runner.submit(commands);
+    let mut dst: Vec<u32> = Vec::new();
+    dst_buf.read(&mut dst).unwrap();
+    if let Some(i) = (0..data.len()).find(|&i| dst[i] != data[i]) {
+        result.fail(format!("mismatch at {}", i));
+    }
+    result
+}
@@ -0,0 +1,78 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for image formats.
+
+use piet_gpu_hal::{BufferUsage, FormatCapabilities, ImageFormat, ImageLayout};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+pub const FORMATS: &[ImageFormat] = &[
+    ImageFormat::A8,
+    ImageFormat::Rgba8,
+    ImageFormat::Bgra8,
+    ImageFormat::Rgba8Srgb,
+    ImageFormat::Bgra8Srgb,
+    ImageFormat::Rgba16Float,
+    ImageFormat::R32Float,
+    ImageFormat::Rgba32Float,
+];
+
+/// Round trip data through an image of each format that supports copies.
+pub unsafe fn run_format_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("image formats");
+    // A width of 256 keeps rows aligned for DX12 in every format.
+    let (width, height) = (256, 4);
+    let mut n_tested = 0;
+    for &format in FORMATS {
+        let session = &runner.session;
+        if !session
+            .format_capabilities(format)
+            .contains(FormatCapabilities::COPY)
+        {
+            continue;
+        }
+        let size = (width * height * format.bytes_per_pixel()) as usize;
+        // Copies don't interpret the data, so any bytes will do.
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let src_buf = session
+            .create_buffer_init(&data, BufferUsage::COPY_SRC)
+            .unwrap();
+        let dst_buf = session
+            .create_buffer(size as u64, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+            .unwrap();
+        let image = session.create_image2d(width, height, format).unwrap();
+        let mut commands = runner.commands();
+        let cmd_buf = &mut commands.cmd_buf;
+        cmd_buf.image_barrier(&image, ImageLayout::Undefined, ImageLayout::BlitDst);
+        cmd_buf.copy_buffer_to_image(&src_buf, &image);
+        cmd_buf.image_barrier(&image, ImageLayout::BlitDst, ImageLayout::BlitSrc);
+        cmd_buf.copy_image_to_buffer(&image, &dst_buf);
+        runner.submit(commands);
+        let mut dst: Vec<u8> = Vec::new();
+        dst_buf.read(&mut dst).unwrap();
+        if dst != data {
+            result.fail(format!("{:?} mismatch", format));
+            return result;
+        }
+        n_tested += 1;
+    }
+    if n_tested == 0 {
+        result.skip("no copyable formats");
+    }
+    result
+}
@@ -0,0 +1,84 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for tracking of image layouts.
+
+use piet_gpu_hal::{BufferUsage, ImageFormat, ImageLayout};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const SIZE: u32 = 64;
+
+/// Copy through an image in command buffers submitted out of order, relying
+/// on tracked layouts.
+pub unsafe fn run_layout_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("image layout tracking");
+    let session = &runner.session;
+    let data: Vec<u32> = (0..SIZE * SIZE).map(|i| i ^ 0x5555).collect();
+    let src_buf = session
+        .create_buffer_init(&data, BufferUsage::COPY_SRC)
+        .unwrap();
+    let size = src_buf.size();
+    let usage = BufferUsage::MAP_READ | BufferUsage::COPY_DST;
+    let dst_bufs = [
+        session.create_buffer(size, usage).unwrap(),
+        session.create_buffer(size, usage).unwrap(),
+    ];
+    let image = session
+        .create_image2d(SIZE, SIZE, ImageFormat::Rgba8)
+        .unwrap();
+
+    // Each command buffer picks up the layout where the one recorded before
+    // it left the image.
+    let mut upload = session.cmd_buf().unwrap();
+    upload.begin();
+    upload.copy_buffer_to_image(&src_buf, &image);
+    upload.finish();
+    let mut cmd_bufs = Vec::new();
+    for dst_buf in &dst_bufs {
+        let mut cmd_buf = session.cmd_buf().unwrap();
+        cmd_buf.begin();
+        cmd_buf.copy_image_to_buffer(&image, dst_buf);
+        cmd_buf.transition(&image, ImageLayout::General);
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        cmd_bufs.push(cmd_buf);
+    }
+    session
+        .run_cmd_buf(upload, &[], &[])
+        .unwrap()
+        .wait()
+        .unwrap();
+    // Submitting in reverse order of recording needs transitions inserted.
+    while let Some(cmd_buf) = cmd_bufs.pop() {
+        session
+            .run_cmd_buf(cmd_buf, &[], &[])
+            .unwrap()
+            .wait()
+            .unwrap();
+    }
+
+    for (i, dst_buf) in dst_bufs.iter().enumerate() {
+        let mut dst: Vec<u32> = Vec::new();
+        dst_buf.read(&mut dst).unwrap();
+        if let Some(j) = (0..data.len()).find(|&j| dst[j] != data[j]) {
+            result.fail(format!("copy {} mismatch at {}", i, j));
+            break;
+        }
+    }
+    result
+}
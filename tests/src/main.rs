@@ -16,19 +16,38 @@
 
 //! Tests for piet-gpu shaders and GPU capabilities.
 
+mod capture;
 mod clear;
 mod clip;
 mod config;
+mod copy;
 mod draw;
+mod errors;
+mod formats;
+mod layout;
 mod linkedlist;
+mod logger;
+mod memory_pool;
+mod memory_report;
 mod message_passing;
 mod prefix;
 mod prefix_tree;
+mod push_constants;
+mod queues;
+mod recording;
 mod runner;
+mod slice;
+mod spec_constants;
+mod staging;
+mod subgroups;
 mod test_result;
 
+#[cfg(target_os = "linux")]
+mod external;
 #[cfg(feature = "piet-gpu")]
 mod path;
+#[cfg(feature = "wgsl")]
+mod wgsl;
 
 use clap::{App, Arg};
 use piet_gpu_hal::InstanceFlags;
@@ -77,6 +96,18 @@ fn main() {
                 .long("dx12")
                 .help("Prefer DX12 backend"),
         )
+        .arg(Arg::with_name("cpu").long("cpu").help("Use CPU backend"))
+        .arg(
+            Arg::with_name("validation")
+                .long("validation")
+                .help("Enable validation layers, failing tests with validation errors"),
+        )
+        .arg(
+            Arg::with_name("adapter")
+                .long("adapter")
+                .help("Use the first adapter whose name contains this string")
+                .takes_value(true),
+        )
         .get_matches();
     let style = if matches.is_present("verbose") {
         ReportStyle::Verbose
@@ -84,60 +115,124 @@ fn main() {
         ReportStyle::Short
     };
     let config = Config::from_matches(&matches);
+    let validation = matches.is_present("validation");
+    logger::init();
     unsafe {
-        let report = |test_result: &TestResult| {
+        let report = |mut test_result: TestResult| {
+            let n_errors = logger::take_error_count();
+            if validation && n_errors > 0 {
+                test_result.fail(format!("{} validation errors", n_errors));
+            }
             test_result.report(style);
         };
         let mut flags = InstanceFlags::empty();
         if matches.is_present("dx12") {
             flags |= InstanceFlags::DX12;
         }
-        let mut runner = Runner::new(flags);
+        if matches.is_present("cpu") {
+            flags |= InstanceFlags::CPU;
+        }
+        if validation {
+            flags |= InstanceFlags::VALIDATION;
+        }
+        let mut runner = Runner::new(flags, matches.value_of("adapter"));
+        // Errors from instance and device creation aren't attributed to a test.
+        logger::take_error_count();
         if style == ReportStyle::Verbose {
-            // TODO: get adapter name in here too
             println!("Backend: {:?}", runner.backend_type());
+            println!("Adapter: {}", runner.session.gpu_info().adapter_name);
+        }
+        report(clear::run_clear_test(&mut runner, &config));
+        if config.groups.matches("recording") {
+            report(recording::run_recording_test(&mut runner));
+        }
+        if config.groups.matches("slice") {
+            report(slice::run_slice_test(&mut runner));
+        }
+        if config.groups.matches("push_constants") {
+            report(push_constants::run_push_constant_test(&mut runner));
+        }
+        if config.groups.matches("spec_constants") {
+            report(spec_constants::run_spec_constant_test(&mut runner));
+        }
+        if config.groups.matches("subgroups") {
+            report(subgroups::run_subgroup_size_test(&mut runner));
+        }
+        if config.groups.matches("copy") {
+            report(copy::run_copy_test(&mut runner));
+        }
+        if config.groups.matches("formats") {
+            report(formats::run_format_test(&mut runner));
+        }
+        if config.groups.matches("errors") {
+            report(errors::run_error_test(&mut runner));
+        }
+        if config.groups.matches("queues") {
+            report(queues::run_queue_test(&mut runner));
+        }
+        #[cfg(target_os = "linux")]
+        if config.groups.matches("external") {
+            report(external::run_external_test(&mut runner));
+        }
+        if config.groups.matches("memory_report") {
+            report(memory_report::run_memory_report_test(&mut runner));
+        }
+        if config.groups.matches("layout") {
+            report(layout::run_layout_test(&mut runner));
+        }
+        if config.groups.matches("capture") {
+            report(capture::run_capture_test(&mut runner));
+        }
+        if config.groups.matches("staging") {
+            report(staging::run_upload_test(&mut runner));
+        }
+        if config.groups.matches("memory_pool") {
+            report(memory_pool::run_memory_pool_test(&mut runner));
+        }
+        #[cfg(feature = "wgsl")]
+        if config.groups.matches("wgsl") {
+            report(wgsl::run_wgsl_test(&mut runner));
         }
-        report(&clear::run_clear_test(&mut runner, &config));
         if config.groups.matches("prefix") {
-            report(&prefix::run_prefix_test(
+            report(prefix::run_prefix_test(
                 &mut runner,
                 &config,
                 prefix::Variant::Compatibility,
             ));
-            report(&prefix::run_prefix_test(
+            report(prefix::run_prefix_test(
                 &mut runner,
                 &config,
                 prefix::Variant::Atomic,
             ));
             if runner.session.gpu_info().has_memory_model {
-                report(&prefix::run_prefix_test(
+                report(prefix::run_prefix_test(
                     &mut runner,
                     &config,
                     prefix::Variant::Vkmm,
                 ));
             }
-            report(&prefix_tree::run_prefix_test(&mut runner, &config));
+            report(prefix_tree::run_prefix_test(&mut runner, &config));
         }
         if config.groups.matches("atomic") {
-            report(&message_passing::run_message_passing_test(
+            report(message_passing::run_message_passing_test(
                 &mut runner,
                 &config,
                 message_passing::Variant::Atomic,
             ));
             if runner.session.gpu_info().has_memory_model {
-                report(&message_passing::run_message_passing_test(
+                report(message_passing::run_message_passing_test(
                     &mut runner,
                     &config,
                     message_passing::Variant::Vkmm,
                 ));
             }
-            report(&linkedlist::run_linkedlist_test(&mut runner, &config));
+            report(linkedlist::run_linkedlist_test(&mut runner, &config));
         }
         #[cfg(feature = "piet-gpu")]
         if config.groups.matches("piet") {
-            report(&path::path_test(&mut runner, &config));
-            report(&draw::draw_test(&mut runner, &config));
-            report(&clip::clip_test(&mut runner, &config));
+            report(path::path_test(&mut runner, &config));
+            report(draw::draw_test(&mut runner, &config));
+            report(clip::clip_test(&mut runner, &config));
         }
     }
 }
@@ -0,0 +1,101 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for the pooled buffer memory allocator.
+
+use piet_gpu_hal::BufferUsage;
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const BLOCK_SIZE: u64 = 1 << 20;
+const BUF_SIZE: u64 = 4096;
+
+/// Allocate, free and reuse pooled buffers, checking the pool statistics.
+///
+/// This enables the pool on the runner's session, so later tests also use it.
+pub unsafe fn run_memory_pool_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("memory pool");
+    let session = &runner.session;
+    session.enable_memory_pool(BLOCK_SIZE).unwrap();
+    if session.enable_memory_pool(BLOCK_SIZE).is_err() {
+        result.fail("enabling the pool again with the same block size failed");
+    }
+    session.trim_memory_pool();
+    let before = session.memory_pool_stats();
+    let usage = BufferUsage::STORAGE;
+    let a = session.create_buffer(BUF_SIZE, usage).unwrap();
+    let b = session.create_buffer(BUF_SIZE, usage).unwrap();
+    let allocated = session.memory_pool_stats();
+    if allocated.n_allocations == before.n_allocations {
+        result.skip("memory pool not supported");
+        return result;
+    }
+    if session.enable_memory_pool(2 * BLOCK_SIZE).is_ok() {
+        result.fail("enabling the pool with a different block size succeeded");
+    }
+    if allocated.n_allocations != before.n_allocations + 2 {
+        result.fail(format!(
+            "expected {} allocations, got {}",
+            before.n_allocations + 2,
+            allocated.n_allocations
+        ));
+    }
+
+    drop(a);
+    let freed = session.memory_pool_stats();
+    if freed.n_allocations != before.n_allocations + 1 {
+        result.fail(format!(
+            "{} allocations after free",
+            freed.n_allocations - before.n_allocations
+        ));
+    }
+    let c = session.create_buffer(BUF_SIZE, usage).unwrap();
+    let reused = session.memory_pool_stats();
+    if reused.n_blocks != allocated.n_blocks {
+        result.fail(format!(
+            "{} blocks after reuse, expected {}",
+            reused.n_blocks, allocated.n_blocks
+        ));
+    }
+
+    // Host visible buffers get mapped blocks, even when the memory type is
+    // shared with device local buffers.
+    let mut mapped = session
+        .create_buffer(BUF_SIZE, BufferUsage::MAP_READ | BufferUsage::MAP_WRITE)
+        .unwrap();
+    let src: Vec<u32> = (0..BUF_SIZE as u32 / 4).collect();
+    if let Err(e) = mapped.write(&src) {
+        result.fail(format!("write to pooled buffer failed: {}", e));
+        return result;
+    }
+    let mut dst: Vec<u32> = Vec::new();
+    mapped.read(&mut dst).unwrap();
+    if dst != src {
+        result.fail("pooled buffer readback mismatch");
+    }
+
+    drop((b, c, mapped));
+    session.trim_memory_pool();
+    let after = session.memory_pool_stats();
+    if after.n_allocations != before.n_allocations || after.n_blocks != before.n_blocks {
+        result.fail(format!(
+            "{} allocations in {} blocks after trim, expected {} in {}",
+            after.n_allocations, after.n_blocks, before.n_allocations, before.n_blocks
+        ));
+    }
+    result
+}
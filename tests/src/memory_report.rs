@@ -0,0 +1,55 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for memory usage reporting.
+
+use piet_gpu_hal::BufferUsage;
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Check that the memory report accounts for live buffers.
+pub unsafe fn run_memory_report_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("memory report");
+    let session = &runner.session;
+    let usage = BufferUsage::STORAGE | BufferUsage::COPY_SRC | BufferUsage::INDIRECT;
+    let bytes_for_usage = || {
+        session
+            .memory_report()
+            .buffer_bytes
+            .iter()
+            .find(|(u, _)| *u == usage)
+            .map_or(0, |(_, bytes)| *bytes)
+    };
+    let size = 1 << 16;
+    let before = bytes_for_usage();
+    let buf = session.create_buffer(size, usage).unwrap();
+    let during = bytes_for_usage();
+    drop(buf);
+    let after = bytes_for_usage();
+    if during != before + size {
+        result.fail(format!("expected {} bytes, got {}", before + size, during));
+    } else if after != before {
+        result.fail(format!("{} bytes reported after drop", after - before));
+    }
+    let report = session.memory_report();
+    if let Some(budgets) = &report.budgets {
+        if budgets.len() != report.heaps.len() {
+            result.fail("budgets don't match heaps");
+        }
+    }
+    result
+}
@@ -20,7 +20,7 @@ use crate::{Config, Runner, TestResult};
 
 use bytemuck::{Pod, Zeroable};
 use piet_gpu::stages::{self, PathCode, PathEncoder, PathStage, Transform};
-use piet_gpu_hal::{BufWrite, BufferUsage};
+use piet_gpu_hal::{BackendType, BufWrite, BufferUsage};
 use rand::{prelude::ThreadRng, Rng};
 
 struct PathData {
@@ -61,6 +61,10 @@ struct Bbox {
 
 pub unsafe fn path_test(runner: &mut Runner, config: &Config) -> TestResult {
     let mut result = TestResult::new("path");
+    if runner.backend_type() == BackendType::Cpu {
+        result.skip("No CPU port of shaders");
+        return result;
+    }
 
     // TODO: implement large scans and raise limit
     let n_path: u64 = config.size.choose(1 << 12, 1 << 16, 209_000);
@@ -14,7 +14,7 @@
 //
 // Also licensed under MIT license, at your choice.
 
-use piet_gpu_hal::{include_shader, BindType, BufferUsage, DescriptorSet, ShaderCode};
+use piet_gpu_hal::{include_shader, BindType, BufferUsage, CpuDispatch, DescriptorSet, ShaderCode};
 use piet_gpu_hal::{Buffer, Pipeline};
 
 use crate::config::Config;
@@ -105,8 +105,12 @@ pub unsafe fn run_prefix_test(
 impl PrefixCode {
     unsafe fn new(runner: &mut Runner, variant: Variant) -> PrefixCode {
         let code = match variant {
-            Variant::Compatibility => include_shader!(&runner.session, "../shader/gen/prefix"),
-            Variant::Atomic => include_shader!(&runner.session, "../shader/gen/prefix_atomic"),
+            Variant::Compatibility => {
+                include_shader!(&runner.session, "../shader/gen/prefix", prefix_cpu)
+            }
+            Variant::Atomic => {
+                include_shader!(&runner.session, "../shader/gen/prefix_atomic", prefix_cpu)
+            }
             Variant::Vkmm => ShaderCode::Spv(include_bytes!("../shader/gen/prefix_vkmm.spv")),
         };
         let pipeline = runner
@@ -171,6 +175,20 @@ impl PrefixStage {
     }
 }
 
+/// A port of the prefix sum shader to the CPU backend.
+///
+/// There's no need for decoupled look-back on the CPU; this is just a
+/// sequential scan, so it also serves for all the variants.
+fn prefix_cpu(dispatch: &CpuDispatch) {
+    let inp = dispatch.bindings[0].as_buf();
+    let mut out = dispatch.bindings[1].as_buf();
+    let mut sum = 0u32;
+    for (x, y) in inp.iter().zip(out.iter_mut()) {
+        sum = sum.wrapping_add(*x);
+        *y = sum;
+    }
+}
+
 // Verify that the data is OEIS A000217
 fn verify(data: &[u32]) -> Option<usize> {
     data.iter()
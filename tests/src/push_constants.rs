@@ -0,0 +1,170 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for push constants.
+
+use piet_gpu_hal::{BackendType, BindType, BufferUsage, CpuDispatch, Error, ShaderCode};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const SHADER: &str = r#"
+struct Params {
+    scale: u32;
+    bias: u32;
+};
+
+struct Data {
+    data: array<u32>;
+};
+
+var<push_constant> params: Params;
+
+[[group(0), binding(0)]]
+var<storage, read_write> output: Data;
+
+[[stage(compute), workgroup_size(64)]]
+fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    output.data[id.x] = id.x * params.scale + params.bias;
+}
+"#;
+
+const N_ELEMENTS: u32 = 256;
+
+/// Write values computed from push constants, and check the validation of
+/// push constant blocks.
+pub unsafe fn run_push_constant_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("push constants");
+    let backend = runner.backend_type();
+    if shader(backend).is_none() {
+        result.skip("no shader for the backend");
+        return result;
+    }
+    let session = &runner.session;
+    let invalid: [(&str, &[BindType]); 3] = [
+        (
+            "a size that isn't a multiple of 4",
+            &[BindType::PushConstants(6)],
+        ),
+        ("a size over 128", &[BindType::PushConstants(132)]),
+        (
+            "two blocks",
+            &[BindType::PushConstants(8), BindType::PushConstants(8)],
+        ),
+    ];
+    for (what, push_constants) in invalid {
+        let bind_types = [&[BindType::Buffer], push_constants].concat();
+        if session
+            .create_compute_pipeline(shader(backend).unwrap(), &bind_types)
+            .is_ok()
+        {
+            result.fail(format!("pipeline with {} succeeded", what));
+        }
+    }
+
+    let bind_types = [BindType::Buffer, BindType::PushConstants(8)];
+    let pipeline = match session.create_compute_pipeline(shader(backend).unwrap(), &bind_types) {
+        Ok(pipeline) => pipeline,
+        Err(Error::Unsupported { feature }) => {
+            result.skip(format!("{} not supported", feature));
+            return result;
+        }
+        Err(e) => {
+            result.fail(format!("pipeline creation failed: {}", e));
+            return result;
+        }
+    };
+    let usage = BufferUsage::MAP_READ | BufferUsage::STORAGE;
+    let buffer = session.create_buffer(N_ELEMENTS as u64 * 4, usage).unwrap();
+    let descriptor_set = session
+        .create_simple_descriptor_set(&pipeline, &[&buffer])
+        .unwrap();
+    let indirect_buf = session.create_buffer(N_ELEMENTS as u64 * 4, usage).unwrap();
+    let indirect_set = session
+        .create_simple_descriptor_set(&pipeline, &[&indirect_buf])
+        .unwrap();
+    let args = session
+        .create_buffer_init(&[N_ELEMENTS / 64, 1, 1], BufferUsage::INDIRECT)
+        .unwrap();
+    let mut commands = runner.commands();
+    let mut pass = commands.compute_pass(0, 1);
+    let workgroup_count = (N_ELEMENTS / 64, 1, 1);
+    if pass
+        .dispatch_with_push_constants(
+            &pipeline,
+            &descriptor_set,
+            workgroup_count,
+            (64, 1, 1),
+            &[3u32, 7, 0],
+        )
+        .is_ok()
+    {
+        result.fail("dispatch with the wrong push constant size succeeded");
+    }
+    pass.dispatch_with_push_constants(
+        &pipeline,
+        &descriptor_set,
+        workgroup_count,
+        (64, 1, 1),
+        &[3u32, 7],
+    )
+    .unwrap();
+    pass.dispatch_indirect_with_push_constants(
+        &pipeline,
+        &indirect_set,
+        &args,
+        0,
+        (64, 1, 1),
+        &[5u32, 1],
+    )
+    .unwrap();
+    pass.end();
+    runner.submit(commands);
+
+    let mut dst: Vec<u32> = Vec::new();
+    buffer.read(&mut dst).unwrap();
+    if let Some(i) = (0..N_ELEMENTS).position(|i| dst[i as usize] != i * 3 + 7) {
+        result.fail(format!("failure at {}", i));
+    }
+    indirect_buf.read(&mut dst).unwrap();
+    if let Some(i) = (0..N_ELEMENTS).position(|i| dst[i as usize] != i * 5 + 1) {
+        result.fail(format!("indirect dispatch failure at {}", i));
+    }
+    result
+}
+
+/// The shader for the backend, or `None` if there isn't one.
+fn shader(backend: BackendType) -> Option<ShaderCode<'static>> {
+    match backend {
+        BackendType::Cpu => Some(ShaderCode::Cpu(push_constants_cpu)),
+        // naga can't translate push constants to HLSL.
+        BackendType::Dx12 => None,
+        _ => Some(ShaderCode::Wgsl(SHADER)),
+    }
+}
+
+/// A port of the shader to the CPU backend.
+fn push_constants_cpu(dispatch: &CpuDispatch) {
+    let word = |i: usize| {
+        let bytes = &dispatch.push_constants[i * 4..i * 4 + 4];
+        u32::from_le_bytes(bytes.try_into().unwrap())
+    };
+    let (scale, bias) = (word(0), word(1));
+    let mut data = dispatch.bindings[0].as_buf();
+    for (i, x) in data.iter_mut().enumerate() {
+        *x = i as u32 * scale + bias;
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for submissions to multiple queues.
+
+use piet_gpu_hal::{BufferUsage, QueueType};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Copy a buffer on the transfer queue, then read it back on the main queue.
+pub unsafe fn run_queue_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("queue transfer");
+    let session = &runner.session;
+    let n_words = 1024;
+    let data: Vec<u32> = (0..n_words).map(|i| i * 3 + 1).collect();
+    // Mappable, so the initial upload doesn't go through the main queue.
+    let src_buf = session
+        .create_buffer_init(&data, BufferUsage::MAP_WRITE | BufferUsage::COPY_SRC)
+        .unwrap();
+    let size = src_buf.size();
+    let mid_buf = session
+        .create_buffer(size, BufferUsage::COPY_SRC | BufferUsage::COPY_DST)
+        .unwrap();
+    let dst_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let semaphore = session.create_semaphore().unwrap();
+
+    let mut transfer = session.cmd_buf_for_queue(QueueType::Transfer).unwrap();
+    transfer.begin();
+    transfer.copy_buffer(&src_buf, &mid_buf);
+    transfer.buffer_queue_transfer(&mid_buf, QueueType::Transfer, QueueType::Main);
+    transfer.finish();
+    let transfer_submitted = session.run_cmd_buf(transfer, &[], &[&semaphore]).unwrap();
+
+    let mut main = session.cmd_buf().unwrap();
+    main.begin();
+    main.buffer_queue_transfer(&mid_buf, QueueType::Transfer, QueueType::Main);
+    main.copy_buffer(&mid_buf, &dst_buf);
+    main.host_barrier();
+    main.finish();
+    let main_submitted = session.run_cmd_buf(main, &[&semaphore], &[]).unwrap();
+    transfer_submitted.wait().unwrap();
+    main_submitted.wait().unwrap();
+
+    let mut dst: Vec<u32> = Vec::new();
+    dst_buf.read(&mut dst).unwrap();
+    if let Some(i) = (0..data.len()).find(|&i| dst[i] != data[i]) {
+        result.fail(format!("mismatch at {}", i));
+    }
+    result
+}
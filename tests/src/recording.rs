@@ -0,0 +1,84 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for the safe recording layer.
+
+use piet_gpu_hal::{include_shader, BindType, Binding, BufferUsage};
+
+use crate::clear::{clear_cpu, verify, WG_SIZE};
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Clear a buffer and copy it through the safe recording layer.
+///
+/// The copy depends on the dispatch, so this also checks that the barrier
+/// between them is inserted.
+pub unsafe fn run_recording_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("safe recording");
+    let session = &runner.session;
+    let n_elements = 1 << 12;
+    let code = include_shader!(session, "../shader/gen/clear", clear_cpu);
+    let kernel = session
+        .create_kernel(code, &[BindType::BufReadOnly, BindType::Buffer])
+        .unwrap();
+    let config_buf = session
+        .create_buffer_init(&[n_elements as u32, 0x42], BufferUsage::STORAGE)
+        .unwrap();
+    let buf_usage = BufferUsage::STORAGE | BufferUsage::COPY_SRC | BufferUsage::COPY_DST;
+    let clear_buf = session.create_buffer(n_elements * 4, buf_usage).unwrap();
+    let dst_buf = session.create_buffer(n_elements * 4, buf_usage).unwrap();
+    if session
+        .create_bindings(&kernel, &[Binding::Buffer(&config_buf)])
+        .is_ok()
+    {
+        result.fail("binding too few resources succeeded");
+        return result;
+    }
+    let bindings = session
+        .create_bindings(
+            &kernel,
+            &[Binding::Buffer(&config_buf), Binding::Buffer(&clear_buf)],
+        )
+        .unwrap();
+    // The bindings keep the config buffer alive.
+    drop(config_buf);
+
+    let mut recording = session.record().unwrap();
+    let n_workgroups = (n_elements + WG_SIZE - 1) / WG_SIZE;
+    recording
+        .dispatch(
+            &bindings,
+            (n_workgroups as u32, 1, 1),
+            (WG_SIZE as u32, 1, 1),
+        )
+        .unwrap();
+    recording.copy_buffer(&clear_buf, &dst_buf).unwrap();
+    recording.write_buffer(&dst_buf, 0, &[7u32, 8]).unwrap();
+    let readback = recording.read_buffer(&dst_buf).unwrap();
+    drop(dst_buf);
+    let submission = recording.submit().unwrap();
+    if readback.read::<u32>().is_ok() {
+        result.fail("readback succeeded before waiting");
+    }
+    submission.wait().unwrap();
+    let data = readback.read::<u32>().unwrap();
+    if data[..2] != [7, 8] {
+        result.fail(format!("write mismatch: {:?}", &data[..2]));
+    } else if let Some(failure) = verify(&data[2..]) {
+        result.fail(format!("failure at {}", failure + 2));
+    }
+    result
+}
@@ -21,20 +21,19 @@ use std::ops::RangeBounds;
 use bytemuck::Pod;
 use piet_gpu_hal::{
     BackendType, BufReadGuard, BufWriteGuard, Buffer, BufferUsage, CmdBuf, ComputePass,
-    ComputePassDescriptor, Instance, InstanceFlags, QueryPool, Session,
+    ComputePassDescriptor, Instance, InstanceFlags, PooledQueryPool, Session,
 };
 
 pub struct Runner {
     #[allow(unused)]
     instance: Instance,
     pub session: Session,
-    cmd_buf_pool: Vec<CmdBuf>,
 }
 
 /// A wrapper around command buffers.
 pub struct Commands {
     pub cmd_buf: CmdBuf,
-    query_pool: QueryPool,
+    query_pool: PooledQueryPool,
 }
 
 /// Buffer for both uploading and downloading
@@ -44,27 +43,29 @@ pub struct BufStage {
 }
 
 impl Runner {
-    pub unsafe fn new(flags: InstanceFlags) -> Runner {
+    /// Create a runner, optionally on the first adapter whose name contains
+    /// the given string.
+    pub unsafe fn new(flags: InstanceFlags, adapter: Option<&str>) -> Runner {
         let instance = Instance::new(flags).unwrap();
-        let device = instance.device().unwrap();
+        let device = match adapter {
+            Some(name) => {
+                let adapters = instance.adapters().unwrap();
+                let adapter = adapters
+                    .iter()
+                    .find(|a| a.name.contains(name))
+                    .unwrap_or_else(|| panic!("no adapter matching {:?}", name));
+                instance.device_from_adapter(adapter).unwrap()
+            }
+            None => instance.device().unwrap(),
+        };
         let session = Session::new(device);
-        let cmd_buf_pool = Vec::new();
-        Runner {
-            instance,
-            session,
-            cmd_buf_pool,
-        }
+        Runner { instance, session }
     }
 
     pub unsafe fn commands(&mut self) -> Commands {
-        let mut cmd_buf = self
-            .cmd_buf_pool
-            .pop()
-            .unwrap_or_else(|| self.session.cmd_buf().unwrap());
+        let mut cmd_buf = self.session.cmd_buf().unwrap();
         cmd_buf.begin();
-        // TODO: also pool these. But we might sort by size, as
-        // we might not always be doing two.
-        let query_pool = self.session.create_query_pool(2).unwrap();
+        let query_pool = self.session.query_pool(2).unwrap();
         cmd_buf.reset_query_pool(&query_pool);
         Commands {
             cmd_buf,
@@ -79,7 +80,7 @@ impl Runner {
         cmd_buf.host_barrier();
         cmd_buf.finish();
         let submitted = self.session.run_cmd_buf(cmd_buf, &[], &[]).unwrap();
-        self.cmd_buf_pool.extend(submitted.wait().unwrap());
+        submitted.wait().unwrap();
         let timestamps = self.session.fetch_query_pool(&query_pool).unwrap();
         timestamps.get(0).copied().unwrap_or_default()
     }
@@ -0,0 +1,65 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for binding slices of buffers.
+
+use piet_gpu_hal::BufferUsage;
+
+use crate::clear::{ClearCode, ClearStage};
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Clear a slice of a buffer, bound at an offset.
+pub unsafe fn run_slice_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("buffer slices");
+    let n_elements = 1024;
+    let offset = runner.session.gpu_info().buffer_offset_alignment;
+    let size = n_elements * 4;
+    let out_buf = runner.buf_down(offset + size + 256, BufferUsage::CLEAR);
+    let code = ClearCode::new(runner);
+    let stage = ClearStage::new_with_value(runner, n_elements, 0x42);
+    let dev_buf = &out_buf.dev_buf;
+    if dev_buf.slice(offset..=u64::MAX).is_ok() {
+        result.fail("slice ending past u64::MAX succeeded");
+    }
+    if dev_buf.slice(offset..offset).is_ok() {
+        result.fail("empty slice succeeded");
+    }
+    if dev_buf.slice(2..offset + size).is_ok() {
+        result.fail("unaligned slice succeeded");
+    }
+    let slice = dev_buf.slice(offset..offset + size).unwrap();
+    let binding = stage.bind_slice(runner, &code, &slice);
+
+    let mut commands = runner.commands();
+    commands.cmd_buf.clear_buffer(&out_buf.dev_buf, None);
+    commands.cmd_buf.memory_barrier();
+    let mut pass = commands.compute_pass(0, 1);
+    stage.record(&mut pass, &code, &binding);
+    pass.end();
+    commands.cmd_buf.memory_barrier();
+    commands.download(&out_buf);
+    runner.submit(commands);
+    let dst = out_buf.map_read(..);
+    let start = (offset / 4) as usize;
+    let cleared = start..start + n_elements as usize;
+    let expected = |i| if cleared.contains(&i) { 0x42 } else { 0 };
+    let data: &[u32] = dst.cast_slice();
+    if let Some(i) = (0..data.len()).position(|i| data[i] != expected(i)) {
+        result.fail(format!("failure at {}", i));
+    }
+    result
+}
@@ -0,0 +1,94 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for specialization constants.
+
+use piet_gpu_hal::{BackendType, BindType, BufferUsage, PipelineOptions, ShaderCode, SpecConstant};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const N_ELEMENTS: u32 = 256;
+const WG_SIZE: u32 = 64;
+
+/// Write a specialization constant, with its default and a specialized value.
+pub unsafe fn run_spec_constant_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("specialization constants");
+    let backend = runner.backend_type();
+    if shader(backend).is_none() {
+        result.skip("no shader for the backend");
+        return result;
+    }
+    let session = &runner.session;
+    let bind_types = [BindType::Buffer];
+    let default = session
+        .create_compute_pipeline(shader(backend).unwrap(), &bind_types)
+        .unwrap();
+    let constants = [SpecConstant::new(0, 42u32)];
+    let options = PipelineOptions::default().constants(&constants);
+    let specialized = session
+        .create_compute_pipeline_with_options(shader(backend).unwrap(), &bind_types, &options)
+        .unwrap();
+    let usage = BufferUsage::MAP_READ | BufferUsage::STORAGE;
+    let mut cases = Vec::new();
+    for (pipeline, value) in [(&default, 1), (&specialized, 42)] {
+        let buffer = session.create_buffer(N_ELEMENTS as u64 * 4, usage).unwrap();
+        let descriptor_set = session
+            .create_simple_descriptor_set(pipeline, &[&buffer])
+            .unwrap();
+        cases.push((pipeline, descriptor_set, buffer, value));
+    }
+
+    let mut commands = runner.commands();
+    let mut pass = commands.compute_pass(0, 1);
+    for (pipeline, descriptor_set, _, _) in &cases {
+        pass.dispatch(
+            pipeline,
+            descriptor_set,
+            (N_ELEMENTS / WG_SIZE, 1, 1),
+            (WG_SIZE, 1, 1),
+        );
+    }
+    pass.end();
+    runner.submit(commands);
+
+    for (_, _, buffer, value) in &cases {
+        let mut dst: Vec<u32> = Vec::new();
+        buffer.read(&mut dst).unwrap();
+        if let Some(i) = (0..N_ELEMENTS).position(|i| dst[i as usize] != i + value) {
+            result.fail(format!("value {}: failure at {}", value, i));
+        }
+    }
+    result
+}
+
+/// The shader for the backend, or `None` if there isn't one.
+fn shader(backend: BackendType) -> Option<ShaderCode<'static>> {
+    match backend {
+        BackendType::Vulkan => Some(ShaderCode::Spv(include_bytes!(
+            "../shader/gen/spec_constant.spv"
+        ))),
+        // Constants are defines in HLSL, so it's compiled from source.
+        BackendType::Dx12 => Some(ShaderCode::Hlsl(include_str!(
+            "../shader/gen/spec_constant.hlsl"
+        ))),
+        BackendType::Metal => Some(ShaderCode::Msl(include_str!(
+            "../shader/gen/spec_constant.msl"
+        ))),
+        // The CPU backend can't specialize shaders.
+        BackendType::Cpu => None,
+    }
+}
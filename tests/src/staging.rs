@@ -0,0 +1,115 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for uploads through the staging ring.
+
+use piet_gpu_hal::{BufferUsage, ImageFormat, ImageRegion};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const SIZE: u32 = 64;
+
+/// Upload to buffers and an image region, through staging and directly.
+pub unsafe fn run_upload_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("staged uploads");
+    let session = &runner.session;
+    let size = (SIZE * SIZE * 4) as u64;
+    let staged_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let mapped_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::MAP_WRITE)
+        .unwrap();
+    let image_buf = session
+        .create_buffer(size, BufferUsage::MAP_READ | BufferUsage::COPY_DST)
+        .unwrap();
+    let image = session
+        .create_image2d(SIZE, SIZE, ImageFormat::Rgba8)
+        .unwrap();
+    let region = ImageRegion {
+        origin: (8, 4),
+        size: (16, 8),
+    };
+    // Later rounds reuse the staging space of earlier ones.
+    for round in 0..3u32 {
+        let data: Vec<u32> = (0..SIZE * SIZE).map(|i| i ^ (round << 24)).collect();
+        let region_data: Vec<u32> = (0..16 * 8).map(|i| i | (round << 24)).collect();
+        let mut cmd_buf = session.cmd_buf().unwrap();
+        cmd_buf.begin();
+        let clear = vec![0u32; (SIZE * SIZE) as usize];
+        let full = ImageRegion {
+            origin: (0, 0),
+            size: (SIZE, SIZE),
+        };
+        session
+            .upload_image(&mut cmd_buf, &image, full, &clear)
+            .unwrap();
+        session
+            .upload_image(&mut cmd_buf, &image, region, &region_data)
+            .unwrap();
+        session.upload(&mut cmd_buf, &staged_buf, 0, &data).unwrap();
+        session
+            .upload(&mut cmd_buf, &mapped_buf, 16, &data[4..])
+            .unwrap();
+        cmd_buf.copy_image_to_buffer(&image, &image_buf);
+        cmd_buf.host_barrier();
+        cmd_buf.finish();
+        session
+            .run_cmd_buf(cmd_buf, &[], &[])
+            .unwrap()
+            .wait()
+            .unwrap();
+
+        let mut staged: Vec<u32> = Vec::new();
+        staged_buf.read(&mut staged).unwrap();
+        if staged != data {
+            result.fail(format!("staged upload mismatch in round {}", round));
+        }
+        let mut mapped: Vec<u32> = Vec::new();
+        mapped_buf.read(&mut mapped).unwrap();
+        if mapped[4..] != data[4..] {
+            result.fail(format!("mapped upload mismatch in round {}", round));
+        }
+        let mut pixels: Vec<u32> = Vec::new();
+        image_buf.read(&mut pixels).unwrap();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let (x0, y0) = region.origin;
+                let inside = (x0..x0 + 16).contains(&x) && (y0..y0 + 8).contains(&y);
+                let expected = if inside {
+                    region_data[((y - y0) * 16 + x - x0) as usize]
+                } else {
+                    0
+                };
+                if pixels[(y * SIZE + x) as usize] != expected {
+                    result.fail(format!(
+                        "image mismatch at ({}, {}) in round {}",
+                        x, y, round
+                    ));
+                    return result;
+                }
+            }
+        }
+    }
+    if session
+        .upload(&mut session.cmd_buf().unwrap(), &staged_buf, 2, &[0u32])
+        .is_ok()
+    {
+        result.fail("unaligned upload accepted");
+    }
+    result
+}
@@ -0,0 +1,73 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for subgroup size control.
+
+use piet_gpu_hal::{include_shader, BackendType, BindType, PipelineOptions};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+/// Request each supported subgroup size, and check the pipeline reports it.
+///
+/// On Metal, where the size can't be requested, check that it's reported.
+pub unsafe fn run_subgroup_size_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("subgroup size");
+    let session = &runner.session;
+    let bind_types = [BindType::BufReadOnly, BindType::Buffer];
+    if runner.backend_type() == BackendType::Metal {
+        // The SIMD-group width is chosen by the driver, but always reported.
+        let code = include_shader!(session, "../shader/gen/clear");
+        let pipeline = session.create_compute_pipeline(code, &bind_types).unwrap();
+        if pipeline.subgroup_size().is_none() {
+            result.fail("no SIMD-group width reported");
+        }
+        return result;
+    }
+    let range = match &session.gpu_info().subgroup_size {
+        Some(range) if range.control => range.clone(),
+        _ => {
+            result.skip("subgroup size control not supported");
+            return result;
+        }
+    };
+    let mut size = range.min;
+    while size <= range.max {
+        let code = include_shader!(session, "../shader/gen/clear");
+        let options = PipelineOptions::default().subgroup_size(size);
+        let pipeline = session
+            .create_compute_pipeline_with_options(code, &bind_types, &options)
+            .unwrap();
+        if pipeline.subgroup_size() != Some(size) {
+            result.fail(format!(
+                "requested size {}, got {:?}",
+                size,
+                pipeline.subgroup_size()
+            ));
+        }
+        size *= 2;
+    }
+    // A size outside the range is ignored.
+    let code = include_shader!(session, "../shader/gen/clear");
+    let options = PipelineOptions::default().subgroup_size(range.max * 2);
+    let pipeline = session
+        .create_compute_pipeline_with_options(code, &bind_types, &options)
+        .unwrap();
+    if pipeline.subgroup_size().is_some() {
+        result.fail("out of range subgroup size was honored");
+    }
+    result
+}
@@ -0,0 +1,108 @@
+// Copyright 2022 The piet-gpu authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Tests for WGSL shaders translated at pipeline creation.
+
+use piet_gpu_hal::{BackendType, BindType, BufferUsage, ShaderCode};
+
+use crate::runner::Runner;
+use crate::test_result::TestResult;
+
+const COLLATZ: &str = r#"
+struct Indices {
+    data: array<u32>;
+};
+
+[[group(0), binding(0)]]
+var<storage, read_write> indices: Indices;
+
+[[stage(compute), workgroup_size(64)]]
+fn collatz([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    var n = indices.data[id.x];
+    var i = 0u;
+    loop {
+        if (n <= 1u) {
+            break;
+        }
+        if (n % 2u == 0u) {
+            n = n / 2u;
+        } else {
+            n = 3u * n + 1u;
+        }
+        i = i + 1u;
+    }
+    indices.data[id.x] = i;
+}
+"#;
+
+const N_ELEMENTS: u32 = 256;
+
+/// Count the steps of the Collatz sequence with a WGSL shader.
+pub unsafe fn run_wgsl_test(runner: &mut Runner) -> TestResult {
+    let mut result = TestResult::new("WGSL shader");
+    if runner.backend_type() == BackendType::Cpu {
+        result.skip("WGSL shaders have no CPU port");
+        return result;
+    }
+    let session = &runner.session;
+    let pipeline =
+        match session.create_compute_pipeline(ShaderCode::Wgsl(COLLATZ), &[BindType::Buffer]) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                result.fail(format!("pipeline creation failed: {}", e));
+                return result;
+            }
+        };
+    let data: Vec<u32> = (1..=N_ELEMENTS).collect();
+    let usage = BufferUsage::MAP_READ | BufferUsage::STORAGE;
+    let buffer = session.create_buffer_init(&data, usage).unwrap();
+    let descriptor_set = session
+        .create_simple_descriptor_set(&pipeline, &[&buffer])
+        .unwrap();
+    let mut commands = runner.commands();
+    let mut pass = commands.compute_pass(0, 1);
+    pass.dispatch(
+        &pipeline,
+        &descriptor_set,
+        (N_ELEMENTS / 64, 1, 1),
+        (64, 1, 1),
+    );
+    pass.end();
+    runner.submit(commands);
+
+    let mut dst: Vec<u32> = Vec::new();
+    buffer.read(&mut dst).unwrap();
+    for (i, (&n, &steps)) in data.iter().zip(&dst).enumerate() {
+        let expected = collatz_steps(n);
+        if steps != expected {
+            result.fail(format!(
+                "element {}: {} steps, expected {}",
+                i, steps, expected
+            ));
+            break;
+        }
+    }
+    result
+}
+
+fn collatz_steps(mut n: u32) -> u32 {
+    let mut i = 0;
+    while n > 1 {
+        n = if n & 1 == 0 { n / 2 } else { 3 * n + 1 };
+        i += 1;
+    }
+    i
+}